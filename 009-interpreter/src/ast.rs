@@ -4,27 +4,43 @@ use std::collections::HashMap;
 
 use crate::lexer::Pos;
 
+// `Type::Type(t)` is the type of the type `t`.
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone, PartialEq)]
 pub enum Type {
     Unkown,
     Kind,
     Unresolved(Rc<Node>),
     Type(Rc<Type>),
-    Generic(i64, Rc<String>),
+    Var(usize),
+    // Only in the type environment, a let-bound polymorphic type:
+    Forall(Vec<usize>, Rc<Type>),
     Int,
     Real,
     Bool,
     Str,
-    Lambda(Vec<Rc<Type>>, Rc<Type>)
+    Lambda(Vec<Rc<Type>>, Rc<Type>),
+    // Fields are sorted by name:
+    Record(Vec<(Rc<String>, Rc<Type>)>),
+    List(Rc<Type>)
 }
 
 #[derive(Debug)]
 pub enum TCError {
-    Unresolvable(Pos),
-    OperandsDoNotMatch(Pos),
-    FunctionArgsDoNotMatch(Pos),
-    ExpectedBool(Pos),
-    WrongNumberOfArgs(Pos),
+    Unresolvable(Pos, Rc<String>),
+    NotAType(Pos),
+    // Expected and found type:
+    Mismatch(Pos, Type, Type),
+    // A type variable would have to contain itself:
+    InfiniteType(Pos, Type, Type),
+    OperandsDoNotMatch(Pos, BinOp, Type, Type),
+    NotAFunction(Pos, Type),
+    ExpectedBool(Pos, Type),
+    // Expected and found number of arguments:
+    WrongNumberOfArgs(Pos, usize, usize),
+    NoSuchField(Pos, Type, Rc<String>),
+    // Field access on a value whose record type is not known (yet):
+    UnknownRecord(Pos, Rc<String>),
 }
 
 impl Type {
@@ -38,63 +54,68 @@ impl Type {
                 write!(out, ">")
             },
             Self::Type(_) => write!(out, "Type"),
-            Self::Generic(id, name) => write!(out, "Kind({}:{})", id, name.as_ref()),
+            Self::Var(id) => write_var(out, *id),
+            Self::Forall(vars, ttype) => {
+                out.push_str("forall");
+                for id in vars {
+                    out.push(' ');
+                    write_var(out, *id)?;
+                }
+                out.push_str(". ");
+                ttype.to_string(out)
+            },
             Self::Int => write!(out, "Int"),
             Self::Real => write!(out, "Real"),
             Self::Bool => write!(out, "Bool"),
             Self::Str => write!(out, "Str"),
             Self::Lambda(argtypes, rettype) => {
                 out.push_str("lambda:(");
-                for i in 0..argtypes.len() {
+                for (i, argtype) in argtypes.iter().enumerate() {
                     if i != 0 { out.push_str(", "); }
-                    argtypes[i].to_string(out)?;
+                    argtype.to_string(out)?;
                 }
                 out.push_str(") => ");
                 rettype.to_string(out)?;
                 Ok(())
-            }
-        }
-    }
-
-    pub fn resolve(&self, env: &Env<Self>) -> Result<Self, TCError> {
-        match self {
-            Self::Unresolved(node) => match node.as_ref() {
-                Node::Id(md, name) => match env.lookup(name) {
-                    Some(Type::Type(t)) => t.as_ref().resolve(env),
-                    Some(Type::Kind) => Ok(Type::Kind),
-                    Some(t) => t.resolve(env),
-                    None => Err(TCError::Unresolvable(md.pos))
-                },
-                _ => unimplemented!()
-            },
-            Self::Generic(_, name) => match env.lookup(name) {
-                Some(t) => Ok(t.clone()),
-                None => Err(TCError::Unresolvable((0, 0, 0)))
             },
-            Self::Lambda(args, ret) => {
-                let mut nargs = Vec::with_capacity(args.len());
-                for arg in args {
-                    nargs.push(Rc::new(arg.resolve(env)?));
+            Self::Record(fields) => {
+                out.push('{');
+                for (i, (name, ttype)) in fields.iter().enumerate() {
+                    out.push_str(if i == 0 { " " } else { ", " });
+                    write!(out, "{}: ", name)?;
+                    ttype.to_string(out)?;
                 }
-                Ok(Type::Lambda(nargs, Rc::new(ret.resolve(env)?)))
+                out.push_str(" }");
+                Ok(())
             },
-            Self::Type(t) => Ok(t.as_ref().clone()),
-            other => Ok(other.clone())
+            Self::List(ttype) => {
+                out.push('[');
+                ttype.to_string(out)?;
+                out.push(']');
+                Ok(())
+            }
         }
     }
 }
 
+/// Type variables are printed as 'a, 'b, ..., 'z, 'a1, ...
+fn write_var(out: &mut String, id: usize) -> std::fmt::Result {
+    let letter = (b'a' + (id % 26) as u8) as char;
+    match id / 26 {
+        0 => write!(out, "'{}", letter),
+        n => write!(out, "'{}{}", letter, n)
+    }
+}
+
 pub struct Env<V> {
     // TODO: Smarter DS with less copies:
-    scopes: Vec<HashMap<String, V>>,
-    ids: i64
+    scopes: Vec<HashMap<String, V>>
 }
 
 impl<V> Env<V> {
     pub fn new() -> Self {
         Self {
-            scopes: vec![HashMap::new()],
-            ids: 0
+            scopes: vec![HashMap::new()]
         }
     }
 
@@ -106,6 +127,15 @@ impl<V> Env<V> {
         self.scopes.pop();
     }
 
+    pub fn depth(&self) -> usize {
+        self.scopes.len()
+    }
+
+    /// Drop the scopes an aborted type check did not pop.
+    pub fn truncate(&mut self, depth: usize) {
+        self.scopes.truncate(depth);
+    }
+
     pub fn lookup(&self, key: &str) -> Option<&V> {
         for scope in self.scopes.iter().rev() {
             if let Some(val) = scope.get(key) {
@@ -120,9 +150,8 @@ impl<V> Env<V> {
         scope.insert(key.to_string(), val);
     }
 
-    pub fn get_id(&mut self) -> i64 {
-        self.ids += 1;
-        return self.ids;
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.scopes.iter().flat_map(|scope| scope.values())
     }
 }
 
@@ -142,6 +171,52 @@ pub struct Metadata {
     pub ttype: Type
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    Wildcard,
+    Bind(Rc<String>),
+    Int(i64),
+    Real(f64),
+    Bool(bool),
+    Str(Rc<String>),
+    // Matches lists of exactly this length:
+    List(Vec<Pattern>),
+    // Matches records having (at least) these fields:
+    Record(Vec<(Rc<String>, Pattern)>)
+}
+
+impl Pattern {
+    pub fn to_string(&self, out: &mut String) -> std::fmt::Result {
+        match self {
+            Self::Wildcard => write!(out, "_"),
+            Self::Bind(name) => write!(out, "{}", name),
+            Self::Int(x) => write!(out, "{:?}", x),
+            Self::Real(x) => write!(out, "{:?}", x),
+            Self::Bool(x) => write!(out, "{}", x),
+            Self::Str(x) => write!(out, "{:?}", x),
+            Self::List(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i != 0 { out.push_str(", "); }
+                    item.to_string(out)?;
+                }
+                out.push(']');
+                Ok(())
+            },
+            Self::Record(fields) => {
+                out.push('{');
+                for (i, (name, pattern)) in fields.iter().enumerate() {
+                    out.push_str(if i == 0 { " " } else { ", " });
+                    write!(out, "{} = ", name)?;
+                    pattern.to_string(out)?;
+                }
+                out.push_str(" }");
+                Ok(())
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Node {
     Int(Metadata, i64),
@@ -153,7 +228,13 @@ pub enum Node {
     Call(Metadata, Box<Node>, Vec<Node>),
     LetIn(Metadata, Rc<String>, Box<Node>, Box<Node>),
     If(Metadata, Box<Node>, Box<Node>, Box<Node>),
-    Lambda(Metadata, Vec<(Rc<String>, Type)>, Rc<Node>)
+    Lambda(Metadata, Vec<(Rc<String>, Type)>, Rc<Node>),
+    Record(Metadata, Vec<(Rc<String>, Node)>),
+    RecordType(Metadata, Vec<(Rc<String>, Node)>),
+    List(Metadata, Vec<Node>),
+    Field(Metadata, Box<Node>, Rc<String>),
+    Index(Metadata, Box<Node>, Box<Node>),
+    Match(Metadata, Box<Node>, Vec<(Pattern, Node)>)
 }
 
 impl Node {
@@ -168,103 +249,34 @@ impl Node {
             Self::Call(md, _, _)     => md,
             Self::LetIn(md, _, _, _) => md,
             Self::If(md, _, _, _)    => md,
-            Self::Lambda(md, _, _)   => md
+            Self::Lambda(md, _, _)   => md,
+            Self::Record(md, _)      => md,
+            Self::RecordType(md, _)  => md,
+            Self::List(md, _)        => md,
+            Self::Field(md, _, _)    => md,
+            Self::Index(md, _, _)    => md,
+            Self::Match(md, _, _)    => md
         }
     }
 
-    pub fn check_types(&mut self, env: &mut Env<Type>) -> Result<&Type, TCError> {
+    pub fn get_metadata_mut(&mut self) -> &mut Metadata {
         match self {
-            Self::Int(md, _) | Self::Real(md, _) | Self::Bool(md, _) | Self::Str(md, _)
-                => Ok(&md.ttype),
-            Self::Id(md, id) => match env.lookup(id.as_str()) {
-                Some(ttype) => { md.ttype = ttype.clone(); Ok(&md.ttype) },
-                None => Err(TCError::Unresolvable(md.pos))
-            },
-            Self::BinOp(md, op, lhs, rhs) => {
-                let lhs = lhs.check_types(env)?;
-                let rhs = rhs.check_types(env)?;
-                let ttype = match (op, lhs.clone(), rhs.clone()) {
-                    (BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div,
-                        Type::Int, Type::Int) => Type::Int,
-                    (BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div,
-                        Type::Real, Type::Real) => Type::Real,
-                    (BinOp::Eq | BinOp::NotEq | BinOp::Lt | BinOp::Gt | BinOp::LtEq | BinOp::GtEq,
-                        Type::Int, Type::Int) => Type::Bool,
-                    (BinOp::Eq | BinOp::NotEq | BinOp::Lt | BinOp::Gt | BinOp::LtEq | BinOp::GtEq,
-                        Type::Real, Type::Real) => Type::Bool,
-                    (BinOp::And | BinOp::Or, Type::Bool, Type::Bool) => Type::Bool,
-                    (BinOp::And | BinOp::Or, Type::Int, Type::Int) => Type::Bool,
-                    (_, _, _) => { return Err(TCError::OperandsDoNotMatch(md.pos)); }
-                };
-                md.ttype = ttype;
-                Ok(&md.ttype)
-            },
-            Self::LetIn(md, name, expr1, expr2) => {
-                let ttype = expr1.check_types(env)?;
-                env.push_scope();
-                env.add(&name, ttype.clone());
-                let ttype = expr2.check_types(env)?;
-                env.pop_scope();
-                md.ttype = ttype.clone();
-                Ok(&md.ttype)
-            },
-            Self::Lambda(md, args, body) => {
-                env.push_scope();
-                let mut argtypes = Vec::with_capacity(args.len());
-                for arg in args {
-                    let ttype = match arg.1.resolve(env)? {
-                        Type::Kind => Type::Generic(env.get_id(), arg.0.clone()),
-                        other => other
-                    };
-                    argtypes.push(Rc::new(ttype.clone()));
-                    env.add(arg.0.as_str(), ttype);
-                }
-
-                let rettype = Rc::get_mut(body).unwrap().check_types(env)?;
-                env.pop_scope();
-                md.ttype = Type::Lambda(argtypes, Rc::new(rettype.clone()));
-                Ok(&md.ttype)
-            },
-            Self::Call(md, callee, args) => {
-                let calleetype = callee.check_types(env)?;
-                let (argtypes, rettype) = match calleetype {
-                    Type::Lambda(argtypes, rettype) => (argtypes, rettype),
-                    _ => { return Err(TCError::FunctionArgsDoNotMatch(md.pos)); }
-                };
-                if args.len() != argtypes.len() {
-                    return Err(TCError::WrongNumberOfArgs(md.pos));
-                }
-
-                let mut rettype = rettype.as_ref().clone();
-                env.push_scope();
-                for i in 0..args.len() {
-                    let ttype = args[i].check_types(env)?.resolve(env)?;
-                    if let Type::Generic(_, name) = argtypes[i].as_ref() {
-                        env.add(name.as_str(), ttype);
-                        rettype = rettype.resolve(env)?;
-                    } else if argtypes[i].as_ref() != &ttype {
-                        return Err(TCError::FunctionArgsDoNotMatch(md.pos));
-                    }
-                }
-                env.pop_scope();
-
-                md.ttype = rettype;
-                Ok(&md.ttype)
-            },
-            Self::If(md, cond, iftrue, iffalse) => {
-                if *cond.check_types(env)? != Type::Bool {
-                    return Err(TCError::ExpectedBool(md.pos));
-                }
-
-                let t1 = iftrue.check_types(env)?;
-                let t2 = iffalse.check_types(env)?;
-                if t1 == t2 {
-                    return Err(TCError::OperandsDoNotMatch(md.pos));
-                }
-
-                md.ttype = t1.clone();
-                Ok(&md.ttype)
-            }
+            Self::Int(md, _)         => md,
+            Self::Real(md, _)        => md,
+            Self::Bool(md, _)        => md,
+            Self::Str(md, _)         => md,
+            Self::Id(md, _)          => md,
+            Self::BinOp(md, _, _, _) => md,
+            Self::Call(md, _, _)     => md,
+            Self::LetIn(md, _, _, _) => md,
+            Self::If(md, _, _, _)    => md,
+            Self::Lambda(md, _, _)   => md,
+            Self::Record(md, _)      => md,
+            Self::RecordType(md, _)  => md,
+            Self::List(md, _)        => md,
+            Self::Field(md, _, _)    => md,
+            Self::Index(md, _, _)    => md,
+            Self::Match(md, _, _)    => md
         }
     }
 
@@ -295,9 +307,9 @@ impl Node {
                 out.push('(');
                 func.to_string(out)?;
                 out.push_str(")(");
-                for i in 0..args.len() {
+                for (i, arg) in args.iter().enumerate() {
                     if i != 0 { out.push_str(", "); }
-                    args[i].to_string(out)?;
+                    arg.to_string(out)?;
                 }
                 out.push(')');
                 Ok(())
@@ -315,19 +327,68 @@ impl Node {
                 iftrue.to_string(out)?;
                 out.push_str(") else (");
                 iffalse.to_string(out)?;
-                out.push_str(")");
+                out.push(')');
                 Ok(())
             },
             Self::Lambda(_, args, body) => {
-                out.push_str("(");
-                for i in 0..args.len() {
+                out.push('(');
+                for (i, (name, ttype)) in args.iter().enumerate() {
                     if i != 0 { out.push_str(", "); }
-                    write!(out, "{}: ", args[i].0.as_str())?;
-                    args[i].1.to_string(out)?;
+                    out.push_str(name);
+                    if *ttype != Type::Unkown {
+                        out.push_str(": ");
+                        ttype.to_string(out)?;
+                    }
                 }
                 out.push_str(") -> (");
                 body.to_string(out)?;
-                out.push_str(")");
+                out.push(')');
+                Ok(())
+            },
+            Self::Record(_, fields) | Self::RecordType(_, fields) => {
+                let sep = if let Self::Record(..) = self { " = " } else { ": " };
+                out.push('{');
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    out.push_str(if i == 0 { " " } else { ", " });
+                    write!(out, "{}{}", name, sep)?;
+                    value.to_string(out)?;
+                }
+                out.push_str(" }");
+                Ok(())
+            },
+            Self::List(_, items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i != 0 { out.push_str(", "); }
+                    item.to_string(out)?;
+                }
+                out.push(']');
+                Ok(())
+            },
+            Self::Field(_, record, name) => {
+                out.push('(');
+                record.to_string(out)?;
+                write!(out, ").{}", name)
+            },
+            Self::Index(_, list, index) => {
+                out.push('(');
+                list.to_string(out)?;
+                out.push_str(")[");
+                index.to_string(out)?;
+                out.push(']');
+                Ok(())
+            },
+            Self::Match(_, value, arms) => {
+                out.push_str("(match (");
+                value.to_string(out)?;
+                out.push_str(") {");
+                for (i, (pattern, body)) in arms.iter().enumerate() {
+                    out.push_str(if i == 0 { " " } else { ", " });
+                    pattern.to_string(out)?;
+                    out.push_str(" => ");
+                    body.to_string(out)?;
+                }
+                out.push_str(" })");
                 Ok(())
             }
         }
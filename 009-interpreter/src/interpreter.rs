@@ -1,5 +1,7 @@
 use crate::ast::*;
+use crate::lexer::Pos;
 use std::rc::Rc;
+use std::fmt::Write;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
@@ -9,59 +11,438 @@ pub enum Value {
     Str(Rc<String>),
     Type(Type),
     Kind,
-    Lambda(Vec<Rc<String>>, Rc<Node>)
+    Lambda(Vec<Rc<String>>, Rc<Node>, Scope),
+    // Fields are sorted by name, like in `Type::Record`:
+    Record(Rc<Vec<(Rc<String>, Value)>>),
+    List(Rc<Vec<Value>>)
 }
 
-impl Node {
-    pub fn run(&self, env: &mut Env<Value>) -> Value {
+impl Value {
+    pub fn to_string(&self, out: &mut String) -> std::fmt::Result {
+        match self {
+            Self::Int(x) => write!(out, "{:?}", x),
+            Self::Real(x) => write!(out, "{:?}", x),
+            Self::Bool(x) => write!(out, "{}", x),
+            Self::Str(x) => write!(out, "{:?}", x),
+            Self::Type(t) => t.to_string(out),
+            Self::Kind => write!(out, "<Kind>"),
+            Self::Lambda(args, body, _) => {
+                out.push_str("<lambda>(");
+                for (i, arg) in args.iter().enumerate() {
+                    if i != 0 { out.push_str(", "); }
+                    out.push_str(arg);
+                }
+                out.push_str(") -> ");
+                body.to_string(out)
+            },
+            Self::Record(fields) => {
+                out.push('{');
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    out.push_str(if i == 0 { " " } else { ", " });
+                    write!(out, "{} = ", name)?;
+                    value.to_string(out)?;
+                }
+                out.push_str(" }");
+                Ok(())
+            },
+            Self::List(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i != 0 { out.push_str(", "); }
+                    item.to_string(out)?;
+                }
+                out.push(']');
+                Ok(())
+            }
+        }
+    }
+}
+
+/*
+ * The environment values are evaluated in. It is persistent: adding a name
+ * creates a new scope pointing to the old one, which stays untouched. That
+ * way a lambda captures the scope it is defined in by just cloning an Rc.
+ */
+#[derive(Clone, Default)]
+pub struct Scope(Option<Rc<Binding>>);
+
+struct Binding {
+    name: Rc<String>,
+    value: Value,
+    parent: Scope
+}
+
+impl Scope {
+    pub fn new() -> Self {
+        Self(None)
+    }
+
+    pub fn add(&self, name: Rc<String>, value: Value) -> Self {
+        Self(Some(Rc::new(Binding { name, value, parent: self.clone() })))
+    }
+
+    pub fn lookup(&self, key: &str) -> Option<&Value> {
+        let mut scope = self;
+        while let Some(binding) = &scope.0 {
+            if binding.name.as_str() == key {
+                return Some(&binding.value);
+            }
+            scope = &binding.parent;
+        }
+        None
+    }
+}
+
+// Only the names, captured values may contain scopes themselves...
+impl std::fmt::Debug for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut names = f.debug_list();
+        let mut scope = self;
+        while let Some(binding) = &scope.0 {
+            names.entry(&binding.name);
+            scope = &binding.parent;
+        }
+        names.finish()
+    }
+}
+
+impl PartialEq for Scope {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false
+        }
+    }
+}
+
+impl Pattern {
+    /// The scope extended with the bound names, if the value matches.
+    fn matches(&self, value: &Value, scope: Scope) -> Option<Scope> {
+        match (self, value) {
+            (Pattern::Wildcard, _) => Some(scope),
+            (Pattern::Bind(name), value) => Some(scope.add(name.clone(), value.clone())),
+            (Pattern::Int(x), Value::Int(y)) if x == y => Some(scope),
+            (Pattern::Real(x), Value::Real(y)) if x == y => Some(scope),
+            (Pattern::Bool(x), Value::Bool(y)) if x == y => Some(scope),
+            (Pattern::Str(x), Value::Str(y)) if x == y => Some(scope),
+            (Pattern::List(patterns), Value::List(items)) if patterns.len() == items.len() =>
+                patterns.iter().zip(items.iter())
+                    .try_fold(scope, |scope, (pattern, item)| pattern.matches(item, scope)),
+            (Pattern::Record(patterns), Value::Record(fields)) =>
+                patterns.iter().try_fold(scope, |scope, (name, pattern)| {
+                    let (_, value) = fields.iter().find(|(field, _)| field == name)?;
+                    pattern.matches(value, scope)
+                }),
+            _ => None
+        }
+    }
+}
+
+fn sorted<T>(mut fields: Vec<(Rc<String>, T)>) -> Vec<(Rc<String>, T)> {
+    fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+    fields
+}
+
+/*
+ * Errors the type checker can not rule out. As it is possible to run code
+ * without checking it first, everything else ends up as `Unexpected`.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    DivisionByZero(Pos),
+    Overflow(Pos),
+    // The index and the length of the list:
+    IndexOutOfBounds(Pos, i64, usize),
+    NoMatchingPattern(Pos),
+    Unexpected(Pos, &'static str)
+}
+
+impl RuntimeError {
+    pub fn pos(&self) -> Pos {
         match self {
+            Self::DivisionByZero(pos) | Self::Overflow(pos) | Self::IndexOutOfBounds(pos, _, _)
+                | Self::NoMatchingPattern(pos) | Self::Unexpected(pos, _) => *pos
+        }
+    }
+}
+
+fn arithmetic(pos: Pos, op: BinOp, lhs: Value, rhs: Value) -> Result<Value, RuntimeError> {
+    let int = |res: Option<i64>| res.map(Value::Int).ok_or(RuntimeError::Overflow(pos));
+    match (op, lhs, rhs) {
+        (BinOp::Div, Value::Int(_), Value::Int(0)) => Err(RuntimeError::DivisionByZero(pos)),
+        (BinOp::Add, Value::Int(lhs), Value::Int(rhs)) => int(lhs.checked_add(rhs)),
+        (BinOp::Sub, Value::Int(lhs), Value::Int(rhs)) => int(lhs.checked_sub(rhs)),
+        (BinOp::Mul, Value::Int(lhs), Value::Int(rhs)) => int(lhs.checked_mul(rhs)),
+        (BinOp::Div, Value::Int(lhs), Value::Int(rhs)) => int(lhs.checked_div(rhs)),
+        (BinOp::Add, Value::Str(lhs), Value::Str(rhs)) => Ok(Value::Str(Rc::new(format!("{}{}", lhs, rhs)))),
+        (op, lhs, rhs) => {
+            let (lhs, rhs) = match (promote(&lhs), promote(&rhs)) {
+                (Some(lhs), Some(rhs)) => (lhs, rhs),
+                _ => return Err(RuntimeError::Unexpected(pos, "operands are not numbers"))
+            };
+            Ok(Value::Real(match op {
+                BinOp::Add => lhs + rhs,
+                BinOp::Sub => lhs - rhs,
+                BinOp::Mul => lhs * rhs,
+                BinOp::Div => lhs / rhs,
+                _ => unreachable!()
+            }))
+        }
+    }
+}
+
+fn compare(pos: Pos, lhs: &Value, rhs: &Value) -> Result<Option<std::cmp::Ordering>, RuntimeError> {
+    match (lhs, rhs) {
+        (Value::Int(lhs), Value::Int(rhs)) => Ok(lhs.partial_cmp(rhs)),
+        (Value::Str(lhs), Value::Str(rhs)) => Ok(lhs.partial_cmp(rhs)),
+        (lhs, rhs) => match (promote(lhs), promote(rhs)) {
+            (Some(lhs), Some(rhs)) => Ok(lhs.partial_cmp(&rhs)),
+            _ => Err(RuntimeError::Unexpected(pos, "operands can not be compared"))
+        }
+    }
+}
+
+fn equal(lhs: &Value, rhs: &Value) -> bool {
+    match (promote(lhs), promote(rhs)) {
+        (Some(lhs), Some(rhs)) => lhs == rhs,
+        _ => lhs == rhs
+    }
+}
+
+fn promote(value: &Value) -> Option<f64> {
+    match value {
+        Value::Int(x) => Some(*x as f64),
+        Value::Real(x) => Some(*x),
+        _ => None
+    }
+}
+
+impl Node {
+    pub fn run(&self, env: &Scope) -> Result<Value, RuntimeError> {
+        Ok(match self {
             Self::Int(_, x) => Value::Int(*x),
             Self::Real(_, x) => Value::Real(*x),
             Self::Bool(_, x) => Value::Bool(*x),
             Self::Str(_, x) => Value::Str(x.clone()),
-            Self::Id(_, name) => env.lookup(name.as_str())
-                .expect("typecheck should have found this...").clone(),
-            Self::BinOp(_, op, lhs, rhs) => match (op, lhs.run(env), rhs.run(env)) {
-                (BinOp::Add, Value::Int(lhs), Value::Int(rhs)) => Value::Int(lhs + rhs),
-                (BinOp::Add, Value::Real(lhs), Value::Real(rhs)) => Value::Real(lhs + rhs),
-                _ => unimplemented!()
+            Self::Id(md, name) => match env.lookup(name.as_str()) {
+                Some(value) => value.clone(),
+                None => return Err(RuntimeError::Unexpected(md.pos, "unbound name"))
+            },
+            Self::BinOp(md, op @ (BinOp::And | BinOp::Or), lhs, rhs) => {
+                // Short-circuit:
+                match (op, lhs.run(env)?) {
+                    (BinOp::And, Value::Bool(false)) => Value::Bool(false),
+                    (BinOp::Or, Value::Bool(true)) => Value::Bool(true),
+                    (_, Value::Bool(_)) => match rhs.run(env)? {
+                        Value::Bool(x) => Value::Bool(x),
+                        _ => return Err(RuntimeError::Unexpected(md.pos, "expected a Bool"))
+                    },
+                    _ => return Err(RuntimeError::Unexpected(md.pos, "expected a Bool"))
+                }
             },
-            Self::Call(_, callee, args) => match callee.run(env) {
-                Value::Lambda(argnames, body) => {
-                    env.push_scope();
-                    assert!(args.len() == argnames.len());
-                    for i in 0..args.len() {
-                        let arg = args[i].run(env);
-                        env.add(argnames[i].as_str(), arg);
+            Self::BinOp(md, op, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.run(env)?, rhs.run(env)?);
+                match op {
+                    BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div
+                        => arithmetic(md.pos, *op, lhs, rhs)?,
+                    BinOp::Eq => Value::Bool(equal(&lhs, &rhs)),
+                    BinOp::NotEq => Value::Bool(!equal(&lhs, &rhs)),
+                    BinOp::Lt => Value::Bool(compare(md.pos, &lhs, &rhs)?.is_some_and(|o| o.is_lt())),
+                    BinOp::LtEq => Value::Bool(compare(md.pos, &lhs, &rhs)?.is_some_and(|o| o.is_le())),
+                    BinOp::Gt => Value::Bool(compare(md.pos, &lhs, &rhs)?.is_some_and(|o| o.is_gt())),
+                    BinOp::GtEq => Value::Bool(compare(md.pos, &lhs, &rhs)?.is_some_and(|o| o.is_ge())),
+                    BinOp::And | BinOp::Or => unreachable!()
+                }
+            },
+            Self::Call(md, callee, args) => match callee.run(env)? {
+                Value::Lambda(argnames, body, captured) => {
+                    if args.len() != argnames.len() {
+                        return Err(RuntimeError::Unexpected(md.pos, "wrong number of arguments"));
+                    }
+                    // Arguments are evaluated in the caller's scope, the body
+                    // in the scope the lambda was defined in:
+                    let mut scope = captured;
+                    for (name, arg) in argnames.iter().zip(args) {
+                        scope = scope.add(name.clone(), arg.run(env)?);
                     }
-                    let val = body.run(env);
-                    env.pop_scope();
-                    val
+                    body.run(&scope)?
                 },
-                _ => panic!("typecheck should have found this...")
+                _ => return Err(RuntimeError::Unexpected(md.pos, "not a function"))
             },
             Self::LetIn(_, name, expr1, expr2) => {
-                let val = expr1.run(env);
-                env.push_scope();
-                env.add(name.as_str(), val);
-                let val = expr2.run(env);
-                env.pop_scope();
-                val
+                let val = expr1.run(env)?;
+                expr2.run(&env.add(name.clone(), val))?
             },
-            Self::If(_, cond, iftrue, ifflase) => match cond.run(env) {
-                Value::Bool(true) => iftrue.run(env),
-                Value::Bool(false) => ifflase.run(env),
-                _ => panic!("typecheck should have found this...")
+            Self::If(md, cond, iftrue, ifflase) => match cond.run(env)? {
+                Value::Bool(true) => iftrue.run(env)?,
+                Value::Bool(false) => ifflase.run(env)?,
+                _ => return Err(RuntimeError::Unexpected(md.pos, "expected a Bool"))
             },
             Self::Lambda(_, args, body) => {
-                let mut lambdaargs = Vec::with_capacity(args.len());
-                for i in 0..args.len() {
-                    lambdaargs.push(args[i].0.clone());
+                let argnames = args.iter().map(|arg| arg.0.clone()).collect();
+                Value::Lambda(argnames, body.clone(), env.clone())
+            },
+            Self::Record(_, fields) => {
+                let mut values = Vec::with_capacity(fields.len());
+                for (name, value) in fields {
+                    values.push((name.clone(), value.run(env)?));
                 }
-
-                Value::Lambda(lambdaargs, body.clone())
+                Value::Record(Rc::new(sorted(values)))
             },
-        }
+            Self::RecordType(md, fields) => {
+                let mut types = Vec::with_capacity(fields.len());
+                for (name, field) in fields {
+                    match field.run(env)? {
+                        Value::Type(t) => types.push((name.clone(), Rc::new(t))),
+                        _ => return Err(RuntimeError::Unexpected(md.pos, "expected a type"))
+                    }
+                }
+                Value::Type(Type::Record(sorted(types)))
+            },
+            Self::List(_, items) => {
+                let mut values = Vec::with_capacity(items.len());
+                for item in items {
+                    values.push(item.run(env)?);
+                }
+                Value::List(Rc::new(values))
+            },
+            Self::Field(md, value, name) => match value.run(env)? {
+                Value::Record(fields) => match fields.iter().find(|(field, _)| field == name) {
+                    Some((_, value)) => value.clone(),
+                    None => return Err(RuntimeError::Unexpected(md.pos, "no such field"))
+                },
+                _ => return Err(RuntimeError::Unexpected(md.pos, "not a record"))
+            },
+            Self::Index(md, list, index) => match (list.run(env)?, index.run(env)?) {
+                (Value::List(items), Value::Int(i)) => match usize::try_from(i).ok().and_then(|i| items.get(i)) {
+                    Some(item) => item.clone(),
+                    None => return Err(RuntimeError::IndexOutOfBounds(md.pos, i, items.len()))
+                },
+                _ => return Err(RuntimeError::Unexpected(md.pos, "not a list or not an Int index"))
+            },
+            Self::Match(md, value, arms) => {
+                let value = value.run(env)?;
+                for (pattern, body) in arms {
+                    if let Some(scope) = pattern.matches(&value, env.clone()) {
+                        return body.run(&scope);
+                    }
+                }
+                return Err(RuntimeError::NoMatchingPattern(md.pos))
+            },
+        })
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    // Not type checked, so ill-typed programs like `1(2)` reach the runtime errors.
+    fn try_run(code: &str) -> Result<Value, RuntimeError> {
+        let mut parser = Parser::new(Lexer::new(0, code));
+        let ast = parser.parse().unwrap();
+        let env = Scope::new().add(Rc::new("Int".to_string()), Value::Type(Type::Int));
+        ast.run(&env)
+    }
+
+    fn run(code: &str) -> Value {
+        try_run(code).unwrap()
+    }
+
+    #[test]
+    fn currying() {
+        assert_eq!(run("let add = L(a: Int) -> L(b: Int) -> a + b in add(40)(2)"), Value::Int(42));
+        assert_eq!(run("let id = L(a: Type) -> L(x: a) -> x in id(Int)(42)"), Value::Int(42));
+        assert_eq!(run("
+            let add = L(a: Int) -> L(b: Int) -> a + b in
+            let inc = add(1) in
+            let a = 100 in
+            inc(41)"), Value::Int(42));
+    }
+
+    #[test]
+    fn shadowing() {
+        assert_eq!(run("
+            let x = 1 in
+            let f = L(y: Int) -> x + y in
+            let x = 100 in
+            f(x)"), Value::Int(101));
+        assert_eq!(run("let x = 1 in let f = L(x: Int) -> x + x in f(21)"), Value::Int(42));
+        assert_eq!(run("let x = 1 in let x = x + 1 in x"), Value::Int(2));
+    }
+
+    #[test]
+    fn higher_order() {
+        assert_eq!(run("
+            let twice = L(f, x) -> f(f(x)) in
+            let n = 20 in
+            twice(L(x: Int) -> x + n, 2)"), Value::Int(42));
+        assert_eq!(run("
+            let compose = L(f, g) -> L(x) -> g(f(x)) in
+            let inc = L(x: Int) -> x + 1 in
+            let f = compose(inc, compose(inc, inc)) in
+            f(39)"), Value::Int(42));
+    }
+
+    #[test]
+    fn records_and_lists() {
+        assert_eq!(run("let p = { y = 2, x = 40 } in p.x + p.y"), Value::Int(42));
+        assert_eq!(run("{ a = { b = [1, 2, 42] } }.a.b[1 + 1]"), Value::Int(42));
+        assert_eq!(run("[L(x: Int) -> x + 1, L(x: Int) -> x + 2][1](40)"), Value::Int(42));
+        assert_eq!(run("{ b = 1, a = 2 }"), run("{ a = 2, b = 1 }"));
+    }
+
+    #[test]
+    fn matching() {
+        let classify = "let f = L(x) -> match x {
+            [] => 0,
+            [a] => a,
+            [1, b] => b + 10,
+            [a, _] => a + 20,
+            _ => 42
+        } in ";
+        assert_eq!(run(&format!("{}f([])", classify)), Value::Int(0));
+        assert_eq!(run(&format!("{}f([5])", classify)), Value::Int(5));
+        assert_eq!(run(&format!("{}f([1, 2])", classify)), Value::Int(12));
+        assert_eq!(run(&format!("{}f([2, 2])", classify)), Value::Int(22));
+        assert_eq!(run(&format!("{}f([1, 2, 3])", classify)), Value::Int(42));
+        assert_eq!(run("match { a = 1, b = { c = \"x\" } } {
+            { b = { c = \"y\" } } => 1,
+            { a = a, b = { c = \"x\" } } => a + 41,
+        }"), Value::Int(42));
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(run("1 + 2 * 3 - 8 / 2"), Value::Int(3));
+        assert_eq!(run("7 / 2"), Value::Int(3));
+        assert_eq!(run("1 + 0.5"), Value::Real(1.5));
+        assert_eq!(run("3 / 2.0"), Value::Real(1.5));
+        assert_eq!(run("1 < 2 and 2 <= 2 and 3 > 2.5 and 3 >= 3 and 1 /= 2 and 2 == 2.0"),
+            Value::Bool(true));
+        assert_eq!(run("\"foo\" + \"bar\""), Value::Str(Rc::new("foobar".to_string())));
+        assert_eq!(run("\"abc\" < \"abd\" and \"b\" > \"abc\" and \"x\" == \"x\""),
+            Value::Bool(true));
+        assert_eq!(run("[1, 2] == [1, 2] and { a = 1 } /= { a = 2 }"), Value::Bool(true));
+    }
+
+    #[test]
+    fn short_circuit() {
+        assert_eq!(run("false and 1 / 0 == 1"), Value::Bool(false));
+        assert_eq!(run("true or 1 / 0 == 1"), Value::Bool(true));
+        assert_eq!(try_run("true and 1 / 0 == 1"), Err(RuntimeError::DivisionByZero((0, 1, 12))));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(try_run("let x = 0 in\n 1 / x"), Err(RuntimeError::DivisionByZero((0, 2, 4))));
+        assert_eq!(try_run("9223372036854775807 + 1"), Err(RuntimeError::Overflow((0, 1, 21))));
+        assert_eq!(try_run("[1, 2][2]"), Err(RuntimeError::IndexOutOfBounds((0, 1, 7), 2, 2)));
+        assert_eq!(try_run("[1, 2][0 - 1]"), Err(RuntimeError::IndexOutOfBounds((0, 1, 7), -1, 2)));
+        assert_eq!(try_run("match 3 { 1 => 1, 2 => 2 }"), Err(RuntimeError::NoMatchingPattern((0, 1, 1))));
+        assert!(matches!(try_run("1(2)"), Err(RuntimeError::Unexpected((0, 1, 2), _))));
+    }
+}
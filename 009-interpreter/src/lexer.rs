@@ -17,6 +17,7 @@ pub enum Tok<'input> {
     Id(Pos, &'input str),
     Colon(Pos),
     Comma(Pos),
+    Dot(Pos),
     Assign(Pos),
     ThinArrow(Pos),
     ThickArrow(Pos),
@@ -48,10 +49,28 @@ pub enum Tok<'input> {
     If(Pos),
     Then(Pos),
     Else(Pos),
+    Match(Pos),
 
     // Special:
-    Error(Pos, String),
-    Nil
+    Error(Pos, String)
+}
+
+impl<'input> Tok<'input> {
+    pub fn pos(&self) -> Pos {
+        match self {
+            Self::Int(pos, _) | Self::Real(pos, _) | Self::Bool(pos, _) | Self::Str(pos, _)
+                | Self::Id(pos, _) | Self::Error(pos, _) => *pos,
+            Self::Colon(pos) | Self::Comma(pos) | Self::Dot(pos) | Self::Assign(pos) | Self::ThinArrow(pos)
+                | Self::ThickArrow(pos) | Self::LeftParen(pos) | Self::RightParen(pos)
+                | Self::LeftCurly(pos) | Self::RightCurly(pos) | Self::LeftSquare(pos)
+                | Self::RightSquare(pos) | Self::Lambda(pos) => *pos,
+            Self::Plus(pos) | Self::Minus(pos) | Self::Star(pos) | Self::Div(pos)
+                | Self::Equal(pos) | Self::NotEqual(pos) | Self::Lower(pos)
+                | Self::Greater(pos) | Self::LowerOrEqual(pos) | Self::GreaterOrEqual(pos) => *pos,
+            Self::And(pos) | Self::Or(pos) | Self::Let(pos) | Self::In(pos) | Self::If(pos)
+                | Self::Then(pos) | Self::Else(pos) | Self::Match(pos) => *pos
+        }
+    }
 }
 
 pub struct Lexer<'input> {
@@ -120,6 +139,7 @@ impl<'input> Iterator for Lexer<'input> {
             '*' => Some(Tok::Star(pos)),
             ':' => Some(Tok::Colon(pos)),
             ',' => Some(Tok::Comma(pos)),
+            '.' => Some(Tok::Dot(pos)),
             '(' => Some(Tok::LeftParen(pos)),
             ')' => Some(Tok::RightParen(pos)),
             '{' => Some(Tok::LeftCurly(pos)),
@@ -154,26 +174,23 @@ impl<'input> Iterator for Lexer<'input> {
                 let mut contains_dot = false;
                 let mut start = self.pos - 1;
                 let base = match self.chars.peek() {
-                    Some(&'x') => { start = self.pos + 1; self.chars.next(); 16 },
-                    Some(&'b') => { start = self.pos + 1; self.chars.next(); 2 },
+                    Some(&'x') => { self.pos += 1; self.chars.next(); start = self.pos; 16 },
+                    Some(&'b') => { self.pos += 1; self.chars.next(); start = self.pos; 2 },
                     _ => 10
                 };
 
-                loop {
-                    match self.chars.peek() {
-                        Some(c) => match *c {
-                            '0'..='9' | 'a'..='f' => {
-                                self.pos += 1;
-                                self.chars.next();
-                            },
-                            '.' => {
-                                self.pos += 1;
-                                self.chars.next();
-                                contains_dot = true;
-                            },
-                            _ => break
+                while let Some(c) = self.chars.peek() {
+                    match *c {
+                        '0'..='9' | 'a'..='f' => {
+                            self.pos += 1;
+                            self.chars.next();
+                        },
+                        '.' => {
+                            self.pos += 1;
+                            self.chars.next();
+                            contains_dot = true;
                         },
-                        None => break,
+                        _ => break
                     }
                 }
 
@@ -196,10 +213,11 @@ impl<'input> Iterator for Lexer<'input> {
                     self.pos += 1;
                     match self.chars.next() {
                         Some('"') => break,
-                        Some('\\') => unimplemented!(),
+                        Some('\\') => return Some(Tok::Error(pos,
+                            "escape sequences in string literals are not supported".to_string())),
                         Some(_) => continue,
                         None => return Some(Tok::Error(self.getpos(),
-                            format!("unexpected EOF in string literal")))
+                            "unexpected EOF in string literal".to_string()))
                     }
                 }
 
@@ -214,26 +232,18 @@ impl<'input> Iterator for Lexer<'input> {
                         Some('`') => break,
                         Some(_) => continue,
                         None => return Some(Tok::Error(self.getpos(),
-                            format!("unexpected EOF in id")))
+                            "unexpected EOF in id".to_string()))
                     }
                 }
 
                 Some(Tok::Id(pos, &self.input[start..(self.pos - 1)]))
             },
 
-            'a'..='z' | 'A'..='Z' => {
+            'a'..='z' | 'A'..='Z' | '_' => {
                 let start = self.pos - 1;
-                loop {
-                    match self.chars.peek() {
-                        Some(c) => match *c {
-                            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' => {
-                                self.pos += 1;
-                                self.chars.next();
-                            },
-                            _ => break
-                        },
-                        None => break,
-                    }
+                while let Some('a'..='z' | 'A'..='Z' | '0'..='9' | '_') = self.chars.peek() {
+                    self.pos += 1;
+                    self.chars.next();
                 }
 
                 match &self.input[start..self.pos] {
@@ -244,6 +254,7 @@ impl<'input> Iterator for Lexer<'input> {
                     "if"    => Some(Tok::If(pos)),
                     "then"  => Some(Tok::Then(pos)),
                     "else"  => Some(Tok::Else(pos)),
+                    "match" => Some(Tok::Match(pos)),
                     "true"  => Some(Tok::Bool(pos, true)),
                     "false" => Some(Tok::Bool(pos, false)),
                     "L"     => Some(Tok::Lambda(pos)),
@@ -259,7 +270,7 @@ impl<'input> Iterator for Lexer<'input> {
 mod test {
     use super::*;
 
-    const TEST_INPUT: &'static str = "hi
+    const TEST_INPUT: &str = "hi
     # hallo welt
     (42)
     let x={\"das ist ein test\"}
@@ -284,4 +295,14 @@ mod test {
         assert_eq!(lexer.next().unwrap(), Tok::Id((0, 5, 9), "hallo"));
         assert_eq!(lexer.next(), None);
     }
+
+    #[test]
+    fn errors() {
+        let mut lexer = Lexer::new(0, "0x1f 0b101 0x");
+        assert_eq!(lexer.next().unwrap(), Tok::Int((0, 1, 1), 31));
+        assert_eq!(lexer.next().unwrap(), Tok::Int((0, 1, 6), 5));
+        assert!(matches!(lexer.next(), Some(Tok::Error((0, 1, 12), _))));
+        let mut lexer = Lexer::new(0, "\"a\\nb\"");
+        assert!(matches!(lexer.next(), Some(Tok::Error((0, 1, 1), _))));
+    }
 }
@@ -1,66 +1,240 @@
+use std::io::{BufRead, IsTerminal, Read, Write};
 use std::rc::Rc;
 
 mod lexer;
 mod ast;
 mod parser;
 mod interpreter;
+mod typechecker;
 
-use interpreter::Value;
+use interpreter::{RuntimeError, Scope, Value};
+use lexer::Pos;
 
-fn main() {
-    let print_ast = false;
-    let print_type = false;
-
-    //let code = "
-    //    let add = L(a: Int, b: Int) -> a + b in
-    //    let double = L(x: Int) -> add(x, x) in double(21)
-    //";
-
-    // let code = "let add = L(a: Int, b: Int) -> a + b in add(21, 21)";
-
-    let code = "let id = L(a: Type) -> L(x: a) -> x in id(Int)(42) ";
-
-    let lexer = lexer::Lexer::new(0, code);
-    let mut parser = parser::Parser::new(lexer);
-    let mut ast = match parser.parse() {
-        Ok(ast) => {
-            if print_ast {
-                let mut buf = String::new();
-                ast.to_string(&mut buf).unwrap();
-                println!("{}", buf);
+const USAGE: &str = "usage: mini-interpreter [options] [FILE...]
+
+Evaluates every FILE (`-` for stdin) and prints its value. Without files
+the input is read from stdin, or a REPL is started if stdin is a terminal.
+
+options:
+  --ast         print the parsed AST
+  --type        print the type of each input
+  -i, --repl    start the REPL after evaluating the files
+  -h, --help    print this help
+";
+
+/*
+ * Everything that survives between two inputs: the names of the inputs
+ * (indexed by the file-id in `Pos`) and the type and value environments,
+ * which the REPL extends with every definition.
+ */
+struct Session {
+    print_ast: bool,
+    print_type: bool,
+    filenames: Vec<String>,
+    types: typechecker::Checker,
+    values: Scope
+}
+
+impl Session {
+    fn new(print_ast: bool, print_type: bool) -> Self {
+        let mut types = typechecker::Checker::new();
+        types.env.add("PI", ast::Type::Real);
+        types.env.add("Int", ast::Type::Type(Rc::new(ast::Type::Int)));
+        types.env.add("Real", ast::Type::Type(Rc::new(ast::Type::Real)));
+        types.env.add("Bool", ast::Type::Type(Rc::new(ast::Type::Bool)));
+        types.env.add("Str", ast::Type::Type(Rc::new(ast::Type::Str)));
+        types.env.add("Type", ast::Type::Kind);
+
+        let values = Scope::new()
+            .add(Rc::new("PI".to_string()), Value::Real(std::f64::consts::PI))
+            .add(Rc::new("Int".to_string()), Value::Type(ast::Type::Int))
+            .add(Rc::new("Real".to_string()), Value::Type(ast::Type::Real))
+            .add(Rc::new("Bool".to_string()), Value::Type(ast::Type::Bool))
+            .add(Rc::new("Str".to_string()), Value::Type(ast::Type::Str))
+            .add(Rc::new("Type".to_string()), Value::Kind);
+
+        Self { print_ast, print_type, filenames: Vec::new(), types, values }
+    }
+
+    fn location(&self, pos: Pos) -> String {
+        let (fileid, line, col) = pos;
+        let filename = self.filenames.get(fileid as usize).map_or("?", |name| name.as_str());
+        format!("{}:{}:{}", filename, line, col)
+    }
+
+    fn parser_error(&self, err: parser::Error) -> String {
+        match err {
+            parser::Error::Lexer(pos, msg) =>
+                format!("{}: Lexer Error: {}", self.location(pos), msg),
+            parser::Error::Message(msg, pos) =>
+                format!("{}: Parser Error: {}", self.location(pos), msg),
+            parser::Error::UnexpectedToken(tok, expected) =>
+                format!("{}: Parser Error: expected {:?}, found {:?}",
+                    self.location(tok.pos()), expected, tok),
+            parser::Error::UnexpectedEOF =>
+                "Parser Error: unexpected end of input".to_string()
+        }
+    }
+
+    fn type_error(&self, err: ast::TCError) -> String {
+        use ast::TCError;
+        let show = |ttype: &ast::Type| {
+            let mut buf = String::new();
+            ttype.to_string(&mut buf).unwrap();
+            buf
+        };
+        let (pos, msg) = match err {
+            TCError::Unresolvable(pos, name) => (pos, format!("unresolvable name: {}", name)),
+            TCError::NotAType(pos) => (pos, "expected a type".to_string()),
+            TCError::Mismatch(pos, expected, found) =>
+                (pos, format!("expected {}, found {}", show(&expected), show(&found))),
+            TCError::InfiniteType(pos, var, ttype) =>
+                (pos, format!("infinite type: {} occurs in {}", show(&var), show(&ttype))),
+            TCError::OperandsDoNotMatch(pos, op, lhs, rhs) =>
+                (pos, format!("operator {:?} can not be applied to {} and {}",
+                    op, show(&lhs), show(&rhs))),
+            TCError::NotAFunction(pos, ttype) =>
+                (pos, format!("{} is not a function", show(&ttype))),
+            TCError::ExpectedBool(pos, ttype) =>
+                (pos, format!("expected Bool, found {}", show(&ttype))),
+            TCError::WrongNumberOfArgs(pos, expected, found) =>
+                (pos, format!("expected {} argument(s), found {}", expected, found)),
+            TCError::NoSuchField(pos, ttype, name) =>
+                (pos, format!("{} has no field {}", show(&ttype), name)),
+            TCError::UnknownRecord(pos, name) =>
+                (pos, format!("can not access field {} of a value of unknown type, \
+                    try annotating it", name)),
+        };
+        format!("{}: Type Error: {}", self.location(pos), msg)
+    }
+
+    fn runtime_error(&self, err: RuntimeError) -> String {
+        let msg = match &err {
+            RuntimeError::DivisionByZero(_) => "division by zero".to_string(),
+            RuntimeError::Overflow(_) => "integer overflow".to_string(),
+            RuntimeError::IndexOutOfBounds(_, index, len) =>
+                format!("index {} is out of bounds for a list of length {}", index, len),
+            RuntimeError::NoMatchingPattern(_) => "no pattern matches the value".to_string(),
+            RuntimeError::Unexpected(_, msg) => msg.to_string()
+        };
+        format!("{}: Runtime Error: {}", self.location(err.pos()), msg)
+    }
+
+    /// Evaluate one input: a file, stdin or a line of the REPL. Only the
+    /// REPL allows definitions (`let x = 42` without `in`).
+    fn eval(&mut self, name: String, code: &str, repl: bool) -> Result<Value, String> {
+        let fileid = u16::try_from(self.filenames.len())
+            .map_err(|_| format!("{}: too many inputs in one session", name))?;
+        self.filenames.push(name);
+
+        let mut parser = parser::Parser::new(lexer::Lexer::new(fileid, code));
+        let parsed = match repl {
+            true => parser.parse_definition(),
+            false => parser.parse().map(|ast| (None, ast))
+        };
+        let (defines, mut ast) = parsed.map_err(|e| self.parser_error(e))?;
+        if self.print_ast {
+            let mut buf = String::new();
+            ast.to_string(&mut buf).unwrap();
+            println!("{}", buf);
+        }
+
+        let ttype = self.types.check(&mut ast).map_err(|e| self.type_error(e))?;
+        if self.print_type {
+            let mut buf = String::new();
+            ttype.to_string(&mut buf).unwrap();
+            println!(": {}", buf);
+        }
+
+        let value = ast.run(&self.values).map_err(|e| self.runtime_error(e))?;
+        if let Some(name) = defines {
+            self.types.define(&name, &ttype);
+            self.values = self.values.add(name, value.clone());
+        }
+        Ok(value)
+    }
+
+    fn repl(&mut self) {
+        let stdin = std::io::stdin();
+        let mut lines = stdin.lock().lines();
+        let mut lineno = 0;
+        loop {
+            print!("> ");
+            std::io::stdout().flush().unwrap();
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                Some(Err(e)) => { eprintln!("{}", e); break },
+                None => { println!(); break }
+            };
+
+            lineno += 1;
+            if line.trim().is_empty() {
+                continue;
             }
-            ast
-        },
-        Err(e) => {
-            eprintln!("Parser/Lexer Error: {:?}", e);
-            std::process::exit(1);
+            match self.eval(format!("<repl:{}>", lineno), &line, true) {
+                Ok(value) => println!("{}", show(&value)),
+                Err(e) => eprintln!("{}", e)
+            }
+        }
+    }
+}
+
+fn show(value: &Value) -> String {
+    let mut buf = String::new();
+    value.to_string(&mut buf).unwrap();
+    buf
+}
+
+fn main() {
+    let mut print_ast = false;
+    let mut print_type = false;
+    let mut repl = false;
+    let mut files = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--ast" => print_ast = true,
+            "--type" => print_type = true,
+            "-i" | "--repl" => repl = true,
+            "-h" | "--help" => { print!("{}", USAGE); return },
+            "-" => files.push(arg),
+            flag if flag.starts_with('-') => {
+                eprint!("unknown option: {}\n\n{}", flag, USAGE);
+                std::process::exit(1);
+            },
+            _ => files.push(arg)
         }
-    };
-
-    let mut env = ast::Env::<ast::Type>::new();
-    env.push_scope();
-    env.add("PI", ast::Type::Int);
-    env.add("Int", ast::Type::Type(Rc::new(ast::Type::Int)));
-    env.add("Type", ast::Type::Kind);
-    let _ = match ast.check_types(&mut env) {
-        Ok(ttype) => {
-            if print_type {
-                println!("{:?}", ttype);
+    }
+
+    if files.is_empty() && !repl {
+        match std::io::stdin().is_terminal() {
+            true => repl = true,
+            false => files.push("-".to_string())
+        }
+    }
+
+    let mut session = Session::new(print_ast, print_type);
+    for file in files {
+        let (name, code) = match file.as_str() {
+            "-" => {
+                let mut code = String::new();
+                std::io::stdin().read_to_string(&mut code).map(|_| ("<stdin>".to_string(), code))
+            },
+            _ => std::fs::read_to_string(&file).map(|code| (file.clone(), code))
+        }.unwrap_or_else(|e| {
+            eprintln!("{}: {}", file, e);
+            std::process::exit(1);
+        });
+
+        match session.eval(name, &code, false) {
+            Ok(value) => println!("{}", show(&value)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
             }
-            ttype
-        },
-        Err(e) => {
-            eprintln!("Type Error: {:?}", e);
-            std::process::exit(2);
-        },
-    };
-    env.pop_scope();
-
-    let mut env = ast::Env::<Value>::new();
-    env.push_scope();
-    env.add("PI", Value::Real(std::f64::consts::PI));
-    env.add("Int", Value::Type(ast::Type::Int));
-    env.add("Type", Value::Kind);
-    println!("{:?}", ast.run(&mut env));
-    env.pop_scope();
+        }
+    }
+
+    if repl {
+        session.repl();
+    }
 }
@@ -1,4 +1,4 @@
-use crate::ast::{Node, Type, Metadata, BinOp};
+use crate::ast::{Node, Type, Metadata, BinOp, Pattern};
 use crate::lexer::{Lexer, Pos, Tok, NULLPOS};
 
 use std::rc::Rc;
@@ -12,6 +12,7 @@ pub struct Parser<'input> {
 
 #[derive(Debug)]
 pub enum Error<'input> {
+    Lexer(Pos, String),
     Message(&'static str, Pos),
     UnexpectedToken(Tok<'input>, Tok<'input>),
     UnexpectedEOF
@@ -27,19 +28,42 @@ impl<'input> Parser<'input> {
 
     pub fn parse(&mut self) -> Result<Node, Error<'input>> {
         let node = self.parse_expr()?;
-        if !self.lexer.next().is_none() {
-            return Err(Error::Message("expected EOF, found trailing tokens", (0, 0, 0)))
-        }
+        self.expect_eof()?;
         Ok(node)
     }
 
+    /// Parse an expression or a definition without body (`let x = 42`), used by the REPL.
+    pub fn parse_definition(&mut self) -> Result<(Option<Rc<String>>, Node), Error<'input>> {
+        let pos = match self.lexer.peek() {
+            Some(&Tok::Let(pos)) => pos,
+            _ => return Ok((None, self.parse()?))
+        };
+
+        let (id, named) = self.parse_let_binding()?;
+        if self.lexer.peek().is_none() {
+            return Ok((Some(id), named));
+        }
+        self.expect(Tok::In(NULLPOS))?;
+        let body = self.parse_expr()?;
+        self.expect_eof()?;
+        Ok((None, Node::LetIn(
+                Metadata{ pos, ttype: Type::Unkown }, id, Box::new(named), Box::new(body))))
+    }
+
+    fn expect_eof(&mut self) -> Result<(), Error<'input>> {
+        match self.lexer.next() {
+            Some(tok) => Err(Error::Message("expected EOF, found trailing tokens", tok.pos())),
+            None => Ok(())
+        }
+    }
+
     fn stringify(&mut self, str: &'input str) -> Rc<String> {
         if let Some(res) = self.string_pool.get(&str) {
-            return res.clone();
+            res.clone()
         } else {
             let res = Rc::new(String::from(str));
             self.string_pool.insert(str, res.clone());
-            return res
+            res
         }
     }
 
@@ -70,6 +94,7 @@ impl<'input> Parser<'input> {
         match (tok, expected) {
             (Tok::LeftParen(_), Tok::LeftParen(_)) => Ok(()),
             (Tok::RightParen(_), Tok::RightParen(_)) => Ok(()),
+            (Tok::LeftCurly(_), Tok::LeftCurly(_)) => Ok(()),
             (Tok::RightCurly(_), Tok::RightCurly(_)) => Ok(()),
             (Tok::RightSquare(_), Tok::RightSquare(_)) => Ok(()),
             (Tok::Colon(_), Tok::Colon(_)) => Ok(()),
@@ -79,6 +104,7 @@ impl<'input> Parser<'input> {
             (Tok::Else(_), Tok::Else(_)) => Ok(()),
             (Tok::Assign(_), Tok::Assign(_)) => Ok(()),
             (Tok::ThinArrow(_), Tok::ThinArrow(_)) => Ok(()),
+            (Tok::ThickArrow(_), Tok::ThickArrow(_)) => Ok(()),
             (tok, expected) => Err(Error::UnexpectedToken(tok, expected))
         }
     }
@@ -87,18 +113,11 @@ impl<'input> Parser<'input> {
     pub fn parse_expr(&mut self) -> Result<Node, Error<'input>> {
         match self.lexer.peek() {
             Some(&Tok::Let(pos)) => {
-                self.lexer.next();
-                if let Some(Tok::Id(_, id)) = self.lexer.next() {
-                    self.expect(Tok::Assign(NULLPOS))?;
-                    let named = self.parse_expr_lvl1(0)?;
-                    self.expect(Tok::In(NULLPOS))?;
-                    let body = self.parse_expr()?;
-                    Ok(Node::LetIn(
-                            Metadata{ pos, ttype: Type::Unkown },
-                            self.stringify(id), Box::new(named), Box::new(body)))
-                } else {
-                    Err(Error::UnexpectedToken(Tok::Nil, Tok::Id(NULLPOS, "<name-of-let>")))
-                }
+                let (id, named) = self.parse_let_binding()?;
+                self.expect(Tok::In(NULLPOS))?;
+                let body = self.parse_expr()?;
+                Ok(Node::LetIn(
+                        Metadata{ pos, ttype: Type::Unkown }, id, Box::new(named), Box::new(body)))
             },
             Some(&Tok::If(pos)) => {
                 self.lexer.next();
@@ -111,19 +130,113 @@ impl<'input> Parser<'input> {
                         Metadata{ pos, ttype: Type::Unkown },
                         Box::new(cond), Box::new(iftrue), Box::new(iffalse)))
             },
+            Some(&Tok::Match(pos)) => {
+                self.lexer.next();
+                let value = self.parse_expr_lvl1(0)?;
+                self.expect(Tok::LeftCurly(NULLPOS))?;
+                let mut arms = Vec::new();
+                loop {
+                    if let Some(&Tok::RightCurly(_)) = self.lexer.peek() {
+                        self.lexer.next();
+                        break;
+                    }
+
+                    if !arms.is_empty() {
+                        self.expect(Tok::Comma(NULLPOS))?;
+                        // Allow a trailing comma:
+                        if let Some(&Tok::RightCurly(_)) = self.lexer.peek() {
+                            continue;
+                        }
+                    }
+
+                    let pattern = self.parse_pattern()?;
+                    self.expect(Tok::ThickArrow(NULLPOS))?;
+                    arms.push((pattern, self.parse_expr()?));
+                }
+                Ok(Node::Match(Metadata{ pos, ttype: Type::Unkown }, Box::new(value), arms))
+            },
             _ => self.parse_expr_lvl1(0)
         }
     }
 
+    /// Parse the pattern of a match arm...
+    fn parse_pattern(&mut self) -> Result<Pattern, Error<'input>> {
+        match self.lexer.next() {
+            Some(Tok::Int(_, x)) => Ok(Pattern::Int(x)),
+            Some(Tok::Real(_, x)) => Ok(Pattern::Real(x)),
+            Some(Tok::Bool(_, x)) => Ok(Pattern::Bool(x)),
+            Some(Tok::Str(_, str)) => Ok(Pattern::Str(self.stringify(str))),
+            Some(Tok::Id(_, "_")) => Ok(Pattern::Wildcard),
+            Some(Tok::Id(_, id)) => Ok(Pattern::Bind(self.stringify(id))),
+            Some(Tok::LeftSquare(_)) => {
+                let mut items = Vec::new();
+                loop {
+                    if let Some(&Tok::RightSquare(_)) = self.lexer.peek() {
+                        self.lexer.next();
+                        break;
+                    }
+
+                    if !items.is_empty() {
+                        self.expect(Tok::Comma(NULLPOS))?;
+                    }
+
+                    items.push(self.parse_pattern()?);
+                }
+                Ok(Pattern::List(items))
+            },
+            Some(Tok::LeftCurly(pos)) => {
+                let mut fields = Vec::new();
+                loop {
+                    if let Some(&Tok::RightCurly(_)) = self.lexer.peek() {
+                        self.lexer.next();
+                        break;
+                    }
+
+                    if !fields.is_empty() {
+                        self.expect(Tok::Comma(NULLPOS))?;
+                    }
+
+                    let name = self.parse_field_name(&fields, pos)?;
+                    self.expect(Tok::Assign(NULLPOS))?;
+                    fields.push((name, self.parse_pattern()?));
+                }
+                Ok(Pattern::Record(fields))
+            },
+            Some(Tok::Error(pos, msg)) => Err(Error::Lexer(pos, msg)),
+            Some(tok) => Err(Error::Message("expected a pattern", tok.pos())),
+            None => Err(Error::UnexpectedEOF)
+        }
+    }
+
+    fn parse_field_name<T>(&mut self, fields: &[(Rc<String>, T)], pos: Pos)
+            -> Result<Rc<String>, Error<'input>> {
+        match self.lexer.next() {
+            Some(Tok::Id(_, id)) if fields.iter().any(|(name, _)| name.as_str() == id) =>
+                Err(Error::Message("duplicate field in record", pos)),
+            Some(Tok::Id(_, id)) => Ok(self.stringify(id)),
+            Some(tok) => Err(Error::UnexpectedToken(tok, Tok::Id(NULLPOS, "<name-of-field>"))),
+            None => Err(Error::UnexpectedEOF)
+        }
+    }
+
+    /// Parse `let <id> = <expr>`, without the `in`...
+    fn parse_let_binding(&mut self) -> Result<(Rc<String>, Node), Error<'input>> {
+        self.lexer.next();
+        match self.lexer.next() {
+            Some(Tok::Id(_, id)) => {
+                self.expect(Tok::Assign(NULLPOS))?;
+                let named = self.parse_expr_lvl1(0)?;
+                Ok((self.stringify(id), named))
+            },
+            Some(tok) => Err(Error::UnexpectedToken(tok, Tok::Id(NULLPOS, "<name-of-let>"))),
+            None => Err(Error::UnexpectedEOF)
+        }
+    }
+
     /// Parse binary operators...
     fn parse_expr_lvl1(&mut self, precedance: i32) -> Result<Node, Error<'input>> {
         let mut node = self.parse_expr_lvl2()?;
-        loop {
-            let peeked = match self.lexer.peek() {
-                Some(tok) => tok,
-                None => break
-            };
-
+        while let Some(peeked) = self.lexer.peek() {
             if let Some((pos, op, prec)) = Self::operator_precedence(peeked) {
                 if prec <= precedance {
                     break
@@ -157,7 +270,7 @@ impl<'input> Parser<'input> {
                         break;
                     }
 
-                    if args.len() != 0 {
+                    if !args.is_empty() {
                         self.expect(Tok::Comma(NULLPOS))?;
                     }
 
@@ -168,17 +281,68 @@ impl<'input> Parser<'input> {
                         None => { return Err(Error::UnexpectedEOF); }
                     };
 
-                    self.expect(Tok::Colon(NULLPOS))?;
-                    let ttype = self.parse_expr()?;
-                    args.push((
-                        self.stringify(id),
-                        Type::Unresolved(Rc::new(ttype))));
+                    // The type annotation is optional:
+                    let ttype = match self.lexer.peek() {
+                        Some(&Tok::Colon(_)) => {
+                            self.lexer.next();
+                            Type::Unresolved(Rc::new(self.parse_expr()?))
+                        },
+                        _ => Type::Unkown
+                    };
+                    args.push((self.stringify(id), ttype));
                 }
 
                 self.expect(Tok::ThinArrow(NULLPOS))?;
                 let body = self.parse_expr()?;
                 Node::Lambda(Metadata { pos, ttype: Type::Unkown }, args, Rc::new(body))
             },
+            // A record (`{ a = 1 }`) or a record type (`{ a: Int }`):
+            Some(Tok::LeftCurly(pos)) => {
+                let mut fields = Vec::new();
+                let mut is_type = false;
+                loop {
+                    if let Some(&Tok::RightCurly(_)) = self.lexer.peek() {
+                        self.lexer.next();
+                        break;
+                    }
+
+                    if !fields.is_empty() {
+                        self.expect(Tok::Comma(NULLPOS))?;
+                    }
+
+                    let name = self.parse_field_name(&fields, pos)?;
+                    match self.lexer.next() {
+                        Some(Tok::Assign(_)) if !is_type => {},
+                        Some(Tok::Colon(_)) if fields.is_empty() || is_type => is_type = true,
+                        Some(tok) => { return Err(Error::Message(
+                                "expected `=` (record) or `:` (record type)", tok.pos())); },
+                        None => { return Err(Error::UnexpectedEOF); }
+                    }
+                    fields.push((name, self.parse_expr()?));
+                }
+
+                let md = Metadata { pos, ttype: Type::Unkown };
+                match is_type {
+                    true => Node::RecordType(md, fields),
+                    false => Node::Record(md, fields)
+                }
+            },
+            Some(Tok::LeftSquare(pos)) => {
+                let mut items = Vec::new();
+                loop {
+                    if let Some(&Tok::RightSquare(_)) = self.lexer.peek() {
+                        self.lexer.next();
+                        break;
+                    }
+
+                    if !items.is_empty() {
+                        self.expect(Tok::Comma(NULLPOS))?;
+                    }
+
+                    items.push(self.parse_expr()?);
+                }
+                Node::List(Metadata { pos, ttype: Type::Unkown }, items)
+            },
             Some(Tok::Int(pos, x))   => Node::Int(Metadata{ pos, ttype: Type::Int }, x),
             Some(Tok::Real(pos, x))  => Node::Real(Metadata{ pos, ttype: Type::Real }, x),
             Some(Tok::Bool(pos, x))  => Node::Bool(Metadata{ pos, ttype: Type::Bool }, x),
@@ -186,32 +350,52 @@ impl<'input> Parser<'input> {
                 Metadata{ pos, ttype: Type::Str }, self.stringify(str)),
             Some(Tok::Id(pos, str))  => Node::Id(
                 Metadata{ pos, ttype: Type::Unkown }, self.stringify(str)),
-            _ => unimplemented!()
+            Some(Tok::Error(pos, msg)) => { return Err(Error::Lexer(pos, msg)); },
+            Some(tok) => { return Err(Error::Message("expected an expression", tok.pos())); },
+            None => { return Err(Error::UnexpectedEOF); }
         };
 
+        // Calls, field access and indexing:
         loop {
-            // A call:
-            if let Some(Tok::LeftParen(pos)) = self.lexer.peek() {
-                let pos = *pos;
-                self.lexer.next();
-                let mut args = Vec::new();
-                loop {
-                    if let Some(&Tok::RightParen(_)) = self.lexer.peek() {
-                        self.lexer.next();
-                        break;
-                    }
+            match self.lexer.peek() {
+                Some(&Tok::LeftParen(pos)) => {
+                    self.lexer.next();
+                    let mut args = Vec::new();
+                    loop {
+                        if let Some(&Tok::RightParen(_)) = self.lexer.peek() {
+                            self.lexer.next();
+                            break;
+                        }
 
-                    if args.len() != 0 {
-                        self.expect(Tok::Comma(NULLPOS))?;
-                    }
+                        if !args.is_empty() {
+                            self.expect(Tok::Comma(NULLPOS))?;
+                        }
 
-                    args.push(self.parse_expr()?);
-                }
+                        args.push(self.parse_expr()?);
+                    }
 
-                node = Node::Call(Metadata { pos, ttype: Type::Unkown },
-                    Box::new(node), args);
-            } else {
-                break;
+                    node = Node::Call(Metadata { pos, ttype: Type::Unkown },
+                        Box::new(node), args);
+                },
+                Some(&Tok::Dot(pos)) => {
+                    self.lexer.next();
+                    let name = match self.lexer.next() {
+                        Some(Tok::Id(_, id)) => self.stringify(id),
+                        Some(tok) => { return Err(Error::UnexpectedToken(
+                                tok, Tok::Id(NULLPOS, "<name-of-field>"))); },
+                        None => { return Err(Error::UnexpectedEOF); }
+                    };
+                    node = Node::Field(Metadata { pos, ttype: Type::Unkown },
+                        Box::new(node), name);
+                },
+                Some(&Tok::LeftSquare(pos)) => {
+                    self.lexer.next();
+                    let index = self.parse_expr()?;
+                    self.expect(Tok::RightSquare(NULLPOS))?;
+                    node = Node::Index(Metadata { pos, ttype: Type::Unkown },
+                        Box::new(node), Box::new(index));
+                },
+                _ => break
             }
         }
 
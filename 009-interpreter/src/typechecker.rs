@@ -0,0 +1,633 @@
+use crate::ast::*;
+use crate::lexer::Pos;
+use std::rc::Rc;
+
+/*
+ * Hindley-Milner type inference: Every node gets a type, which may contain
+ * type variables. Those are bound by unification and the bindings are kept
+ * in `subst`, so a type has to be `apply`ed to see what is known about it.
+ * The types of let-bound names are generalized over all type variables not
+ * appearing in the environment, every use of the name gets fresh ones.
+ *
+ * Types as arguments (`L(a: Type) -> L(x: a) -> x`) still work: the type of
+ * `a` is `Type('t)` for a fresh `'t`, which is what `x` is annotated with.
+ */
+pub struct Checker {
+    pub env: Env<Type>,
+    subst: Vec<Option<Type>>
+}
+
+enum Failure {
+    Mismatch,
+    Occurs(usize, Type)
+}
+
+impl Default for Checker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Checker {
+    pub fn new() -> Self {
+        Self { env: Env::new(), subst: Vec::new() }
+    }
+
+    /// Infer the type of a whole expression, on success all nodes have their
+    /// final type in their metadata.
+    pub fn check(&mut self, node: &mut Node) -> Result<Type, TCError> {
+        let depth = self.env.depth();
+        let res = node.check_types(self);
+        self.env.truncate(depth);
+        let ttype = res?;
+        node.apply_types(self);
+        Ok(self.apply(&ttype))
+    }
+
+    /// Add a (generalized) name to the outermost scope, used by the REPL.
+    pub fn define(&mut self, name: &str, ttype: &Type) {
+        let scheme = self.generalize(ttype);
+        self.env.add(name, scheme);
+    }
+
+    fn fresh(&mut self) -> Type {
+        self.subst.push(None);
+        Type::Var(self.subst.len() - 1)
+    }
+
+    /// Replace all bound type variables.
+    pub fn apply(&self, ttype: &Type) -> Type {
+        match ttype {
+            Type::Var(id) => match &self.subst[*id] {
+                Some(bound) => self.apply(bound),
+                None => ttype.clone()
+            },
+            Type::Type(t) => Type::Type(Rc::new(self.apply(t))),
+            Type::Lambda(args, ret) => Type::Lambda(
+                args.iter().map(|arg| Rc::new(self.apply(arg))).collect(),
+                Rc::new(self.apply(ret))),
+            Type::Forall(vars, t) => Type::Forall(vars.clone(), Rc::new(self.apply(t))),
+            Type::Record(fields) => Type::Record(fields.iter()
+                .map(|(name, t)| (name.clone(), Rc::new(self.apply(t)))).collect()),
+            Type::List(t) => Type::List(Rc::new(self.apply(t))),
+            other => other.clone()
+        }
+    }
+
+    fn unify(&mut self, pos: Pos, expected: &Type, found: &Type) -> Result<(), TCError> {
+        match self.unify_inner(expected, found) {
+            Ok(()) => Ok(()),
+            Err(Failure::Mismatch) =>
+                Err(TCError::Mismatch(pos, self.apply(expected), self.apply(found))),
+            Err(Failure::Occurs(id, ttype)) =>
+                Err(TCError::InfiniteType(pos, Type::Var(id), ttype))
+        }
+    }
+
+    fn unify_inner(&mut self, a: &Type, b: &Type) -> Result<(), Failure> {
+        match (self.apply(a), self.apply(b)) {
+            (Type::Var(x), Type::Var(y)) if x == y => Ok(()),
+            (Type::Var(id), t) | (t, Type::Var(id)) => {
+                if occurs(id, &t) {
+                    return Err(Failure::Occurs(id, t));
+                }
+                self.subst[id] = Some(t);
+                Ok(())
+            },
+            (Type::Type(a), Type::Type(b)) => self.unify_inner(&a, &b),
+            (Type::Lambda(aargs, aret), Type::Lambda(bargs, bret)) => {
+                if aargs.len() != bargs.len() {
+                    return Err(Failure::Mismatch);
+                }
+                for (a, b) in aargs.iter().zip(bargs.iter()) {
+                    self.unify_inner(a, b)?;
+                }
+                self.unify_inner(&aret, &bret)
+            },
+            (Type::Record(afields), Type::Record(bfields)) => {
+                if afields.len() != bfields.len() {
+                    return Err(Failure::Mismatch);
+                }
+                for ((aname, a), (bname, b)) in afields.iter().zip(bfields.iter()) {
+                    if aname != bname {
+                        return Err(Failure::Mismatch);
+                    }
+                    self.unify_inner(a, b)?;
+                }
+                Ok(())
+            },
+            (Type::List(a), Type::List(b)) => self.unify_inner(&a, &b),
+            (a, b) if a == b => Ok(()),
+            _ => Err(Failure::Mismatch)
+        }
+    }
+
+    fn generalize(&self, ttype: &Type) -> Type {
+        let ttype = self.apply(ttype);
+        let mut vars = Vec::new();
+        free_vars(&ttype, &mut vars);
+
+        let mut bound = Vec::new();
+        for t in self.env.values() {
+            free_vars(&self.apply(t), &mut bound);
+        }
+        vars.retain(|id| !bound.contains(id));
+
+        match vars.is_empty() {
+            true => ttype,
+            false => Type::Forall(vars, Rc::new(ttype))
+        }
+    }
+
+    fn instantiate(&mut self, ttype: &Type) -> Type {
+        match ttype {
+            Type::Forall(vars, t) => {
+                let fresh: Vec<(usize, Type)> = vars.iter().map(|id| (*id, self.fresh())).collect();
+                replace(t, &fresh)
+            },
+            other => other.clone()
+        }
+    }
+
+    /// The type an argument annotation stands for, an argument annotated with
+    /// `Type` is a type itself.
+    fn annotation(&mut self, pos: Pos, annotation: &Type) -> Result<Type, TCError> {
+        let node = match annotation {
+            Type::Unkown => return Ok(self.fresh()),
+            Type::Unresolved(node) => node,
+            other => return Ok(other.clone())
+        };
+        if let Node::Id(_, name) = node.as_ref() {
+            if let Some(Type::Kind) = self.env.lookup(name) {
+                return Ok(Type::Type(Rc::new(self.fresh())));
+            }
+        }
+        self.type_of_node(pos, node)
+    }
+
+    /// The type a type expression (`Int`, `{ x: Int }`, `[Int]`) stands for.
+    fn type_of_node(&mut self, pos: Pos, node: &Node) -> Result<Type, TCError> {
+        match node {
+            Node::Id(_, name) => {
+                let ttype = match self.env.lookup(name) {
+                    Some(ttype) => ttype.clone(),
+                    None => return Err(TCError::Unresolvable(pos, name.clone()))
+                };
+                let ttype = self.instantiate(&ttype);
+                match self.apply(&ttype) {
+                    Type::Type(t) => Ok(t.as_ref().clone()),
+                    _ => Err(TCError::NotAType(pos))
+                }
+            },
+            Node::RecordType(_, fields) => {
+                let mut types = Vec::with_capacity(fields.len());
+                for (name, field) in fields {
+                    types.push((name.clone(), Rc::new(self.type_of_node(pos, field)?)));
+                }
+                Ok(record(types))
+            },
+            Node::List(_, items) if items.len() == 1 =>
+                Ok(Type::List(Rc::new(self.type_of_node(pos, &items[0])?))),
+            _ => Err(TCError::NotAType(pos))
+        }
+    }
+
+    /// Bind the names in a pattern matching a value of type `ttype`.
+    fn check_pattern(&mut self, pos: Pos, pattern: &Pattern, ttype: &Type) -> Result<(), TCError> {
+        match pattern {
+            Pattern::Wildcard => Ok(()),
+            Pattern::Bind(name) => {
+                self.env.add(name, ttype.clone());
+                Ok(())
+            },
+            Pattern::Int(_) => self.unify(pos, ttype, &Type::Int),
+            Pattern::Real(_) => self.unify(pos, ttype, &Type::Real),
+            Pattern::Bool(_) => self.unify(pos, ttype, &Type::Bool),
+            Pattern::Str(_) => self.unify(pos, ttype, &Type::Str),
+            Pattern::List(items) => {
+                let elem = self.fresh();
+                self.unify(pos, ttype, &Type::List(Rc::new(elem.clone())))?;
+                for item in items {
+                    self.check_pattern(pos, item, &elem)?;
+                }
+                Ok(())
+            },
+            Pattern::Record(fields) => match self.apply(ttype) {
+                // Only the mentioned fields have to match:
+                Type::Record(tfields) => {
+                    for (name, pattern) in fields {
+                        match tfields.iter().find(|(tname, _)| tname == name) {
+                            Some((_, t)) => self.check_pattern(pos, pattern, t)?,
+                            None => return Err(TCError::NoSuchField(
+                                    pos, Type::Record(tfields.clone()), name.clone()))
+                        }
+                    }
+                    Ok(())
+                },
+                _ => {
+                    let types = fields.iter()
+                        .map(|(name, _)| (name.clone(), Rc::new(self.fresh()))).collect();
+                    self.unify(pos, ttype, &record(types))?;
+                    self.check_pattern(pos, pattern, ttype)
+                }
+            }
+        }
+    }
+
+    /// Operands of arithmetic and ordering operators are numbers, Int unless
+    /// known otherwise. An Int is promoted to Real if the other one is Real.
+    /// Strings can be concatenated and compared.
+    fn numeric(&mut self, pos: Pos, op: BinOp, lhs: &Type, rhs: &Type, strings: bool)
+            -> Result<Type, TCError> {
+        if let (Type::Int, Type::Real) | (Type::Real, Type::Int) = (self.apply(lhs), self.apply(rhs)) {
+            return Ok(Type::Real);
+        }
+        if self.unify_inner(lhs, rhs).is_err() {
+            return Err(TCError::OperandsDoNotMatch(pos, op, self.apply(lhs), self.apply(rhs)));
+        }
+        match self.apply(lhs) {
+            Type::Var(id) => {
+                self.subst[id] = Some(Type::Int);
+                Ok(Type::Int)
+            },
+            t @ (Type::Int | Type::Real) => Ok(t),
+            Type::Str if strings => Ok(Type::Str),
+            t => Err(TCError::OperandsDoNotMatch(pos, op, t.clone(), t))
+        }
+    }
+}
+
+fn record(mut fields: Vec<(Rc<String>, Rc<Type>)>) -> Type {
+    fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Type::Record(fields)
+}
+
+fn occurs(id: usize, ttype: &Type) -> bool {
+    match ttype {
+        Type::Var(other) => *other == id,
+        Type::Type(t) => occurs(id, t),
+        Type::Lambda(args, ret) => args.iter().any(|arg| occurs(id, arg)) || occurs(id, ret),
+        Type::Record(fields) => fields.iter().any(|(_, t)| occurs(id, t)),
+        Type::List(t) => occurs(id, t),
+        _ => false
+    }
+}
+
+fn free_vars(ttype: &Type, vars: &mut Vec<usize>) {
+    match ttype {
+        Type::Var(id) if !vars.contains(id) => vars.push(*id),
+        Type::Type(t) => free_vars(t, vars),
+        Type::Lambda(args, ret) => {
+            for arg in args {
+                free_vars(arg, vars);
+            }
+            free_vars(ret, vars);
+        },
+        Type::Record(fields) => {
+            for (_, t) in fields {
+                free_vars(t, vars);
+            }
+        },
+        Type::List(t) => free_vars(t, vars),
+        Type::Forall(bound, t) => {
+            let mut inner = Vec::new();
+            free_vars(t, &mut inner);
+            for id in inner {
+                if !bound.contains(&id) && !vars.contains(&id) {
+                    vars.push(id);
+                }
+            }
+        },
+        _ => {}
+    }
+}
+
+fn replace(ttype: &Type, with: &[(usize, Type)]) -> Type {
+    match ttype {
+        Type::Var(id) => match with.iter().find(|(var, _)| var == id) {
+            Some((_, t)) => t.clone(),
+            None => ttype.clone()
+        },
+        Type::Type(t) => Type::Type(Rc::new(replace(t, with))),
+        Type::Lambda(args, ret) => Type::Lambda(
+            args.iter().map(|arg| Rc::new(replace(arg, with))).collect(),
+            Rc::new(replace(ret, with))),
+        Type::Record(fields) => Type::Record(fields.iter()
+            .map(|(name, t)| (name.clone(), Rc::new(replace(t, with)))).collect()),
+        Type::List(t) => Type::List(Rc::new(replace(t, with))),
+        other => other.clone()
+    }
+}
+
+impl Node {
+    pub fn check_types(&mut self, tc: &mut Checker) -> Result<Type, TCError> {
+        let ttype = match self {
+            Self::Int(md, _) | Self::Real(md, _) | Self::Bool(md, _) | Self::Str(md, _)
+                => return Ok(md.ttype.clone()),
+            Self::Id(md, id) => match tc.env.lookup(id.as_str()) {
+                Some(ttype) => {
+                    let ttype = ttype.clone();
+                    tc.instantiate(&ttype)
+                },
+                None => return Err(TCError::Unresolvable(md.pos, id.clone()))
+            },
+            Self::BinOp(md, op, lhs, rhs) => {
+                let lhs = lhs.check_types(tc)?;
+                let rhs = rhs.check_types(tc)?;
+                match op {
+                    BinOp::Add => tc.numeric(md.pos, *op, &lhs, &rhs, true)?,
+                    BinOp::Sub | BinOp::Mul | BinOp::Div
+                        => tc.numeric(md.pos, *op, &lhs, &rhs, false)?,
+                    BinOp::Lt | BinOp::Gt | BinOp::LtEq | BinOp::GtEq => {
+                        tc.numeric(md.pos, *op, &lhs, &rhs, true)?;
+                        Type::Bool
+                    },
+                    BinOp::Eq | BinOp::NotEq => {
+                        let promoted = matches!((tc.apply(&lhs), tc.apply(&rhs)),
+                            (Type::Int, Type::Real) | (Type::Real, Type::Int));
+                        if !promoted && tc.unify_inner(&lhs, &rhs).is_err() {
+                            return Err(TCError::OperandsDoNotMatch(
+                                    md.pos, *op, tc.apply(&lhs), tc.apply(&rhs)));
+                        }
+                        Type::Bool
+                    },
+                    BinOp::And | BinOp::Or => {
+                        if tc.unify_inner(&lhs, &Type::Bool).is_err()
+                            || tc.unify_inner(&rhs, &Type::Bool).is_err() {
+                            return Err(TCError::OperandsDoNotMatch(
+                                    md.pos, *op, tc.apply(&lhs), tc.apply(&rhs)));
+                        }
+                        Type::Bool
+                    }
+                }
+            },
+            Self::LetIn(md, name, expr1, expr2) => {
+                let ttype = expr1.check_types(tc)?;
+                let scheme = tc.generalize(&ttype);
+                tc.env.push_scope();
+                tc.env.add(name, scheme);
+                let ttype = expr2.check_types(tc)?;
+                tc.env.pop_scope();
+                md.ttype = ttype;
+                return Ok(md.ttype.clone());
+            },
+            Self::Lambda(md, args, body) => {
+                // Later annotations may refer to earlier (type) arguments:
+                tc.env.push_scope();
+                let mut argtypes = Vec::with_capacity(args.len());
+                for (name, annotation) in args.iter() {
+                    let ttype = tc.annotation(md.pos, annotation)?;
+                    argtypes.push(Rc::new(ttype.clone()));
+                    tc.env.add(name.as_str(), ttype);
+                }
+                let rettype = Rc::get_mut(body).unwrap().check_types(tc)?;
+                tc.env.pop_scope();
+                Type::Lambda(argtypes, Rc::new(rettype))
+            },
+            Self::Call(md, callee, args) => {
+                let calleetype = callee.check_types(tc)?;
+                let mut argtypes = Vec::with_capacity(args.len());
+                for arg in args.iter_mut() {
+                    argtypes.push((arg.get_metadata().pos, arg.check_types(tc)?));
+                }
+
+                match tc.apply(&calleetype) {
+                    Type::Lambda(params, rettype) => {
+                        if params.len() != args.len() {
+                            return Err(TCError::WrongNumberOfArgs(md.pos, params.len(), args.len()));
+                        }
+                        for (param, (pos, arg)) in params.iter().zip(argtypes.iter()) {
+                            tc.unify(*pos, param, arg)?;
+                        }
+                        rettype.as_ref().clone()
+                    },
+                    Type::Var(_) => {
+                        let rettype = tc.fresh();
+                        let lambda = Type::Lambda(
+                            argtypes.into_iter().map(|(_, arg)| Rc::new(arg)).collect(),
+                            Rc::new(rettype.clone()));
+                        tc.unify(md.pos, &calleetype, &lambda)?;
+                        rettype
+                    },
+                    other => return Err(TCError::NotAFunction(md.pos, other))
+                }
+            },
+            Self::If(md, cond, iftrue, iffalse) => {
+                let condtype = cond.check_types(tc)?;
+                if tc.unify_inner(&Type::Bool, &condtype).is_err() {
+                    return Err(TCError::ExpectedBool(md.pos, tc.apply(&condtype)));
+                }
+
+                let t1 = iftrue.check_types(tc)?;
+                let t2 = iffalse.check_types(tc)?;
+                tc.unify(iffalse.get_metadata().pos, &t1, &t2)?;
+                t1
+            },
+            Self::Record(_, fields) => {
+                let mut types = Vec::with_capacity(fields.len());
+                for (name, value) in fields.iter_mut() {
+                    types.push((name.clone(), Rc::new(value.check_types(tc)?)));
+                }
+                record(types)
+            },
+            Self::RecordType(_, fields) => {
+                let mut types = Vec::with_capacity(fields.len());
+                for (name, field) in fields.iter_mut() {
+                    let pos = field.get_metadata().pos;
+                    let ttype = field.check_types(tc)?;
+                    match tc.apply(&ttype) {
+                        Type::Type(t) => types.push((name.clone(), t)),
+                        _ => return Err(TCError::NotAType(pos))
+                    }
+                }
+                Type::Type(Rc::new(record(types)))
+            },
+            Self::List(_, items) => {
+                let elem = tc.fresh();
+                for item in items.iter_mut() {
+                    let ttype = item.check_types(tc)?;
+                    tc.unify(item.get_metadata().pos, &elem, &ttype)?;
+                }
+                Type::List(Rc::new(elem))
+            },
+            Self::Field(md, value, name) => {
+                let ttype = value.check_types(tc)?;
+                match tc.apply(&ttype) {
+                    Type::Record(fields) => match fields.iter().find(|(field, _)| field == name) {
+                        Some((_, t)) => t.as_ref().clone(),
+                        None => return Err(TCError::NoSuchField(
+                                md.pos, Type::Record(fields.clone()), name.clone()))
+                    },
+                    Type::Var(_) => return Err(TCError::UnknownRecord(md.pos, name.clone())),
+                    other => return Err(TCError::NoSuchField(md.pos, other, name.clone()))
+                }
+            },
+            Self::Index(md, list, index) => {
+                let elem = tc.fresh();
+                let ttype = list.check_types(tc)?;
+                tc.unify(md.pos, &Type::List(Rc::new(elem.clone())), &ttype)?;
+                let ttype = index.check_types(tc)?;
+                tc.unify(index.get_metadata().pos, &Type::Int, &ttype)?;
+                elem
+            },
+            Self::Match(md, value, arms) => {
+                let ttype = value.check_types(tc)?;
+                let result = tc.fresh();
+                for (pattern, body) in arms.iter_mut() {
+                    tc.env.push_scope();
+                    tc.check_pattern(md.pos, pattern, &ttype)?;
+                    let bodytype = body.check_types(tc)?;
+                    tc.env.pop_scope();
+                    tc.unify(body.get_metadata().pos, &result, &bodytype)?;
+                }
+                result
+            }
+        };
+
+        let md = self.get_metadata_mut();
+        md.ttype = ttype;
+        Ok(md.ttype.clone())
+    }
+
+    /// Store the final types (after inference is done) in the metadata.
+    fn apply_types(&mut self, tc: &Checker) {
+        match self {
+            Self::Int(..) | Self::Real(..) | Self::Bool(..) | Self::Str(..) => return,
+            Self::Id(..) => {},
+            Self::BinOp(_, _, lhs, rhs) => {
+                lhs.apply_types(tc);
+                rhs.apply_types(tc);
+            },
+            Self::Call(_, callee, args) => {
+                callee.apply_types(tc);
+                for arg in args {
+                    arg.apply_types(tc);
+                }
+            },
+            Self::LetIn(_, _, expr1, expr2) => {
+                expr1.apply_types(tc);
+                expr2.apply_types(tc);
+            },
+            Self::If(_, cond, iftrue, iffalse) => {
+                cond.apply_types(tc);
+                iftrue.apply_types(tc);
+                iffalse.apply_types(tc);
+            },
+            Self::Lambda(_, _, body) => Rc::get_mut(body).unwrap().apply_types(tc),
+            Self::Record(_, fields) | Self::RecordType(_, fields) => {
+                for (_, value) in fields {
+                    value.apply_types(tc);
+                }
+            },
+            Self::List(_, items) => {
+                for item in items {
+                    item.apply_types(tc);
+                }
+            },
+            Self::Field(_, value, _) => value.apply_types(tc),
+            Self::Index(_, list, index) => {
+                list.apply_types(tc);
+                index.apply_types(tc);
+            },
+            Self::Match(_, value, arms) => {
+                value.apply_types(tc);
+                for (_, body) in arms {
+                    body.apply_types(tc);
+                }
+            }
+        }
+        let md = self.get_metadata_mut();
+        md.ttype = tc.apply(&md.ttype);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn check(code: &str) -> Result<String, TCError> {
+        let mut tc = Checker::new();
+        tc.env.add("Int", Type::Type(Rc::new(Type::Int)));
+        tc.env.add("Real", Type::Type(Rc::new(Type::Real)));
+        tc.env.add("Str", Type::Type(Rc::new(Type::Str)));
+        tc.env.add("Type", Type::Kind);
+        let mut ast = Parser::new(Lexer::new(0, code)).parse().unwrap();
+        let ttype = tc.check(&mut ast)?;
+        let mut buf = String::new();
+        ttype.to_string(&mut buf).unwrap();
+        Ok(buf)
+    }
+
+    #[test]
+    fn inference() {
+        assert_eq!(check("let id = L(x) -> x in id(42)").unwrap(), "Int");
+        assert_eq!(check("L(x, y) -> x + y").unwrap(), "lambda:(Int, Int) => Int");
+        assert_eq!(check("L(x: Real, y) -> x + y").unwrap(), "lambda:(Real, Real) => Real");
+        assert_eq!(check("L(f, x) -> f(f(x))").unwrap(), "lambda:(lambda:('c) => 'c, 'c) => 'c");
+        assert_eq!(check("L(x) -> if x then 1 else 2").unwrap(), "lambda:(Bool) => Int");
+        assert_eq!(check("let id = L(a: Type) -> L(x: a) -> x in id(Int)(42)").unwrap(), "Int");
+    }
+
+    #[test]
+    fn let_polymorphism() {
+        assert_eq!(check("
+            let id = L(x) -> x in
+            let const = L(x) -> L(y) -> x in
+            const(id(1))(id(true))").unwrap(), "Int");
+        assert_eq!(check("
+            let compose = L(f, g) -> L(x) -> g(f(x)) in
+            let not = L(b) -> if b then false else true in
+            let inc = L(n) -> n + 1 in
+            compose(compose(inc, inc), L(n) -> n > 2)(0)").unwrap(), "Bool");
+        // Lambda arguments are not generalized:
+        assert!(matches!(check("L(id) -> id(id(1) == 1)"), Err(TCError::Mismatch(..))));
+    }
+
+    #[test]
+    fn records_lists_and_match() {
+        assert_eq!(check("{ y = true, x = 1 }").unwrap(), "{ x: Int, y: Bool }");
+        assert_eq!(check("L(p: { x: Int, y: Real }) -> p.y").unwrap(),
+            "lambda:({ x: Int, y: Real }) => Real");
+        assert_eq!(check("L(xs, i) -> xs[i] + 1").unwrap(), "lambda:([Int], Int) => Int");
+        assert_eq!(check("L(xs: [Real]) -> xs[0]").unwrap(), "lambda:([Real]) => Real");
+        assert_eq!(check("let Point = { x: Int, y: Int } in L(p: Point) -> p.x").unwrap(),
+            "lambda:({ x: Int, y: Int }) => Int");
+        assert_eq!(check("L(x) -> match x { [a, b] => a == b, _ => false }").unwrap(),
+            "lambda:(['c]) => Bool");
+        assert_eq!(check("L(r) -> match r { { a = 1, b = b } => b }").unwrap(),
+            "lambda:({ a: Int, b: 'd }) => 'd");
+        assert!(matches!(check("[1, true]"), Err(TCError::Mismatch(_, Type::Int, Type::Bool))));
+        assert!(matches!(check("{ a = 1 }.b"), Err(TCError::NoSuchField(..))));
+        assert!(matches!(check("L(p) -> p.x"), Err(TCError::UnknownRecord(..))));
+        assert!(matches!(check("match 1 { 1 => 2, _ => true }"), Err(TCError::Mismatch(..))));
+        assert!(matches!(check("match 1 { [] => 2 }"), Err(TCError::Mismatch(..))));
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(check("1 + 2 * 3 - 4 / 2").unwrap(), "Int");
+        assert_eq!(check("1 + 0.5").unwrap(), "Real");
+        assert_eq!(check("2.0 * 3").unwrap(), "Real");
+        assert_eq!(check("1 < 1.5 and 1 == 1.0 or false").unwrap(), "Bool");
+        assert_eq!(check("\"a\" + \"b\"").unwrap(), "Str");
+        assert_eq!(check("\"a\" <= \"b\"").unwrap(), "Bool");
+        assert_eq!(check("L(s: Str, t) -> s + t").unwrap(), "lambda:(Str, Str) => Str");
+        assert!(matches!(check("\"a\" - \"b\""), Err(TCError::OperandsDoNotMatch(_, BinOp::Sub, ..))));
+        assert!(matches!(check("1 and true"), Err(TCError::OperandsDoNotMatch(_, BinOp::And, ..))));
+        assert!(matches!(check("1 + \"a\""), Err(TCError::OperandsDoNotMatch(..))));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(check("L(x) -> x(x)"), Err(TCError::InfiniteType(..))));
+        assert!(matches!(check("1 + true"), Err(TCError::OperandsDoNotMatch(_, BinOp::Add, ..))));
+        assert!(matches!(check("if 1 then 2 else 3"), Err(TCError::ExpectedBool(_, Type::Int))));
+        assert!(matches!(check("if true then 2 else 3.0"),
+            Err(TCError::Mismatch(_, Type::Int, Type::Real))));
+        assert!(matches!(check("(L(x, y) -> x)(1)"), Err(TCError::WrongNumberOfArgs(_, 2, 1))));
+        assert!(matches!(check("42(1)"), Err(TCError::NotAFunction(_, Type::Int))));
+        assert!(matches!(check("L(x: Int) -> y"), Err(TCError::Unresolvable(..))));
+    }
+}
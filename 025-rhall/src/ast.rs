@@ -1,7 +1,7 @@
 use std::fmt;
 use std::{cell::RefCell, rc::Rc};
 
-use crate::core::{Error, SLoc, Type, Value};
+use crate::core::{merge_fields, Error, SLoc, Type, Value};
 use crate::eval::Runtime;
 use crate::lex::{Lexer, Tok};
 
@@ -19,6 +19,9 @@ pub enum BinOp {
     LE,
     GT,
     GE,
+    Prefer,
+    Combine,
+    CombineTypes,
 }
 
 #[derive(Debug, Clone)]
@@ -33,6 +36,11 @@ pub enum Node {
         typ: Option<Rc<Type>>,
         value: i64,
     },
+    Double {
+        sloc: SLoc,
+        typ: Option<Rc<Type>>,
+        value: f64,
+    },
     Boolean {
         sloc: SLoc,
         typ: Option<Rc<Type>>,
@@ -111,6 +119,14 @@ pub enum Node {
         op0: Box<Node>,
         field: Rc<str>,
     },
+    Project {
+        sloc: SLoc,
+        typ: Option<Rc<Type>>,
+        op0: Box<Node>,
+        fields: Vec<Rc<str>>,
+        // For `r.(T)`, fields is populated from T during the type-check.
+        by_type: Option<Box<Node>>,
+    },
     As {
         sloc: SLoc,
         typ: Option<Rc<Type>>,
@@ -131,6 +147,7 @@ impl Node {
         let typ = match self {
             Node::Id { typ, .. } => typ,
             Node::Integer { typ, .. } => typ,
+            Node::Double { typ, .. } => typ,
             Node::Boolean { typ, .. } => typ,
             Node::String { typ, .. } => typ,
             Node::TypeAnno { typ, .. } => typ,
@@ -144,17 +161,43 @@ impl Node {
             Node::Record { typ, .. } => typ,
             Node::RecordType { typ, .. } => typ,
             Node::AccessField { typ, .. } => typ,
+            Node::Project { typ, .. } => typ,
             Node::As { typ, .. } => typ,
             Node::TypeOf { typ, .. } => typ,
         };
         typ.clone()
     }
 
+    pub fn sloc(&self) -> SLoc {
+        match self {
+            Node::Id { sloc, .. } => *sloc,
+            Node::Integer { sloc, .. } => *sloc,
+            Node::Double { sloc, .. } => *sloc,
+            Node::Boolean { sloc, .. } => *sloc,
+            Node::String { sloc, .. } => *sloc,
+            Node::TypeAnno { sloc, .. } => *sloc,
+            Node::Invert { sloc, .. } => *sloc,
+            Node::BinOp { sloc, .. } => *sloc,
+            Node::Call { sloc, .. } => *sloc,
+            Node::IfThenElse { sloc, .. } => *sloc,
+            Node::LetIn { sloc, .. } => *sloc,
+            Node::Lambda { sloc, .. } => *sloc,
+            Node::Forall { sloc, .. } => *sloc,
+            Node::Record { sloc, .. } => *sloc,
+            Node::RecordType { sloc, .. } => *sloc,
+            Node::AccessField { sloc, .. } => *sloc,
+            Node::Project { sloc, .. } => *sloc,
+            Node::As { sloc, .. } => *sloc,
+            Node::TypeOf { sloc, .. } => *sloc,
+        }
+    }
+
     #[allow(unused)]
     pub fn set_type(&mut self, t: Rc<Type>) {
         match self {
             Node::Id { typ, .. } => *typ = Some(t),
             Node::Integer { typ, .. } => *typ = Some(t),
+            Node::Double { typ, .. } => *typ = Some(t),
             Node::Boolean { typ, .. } => *typ = Some(t),
             Node::String { typ, .. } => *typ = Some(t),
             Node::TypeAnno { typ, .. } => *typ = Some(t),
@@ -168,15 +211,18 @@ impl Node {
             Node::Record { typ, .. } => *typ = Some(t),
             Node::RecordType { typ, .. } => *typ = Some(t),
             Node::AccessField { typ, .. } => *typ = Some(t),
+            Node::Project { typ, .. } => *typ = Some(t),
             Node::As { typ, .. } => *typ = Some(t),
             Node::TypeOf { typ, .. } => *typ = Some(t),
         };
     }
 
+    /* The hint is the type expected by the context, it is used to give
+     * integer literals the type Natural where a Natural is expected. */
     pub fn typecheck(
         &mut self,
         rt: &mut Runtime,
-        _hint: Option<Rc<Type>>,
+        hint: Option<Rc<Type>>,
     ) -> Result<Rc<Type>, Error> {
         match self {
             Node::Id { typ: Some(t), .. } => Ok(t.clone()),
@@ -196,14 +242,20 @@ impl Node {
                     *typ = Some(t.clone());
                     Ok(t)
                 }
-                None => Err(Error::TypeError(
-                    *sloc,
-                    format!("undefined id: {}", name.as_ref()),
-                )),
+                None => Err(Error::UndefinedValue(*sloc, name.clone(), rt.suggest(name))),
             },
             Node::Integer { typ: Some(t), .. } => Ok(t.clone()),
-            Node::Integer { typ, .. } => {
-                let t = rt.int_type.clone();
+            Node::Integer { typ, value, .. } => {
+                let t = match hint {
+                    Some(t) if *t == Type::Natural && *value >= 0 => rt.natural_type.clone(),
+                    _ => rt.int_type.clone(),
+                };
+                *typ = Some(t.clone());
+                Ok(t)
+            }
+            Node::Double { typ: Some(t), .. } => Ok(t.clone()),
+            Node::Double { typ, .. } => {
+                let t = rt.double_type.clone();
                 *typ = Some(t.clone());
                 Ok(t)
             }
@@ -229,15 +281,12 @@ impl Node {
                 Type::TypeOf(t) => {
                     let op0t = op0.typecheck(rt, Some(t.clone()))?;
                     if *op0t != **t {
-                        return Err(Error::TypeError(
-                            *sloc,
-                            format!(
-                                "{} expected to be of type {}, found {}",
-                                op0,
-                                t,
-                                op0t.as_ref()
-                            ),
-                        ));
+                        return Err(Error::TypeMismatch {
+                            sloc: op0.sloc(),
+                            context: "expression does not match its type annotation".to_string(),
+                            expected: t.clone(),
+                            found: op0t,
+                        });
                     }
                     *typ = Some(op0t.clone());
                     Ok(op0t)
@@ -267,22 +316,76 @@ impl Node {
                 lhs,
                 rhs,
                 iscmp,
-            } => {
+            } if matches!(op, BinOp::Prefer | BinOp::Combine | BinOp::CombineTypes) => {
                 let lhsty = lhs.typecheck(rt, None)?;
                 let rhsty = rhs.typecheck(rt, None)?;
+                let t = match (op, lhsty.as_ref(), rhsty.as_ref()) {
+                    (BinOp::Prefer, Type::Record(l), Type::Record(r)) => Rc::new(Type::Record(
+                        merge_fields(l, r, |_, _, r| Ok::<_, Error>(r.clone()))?,
+                    )),
+                    (BinOp::Combine, Type::Record(l), Type::Record(r)) => Rc::new(Type::Record(
+                        Type::combine_records(l, r).map_err(|e| Error::TypeError(*sloc, e))?,
+                    )),
+                    (BinOp::CombineTypes, Type::TypeOf(l), Type::TypeOf(r))
+                        if matches!(
+                            (l.as_ref(), r.as_ref()),
+                            (Type::Record(_), Type::Record(_))
+                        ) =>
+                    {
+                        let (Type::Record(l), Type::Record(r)) = (l.as_ref(), r.as_ref()) else {
+                            unreachable!()
+                        };
+                        let fields =
+                            Type::combine_records(l, r).map_err(|e| Error::TypeError(*sloc, e))?;
+                        Rc::new(Type::TypeOf(Rc::new(Type::Record(fields))))
+                    }
+                    (op, l, r) => {
+                        return Err(Error::TypeError(
+                            *sloc,
+                            format!(
+                                "operator {} expects {} on both sides, found: {} and {}",
+                                op,
+                                if *op == BinOp::CombineTypes { "record types" } else { "records" },
+                                l,
+                                r
+                            ),
+                        ))
+                    }
+                };
+                *typ = Some(t.clone());
+                Ok(t)
+            }
+            Node::BinOp {
+                sloc,
+                typ,
+                op,
+                lhs,
+                rhs,
+                iscmp,
+            } => {
+                // Integer literals take the type of the other operand:
+                let hint = if *iscmp { None } else { hint };
+                let (lhsty, rhsty) = if let Node::Integer { .. } = lhs.as_ref() {
+                    let rhsty = rhs.typecheck(rt, hint)?;
+                    (lhs.typecheck(rt, Some(rhsty.clone()))?, rhsty)
+                } else {
+                    let lhsty = lhs.typecheck(rt, hint)?;
+                    (lhsty.clone(), rhs.typecheck(rt, Some(lhsty))?)
+                };
                 if *lhsty != *rhsty {
-                    return Err(Error::TypeError(
-                        *sloc,
-                        format!(
-                            "binary operator with different operand types: {} and {}",
-                            lhsty.as_ref(),
-                            rhsty.as_ref()
-                        ),
-                    ));
+                    return Err(Error::TypeMismatch {
+                        sloc: *sloc,
+                        context: format!("operands of '{}' have different types", op),
+                        expected: lhsty,
+                        found: rhsty,
+                    });
                 }
 
                 let t = match (op, iscmp, lhsty.as_ref()) {
-                    (_, true, Type::Int) => rt.bool_type.clone(),
+                    (_, true, Type::Int | Type::Natural | Type::Double) => rt.bool_type.clone(),
+                    (BinOp::EQ | BinOp::NE, true, Type::Text | Type::Bool) => {
+                        rt.bool_type.clone()
+                    }
                     (BinOp::And | BinOp::Or, _, Type::Bool) => lhsty,
                     (BinOp::And | BinOp::Or, _, _) => {
                         return Err(Error::TypeError(
@@ -290,48 +393,50 @@ impl Node {
                             "'&' and '|' only work for booleans".to_string(),
                         ))
                     }
-                    (_, false, Type::Int) => lhsty,
+                    (_, false, Type::Int | Type::Natural | Type::Double) => lhsty,
                     (BinOp::Add, _, Type::Text) => lhsty,
-                    (op, _, _) => panic!(
-                        "binop error: {:?} {:?} {:?}",
-                        lhsty.as_ref(),
-                        op,
-                        rhsty.as_ref()
-                    ),
+                    (op, _, _) => {
+                        return Err(Error::TypeError(
+                            *sloc,
+                            format!("operator '{}' is not defined for {}", op, lhsty.as_ref()),
+                        ))
+                    }
                 };
                 *typ = Some(t.clone());
                 Ok(t)
             }
             Node::IfThenElse { typ: Some(t), .. } => Ok(t.clone()),
             Node::IfThenElse {
-                sloc,
                 typ,
                 op0,
                 op1,
                 op2,
+                ..
             } => {
                 let op0ty = op0.typecheck(rt, None)?;
                 if *op0ty != Type::Bool {
-                    return Err(Error::TypeError(
-                        *sloc,
-                        format!(
-                            "condition of if-then-else needs to be a boolean, not: {}",
-                            op0ty.as_ref()
-                        ),
-                    ));
+                    return Err(Error::TypeMismatch {
+                        sloc: op0.sloc(),
+                        context: "condition of if-then-else needs to be a boolean".to_string(),
+                        expected: rt.bool_type.clone(),
+                        found: op0ty,
+                    });
                 }
 
-                let op1ty = op1.typecheck(rt, None)?;
-                let op2ty = op2.typecheck(rt, None)?;
+                let (op1ty, op2ty) = if let Node::Integer { .. } = op1.as_ref() {
+                    let op2ty = op2.typecheck(rt, hint)?;
+                    (op1.typecheck(rt, Some(op2ty.clone()))?, op2ty)
+                } else {
+                    let op1ty = op1.typecheck(rt, hint)?;
+                    (op1ty.clone(), op2.typecheck(rt, Some(op1ty))?)
+                };
                 if *op1ty != *op2ty {
-                    return Err(Error::TypeError(
-                        *sloc,
-                        format!(
-                            "branches of if-then-else needs to be of same type, not: {} and {}",
-                            op1ty.as_ref(),
-                            op2ty.as_ref()
-                        ),
-                    ));
+                    return Err(Error::TypeMismatch {
+                        sloc: op2.sloc(),
+                        context: "branches of if-then-else need to be of the same type".to_string(),
+                        expected: op1ty,
+                        found: op2ty,
+                    });
                 }
 
                 *typ = Some(op1ty.clone());
@@ -371,7 +476,11 @@ impl Node {
                         }
                     };
                 }
-                let ret_type = body.borrow_mut().typecheck(rt, None)?;
+                let ret_hint = match hint.as_deref() {
+                    Some(Type::Lambda(_, ret_hint)) => Some(ret_hint.clone()),
+                    _ => None,
+                };
+                let ret_type = body.borrow_mut().typecheck(rt, ret_hint)?;
                 rt.pop(args.len());
                 let t = Rc::new(Type::Lambda(arg_types, ret_type));
                 *typ = Some(t.clone());
@@ -447,7 +556,7 @@ impl Node {
 
                 let mut ret_type = ret_type.clone();
                 for (arg, (arg_name, arg_type)) in args.iter_mut().zip(arg_types) {
-                    let typ = arg.typecheck(rt, None)?;
+                    let typ = arg.typecheck(rt, Some(arg_type.clone()))?;
                     if **arg_type == Type::TypeOfType {
                         ret_type = ret_type.subst(
                             arg_name.as_ref(),
@@ -459,17 +568,12 @@ impl Node {
                         continue;
                     }
                     if **arg_type != *typ {
-                        return Err(Error::Uncallable(
-                            *sloc,
-                            format!(
-                                "argument {} has type {} ({:?}), expected: {} ({:?})",
-                                arg_name.as_ref(),
-                                typ.as_ref(),
-                                typ.as_ref(),
-                                arg_type.as_ref(),
-                                arg_type.as_ref()
-                            ),
-                        ));
+                        return Err(Error::TypeMismatch {
+                            sloc: arg.sloc(),
+                            context: format!("argument {} of call to {}", arg_name, callable),
+                            expected: arg_type.clone(),
+                            found: typ,
+                        });
                     }
                 }
 
@@ -516,10 +620,12 @@ impl Node {
                 rt.push(name, Value::Pseudo(annot.clone()));
                 let valuety = value.typecheck(rt, Some(annot.clone()))?;
                 if **annot != *valuety {
-                    return Err(Error::TypeError(
-                        *sloc,
-                        format!("type missmatch: {} vs {}", valuety.as_ref(), annot.as_ref()),
-                    ));
+                    return Err(Error::TypeMismatch {
+                        sloc: value.sloc(),
+                        context: format!("value of {} does not match its type annotation", name),
+                        expected: annot.clone(),
+                        found: valuety,
+                    });
                 }
                 let bodyty = body.typecheck(rt, None)?;
                 rt.pop(1);
@@ -585,6 +691,70 @@ impl Node {
                 },
                 _ => Err(Error::TypeError(*sloc, format!("not a record: {}", op0))),
             },
+            Node::Project { typ: Some(t), .. } => Ok(t.clone()),
+            Node::Project {
+                sloc,
+                typ,
+                op0,
+                fields,
+                by_type,
+            } => {
+                let op0ty = op0.typecheck(rt, None)?;
+                let Type::Record(op0fields) = op0ty.as_ref() else {
+                    return Err(Error::TypeError(*sloc, format!("not a record: {}", op0)));
+                };
+
+                let mut projected: Vec<(Rc<str>, Rc<Type>)> = Vec::with_capacity(fields.len());
+                let mut expected = Vec::new();
+                if let Some(rawtyp) = by_type {
+                    match rawtyp.typecheck(rt, None)?.as_ref() {
+                        Type::TypeOf(t) if matches!(t.as_ref(), Type::Record(_)) => {
+                            let Type::Record(tfields) = t.as_ref() else { unreachable!() };
+                            expected = tfields.clone();
+                            *fields = tfields.iter().map(|(name, _)| name.clone()).collect();
+                        }
+                        other => {
+                            return Err(Error::TypeError(
+                                *sloc,
+                                format!("expected a record type to project by, found: {}", other),
+                            ))
+                        }
+                    }
+                }
+
+                for (i, field) in fields.iter().enumerate() {
+                    if projected.iter().any(|(name, _)| name.as_ref() == field.as_ref()) {
+                        return Err(Error::TypeError(
+                            *sloc,
+                            format!("field {:?} projected twice", field.as_ref()),
+                        ));
+                    }
+                    let Some((_, t)) = op0fields
+                        .iter()
+                        .find(|(name, _)| name.as_ref() == field.as_ref())
+                    else {
+                        return Err(Error::TypeError(
+                            *sloc,
+                            format!("record does not have field {:?}: {}", field.as_ref(), op0),
+                        ));
+                    };
+                    if let Some((_, et)) = expected.get(i) {
+                        if **et != **t {
+                            return Err(Error::TypeMismatch {
+                                sloc: *sloc,
+                                context: format!("field {} of projection", field),
+                                expected: et.clone(),
+                                found: t.clone(),
+                            });
+                        }
+                    }
+                    projected.push((field.clone(), t.clone()));
+                }
+
+                let t = Rc::new(Type::Record(projected));
+                *typ = Some(t.clone());
+                Ok(t)
+            }
             Node::As { typ: Some(t), .. } => Ok(t.clone()),
             Node::As {
                 sloc,
@@ -637,11 +807,34 @@ impl Node {
     }
 }
 
+impl std::fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::And => "&",
+            BinOp::Or => "|",
+            BinOp::EQ => "==",
+            BinOp::NE => "!=",
+            BinOp::LT => "<",
+            BinOp::LE => "<=",
+            BinOp::GT => ">",
+            BinOp::GE => ">=",
+            BinOp::Prefer => "⫽",
+            BinOp::Combine => "∧",
+            BinOp::CombineTypes => "⩓",
+        })
+    }
+}
+
 impl std::fmt::Display for Node {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Node::Id { name, .. } => f.write_str(name.as_ref()),
             Node::Integer { value, .. } => write!(f, "{}", value),
+            Node::Double { value, .. } => write!(f, "{:?}", value),
             Node::Boolean { value: true, .. } => f.write_str("⊤"),
             Node::Boolean { value: false, .. } => f.write_str("⊥"),
             Node::String { value, .. } => write!(f, "{:?}", value.as_ref()),
@@ -655,26 +848,9 @@ impl std::fmt::Display for Node {
                 ..
             } => write!(f, "({} : {})", op0.as_ref(), rawtyp.as_ref()),
             Node::Invert { op0, .. } => write!(f, "(~{})", op0.as_ref()),
-            Node::BinOp { op, lhs, rhs, .. } => write!(
-                f,
-                "({} {} {})",
-                lhs.as_ref(),
-                match op {
-                    BinOp::Add => "+",
-                    BinOp::Sub => "-",
-                    BinOp::Mul => "*",
-                    BinOp::Div => "*",
-                    BinOp::And => "&",
-                    BinOp::Or => "|",
-                    BinOp::EQ => "==",
-                    BinOp::NE => "!=",
-                    BinOp::LT => "<",
-                    BinOp::LE => "<=",
-                    BinOp::GT => ">",
-                    BinOp::GE => "<=",
-                },
-                rhs.as_ref()
-            ),
+            Node::BinOp { op, lhs, rhs, .. } => {
+                write!(f, "({} {} {})", lhs.as_ref(), op, rhs.as_ref())
+            }
             Node::Call { callable, args, .. } => {
                 write!(f, "({})(", callable.as_ref())?;
                 for (i, arg) in args.iter().enumerate() {
@@ -750,6 +926,18 @@ impl std::fmt::Display for Node {
             Node::AccessField { op0, field, .. } => {
                 write!(f, "({}).{}", op0.as_ref(), field.as_ref())
             }
+            Node::Project {
+                op0,
+                by_type: Some(rawtyp),
+                ..
+            } => write!(f, "({}).({})", op0.as_ref(), rawtyp.as_ref()),
+            Node::Project { op0, fields, .. } => {
+                write!(f, "({}).{{", op0.as_ref())?;
+                for (i, field) in fields.iter().enumerate() {
+                    write!(f, "{}{}", if i != 0 { ", " } else { " " }, field.as_ref())?;
+                }
+                write!(f, " }}")
+            }
             Node::As { op0, as_raw, .. } => write!(f, "({} as {})", op0, as_raw),
             Node::TypeOf { op0, .. } => write!(f, "typeof({})", op0)
         }
@@ -888,6 +1076,9 @@ impl<'a> Parser<'a> {
             Tok::GreaterOrEqual => Some((BinOp::GE, 80, true, true)),
             Tok::Ampersand => Some((BinOp::And, 70, true, false)),
             Tok::Pipe => Some((BinOp::Or, 70, true, false)),
+            Tok::Prefer => Some((BinOp::Prefer, 60, true, false)),
+            Tok::Combine => Some((BinOp::Combine, 60, true, false)),
+            Tok::CombineTypes => Some((BinOp::CombineTypes, 60, true, false)),
             _ => None,
         }
     }
@@ -971,6 +1162,38 @@ impl<'a> Parser<'a> {
             }
 
             if self.consume_if(Tok::Dot) {
+                let sloc = self.consumed_sloc;
+                if self.consume_if(Tok::LBrace) {
+                    let mut fields = vec![];
+                    while !self.consume_if(Tok::RBrace) {
+                        if !fields.is_empty() {
+                            self.expect_token(Tok::Comma)?;
+                        }
+                        fields.push(self.expect_id()?.1);
+                    }
+                    expr = Box::new(Node::Project {
+                        sloc,
+                        typ: None,
+                        op0: expr,
+                        fields,
+                        by_type: None,
+                    });
+                    continue;
+                }
+
+                if self.consume_if(Tok::LParen) {
+                    let rawtyp = self.parse()?;
+                    self.expect_token(Tok::RParen)?;
+                    expr = Box::new(Node::Project {
+                        sloc,
+                        typ: None,
+                        op0: expr,
+                        fields: vec![],
+                        by_type: Some(rawtyp),
+                    });
+                    continue;
+                }
+
                 let (sloc, name) = self.expect_id()?;
                 expr = Box::new(Node::AccessField {
                     sloc,
@@ -978,6 +1201,7 @@ impl<'a> Parser<'a> {
                     op0: expr,
                     field: name,
                 });
+                continue;
             }
 
             break;
@@ -1003,11 +1227,17 @@ impl<'a> Parser<'a> {
                 typ: None,
                 value,
             },
+            Tok::Real(value) => Node::Double {
+                sloc,
+                typ: None,
+                value,
+            },
             Tok::String(value) => Node::String {
                 sloc,
                 typ: None,
                 value,
             },
+            Tok::InterpBegin(value) => return self.parse_interpolation(sloc, value),
             Tok::Tilde => Node::Invert {
                 sloc,
                 typ: None,
@@ -1100,6 +1330,55 @@ impl<'a> Parser<'a> {
         }))
     }
 
+    /* "a${x}b" is desugared to ("a" + x) + "b". */
+    fn parse_interpolation(&mut self, sloc: SLoc, prefix: Rc<str>) -> Result<Box<Node>, Error> {
+        let mut expr = Box::new(Node::String {
+            sloc,
+            typ: None,
+            value: prefix,
+        });
+        loop {
+            let interpolated = self.parse()?;
+            expr = Box::new(Node::BinOp {
+                sloc,
+                typ: None,
+                op: BinOp::Add,
+                lhs: expr,
+                rhs: interpolated,
+                iscmp: false,
+            });
+
+            let (strsloc, tok) = self.lexer.next().ok_or(Error::UnexpectedEOF)??;
+            let (value, done) = match tok {
+                Tok::InterpMid(value) => (value, false),
+                Tok::InterpEnd(value) => (value, true),
+                tok => {
+                    return Err(Error::Parser(
+                        strsloc,
+                        format!("expected '}}' closing the interpolation, found: {:?}", tok),
+                    ))
+                }
+            };
+            if !value.is_empty() {
+                expr = Box::new(Node::BinOp {
+                    sloc,
+                    typ: None,
+                    op: BinOp::Add,
+                    lhs: expr,
+                    rhs: Box::new(Node::String {
+                        sloc: strsloc,
+                        typ: None,
+                        value,
+                    }),
+                    iscmp: false,
+                });
+            }
+            if done {
+                return Ok(expr);
+            }
+        }
+    }
+
     fn parse_record(&mut self, sloc: SLoc, id0: Rc<str>) -> Result<Box<Node>, Error> {
         let val0 = self.parse()?;
         let mut fields = vec![(id0, *val0)];
@@ -0,0 +1,353 @@
+use std::rc::Rc;
+
+use crate::{
+    ast::Node,
+    core::{Error, SLoc, Type, Value},
+    eval::{natural, Runtime},
+    vm::{Function, Instr},
+};
+
+/*
+ * Compiles a type-checked AST into bytecode for the VM (see vm.rs). Names
+ * are resolved at compile time: to a stack slot in the current function,
+ * to a captured value of the current closure, or to a global (which ends
+ * up in the constant pool).
+ */
+pub fn compile(node: &Node, rt: &Runtime) -> Result<Rc<Function>, Error> {
+    let mut compiler = Compiler {
+        rt,
+        fns: vec![FnCtx::new(None, Function::default())],
+    };
+    compiler.compile(node)?;
+    compiler.emit(Instr::Return, node.sloc());
+    let ctx = compiler.fns.pop().unwrap();
+    Ok(Rc::new(ctx.func))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Var {
+    Local(u32),
+    Capture(u32),
+    SelfRef,
+}
+
+struct FnCtx {
+    func: Function,
+    // Name of the let-binding this lambda is the value of.
+    self_name: Option<Rc<str>>,
+    // Names and their stack slot relative to the frame base.
+    locals: Vec<(Rc<str>, u32)>,
+    // The captured names and where to get them from in the enclosing function.
+    captures: Vec<(Rc<str>, Var)>,
+    // Current stack height relative to the frame base.
+    sp: u32,
+}
+
+impl FnCtx {
+    fn new(self_name: Option<Rc<str>>, func: Function) -> Self {
+        Self {
+            func,
+            self_name,
+            locals: vec![],
+            captures: vec![],
+            sp: 0,
+        }
+    }
+}
+
+struct Compiler<'a> {
+    rt: &'a Runtime,
+    fns: Vec<FnCtx>,
+}
+
+impl<'a> Compiler<'a> {
+    fn ctx(&mut self) -> &mut FnCtx {
+        self.fns.last_mut().unwrap()
+    }
+
+    fn emit(&mut self, instr: Instr, sloc: SLoc) -> usize {
+        let ctx = self.fns.last_mut().unwrap();
+        let func = &ctx.func;
+        let effect: i64 = match instr {
+            Instr::Const(_) | Instr::Load(_) | Instr::LoadCapture(_) | Instr::LoadSelf => 1,
+            Instr::Slide(n) => -(n as i64),
+            Instr::Invert | Instr::Jump(_) | Instr::MakeForall(_) => 0,
+            Instr::Field(_) | Instr::Project(_) => 0,
+            Instr::BinOp(_) | Instr::JumpIfFalse(_) | Instr::Return | Instr::As => -1,
+            Instr::Call(n) => -(n as i64),
+            Instr::MakeClosure(idx) => 1 - func.functions[idx as usize].ncaptures as i64,
+            Instr::MakeRecord(idx) | Instr::MakeRecordType(idx) => {
+                1 - func.fieldnames[idx as usize].len() as i64
+            }
+        };
+        ctx.sp = (ctx.sp as i64 + effect) as u32;
+        ctx.func.code.push(instr);
+        ctx.func.slocs.push(sloc);
+        ctx.func.code.len() - 1
+    }
+
+    fn patch(&mut self, at: usize) {
+        let ctx = self.ctx();
+        let target = ctx.func.code.len() as u32;
+        match &mut ctx.func.code[at] {
+            Instr::Jump(t) | Instr::JumpIfFalse(t) => *t = target,
+            _ => unreachable!(),
+        }
+    }
+
+    fn constant(&mut self, value: Value, sloc: SLoc) {
+        let consts = &mut self.ctx().func.consts;
+        consts.push(value);
+        let idx = consts.len() as u32 - 1;
+        self.emit(Instr::Const(idx), sloc);
+    }
+
+    fn push_local(&mut self, name: &Rc<str>) {
+        let ctx = self.ctx();
+        ctx.locals.push((name.clone(), ctx.sp - 1));
+    }
+
+    fn resolve(&mut self, name: &str, level: usize) -> Option<Var> {
+        let ctx = &self.fns[level];
+        if let Some((_, slot)) = ctx.locals.iter().rev().find(|(n, _)| n.as_ref() == name) {
+            return Some(Var::Local(*slot));
+        }
+        if ctx.self_name.as_deref() == Some(name) {
+            return Some(Var::SelfRef);
+        }
+        if let Some(idx) = ctx.captures.iter().position(|(n, _)| n.as_ref() == name) {
+            return Some(Var::Capture(idx as u32));
+        }
+        if level == 0 {
+            return None;
+        }
+
+        let var = self.resolve(name, level - 1)?;
+        let ctx = &mut self.fns[level];
+        ctx.captures.push((Rc::from(name), var));
+        Some(Var::Capture(ctx.captures.len() as u32 - 1))
+    }
+
+    fn load(&mut self, var: Var, sloc: SLoc) {
+        match var {
+            Var::Local(slot) => self.emit(Instr::Load(slot), sloc),
+            Var::Capture(idx) => self.emit(Instr::LoadCapture(idx), sloc),
+            Var::SelfRef => self.emit(Instr::LoadSelf, sloc),
+        };
+    }
+
+    fn compile(&mut self, node: &Node) -> Result<(), Error> {
+        match node {
+            Node::Id { sloc, name, .. } => {
+                let level = self.fns.len() - 1;
+                if let Some(var) = self.resolve(name, level) {
+                    self.load(var, *sloc);
+                    return Ok(());
+                }
+                match self.rt.globals.get(name.as_ref()) {
+                    Some(value) => self.constant(value.clone(), *sloc),
+                    None => {
+                        return Err(Error::UndefinedValue(
+                            *sloc,
+                            name.clone(),
+                            self.rt.suggest(name),
+                        ))
+                    }
+                }
+            }
+            Node::Integer {
+                sloc,
+                typ: Some(t),
+                value,
+            } if **t == Type::Natural => self.constant(natural(*sloc, *value)?, *sloc),
+            Node::Integer { sloc, value, .. } => self.constant(Value::Int(*value), *sloc),
+            Node::Double { sloc, value, .. } => self.constant(Value::Double(*value), *sloc),
+            Node::Boolean { sloc, value, .. } => self.constant(Value::Bool(*value), *sloc),
+            Node::String { sloc, value, .. } => self.constant(Value::Text(value.clone()), *sloc),
+            Node::TypeAnno { op0, .. } => self.compile(op0)?,
+            Node::Invert { sloc, op0, .. } => {
+                self.compile(op0)?;
+                self.emit(Instr::Invert, *sloc);
+            }
+            Node::BinOp {
+                sloc, op, lhs, rhs, ..
+            } => {
+                self.compile(lhs)?;
+                self.compile(rhs)?;
+                self.emit(Instr::BinOp(*op), *sloc);
+            }
+            Node::Call {
+                sloc,
+                callable,
+                args,
+                ..
+            } => {
+                self.compile(callable)?;
+                for arg in args {
+                    self.compile(arg)?;
+                }
+                self.emit(Instr::Call(args.len() as u32), *sloc);
+            }
+            Node::IfThenElse {
+                sloc,
+                op0,
+                op1,
+                op2,
+                ..
+            } => {
+                self.compile(op0)?;
+                let jump_else = self.emit(Instr::JumpIfFalse(0), *sloc);
+                self.compile(op1)?;
+                let jump_end = self.emit(Instr::Jump(0), *sloc);
+                self.ctx().sp -= 1;
+                self.patch(jump_else);
+                self.compile(op2)?;
+                self.patch(jump_end);
+            }
+            Node::LetIn {
+                sloc,
+                name,
+                value,
+                body,
+                ..
+            } => {
+                let mut lambda = value.as_ref();
+                while let Node::TypeAnno { op0, .. } = lambda {
+                    lambda = op0;
+                }
+                match lambda {
+                    Node::Lambda { .. } => self.compile_lambda(lambda, Some(name.clone()))?,
+                    _ => self.compile(value)?,
+                }
+                self.push_local(name);
+                self.compile(body)?;
+                self.ctx().locals.pop();
+                self.emit(Instr::Slide(1), *sloc);
+            }
+            Node::Lambda { .. } => self.compile_lambda(node, None)?,
+            Node::Forall {
+                sloc,
+                argtypes,
+                rettyp,
+                ..
+            } => {
+                // The arguments are in scope as their types for the return type.
+                let mut args = Vec::with_capacity(argtypes.len());
+                for (name, typ, _) in argtypes {
+                    let typ = typ.clone().unwrap();
+                    args.push((name.clone(), typ.clone()));
+                    self.constant(Value::Type(typ), *sloc);
+                    self.push_local(name);
+                }
+                self.compile(&rettyp.borrow())?;
+                let foralls = &mut self.ctx().func.foralls;
+                foralls.push(args);
+                let idx = foralls.len() as u32 - 1;
+                self.emit(Instr::MakeForall(idx), *sloc);
+                let ctx = self.ctx();
+                ctx.locals.truncate(ctx.locals.len() - argtypes.len());
+                self.emit(Instr::Slide(argtypes.len() as u32), *sloc);
+            }
+            Node::Record { sloc, fields, .. } => {
+                for (_, value) in fields {
+                    self.compile(value)?;
+                }
+                let idx = self.fieldnames(fields);
+                self.emit(Instr::MakeRecord(idx), *sloc);
+            }
+            Node::RecordType { sloc, fields, .. } => {
+                for (_, typ) in fields {
+                    self.compile(typ)?;
+                }
+                let idx = self.fieldnames(fields);
+                self.emit(Instr::MakeRecordType(idx), *sloc);
+            }
+            Node::AccessField {
+                sloc, op0, field, ..
+            } => {
+                self.compile(op0)?;
+                let idx = field_index(op0, field)?;
+                self.emit(Instr::Field(idx), *sloc);
+            }
+            Node::Project {
+                sloc, op0, fields, ..
+            } => {
+                self.compile(op0)?;
+                let indices = fields
+                    .iter()
+                    .map(|f| field_index(op0, f))
+                    .collect::<Result<_, _>>()?;
+                let projections = &mut self.ctx().func.projections;
+                projections.push(indices);
+                let idx = projections.len() as u32 - 1;
+                self.emit(Instr::Project(idx), *sloc);
+            }
+            Node::As {
+                sloc, op0, as_raw, ..
+            } => {
+                self.compile(op0)?;
+                self.compile(as_raw)?;
+                self.emit(Instr::As, *sloc);
+            }
+            Node::TypeOf { sloc, typ, .. } => {
+                self.constant(Value::Type(typ.clone().unwrap()), *sloc)
+            }
+        };
+        Ok(())
+    }
+
+    fn compile_lambda(&mut self, node: &Node, self_name: Option<Rc<str>>) -> Result<(), Error> {
+        let Node::Lambda {
+            sloc, args, body, ..
+        } = node
+        else {
+            unreachable!()
+        };
+
+        let func = Function {
+            args: args
+                .iter()
+                .map(|(name, typ, _)| (name.clone(), typ.clone().unwrap()))
+                .collect(),
+            rettyp: body.borrow().get_type(),
+            body: Some(body.clone()),
+            ..Default::default()
+        };
+        let mut ctx = FnCtx::new(self_name, func);
+        for (name, _, _) in args {
+            ctx.locals.push((name.clone(), ctx.sp));
+            ctx.sp += 1;
+        }
+        self.fns.push(ctx);
+        self.compile(&body.borrow())?;
+        self.emit(Instr::Return, *sloc);
+
+        let mut ctx = self.fns.pop().unwrap();
+        ctx.func.ncaptures = ctx.captures.len() as u32;
+        for (_, var) in ctx.captures.iter() {
+            self.load(*var, *sloc);
+        }
+        let functions = &mut self.ctx().func.functions;
+        functions.push(Rc::new(ctx.func));
+        let idx = functions.len() as u32 - 1;
+        self.emit(Instr::MakeClosure(idx), *sloc);
+        Ok(())
+    }
+
+    fn fieldnames(&mut self, fields: &[(Rc<str>, Node)]) -> u32 {
+        let fieldnames = &mut self.ctx().func.fieldnames;
+        fieldnames.push(fields.iter().map(|(name, _)| name.clone()).collect());
+        fieldnames.len() as u32 - 1
+    }
+}
+
+/* Record values have their fields in the same order as their type. */
+fn field_index(record: &Node, field: &str) -> Result<u32, Error> {
+    let pos = match record.get_type().as_deref() {
+        Some(Type::Record(fields)) => fields.iter().position(|(name, _)| name.as_ref() == field),
+        _ => None,
+    };
+    pos.map(|pos| pos as u32).ok_or_else(|| {
+        Error::TypeError(record.sloc(), format!("no field '{}' in the record", field))
+    })
+}
@@ -6,8 +6,8 @@ use std::{
 
 use crate::{
     ast::Node,
-    eval::{eval, Scope},
-    lex,
+    eval::{eval, Budget, Scope},
+    lex, vm,
 };
 
 #[derive(Clone, Copy, Debug, Default)]
@@ -34,10 +34,20 @@ pub enum Error {
         expected: lex::Tok,
         found: lex::Tok,
     },
-    UndefinedValue(Rc<str>),
+    // The last field is a similarly named value as a suggestion.
+    UndefinedValue(SLoc, Rc<str>, Option<Rc<str>>),
     Uncallable(SLoc, String),
     ExpectedType(SLoc),
     TypeError(SLoc, String),
+    TypeMismatch {
+        sloc: SLoc,
+        context: String,
+        expected: Rc<Type>,
+        found: Rc<Type>,
+    },
+    LimitExceeded(SLoc, String),
+    // Failures the type-check cannot rule out, like a division by zero.
+    Eval(SLoc, String),
 }
 
 // TODO: Do something string_pool like for types?
@@ -47,7 +57,9 @@ pub enum Error {
 pub enum Type {
     Placeholder(Rc<str>),
     Bool,
+    Natural,
     Int,
+    Double,
     Text,
     Any,
     TypeOfType,
@@ -62,7 +74,9 @@ impl Display for Type {
         match self {
             Type::Placeholder(name) => write!(f, "{}", name.as_ref()),
             Type::Bool => write!(f, "Bool"),
+            Type::Natural => write!(f, "Natural"),
             Type::Int => write!(f, "Int"),
+            Type::Double => write!(f, "Double"),
             Type::Text => write!(f, "Text"),
             Type::Any => write!(f, "Any"),
             Type::TypeOfType => write!(f, "Type"),
@@ -102,7 +116,9 @@ impl PartialEq for Type {
             // a different but equal in all positions ID is used in the rhs.
             (Type::Placeholder(tp1), Type::Placeholder(tp2)) => tp1.as_ref() == tp2.as_ref(),
             (Type::Bool, Type::Bool) => true,
+            (Type::Natural, Type::Natural) => true,
             (Type::Int, Type::Int) => true,
+            (Type::Double, Type::Double) => true,
             (Type::Text, Type::Text) => true,
             (Type::Any, Type::Any) => true,
             (Type::TypeOfType, Type::TypeOfType) => true,
@@ -137,7 +153,9 @@ impl Type {
             Type::Placeholder(placeholder) if placeholder.as_ref() == name => subst.clone(),
             Type::Placeholder(_) => self.clone(),
             Type::Bool => self.clone(),
+            Type::Natural => self.clone(),
             Type::Int => self.clone(),
+            Type::Double => self.clone(),
             Type::Text => self.clone(),
             Type::Any => self.clone(),
             Type::TypeOfType => self.clone(),
@@ -187,17 +205,65 @@ impl Type {
     }
 }
 
+/* Merges the fields of two records (or record types). Fields only present on one
+ * side are kept, fields present on both sides are merged using `on_collision`.
+ * The order of the lhs is preserved, new fields from the rhs are appended. */
+pub fn merge_fields<T: Clone, E>(
+    lhs: &[(Rc<str>, T)],
+    rhs: &[(Rc<str>, T)],
+    mut on_collision: impl FnMut(&Rc<str>, &T, &T) -> Result<T, E>,
+) -> Result<Vec<(Rc<str>, T)>, E> {
+    let mut fields = Vec::with_capacity(lhs.len() + rhs.len());
+    for (name, l) in lhs {
+        match rhs.iter().find(|(n, _)| n.as_ref() == name.as_ref()) {
+            Some((_, r)) => fields.push((name.clone(), on_collision(name, l, r)?)),
+            None => fields.push((name.clone(), l.clone())),
+        }
+    }
+    for (name, r) in rhs {
+        if !lhs.iter().any(|(n, _)| n.as_ref() == name.as_ref()) {
+            fields.push((name.clone(), r.clone()));
+        }
+    }
+    Ok(fields)
+}
+
+impl Type {
+    /* The type-level equivalent of the recursive record merge: Fields present
+     * in both records need to be records themselves. */
+    pub fn combine_records(
+        lhs: &[(Rc<str>, Rc<Type>)],
+        rhs: &[(Rc<str>, Rc<Type>)],
+    ) -> Result<Vec<(Rc<str>, Rc<Type>)>, String> {
+        merge_fields(lhs, rhs, |name, l, r| match (l.as_ref(), r.as_ref()) {
+            (Type::Record(l), Type::Record(r)) => {
+                Type::combine_records(l, r).map(|fields| Rc::new(Type::Record(fields)))
+            }
+            (l, r) => Err(format!(
+                "field {:?} present on both sides but not a record: {} and {}",
+                name.as_ref(),
+                l,
+                r
+            )),
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Value {
     Pseudo(Rc<Type>),
     Bool(bool),
+    Natural(u64),
     Int(i64),
+    Double(f64),
     Text(Rc<str>),
     Type(Rc<Type>),
     // TODO: This will cause cyclic Rc<...> references: The lambda is in the
     // scope for this lambda. This is unavoidable for recursive functions.
     // Solution: A weak rc somewhere... But where?
     Lambda(Rc<Lambda>),
+    // A lambda compiled to bytecode, see vm.rs.
+    Closure(Rc<vm::Closure>),
     Builtin(Rc<Builtin>),
     Option(Rc<Type>, Option<Box<Value>>),
     Record(Vec<(Rc<str>, Value)>),
@@ -208,7 +274,8 @@ pub struct Builtin {
     pub name: &'static str,
     pub argtypes: Vec<(Rc<str>, Rc<Type>)>,
     pub rettyp: Rc<Type>,
-    pub f: Box<dyn Fn(Vec<Value>) -> Result<Value, Error>>,
+    // Gets the location of the call for its errors.
+    pub f: Box<dyn Fn(SLoc, Vec<Value>) -> Result<Value, Error>>,
 }
 
 #[derive(Debug)]
@@ -233,7 +300,9 @@ impl Value {
         match self {
             Value::Pseudo(t) => t.clone(),
             Value::Bool(_) => Rc::new(Type::Bool),
+            Value::Natural(_) => Rc::new(Type::Natural),
             Value::Int(_) => Rc::new(Type::Int),
+            Value::Double(_) => Rc::new(Type::Double),
             Value::Text(_) => Rc::new(Type::Text),
             Value::Type(t) => {
                 if **t == Type::TypeOfType {
@@ -246,6 +315,10 @@ impl Value {
                 lambda.args.clone(),
                 lambda.body.borrow().get_type().unwrap(),
             )),
+            Value::Closure(closure) => Rc::new(Type::Lambda(
+                closure.func.args.clone(),
+                closure.func.rettyp.clone().unwrap(),
+            )),
             Value::Builtin(b) => Rc::new(Type::Lambda(b.argtypes.clone(), b.rettyp.clone())),
             Value::Option(t, _) => Rc::new(Type::Option(t.clone())),
             Value::Record(fields) => Rc::new(Type::Record(
@@ -258,7 +331,7 @@ impl Value {
         }
     }
 
-    pub fn apply(&self, sloc: SLoc, args: Vec<Value>) -> Result<Value, Error> {
+    pub fn apply(&self, sloc: SLoc, args: Vec<Value>, budget: &mut Budget) -> Result<Value, Error> {
         match self {
             Value::Lambda(lambda) => {
                 let scope = lambda.scope.borrow();
@@ -268,9 +341,10 @@ impl Value {
                     scope = scope.push(name, value);
                 }
 
-                eval(&lambda.body.borrow(), &scope)
+                eval(&lambda.body.borrow(), &scope, budget)
             }
-            Value::Builtin(b) => (b.f)(args),
+            Value::Closure(closure) => vm::call(closure, args, budget),
+            Value::Builtin(b) => (b.f)(sloc, args),
             _ => Err(Error::Uncallable(sloc, format!("{}", self))),
         }
     }
@@ -289,7 +363,9 @@ impl Display for Value {
             Value::Pseudo(t) => write!(f, "<Something of Type {}>", t.as_ref()),
             Value::Bool(true) => write!(f, "true"),
             Value::Bool(false) => write!(f, "false"),
+            Value::Natural(x) => write!(f, "{}", x),
             Value::Int(x) => write!(f, "{}", x),
+            Value::Double(x) => write!(f, "{:?}", x),
             Value::Text(s) => write!(f, "{:?}", s.as_ref()),
             Value::Type(t) => Display::fmt(t.as_ref(), f),
             Value::Lambda(lambda) => {
@@ -303,6 +379,17 @@ impl Display for Value {
                 }
                 write!(f, ") -> ({})", lambda.body.as_ref().borrow())
             }
+            Value::Closure(closure) => {
+                write!(f, "λ(")?;
+                for (i, (name, typ)) in closure.func.args.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", {}: {}", name.as_ref(), typ)?;
+                    } else {
+                        write!(f, "{}: {}", name.as_ref(), typ)?;
+                    }
+                }
+                write!(f, ") -> ({})", closure.func.body.as_ref().unwrap().borrow())
+            }
             Value::Builtin(b) => write!(f, "{}", b.name),
             Value::Option(_, Some(val)) => write!(f, "Some({})", val),
             Value::Option(t, None) => write!(f, "None({})", t.as_ref()),
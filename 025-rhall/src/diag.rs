@@ -0,0 +1,264 @@
+use std::fmt::Write;
+
+use crate::core::{Error, SLoc, Type};
+
+/*
+ * Renders errors for humans: The message, the source line with a caret
+ * pointing at the location, and for type mismatches a list of the actual
+ * differences between the expected and the found type (which can be hard
+ * to spot in the printed types of large records or lambdas).
+ */
+pub fn render(err: &Error, filename: &str, source: &str) -> String {
+    let mut out = String::new();
+    let mut notes = vec![];
+    let (sloc, msg) = match err {
+        Error::Lexer(sloc, msg) => (Some(*sloc), msg.clone()),
+        Error::Parser(sloc, msg) => (Some(*sloc), msg.clone()),
+        Error::UnexpectedEOF => (None, "unexpected end of input".to_string()),
+        Error::ExpectedToken {
+            sloc,
+            expected,
+            found,
+        } => (Some(*sloc), format!("expected {:?}, found {:?}", expected, found)),
+        Error::UndefinedValue(sloc, name, suggestion) => {
+            if let Some(suggestion) = suggestion {
+                notes.push(format!("help: did you mean `{}`?", suggestion));
+            }
+            (Some(*sloc), format!("undefined value: `{}`", name))
+        }
+        Error::Uncallable(sloc, msg) => (Some(*sloc), msg.clone()),
+        Error::ExpectedType(sloc) => (Some(*sloc), "expected a type".to_string()),
+        Error::TypeError(sloc, msg) => (Some(*sloc), msg.clone()),
+        Error::LimitExceeded(sloc, msg) => (Some(*sloc), msg.clone()),
+        Error::Eval(sloc, msg) => (Some(*sloc), msg.clone()),
+        Error::TypeMismatch {
+            sloc,
+            context,
+            expected,
+            found,
+        } => {
+            notes.push(format!("expected: {}", expected));
+            notes.push(format!("   found: {}", found));
+            notes.extend(type_diff(expected, found));
+            (Some(*sloc), format!("type mismatch: {}", context))
+        }
+    };
+
+    writeln!(out, "error: {}", msg).unwrap();
+    let gutter = match sloc {
+        Some(sloc) => render_sloc(&mut out, sloc, filename, source),
+        None => 1,
+    };
+    for note in notes {
+        writeln!(out, "{:gutter$} = {}", "", note, gutter = gutter).unwrap();
+    }
+    out
+}
+
+/* Returns the width of the line number gutter. */
+fn render_sloc(out: &mut String, sloc: SLoc, filename: &str, source: &str) -> usize {
+    let lineno = sloc.line.to_string();
+    let gutter = lineno.len();
+    writeln!(out, "{:gutter$}--> {}:{}:{}", "", filename, sloc.line, sloc.col).unwrap();
+    let Some(line) = source.lines().nth((sloc.line as usize).saturating_sub(1)) else {
+        return gutter;
+    };
+
+    // The lexer counts a tab as four columns:
+    let line = line.replace('\t', "    ");
+    writeln!(out, "{:gutter$} |", "").unwrap();
+    writeln!(out, "{} | {}", lineno, line).unwrap();
+    writeln!(
+        out,
+        "{:gutter$} | {:col$}^",
+        "",
+        "",
+        col = (sloc.col as usize).saturating_sub(1)
+    )
+    .unwrap();
+    gutter
+}
+
+/*
+ * The structural differences between two types, one line per difference.
+ * Only records, lambdas and the types wrapping them are looked into, for
+ * everything else the expected/found types are already precise enough.
+ */
+pub fn type_diff(expected: &Type, found: &Type) -> Vec<String> {
+    let mut diffs = vec![];
+    diff_into("", expected, found, &mut diffs);
+    diffs
+}
+
+fn diff_into(path: &str, expected: &Type, found: &Type, diffs: &mut Vec<String>) {
+    if expected == found {
+        return;
+    }
+
+    match (expected, found) {
+        (Type::Record(efields), Type::Record(ffields)) => {
+            let before = diffs.len();
+            for (name, etyp) in efields {
+                match ffields.iter().find(|(n, _)| n.as_ref() == name.as_ref()) {
+                    Some((_, ftyp)) => diff_into(&format!("{}.{}", path, name), etyp, ftyp, diffs),
+                    None => diffs.push(format!("missing field `{}.{}: {}`", path, name, etyp)),
+                }
+            }
+            for (name, ftyp) in ffields {
+                if !efields.iter().any(|(n, _)| n.as_ref() == name.as_ref()) {
+                    diffs.push(format!("unexpected field `{}.{}: {}`", path, name, ftyp));
+                }
+            }
+            if diffs.len() == before {
+                diffs.push(format!("fields of `{}` are in a different order", path_or_root(path)));
+            }
+        }
+        (Type::Lambda(eargs, eret), Type::Lambda(fargs, fret)) => {
+            if eargs.len() != fargs.len() {
+                diffs.push(format!(
+                    "`{}` takes {} argument(s), expected {}",
+                    path_or_root(path),
+                    fargs.len(),
+                    eargs.len()
+                ));
+                return;
+            }
+            for ((name, etyp), (_, ftyp)) in eargs.iter().zip(fargs.iter()) {
+                diff_into(&format!("{}({})", path, name), etyp, ftyp, diffs);
+            }
+            diff_into(&format!("{}->", path), eret, fret, diffs);
+        }
+        (Type::Option(e), Type::Option(f)) => diff_into(&format!("{}?", path), e, f, diffs),
+        (Type::TypeOf(e), Type::TypeOf(f)) => diff_into(path, e, f, diffs),
+        (_, _) if path.is_empty() => {}
+        (_, _) => diffs.push(format!("at `{}`: expected {}, found {}", path, expected, found)),
+    }
+}
+
+fn path_or_root(path: &str) -> &str {
+    if path.is_empty() {
+        "<root>"
+    } else {
+        path
+    }
+}
+
+/* The candidate closest to `name` (by edit distance), if it is close enough. */
+pub fn suggest<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(1);
+    candidates
+        .filter(|c| !c.is_empty() && *c != name)
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|(d, _)| *d <= max_distance)
+        .min()
+        .map(|(_, c)| c)
+}
+
+/* Levenshtein distance, where swapping two adjacent characters counts as one edit. */
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Parser;
+    use crate::eval::{Runtime, RuntimeOptions};
+    use crate::lex::Lexer;
+
+    fn typecheck(input: &str) -> Error {
+        let rt = Runtime::with_options(RuntimeOptions::default());
+        let mut rt = rt.borrow_mut();
+        let mut spool = std::collections::HashSet::new();
+        let mut lexer = Lexer::new(input, 0, &mut spool);
+        let mut parser = Parser::new(&mut lexer);
+        let mut node = parser.parse_all().expect("parsing failed");
+        node.typecheck(&mut rt, None).expect_err("type-check succeeded")
+    }
+
+    #[test]
+    fn caret() {
+        let input = "let x = 1\nlet y: Text = x + 2\nin y";
+        let err = typecheck(input);
+        let expected = "error: type mismatch: value of y does not match its type annotation
+ --> test.dhall:2:17
+  |
+2 | let y: Text = x + 2
+  |                 ^
+  = expected: Text
+  =    found: Int
+";
+        assert_eq!(render(&err, "test.dhall", input), expected);
+    }
+
+    #[test]
+    fn record_diff() {
+        let err = typecheck(
+            "let f = λ(r: { a: Int, b: { c: Bool, d: Text } }) -> r.a in f({ a = 1, b = { c = 1, e = 2 } })",
+        );
+        let Error::TypeMismatch {
+            expected, found, ..
+        } = err
+        else {
+            panic!("unexpected error: {:?}", err)
+        };
+        assert_eq!(
+            type_diff(&expected, &found),
+            vec![
+                "at `.b.c`: expected Bool, found Int",
+                "missing field `.b.d: Text`",
+                "unexpected field `.b.e: Int`",
+            ]
+        );
+    }
+
+    #[test]
+    fn lambda_diff() {
+        let err = typecheck(
+            "let twice = λ(f: ∀(x: Int) -> Int, x: Int) -> f(f(x)) in twice(λ(x: Int) -> x == 0, 1)",
+        );
+        let Error::TypeMismatch {
+            expected, found, ..
+        } = err
+        else {
+            panic!("unexpected error: {:?}", err)
+        };
+        assert_eq!(
+            type_diff(&expected, &found),
+            vec!["at `->`: expected Int, found Bool"]
+        );
+    }
+
+    #[test]
+    fn did_you_mean() {
+        let input = "let value = 41 in valeu + 1";
+        let err = typecheck(input);
+        assert!(render(&err, "-", input).contains("did you mean `value`?"));
+
+        let err = typecheck("Natural/shwo(1)");
+        assert!(render(&err, "-", "").contains("did you mean `Natural/show`?"));
+
+        let err = typecheck("somethingelse");
+        assert!(!render(&err, "-", "").contains("did you mean"));
+    }
+}
@@ -1,16 +1,182 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, fmt::Display, rc::Rc};
 
 use crate::{
     ast::{BinOp, Node},
-    core::{Builtin, Error, Lambda, Type, Value},
+    core::{merge_fields, Builtin, Error, Lambda, SLoc, Type, Value},
+    diag,
 };
 
+pub const BUILTIN_NAMESPACES: [&str; 6] = ["Natural", "Integer", "Double", "Text", "Option", "Process"];
+
+/* Limits for the evaluation, enforced by both the VM and eval(). */
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_steps: Option<u64>,
+    pub timeout: Option<std::time::Duration>,
+    // Calls eval() may nest. It recurses on the native stack, so unlike
+    // the others this limit is always there. The VM keeps its frames on
+    // the heap and only counts calls that go through eval().
+    pub max_depth: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_steps: None,
+            timeout: None,
+            max_depth: 1000,
+        }
+    }
+}
+
+/*
+ * What is left of the Limits during an evaluation. There is one per
+ * evaluation, handed down to every function it calls, so closures called
+ * from builtins or the other evaluator count against the same limits.
+ */
+#[derive(Debug, Default)]
+pub struct Budget {
+    limits: Limits,
+    steps: u64,
+    depth: u32,
+    deadline: Option<std::time::Instant>,
+}
+
+impl Budget {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            steps: 0,
+            depth: 0,
+            deadline: limits.timeout.map(|timeout| std::time::Instant::now() + timeout),
+        }
+    }
+
+    /* Counts one step (an instruction or an AST node). */
+    #[inline]
+    pub fn step(&mut self, sloc: SLoc) -> Result<(), Error> {
+        self.steps += 1;
+        if let Some(max_steps) = self.limits.max_steps.filter(|max| self.steps > *max) {
+            return Err(Error::LimitExceeded(
+                sloc,
+                format!("evaluation exceeded the limit of {} steps", max_steps),
+            ));
+        }
+        // Looking at the clock is expensive, do it only every few thousand steps:
+        if self.steps.is_multiple_of(4096)
+            && self.deadline.is_some_and(|deadline| std::time::Instant::now() > deadline)
+        {
+            return Err(Error::LimitExceeded(
+                sloc,
+                format!("evaluation exceeded the time limit of {:?}", self.limits.timeout.unwrap()),
+            ));
+        }
+        Ok(())
+    }
+
+    /* Runs f as a call nested one deeper than the current one. */
+    pub fn nested<T>(
+        &mut self,
+        sloc: SLoc,
+        f: impl FnOnce(&mut Self) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        if self.depth >= self.limits.max_depth {
+            return Err(Error::LimitExceeded(
+                sloc,
+                format!(
+                    "evaluation exceeded the limit of {} nested calls",
+                    self.limits.max_depth
+                ),
+            ));
+        }
+        self.depth += 1;
+        let res = f(self);
+        self.depth -= 1;
+        res
+    }
+}
+
+/*
+ * What a program is allowed to do. The default allows everything, for
+ * evaluating untrusted code use RuntimeOptions::sandboxed(), which also
+ * limits steps and time (override them to taste).
+ */
+#[derive(Debug, Clone)]
+pub struct RuntimeOptions {
+    // Builtins are only registered if their namespace (the part before the
+    // '/', `Some` and `None` are part of "Option") is in this list.
+    pub namespaces: Vec<&'static str>,
+    // Environment variables readable by Process/getenv, `None` allows all.
+    pub env_allowlist: Option<Vec<String>>,
+    // The environment Process/getenv reads, `None` is the one of the process.
+    pub env: Option<Vec<(String, String)>>,
+    pub limits: Limits,
+}
+
+impl Default for RuntimeOptions {
+    fn default() -> Self {
+        Self {
+            namespaces: BUILTIN_NAMESPACES.to_vec(),
+            env_allowlist: None,
+            env: None,
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl RuntimeOptions {
+    pub fn sandboxed() -> Self {
+        Self {
+            namespaces: BUILTIN_NAMESPACES
+                .into_iter()
+                .filter(|ns| *ns != "Process")
+                .collect(),
+            env_allowlist: Some(vec![]),
+            env: None,
+            limits: Limits {
+                max_steps: Some(100_000_000),
+                timeout: Some(std::time::Duration::from_secs(10)),
+                ..Limits::default()
+            },
+        }
+    }
+
+    pub fn allows(&self, builtin: &str) -> bool {
+        let namespace = match builtin.split_once('/') {
+            Some((namespace, _)) => namespace,
+            None if builtin == "Some" || builtin == "None" => "Option",
+            None => builtin,
+        };
+        self.namespaces.contains(&namespace)
+    }
+
+    pub fn allows_env(&self, var: &str) -> bool {
+        match &self.env_allowlist {
+            Some(allowlist) => allowlist.iter().any(|allowed| allowed == var),
+            None => true,
+        }
+    }
+
+    pub fn getenv(&self, var: &str) -> Option<String> {
+        if !self.allows_env(var) {
+            return None;
+        }
+        match &self.env {
+            Some(env) => env.iter().find(|(name, _)| name == var).map(|(_, value)| value.clone()),
+            None => std::env::var(var).ok(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Runtime {
+    pub options: RuntimeOptions,
     pub globals: std::collections::HashMap<&'static str, Value>,
     pub string_pool: std::collections::HashSet<Rc<str>>,
     pub locals: Vec<(Rc<str>, Value)>, // <- only to use during type-check!
+    pub natural_type: Rc<Type>,
     pub int_type: Rc<Type>,
+    pub double_type: Rc<Type>,
     pub bool_type: Rc<Type>,
     pub text_type: Rc<Type>,
     pub type_type: Rc<Type>,
@@ -18,12 +184,15 @@ pub struct Runtime {
 }
 
 impl Runtime {
-    pub fn new() -> Rc<RefCell<Runtime>> {
+    pub fn with_options(options: RuntimeOptions) -> Rc<RefCell<Runtime>> {
         let mut rt = Runtime {
+            options,
             globals: std::collections::HashMap::new(),
             string_pool: std::collections::HashSet::new(),
             locals: Vec::new(),
+            natural_type: Rc::new(Type::Natural),
             int_type: Rc::new(Type::Int),
+            double_type: Rc::new(Type::Double),
             bool_type: Rc::new(Type::Bool),
             text_type: Rc::new(Type::Text),
             type_type: Rc::new(Type::TypeOfType),
@@ -52,8 +221,18 @@ impl Runtime {
         s
     }
 
+    /* A similarly named local or global for "did you mean ..." hints. */
+    pub fn suggest(&self, name: &str) -> Option<Rc<str>> {
+        let locals = self.locals.iter().map(|(name, _)| name.as_ref());
+        let globals = self.globals.keys().cloned();
+        diag::suggest(name, locals.chain(globals)).map(Rc::from)
+    }
+
     pub fn add_builtin(&mut self, name: &'static str, builtin: Builtin) {
         assert!(!self.globals.contains_key(name));
+        if !self.options.allows(name) {
+            return;
+        }
         self.globals.insert(name, Value::Builtin(Rc::new(builtin)));
     }
 
@@ -101,6 +280,21 @@ impl Scope {
         None
     }
 
+    pub fn suggest(&self, name: &str) -> Option<Rc<str>> {
+        let mut names = vec![];
+        let mut scope = Some(self);
+        while let Some(s) = scope {
+            names.push(s.local.0.as_ref());
+            if let Some(rt) = &s.runtime {
+                let rt = rt.borrow();
+                let globals = rt.globals.keys().cloned();
+                return diag::suggest(name, names.into_iter().chain(globals)).map(Rc::from);
+            }
+            scope = s.up.as_deref();
+        }
+        diag::suggest(name, names.into_iter()).map(Rc::from)
+    }
+
     pub fn push(self: &Rc<Self>, name: &Rc<str>, value: Value) -> Rc<Self> {
         Rc::new(Self {
             depth: self.depth + 1,
@@ -130,51 +324,47 @@ impl std::fmt::Display for Scope {
     }
 }
 
-pub fn eval(node: &Node, scope: &Rc<Scope>) -> Result<Value, Error> {
+pub fn eval(node: &Node, scope: &Rc<Scope>, budget: &mut Budget) -> Result<Value, Error> {
+    budget.step(node.sloc())?;
     match node {
-        Node::Id { name, .. } => scope
+        Node::Id { sloc, name, .. } => scope
             .lookup(name.as_ref())
-            .ok_or(Error::UndefinedValue(name.clone())),
+            .ok_or_else(|| Error::UndefinedValue(*sloc, name.clone(), scope.suggest(name))),
+        Node::Integer { sloc, typ: Some(t), value } if **t == Type::Natural => {
+            natural(*sloc, *value)
+        }
         Node::Integer { value, .. } => Ok(Value::Int(*value)),
+        Node::Double { value, .. } => Ok(Value::Double(*value)),
         Node::Boolean { value, .. } => Ok(Value::Bool(*value)),
         Node::String { value, .. } => Ok(Value::Text(value.clone())),
-        Node::TypeAnno { op0, .. } => eval(op0, scope),
-        Node::Invert { op0, .. } => Ok(match eval(op0, scope)? {
-            Value::Bool(value) => Value::Bool(!value),
-            Value::Int(value) => Value::Int(!value),
-            _ => unimplemented!(),
-        }),
-        Node::BinOp { op, lhs, rhs, .. } => Ok(match (op, eval(lhs, scope)?, eval(rhs, scope)?) {
-            (BinOp::Add, Value::Int(lhs), Value::Int(rhs)) => Value::Int(lhs + rhs),
-            (BinOp::Sub, Value::Int(lhs), Value::Int(rhs)) => Value::Int(lhs - rhs),
-            (BinOp::Mul, Value::Int(lhs), Value::Int(rhs)) => Value::Int(lhs * rhs),
-            (BinOp::Div, Value::Int(lhs), Value::Int(rhs)) => Value::Int(lhs / rhs),
-            (BinOp::EQ, Value::Int(lhs), Value::Int(rhs)) => Value::Bool(lhs == rhs),
-            (BinOp::NE, Value::Int(lhs), Value::Int(rhs)) => Value::Bool(lhs == rhs),
-            (BinOp::LT, Value::Int(lhs), Value::Int(rhs)) => Value::Bool(lhs < rhs),
-            (BinOp::LE, Value::Int(lhs), Value::Int(rhs)) => Value::Bool(lhs <= rhs),
-            (BinOp::Add, Value::Text(lhs), Value::Text(rhs)) => Value::Text(Rc::from(lhs.to_string() + rhs.as_ref())),
-            (op, lhs, rhs) => panic!("op: {:?}, lhs: {:?}, rhs: {:?}", op, lhs, rhs),
-        }),
+        Node::TypeAnno { op0, .. } => eval(op0, scope, budget),
+        Node::Invert { sloc, op0, .. } => invert(*sloc, eval(op0, scope, budget)?),
+        Node::BinOp { sloc, op, lhs, rhs, .. } => {
+            binop(*sloc, *op, eval(lhs, scope, budget)?, eval(rhs, scope, budget)?)
+        }
         Node::Call {
             sloc,
             callable,
             args,
             ..
         } => {
-            let callable = eval(callable, scope)?;
-            let args: Result<Vec<_>, _> = args.iter().map(|arg| eval(arg, scope)).collect();
-            callable.apply(*sloc, args?)
-        }
-        Node::IfThenElse { op0, op1, op2, .. } => match eval(op0, scope)? {
-            Value::Bool(true) => eval(op1, scope),
-            Value::Bool(false) => eval(op2, scope),
-            _ => unimplemented!(),
+            let callable = eval(callable, scope, budget)?;
+            let args: Result<Vec<_>, _> = args.iter().map(|arg| eval(arg, scope, budget)).collect();
+            let args = args?;
+            budget.nested(*sloc, |budget| callable.apply(*sloc, args, budget))
+        }
+        Node::IfThenElse { op0, op1, op2, .. } => match eval(op0, scope, budget)? {
+            Value::Bool(true) => eval(op1, scope, budget),
+            Value::Bool(false) => eval(op2, scope, budget),
+            value => Err(Error::TypeError(
+                op0.sloc(),
+                format!("condition of if-then-else is not a boolean: {}", value),
+            )),
         },
         Node::LetIn {
             name, value, body, ..
         } => {
-            let value = eval(value, scope)?;
+            let value = eval(value, scope, budget)?;
             if let Value::Lambda(l) = &value {
                 let oldscope = l.scope.borrow().clone();
                 l.scope
@@ -182,7 +372,7 @@ pub fn eval(node: &Node, scope: &Rc<Scope>) -> Result<Value, Error> {
             }
 
             let scope = scope.push(name, value);
-            let res = eval(body, &scope)?;
+            let res = eval(body, &scope, budget)?;
             Ok(res)
         }
         Node::Lambda { args, body, .. } => Ok(Value::Lambda(Rc::new(Lambda {
@@ -206,7 +396,7 @@ pub fn eval(node: &Node, scope: &Rc<Scope>) -> Result<Value, Error> {
                     scope = scope.push(name, Value::Type(typ.clone()));
                     args.push((name.clone(), typ.clone()));
                 } else {
-                    let typval = eval(rawargtyp, &scope)?;
+                    let typval = eval(rawargtyp, &scope, budget)?;
                     let typ = match typval {
                         Value::Type(ref t) => t.clone(),
                         _ => return Err(Error::ExpectedType(*sloc)),
@@ -216,7 +406,7 @@ pub fn eval(node: &Node, scope: &Rc<Scope>) -> Result<Value, Error> {
                 }
             }
 
-            let rettyp = match eval(&rettyp.borrow(), &scope)? {
+            let rettyp = match eval(&rettyp.borrow(), &scope, budget)? {
                 Value::Type(t) => t,
                 _ => return Err(Error::ExpectedType(*sloc)),
             };
@@ -227,7 +417,7 @@ pub fn eval(node: &Node, scope: &Rc<Scope>) -> Result<Value, Error> {
         Node::Record { fields, .. } => {
             let fields: Result<Vec<_>, _> = fields
                 .iter()
-                .map(|(name, value)| eval(value, scope).map(|v| (name.clone(), v)))
+                .map(|(name, value)| eval(value, scope, budget).map(|v| (name.clone(), v)))
                 .collect();
             Ok(Value::Record(fields?))
         }
@@ -236,32 +426,31 @@ pub fn eval(node: &Node, scope: &Rc<Scope>) -> Result<Value, Error> {
             // Ok(Value::Type(typ.clone().unwrap()))
             let fields: Result<Vec<_>, _> = fields
                 .iter()
-                .map(|(name, typ)| eval(typ, scope).map(|v| (name.clone(), v.expect_type())))
+                .map(|(name, typ)| eval(typ, scope, budget).map(|v| (name.clone(), v.expect_type())))
                 .collect();
             Ok(Value::Type(Rc::new(Type::Record(fields?))))
         }
-        Node::AccessField { op0, field, .. } => match eval(op0, scope)? {
-            Value::Record(fields) => Ok(fields
+        Node::AccessField {
+            sloc, op0, field, ..
+        } => {
+            let record = eval(op0, scope, budget)?;
+            Ok(get_field(*sloc, &record, field)?.1.clone())
+        }
+        Node::Project {
+            sloc, op0, fields, ..
+        } => {
+            let record = eval(op0, scope, budget)?;
+            let fields: Result<Vec<_>, _> = fields
                 .iter()
-                .find(|(name, _)| name.as_ref() == field.as_ref())
-                .unwrap()
-                .1
-                .clone()),
-            _ => panic!(),
-        },
-        Node::As { op0, as_raw, .. } => {
-            let t = eval(as_raw, scope)?.expect_type();
-            match (eval(op0, scope)?, t.as_ref()) {
-                (v, Type::Any) => Ok(Value::Any(Box::new(v))),
-                (Value::Any(v), _) if *v.get_type() == *t => {
-                    Ok(Value::Option(t.clone(), Some(v)))
-                }
-                (Value::Any(v), _) if *v.get_type() != *t => {
-                    Ok(Value::Option(t.clone(), None))
-                }
-                (v, Type::Text) => Ok(Value::Text(Rc::from(format!("{}", v)))),
-                (a, b) => todo!("{} as {}", a, b),
-            }
+                .map(|field| get_field(*sloc, &record, field).cloned())
+                .collect();
+            Ok(Value::Record(fields?))
+        }
+        Node::As {
+            sloc, op0, as_raw, ..
+        } => {
+            let t = eval(as_raw, scope, budget)?.expect_type();
+            cast(*sloc, eval(op0, scope, budget)?, &t)
         }
         Node::TypeOf { typ, .. } => {
             Ok(Value::Type(typ.clone().unwrap()))
@@ -269,8 +458,142 @@ pub fn eval(node: &Node, scope: &Rc<Scope>) -> Result<Value, Error> {
     }
 }
 
+fn get_field<'a>(sloc: SLoc, record: &'a Value, field: &str) -> Result<&'a (Rc<str>, Value), Error> {
+    let found = match record {
+        Value::Record(fields) => fields.iter().find(|(name, _)| name.as_ref() == field),
+        _ => None,
+    };
+    found.ok_or_else(|| Error::TypeError(sloc, format!("no field '{}' in {}", field, record)))
+}
+
+pub fn invert(sloc: SLoc, value: Value) -> Result<Value, Error> {
+    match value {
+        Value::Bool(value) => Ok(Value::Bool(!value)),
+        Value::Int(value) => Ok(Value::Int(!value)),
+        value => Err(Error::TypeError(sloc, format!("cannot invert {}", value))),
+    }
+}
+
+pub fn binop(sloc: SLoc, op: BinOp, lhs: Value, rhs: Value) -> Result<Value, Error> {
+    Ok(match (op, lhs, rhs) {
+        (BinOp::Add, Value::Int(lhs), Value::Int(rhs)) => Value::Int(arith(sloc, op, lhs.checked_add(rhs))?),
+        (BinOp::Sub, Value::Int(lhs), Value::Int(rhs)) => Value::Int(arith(sloc, op, lhs.checked_sub(rhs))?),
+        (BinOp::Mul, Value::Int(lhs), Value::Int(rhs)) => Value::Int(arith(sloc, op, lhs.checked_mul(rhs))?),
+        (BinOp::Div, Value::Int(lhs), Value::Int(rhs)) => Value::Int(divide(sloc, lhs, rhs, i64::checked_div)?),
+        (BinOp::EQ, Value::Int(lhs), Value::Int(rhs)) => Value::Bool(lhs == rhs),
+        (BinOp::NE, Value::Int(lhs), Value::Int(rhs)) => Value::Bool(lhs != rhs),
+        (BinOp::LT, Value::Int(lhs), Value::Int(rhs)) => Value::Bool(lhs < rhs),
+        (BinOp::LE, Value::Int(lhs), Value::Int(rhs)) => Value::Bool(lhs <= rhs),
+        (BinOp::GT, Value::Int(lhs), Value::Int(rhs)) => Value::Bool(lhs > rhs),
+        (BinOp::GE, Value::Int(lhs), Value::Int(rhs)) => Value::Bool(lhs >= rhs),
+        (BinOp::Add, Value::Natural(lhs), Value::Natural(rhs)) => Value::Natural(arith(sloc, op, lhs.checked_add(rhs))?),
+        // Like Dhall's Natural/subtract, this truncates at zero:
+        (BinOp::Sub, Value::Natural(lhs), Value::Natural(rhs)) => Value::Natural(lhs.saturating_sub(rhs)),
+        (BinOp::Mul, Value::Natural(lhs), Value::Natural(rhs)) => Value::Natural(arith(sloc, op, lhs.checked_mul(rhs))?),
+        (BinOp::Div, Value::Natural(lhs), Value::Natural(rhs)) => Value::Natural(divide(sloc, lhs, rhs, u64::checked_div)?),
+        (BinOp::EQ, Value::Natural(lhs), Value::Natural(rhs)) => Value::Bool(lhs == rhs),
+        (BinOp::NE, Value::Natural(lhs), Value::Natural(rhs)) => Value::Bool(lhs != rhs),
+        (BinOp::LT, Value::Natural(lhs), Value::Natural(rhs)) => Value::Bool(lhs < rhs),
+        (BinOp::LE, Value::Natural(lhs), Value::Natural(rhs)) => Value::Bool(lhs <= rhs),
+        (BinOp::GT, Value::Natural(lhs), Value::Natural(rhs)) => Value::Bool(lhs > rhs),
+        (BinOp::GE, Value::Natural(lhs), Value::Natural(rhs)) => Value::Bool(lhs >= rhs),
+        (BinOp::Add, Value::Double(lhs), Value::Double(rhs)) => Value::Double(lhs + rhs),
+        (BinOp::Sub, Value::Double(lhs), Value::Double(rhs)) => Value::Double(lhs - rhs),
+        (BinOp::Mul, Value::Double(lhs), Value::Double(rhs)) => Value::Double(lhs * rhs),
+        (BinOp::Div, Value::Double(lhs), Value::Double(rhs)) => Value::Double(lhs / rhs),
+        (BinOp::EQ, Value::Double(lhs), Value::Double(rhs)) => Value::Bool(lhs == rhs),
+        (BinOp::NE, Value::Double(lhs), Value::Double(rhs)) => Value::Bool(lhs != rhs),
+        (BinOp::LT, Value::Double(lhs), Value::Double(rhs)) => Value::Bool(lhs < rhs),
+        (BinOp::LE, Value::Double(lhs), Value::Double(rhs)) => Value::Bool(lhs <= rhs),
+        (BinOp::GT, Value::Double(lhs), Value::Double(rhs)) => Value::Bool(lhs > rhs),
+        (BinOp::GE, Value::Double(lhs), Value::Double(rhs)) => Value::Bool(lhs >= rhs),
+        (BinOp::And, Value::Bool(lhs), Value::Bool(rhs)) => Value::Bool(lhs && rhs),
+        (BinOp::Or, Value::Bool(lhs), Value::Bool(rhs)) => Value::Bool(lhs || rhs),
+        (BinOp::EQ, Value::Bool(lhs), Value::Bool(rhs)) => Value::Bool(lhs == rhs),
+        (BinOp::NE, Value::Bool(lhs), Value::Bool(rhs)) => Value::Bool(lhs != rhs),
+        (BinOp::Add, Value::Text(lhs), Value::Text(rhs)) => Value::Text(Rc::from(lhs.to_string() + rhs.as_ref())),
+        (BinOp::EQ, Value::Text(lhs), Value::Text(rhs)) => Value::Bool(lhs == rhs),
+        (BinOp::NE, Value::Text(lhs), Value::Text(rhs)) => Value::Bool(lhs != rhs),
+        (BinOp::Prefer, Value::Record(lhs), Value::Record(rhs)) => {
+            Value::Record(merge_fields(&lhs, &rhs, |_, _, r| Ok::<_, Error>(r.clone()))?)
+        }
+        (BinOp::Combine, Value::Record(lhs), Value::Record(rhs)) => {
+            Value::Record(combine_records(sloc, &lhs, &rhs)?)
+        }
+        (BinOp::CombineTypes, Value::Type(lhs), Value::Type(rhs)) => {
+            match (lhs.as_ref(), rhs.as_ref()) {
+                (Type::Record(lhs), Type::Record(rhs)) => Value::Type(Rc::new(Type::Record(
+                    Type::combine_records(lhs, rhs).map_err(|e| Error::TypeError(sloc, e))?,
+                ))),
+                (lhs, rhs) => return Err(undefined_op(sloc, op, lhs, rhs)),
+            }
+        }
+        (op, lhs, rhs) => return Err(undefined_op(sloc, op, lhs, rhs)),
+    })
+}
+
+fn undefined_op(sloc: SLoc, op: BinOp, lhs: impl Display, rhs: impl Display) -> Error {
+    Error::TypeError(sloc, format!("operator '{}' is not defined for {} and {}", op, lhs, rhs))
+}
+
+/* The result of a checked arithmetic operation, `None` being an overflow. */
+fn arith<T>(sloc: SLoc, op: BinOp, res: Option<T>) -> Result<T, Error> {
+    res.ok_or_else(|| Error::Eval(sloc, format!("overflow in '{}'", op)))
+}
+
+fn divide<T: Default + PartialEq>(
+    sloc: SLoc,
+    lhs: T,
+    rhs: T,
+    checked_div: fn(T, T) -> Option<T>,
+) -> Result<T, Error> {
+    if rhs == T::default() {
+        return Err(Error::Eval(sloc, "division by zero".to_string()));
+    }
+    arith(sloc, BinOp::Div, checked_div(lhs, rhs))
+}
+
+/* A Natural literal, the type-check only gives non-negative literals that type. */
+pub fn natural(sloc: SLoc, value: i64) -> Result<Value, Error> {
+    u64::try_from(value)
+        .map(Value::Natural)
+        .map_err(|_| Error::Eval(sloc, format!("{} is not a Natural", value)))
+}
+
+/* The `as` operator, the type-check made sure that `t` is a valid target. */
+pub fn cast(sloc: SLoc, value: Value, t: &Rc<Type>) -> Result<Value, Error> {
+    Ok(match (value, t.as_ref()) {
+        (v, Type::Any) => Value::Any(Box::new(v)),
+        (Value::Any(v), _) if *v.get_type() == **t => Value::Option(t.clone(), Some(v)),
+        (Value::Any(v), _) if *v.get_type() != **t => Value::Option(t.clone(), None),
+        (v, Type::Text) => Value::Text(Rc::from(format!("{}", v))),
+        (a, b) => return Err(Error::TypeError(sloc, format!("cannot cast {} to {}", a, b))),
+    })
+}
+
+/* The recursive record merge (`/\`), colliding fields are records (ensured by the type-check). */
+fn combine_records(
+    sloc: SLoc,
+    lhs: &[(Rc<str>, Value)],
+    rhs: &[(Rc<str>, Value)],
+) -> Result<Vec<(Rc<str>, Value)>, Error> {
+    merge_fields(lhs, rhs, |_, l, r| match (l, r) {
+        (Value::Record(l), Value::Record(r)) => combine_records(sloc, l, r).map(Value::Record),
+        (l, r) => Err(Error::TypeError(sloc, format!("cannot combine: {} and {}", l, r))),
+    })
+}
+
+/* The type-check makes sure builtins get the arguments they expect, this is
+ * for whatever slips through anyway. */
+fn unexpected_args(sloc: SLoc, name: &str, args: &[Value]) -> Error {
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    Error::TypeError(sloc, format!("unexpected arguments for {}: {}", name, args.join(", ")))
+}
+
 pub fn add_builtins(rt: &mut Runtime) {
+    rt.globals.insert("Natural", Value::Type(rt.natural_type.clone()));
     rt.globals.insert("Int", Value::Type(rt.int_type.clone()));
+    rt.globals.insert("Double", Value::Type(rt.double_type.clone()));
     rt.globals.insert("Bool", Value::Type(rt.bool_type.clone()));
     rt.globals.insert("Text", Value::Type(rt.text_type.clone()));
     rt.globals.insert("Type", Value::Type(rt.type_type.clone()));
@@ -285,37 +608,141 @@ pub fn add_builtins(rt: &mut Runtime) {
             name: "Process/exit",
             argtypes: vec![(Rc::from("code"), rt.int_type.clone())],
             rettyp: rt.int_type.clone(),
-            f: Box::new(|args| {
+            f: Box::new(|sloc, args| {
                 let code = match args[0] {
                     Value::Int(x) => x,
-                    _ => panic!(),
+                    _ => return Err(unexpected_args(sloc, "Process/exit", &args)),
                 };
                 std::process::exit(code as i32)
             }),
         },
     );
 
+    let options = rt.options.clone();
     rt.add_builtin(
         "Process/getenv",
         Builtin {
             name: "Process/getenv",
             argtypes: vec![(x_str.clone(), rt.text_type.clone())],
             rettyp: Rc::new(Type::Option(rt.text_type.clone())),
-            f: Box::new(|args| {
+            f: Box::new(move |sloc, args| {
                 let x = match &args[0] {
                     Value::Text(x) => x,
-                    _ => panic!(),
+                    _ => return Err(unexpected_args(sloc, "Process/getenv", &args)),
                 };
                 Ok(Value::Option(
                     Rc::new(Type::Text),
-                    std::env::var(x.as_ref())
-                        .ok()
+                    options
+                        .getenv(x)
                         .map(|x| Box::new(Value::Text(Rc::from(x.as_ref())))),
                 ))
             }),
         },
     );
 
+    rt.add_builtin(
+        "Natural/show",
+        Builtin {
+            name: "Natural/show",
+            argtypes: vec![(x_str.clone(), rt.natural_type.clone())],
+            rettyp: rt.text_type.clone(),
+            f: Box::new(|sloc, args| match args[0] {
+                Value::Natural(x) => Ok(Value::Text(Rc::from(x.to_string()))),
+                _ => Err(unexpected_args(sloc, "Natural/show", &args)),
+            }),
+        },
+    );
+
+    rt.add_builtin(
+        "Natural/toInteger",
+        Builtin {
+            name: "Natural/toInteger",
+            argtypes: vec![(x_str.clone(), rt.natural_type.clone())],
+            rettyp: rt.int_type.clone(),
+            f: Box::new(|sloc, args| match args[0] {
+                Value::Natural(x) => i64::try_from(x).map(Value::Int).map_err(|_| {
+                    Error::Eval(sloc, format!("Natural/toInteger: {} is too large for an Int", x))
+                }),
+                _ => Err(unexpected_args(sloc, "Natural/toInteger", &args)),
+            }),
+        },
+    );
+
+    rt.add_builtin(
+        "Integer/clamp",
+        Builtin {
+            name: "Integer/clamp",
+            argtypes: vec![(x_str.clone(), rt.int_type.clone())],
+            rettyp: rt.natural_type.clone(),
+            f: Box::new(|sloc, args| match args[0] {
+                Value::Int(x) => Ok(Value::Natural(x.max(0) as u64)),
+                _ => Err(unexpected_args(sloc, "Integer/clamp", &args)),
+            }),
+        },
+    );
+
+    rt.add_builtin(
+        "Integer/toDouble",
+        Builtin {
+            name: "Integer/toDouble",
+            argtypes: vec![(x_str.clone(), rt.int_type.clone())],
+            rettyp: rt.double_type.clone(),
+            f: Box::new(|sloc, args| match args[0] {
+                Value::Int(x) => Ok(Value::Double(x as f64)),
+                _ => Err(unexpected_args(sloc, "Integer/toDouble", &args)),
+            }),
+        },
+    );
+
+    rt.add_builtin(
+        "Double/show",
+        Builtin {
+            name: "Double/show",
+            argtypes: vec![(x_str.clone(), rt.double_type.clone())],
+            rettyp: rt.text_type.clone(),
+            f: Box::new(|sloc, args| match args[0] {
+                Value::Double(x) => Ok(Value::Text(Rc::from(format!("{:?}", x)))),
+                _ => Err(unexpected_args(sloc, "Double/show", &args)),
+            }),
+        },
+    );
+
+    // Text/show: Text as a (escaped and quoted) Text literal
+    rt.add_builtin(
+        "Text/show",
+        Builtin {
+            name: "Text/show",
+            argtypes: vec![(x_str.clone(), rt.text_type.clone())],
+            rettyp: rt.text_type.clone(),
+            f: Box::new(|sloc, args| match &args[0] {
+                Value::Text(x) => Ok(Value::Text(Rc::from(format!("{:?}", x.as_ref())))),
+                _ => Err(unexpected_args(sloc, "Text/show", &args)),
+            }),
+        },
+    );
+
+    rt.add_builtin(
+        "Text/replace",
+        Builtin {
+            name: "Text/replace",
+            argtypes: vec![
+                (Rc::from("needle"), rt.text_type.clone()),
+                (Rc::from("replacement"), rt.text_type.clone()),
+                (Rc::from("haystack"), rt.text_type.clone()),
+            ],
+            rettyp: rt.text_type.clone(),
+            f: Box::new(|sloc, args| match (&args[0], &args[1], &args[2]) {
+                (Value::Text(needle), _, Value::Text(haystack)) if needle.is_empty() => {
+                    Ok(Value::Text(haystack.clone()))
+                }
+                (Value::Text(needle), Value::Text(replacement), Value::Text(haystack)) => Ok(
+                    Value::Text(Rc::from(haystack.replace(needle.as_ref(), replacement))),
+                ),
+                _ => Err(unexpected_args(sloc, "Text/replace", &args)),
+            }),
+        },
+    );
+
     // Option: ∀(A: Type) -> Option(A)
     let ph = Rc::new(Type::Placeholder(a_str.clone()));
     rt.add_builtin(
@@ -324,7 +751,7 @@ pub fn add_builtins(rt: &mut Runtime) {
             name: "Option",
             argtypes: vec![(a_str.clone(), rt.type_type.clone())],
             rettyp: Rc::new(Type::TypeOf(Rc::new(Type::Option(ph)))),
-            f: Box::new(|args| {
+            f: Box::new(|_, args| {
                 let a = args[0].expect_type();
                 Ok(Value::Type(Rc::new(Type::Option(a))))
             }),
@@ -339,7 +766,7 @@ pub fn add_builtins(rt: &mut Runtime) {
             name: "None",
             argtypes: vec![(a_str.clone(), rt.type_type.clone())],
             rettyp: Rc::new(Type::Option(ph)),
-            f: Box::new(|args| {
+            f: Box::new(|_, args| {
                 let a = args[0].expect_type();
                 Ok(Value::Option(a, None))
             }),
@@ -357,13 +784,13 @@ pub fn add_builtins(rt: &mut Runtime) {
                 vec![(x_str.clone(), ph.clone())],
                 Rc::new(Type::Option(ph)),
             )),
-            f: Box::new(|args| {
+            f: Box::new(|_, args| {
                 let a = args[0].expect_type();
                 Ok(Value::Builtin(Rc::new(Builtin {
                     name: "None(A)",
                     argtypes: vec![(Rc::from("x"), a.clone())],
                     rettyp: Rc::new(Type::Option(a.clone())),
-                    f: Box::new(move |args| {
+                    f: Box::new(move |_, args| {
                         Ok(Value::Option(a.clone(), Some(Box::new(args[0].clone()))))
                     }),
                 })))
@@ -385,7 +812,7 @@ pub fn add_builtins(rt: &mut Runtime) {
                 ],
                 ph,
             )),
-            f: Box::new(|args| {
+            f: Box::new(|_, args| {
                 let a = args[0].expect_type();
                 Ok(Value::Builtin(Rc::new(Builtin {
                     name: "Option/or(A)",
@@ -394,11 +821,11 @@ pub fn add_builtins(rt: &mut Runtime) {
                         (Rc::from("x"), a.clone()),
                     ],
                     rettyp: a,
-                    f: Box::new(|args| {
+                    f: Box::new(|sloc, args| {
                         Ok(match (&args[0], &args[1]) {
                             (Value::Option(_, Some(v)), _) => *v.clone(),
                             (Value::Option(_, None), v) => v.clone(),
-                            _ => panic!(),
+                            _ => return Err(unexpected_args(sloc, "Option/or", &args)),
                         })
                     }),
                 })))
@@ -417,18 +844,22 @@ pub fn add_builtins(rt: &mut Runtime) {
                 vec![(x_str.clone(), Rc::new(Type::Option(ph.clone())))],
                 ph,
             )),
-            f: Box::new(|args| {
+            f: Box::new(|_, args| {
                 let a = args[0].expect_type();
                 Ok(Value::Builtin(Rc::new(Builtin {
                     name: "Option/or(A)",
                     argtypes: vec![(Rc::from("x"), Rc::new(Type::Option(a.clone())))],
                     rettyp: a,
-                    f: Box::new(move |args| {
+                    f: Box::new(move |sloc, args| {
                         Ok(match &args[0] {
                             Value::Option(_, Some(v)) => *v.clone(),
-                            Value::Option(t, None) =>
-                                panic!("Rhall Option/unwrap called on None (type: {})!", t),
-                            _ => panic!(),
+                            Value::Option(t, None) => {
+                                return Err(Error::Eval(
+                                    sloc,
+                                    format!("Option/unwrap called on None({})", t),
+                                ))
+                            }
+                            _ => return Err(unexpected_args(sloc, "Option/unwrap", &args)),
                         })
                     }),
                 })))
@@ -447,17 +878,17 @@ pub fn add_builtins(rt: &mut Runtime) {
                 vec![(x_str.clone(), Rc::new(Type::Option(ph)))],
                 Rc::new(Type::Bool),
             )),
-            f: Box::new(|args| {
+            f: Box::new(|_, args| {
                 let a = args[0].expect_type();
                 Ok(Value::Builtin(Rc::new(Builtin {
                     name: "Option/or(A)",
                     argtypes: vec![(Rc::from("x"), Rc::new(Type::Option(a)))],
                     rettyp: Rc::new(Type::Bool),
-                    f: Box::new(move |args| {
+                    f: Box::new(move |sloc, args| {
                         Ok(Value::Bool(match &args[0] {
                             Value::Option(_, Some(_)) => true,
                             Value::Option(_, None) => false,
-                            _ => panic!(),
+                            _ => return Err(unexpected_args(sloc, "Option/isSome", &args)),
                         }))
                     }),
                 })))
@@ -486,21 +917,120 @@ mod tests {
 
     #[test]
     fn incto42() {
-        let rt = Runtime::new();
+        let rt = Runtime::with_options(RuntimeOptions::default());
         let mut expr = parse("let inc = λ(x: Int) -> x + 1 in inc(41)").unwrap();
         expr.typecheck(&mut *rt.borrow_mut(), None)
             .expect("typecheck failed");
-        assert_matches!(eval(&expr, &Scope::from(rt)), Ok(Value::Int(42)));
+        assert_matches!(eval(&expr, &Scope::from(rt), &mut Budget::default()), Ok(Value::Int(42)));
+    }
+
+    #[test]
+    fn sandbox() {
+        let options = RuntimeOptions {
+            env_allowlist: Some(vec!["RHALL_TEST_ALLOWED".to_string()]),
+            ..RuntimeOptions::sandboxed()
+        };
+        let rt = Runtime::with_options(options.clone());
+        let mut expr = parse("Process/exit(1)").unwrap();
+        assert_matches!(
+            expr.typecheck(&mut *rt.borrow_mut(), None),
+            Err(Error::UndefinedValue(..))
+        );
+
+        let rt = Runtime::with_options(RuntimeOptions {
+            namespaces: BUILTIN_NAMESPACES.to_vec(),
+            env: Some(vec![
+                ("RHALL_TEST_ALLOWED".to_string(), "yes".to_string()),
+                ("RHALL_TEST_DENIED".to_string(), "no".to_string()),
+            ]),
+            ..options
+        });
+        let mut expr = parse(
+            "{ a = Process/getenv(\"RHALL_TEST_ALLOWED\"), b = Process/getenv(\"RHALL_TEST_DENIED\") }",
+        )
+        .unwrap();
+        expr.typecheck(&mut *rt.borrow_mut(), None)
+            .expect("typecheck failed");
+        let val = eval(&expr, &Scope::from(rt), &mut Budget::default()).unwrap();
+        assert_eq!(val.to_string(), "{ a = Some(\"yes\"), b = None(Text) }");
+    }
+
+    #[test]
+    fn limits() {
+        let rt = Runtime::with_options(RuntimeOptions::default());
+        let mut expr =
+            parse("let f: ∀(n: Int) -> Int = λ(n: Int) -> f(n + 1) in f(0)").unwrap();
+        expr.typecheck(&mut *rt.borrow_mut(), None)
+            .expect("typecheck failed");
+        let main = crate::compile::compile(&expr, &rt.borrow()).unwrap();
+
+        let limits = Limits {
+            max_steps: Some(10_000),
+            ..Limits::default()
+        };
+        assert_matches!(
+            crate::vm::run(&main, limits),
+            Err(Error::LimitExceeded(..))
+        );
+
+        let limits = Limits {
+            timeout: Some(std::time::Duration::from_millis(50)),
+            ..Limits::default()
+        };
+        assert_matches!(
+            crate::vm::run(&main, limits),
+            Err(Error::LimitExceeded(..))
+        );
+
+        let limits = Limits {
+            max_steps: Some(1_000),
+            ..Limits::default()
+        };
+        assert_matches!(
+            eval(&expr, &Scope::from(rt.clone()), &mut Budget::new(limits)),
+            Err(Error::LimitExceeded(..))
+        );
+
+        // Runs out of calls long before steps, instead of out of stack.
+        let limits = Limits {
+            max_steps: Some(100_000_000),
+            ..Limits::default()
+        };
+        assert_matches!(
+            eval(&expr, &Scope::from(rt.clone()), &mut Budget::new(limits)),
+            Err(Error::LimitExceeded(_, msg)) if msg.contains("nested calls")
+        );
+        let limits = RuntimeOptions::sandboxed().limits;
+        assert_matches!(
+            eval(&expr, &Scope::from(rt), &mut Budget::new(limits)),
+            Err(Error::LimitExceeded(..))
+        );
+    }
+
+    #[test]
+    fn arithmetic_errors() {
+        for input in [
+            "let n: Natural = 0 in 1 / n",
+            "let n: Int = 0 in 1 / n",
+            "let n: Natural = 4294967296 in n * n",
+            "let n: Int = 9223372036854775807 in n * 2",
+        ] {
+            let rt = Runtime::with_options(RuntimeOptions::default());
+            let mut expr = parse(input).unwrap();
+            expr.typecheck(&mut *rt.borrow_mut(), None)
+                .expect("typecheck failed");
+            assert_matches!(eval(&expr, &Scope::from(rt), &mut Budget::default()), Err(Error::Eval(..)), "{}", input);
+        }
     }
 
     #[test]
     fn fib10() {
-        let rt = Runtime::new();
+        let rt = Runtime::with_options(RuntimeOptions::default());
         let mut expr =
             parse("let fib: ∀(n: Int) -> Int = λ(n: Int) -> if n < 2 then n else fib(n - 1) + fib(n - 2) in fib(10)")
                 .unwrap();
         expr.typecheck(&mut *rt.borrow_mut(), None)
             .expect("typecheck failed");
-        assert_matches!(eval(&expr, &Scope::from(rt)), Ok(Value::Int(55)));
+        assert_matches!(eval(&expr, &Scope::from(rt), &mut Budget::default()), Ok(Value::Int(55)));
     }
 }
@@ -8,6 +8,9 @@ pub struct Lexer<'a> {
     buffer: String,
     string_pool: &'a mut std::collections::HashSet<Rc<str>>,
     peeked: Option<Result<(SLoc, Tok), Error>>,
+    // One entry per currently open `${...}` in a string literal, counting the
+    // '{' opened inside of it so that the matching '}' can be found.
+    interpolations: Vec<usize>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -17,6 +20,10 @@ pub enum Tok {
     Int(i64),
     Real(f64),
     String(Rc<str>),
+    // "a${x}b${y}c" is lexed as: InterpBegin("a") x InterpMid("b") y InterpEnd("c")
+    InterpBegin(Rc<str>),
+    InterpMid(Rc<str>),
+    InterpEnd(Rc<str>),
 
     Let,
     In,
@@ -50,6 +57,9 @@ pub enum Tok {
     Slash,
     Ampersand,
     Pipe,
+    Prefer,       // '//' or '⫽'
+    Combine,      // '/\' or '∧'
+    CombineTypes, // '//\\' or '⩓'
     Equal,
     NotEqual,
     Lower,
@@ -74,6 +84,7 @@ impl<'a> Lexer<'a> {
             buffer: String::with_capacity(64),
             string_pool,
             peeked: None,
+            interpolations: Vec::new(),
         }
     }
 
@@ -81,7 +92,7 @@ impl<'a> Lexer<'a> {
         if let Some(c) = self.chars.next() {
             if c == '\n' {
                 self.sloc.line += 1;
-                self.sloc.col = 1;
+                self.sloc.col = 0;
             } else if c == '\t' {
                 self.sloc.col += 4;
             } else {
@@ -106,7 +117,7 @@ impl<'a> Lexer<'a> {
         'outer: while let Some(c) = self.chars.next() {
             if c == '\n' {
                 self.sloc.line += 1;
-                self.sloc.col = 1;
+                self.sloc.col = 0;
                 continue;
             }
 
@@ -119,7 +130,7 @@ impl<'a> Lexer<'a> {
                 for c in self.chars.by_ref() {
                     if c == '\n' {
                         self.sloc.line += 1;
-                        self.sloc.col = 1;
+                        self.sloc.col = 0;
                         break;
                     }
                 }
@@ -156,7 +167,6 @@ impl<'a> Lexer<'a> {
             if !c.is_digit(base) {
                 break;
             }
-            self.sloc.col += 1;
             let c = self.next_char().unwrap();
             self.buffer.push(c);
         }
@@ -175,7 +185,6 @@ impl<'a> Lexer<'a> {
                 break;
             }
 
-            self.sloc.col += 1;
             let c = self.next_char().unwrap();
             if c == '_' {
                 continue;
@@ -198,10 +207,16 @@ impl<'a> Lexer<'a> {
         }
     }
 
-    fn parse_string_into_buffer(&mut self) -> Result<(), Error> {
+    /* Returns true if the string was interrupted by a `${` interpolation. */
+    fn parse_string_into_buffer(&mut self) -> Result<bool, Error> {
         loop {
             match self.next_char() {
-                Some('"') => return Ok(()),
+                Some('"') => return Ok(false),
+                Some('$') if self.chars.peek().cloned() == Some('{') => {
+                    self.next_char();
+                    self.interpolations.push(0);
+                    return Ok(true);
+                }
                 Some('\\') => {
                     let c = self.next_char();
                     let x = match c {
@@ -266,90 +281,131 @@ impl<'a> std::iter::Iterator for Lexer<'a> {
             Some(c) => c,
             None => return None,
         };
+        let sloc = self.sloc;
 
         Some(match c {
-            '(' => Ok((self.sloc, Tok::LParen)),
-            ')' => Ok((self.sloc, Tok::RParen)),
-            '[' => Ok((self.sloc, Tok::LBracket)),
-            ']' => Ok((self.sloc, Tok::RBracket)),
-            '{' => Ok((self.sloc, Tok::LBrace)),
-            '}' => Ok((self.sloc, Tok::RBrace)),
-            ',' => Ok((self.sloc, Tok::Comma)),
-            ':' => Ok((self.sloc, Tok::Colon)),
-
-            '+' => Ok((self.sloc, Tok::Plus)),
-            '*' => Ok((self.sloc, Tok::Star)),
-            '/' => Ok((self.sloc, Tok::Slash)),
-            '&' => Ok((self.sloc, Tok::Ampersand)),
-            '|' => Ok((self.sloc, Tok::Pipe)),
-            '~' => Ok((self.sloc, Tok::Tilde)),
-            '.' => Ok((self.sloc, Tok::Dot)),
+            '(' => Ok((sloc, Tok::LParen)),
+            ')' => Ok((sloc, Tok::RParen)),
+            '[' => Ok((sloc, Tok::LBracket)),
+            ']' => Ok((sloc, Tok::RBracket)),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                Ok((sloc, Tok::LBrace))
+            }
+            '}' if self.interpolations.last().cloned() == Some(0) => {
+                self.interpolations.pop();
+                self.buffer.clear();
+                Ok(match self.parse_string_into_buffer() {
+                    Ok(true) => (sloc, Tok::InterpMid(self.get_buffer_as_string())),
+                    Ok(false) => (sloc, Tok::InterpEnd(self.get_buffer_as_string())),
+                    Err(e) => return Some(Err(e)),
+                })
+            }
+            '}' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth -= 1;
+                }
+                Ok((sloc, Tok::RBrace))
+            }
+            ',' => Ok((sloc, Tok::Comma)),
+            ':' => Ok((sloc, Tok::Colon)),
+
+            '+' => Ok((sloc, Tok::Plus)),
+            '*' => Ok((sloc, Tok::Star)),
+            '/' => match self.chars.peek() {
+                Some('/') => {
+                    self.next_char();
+                    if self.chars.peek().cloned() != Some('\\') {
+                        return Some(Ok((sloc, Tok::Prefer)));
+                    }
+                    self.next_char();
+                    match self.next_char() {
+                        Some('\\') => Ok((sloc, Tok::CombineTypes)),
+                        _ => Err(Error::Lexer(self.sloc, "expected '//\\\\'".to_string())),
+                    }
+                }
+                Some('\\') => {
+                    self.next_char();
+                    Ok((sloc, Tok::Combine))
+                }
+                _ => Ok((sloc, Tok::Slash)),
+            },
+            '&' => Ok((sloc, Tok::Ampersand)),
+            '|' => Ok((sloc, Tok::Pipe)),
+            '~' => Ok((sloc, Tok::Tilde)),
+            '.' => Ok((sloc, Tok::Dot)),
             '-' => match self.chars.peek() {
                 Some('>') => {
                     self.next_char();
-                    Ok((self.sloc, Tok::Arrow))
+                    Ok((sloc, Tok::Arrow))
                 }
-                _ => Ok((self.sloc, Tok::Minus)),
+                _ => Ok((sloc, Tok::Minus)),
             },
             '=' => match self.chars.peek() {
                 Some('=') => {
                     self.next_char();
-                    Ok((self.sloc, Tok::Equal))
+                    Ok((sloc, Tok::Equal))
                 }
                 Some('>') => {
                     self.next_char();
-                    Ok((self.sloc, Tok::ThickArrow))
+                    Ok((sloc, Tok::ThickArrow))
                 }
-                _ => Ok((self.sloc, Tok::Assign)),
+                _ => Ok((sloc, Tok::Assign)),
             },
             '!' => match self.chars.peek() {
                 Some('=') => {
                     self.next_char();
-                    Ok((self.sloc, Tok::NotEqual))
+                    Ok((sloc, Tok::NotEqual))
                 }
                 _ => todo!(),
             },
             '<' => match self.chars.peek() {
                 Some('=') => {
                     self.next_char();
-                    Ok((self.sloc, Tok::LowerOrEqual))
+                    Ok((sloc, Tok::LowerOrEqual))
                 }
-                _ => Ok((self.sloc, Tok::Lower)),
+                _ => Ok((sloc, Tok::Lower)),
             },
             '>' => match self.chars.peek() {
                 Some('=') => {
                     self.next_char();
-                    Ok((self.sloc, Tok::GreaterOrEqual))
+                    Ok((sloc, Tok::GreaterOrEqual))
                 }
-                _ => Ok((self.sloc, Tok::Greater)),
+                _ => Ok((sloc, Tok::Greater)),
             },
 
-            '\\' | 'λ' => Ok((self.sloc, Tok::Lambda)),
-            '∀' => Ok((self.sloc, Tok::Forall)),
-            '⊤' => Ok((self.sloc, Tok::Bool(true))),
-            '⊥' => Ok((self.sloc, Tok::Bool(false))),
-            '→' => Ok((self.sloc, Tok::Arrow)),
+            '\\' | 'λ' => Ok((sloc, Tok::Lambda)),
+            '∀' => Ok((sloc, Tok::Forall)),
+            '⊤' => Ok((sloc, Tok::Bool(true))),
+            '⊥' => Ok((sloc, Tok::Bool(false))),
+            '→' => Ok((sloc, Tok::Arrow)),
+            '⫽' => Ok((sloc, Tok::Prefer)),
+            '∧' => Ok((sloc, Tok::Combine)),
+            '⩓' => Ok((sloc, Tok::CombineTypes)),
 
             '0' => match self.chars.peek().cloned() {
                 Some('b') => self
                     .parse_integer_with_base(2)
-                    .map(|i| (self.sloc, Tok::Int(i))),
+                    .map(|i| (sloc, Tok::Int(i))),
                 Some('o') => self
                     .parse_integer_with_base(8)
-                    .map(|i| (self.sloc, Tok::Int(i))),
+                    .map(|i| (sloc, Tok::Int(i))),
                 Some('x') => self
                     .parse_integer_with_base(16)
-                    .map(|i| (self.sloc, Tok::Int(i))),
-                _ => self.parse_number(self.sloc, '0'),
+                    .map(|i| (sloc, Tok::Int(i))),
+                _ => self.parse_number(sloc, '0'),
             },
             '1' | '2' | '3' | '4' | '5' | '6' | '7' | '8' | '9' =>
-                self.parse_number(self.sloc, c),
+                self.parse_number(sloc, c),
             '"' => {
                 self.buffer.clear();
-                if let Err(e) = self.parse_string_into_buffer() {
-                    return Some(Err(e));
-                }
-                Ok((self.sloc, Tok::String(self.get_buffer_as_string())))
+                Ok(match self.parse_string_into_buffer() {
+                    Ok(true) => (sloc, Tok::InterpBegin(self.get_buffer_as_string())),
+                    Ok(false) => (sloc, Tok::String(self.get_buffer_as_string())),
+                    Err(e) => return Some(Err(e)),
+                })
             }
 
             '`' => {
@@ -367,7 +423,7 @@ impl<'a> std::iter::Iterator for Lexer<'a> {
                     };
                     self.buffer.push(c);
                 }
-                Ok((self.sloc, Tok::String(self.get_buffer_as_string())))
+                Ok((sloc, Tok::String(self.get_buffer_as_string())))
             }
 
             c if c.is_alphabetic() || c == '_' => {
@@ -383,19 +439,19 @@ impl<'a> std::iter::Iterator for Lexer<'a> {
                 }
 
                 match self.buffer.as_str() {
-                    "lambda" => Ok((self.sloc, Tok::Lambda)),
-                    "forall" => Ok((self.sloc, Tok::Forall)),
-                    "true" => Ok((self.sloc, Tok::Bool(true))),
-                    "false" => Ok((self.sloc, Tok::Bool(false))),
-                    "let" => Ok((self.sloc, Tok::Let)),
-                    "in" => Ok((self.sloc, Tok::In)),
-                    "if" => Ok((self.sloc, Tok::If)),
-                    "then" => Ok((self.sloc, Tok::Then)),
-                    "else" => Ok((self.sloc, Tok::Else)),
-                    "typeof" => Ok((self.sloc, Tok::Typeof)),
-                    "as" => Ok((self.sloc, Tok::As)),
-                    "match" => Ok((self.sloc, Tok::Match)),
-                    _ => Ok((self.sloc, Tok::Id(self.get_buffer_as_string()))),
+                    "lambda" => Ok((sloc, Tok::Lambda)),
+                    "forall" => Ok((sloc, Tok::Forall)),
+                    "true" => Ok((sloc, Tok::Bool(true))),
+                    "false" => Ok((sloc, Tok::Bool(false))),
+                    "let" => Ok((sloc, Tok::Let)),
+                    "in" => Ok((sloc, Tok::In)),
+                    "if" => Ok((sloc, Tok::If)),
+                    "then" => Ok((sloc, Tok::Then)),
+                    "else" => Ok((sloc, Tok::Else)),
+                    "typeof" => Ok((sloc, Tok::Typeof)),
+                    "as" => Ok((sloc, Tok::As)),
+                    "match" => Ok((sloc, Tok::Match)),
+                    _ => Ok((sloc, Tok::Id(self.get_buffer_as_string()))),
                 }
             }
 
@@ -434,4 +490,51 @@ mod test {
         assert_matches!(lexer.next(), Some(Ok((_, Tok::Real(3.14)))));
         assert_matches!(lexer.next(), None);
     }
+
+    #[test]
+    fn interpolation() {
+        let input = "\"a${x + \"b${{ y = 1 }.y}\"}c${z}\"";
+        let mut string_pool = std::collections::HashSet::<Rc<str>>::new();
+        let mut lexer = Lexer::new(input, 0, &mut string_pool);
+
+        assert_matches!(lexer.next(), Some(Ok((_, Tok::InterpBegin(s)))) if s.as_ref() == "a");
+        assert_matches!(lexer.next(), Some(Ok((_, Tok::Id(id)))) if id.as_ref() == "x");
+        assert_matches!(lexer.next(), Some(Ok((_, Tok::Plus))));
+        assert_matches!(lexer.next(), Some(Ok((_, Tok::InterpBegin(s)))) if s.as_ref() == "b");
+        assert_matches!(lexer.next(), Some(Ok((_, Tok::LBrace))));
+        assert_matches!(lexer.next(), Some(Ok((_, Tok::Id(id)))) if id.as_ref() == "y");
+        assert_matches!(lexer.next(), Some(Ok((_, Tok::Assign))));
+        assert_matches!(lexer.next(), Some(Ok((_, Tok::Int(1)))));
+        assert_matches!(lexer.next(), Some(Ok((_, Tok::RBrace))));
+        assert_matches!(lexer.next(), Some(Ok((_, Tok::Dot))));
+        assert_matches!(lexer.next(), Some(Ok((_, Tok::Id(id)))) if id.as_ref() == "y");
+        assert_matches!(lexer.next(), Some(Ok((_, Tok::InterpEnd(s)))) if s.is_empty());
+        assert_matches!(lexer.next(), Some(Ok((_, Tok::InterpMid(s)))) if s.as_ref() == "c");
+        assert_matches!(lexer.next(), Some(Ok((_, Tok::Id(id)))) if id.as_ref() == "z");
+        assert_matches!(lexer.next(), Some(Ok((_, Tok::InterpEnd(s)))) if s.is_empty());
+        assert_matches!(lexer.next(), None);
+    }
+
+    #[test]
+    fn record_operators() {
+        let input = "a // b /\\ c //\\\\ d ⫽ e ∧ f ⩓ g / h";
+        let mut string_pool = std::collections::HashSet::<Rc<str>>::new();
+        let lexer = Lexer::new(input, 0, &mut string_pool);
+        let toks: Vec<Tok> = lexer
+            .map(|t| t.unwrap().1)
+            .filter(|t| !matches!(t, Tok::Id(_)))
+            .collect();
+        assert_eq!(
+            toks,
+            vec![
+                Tok::Prefer,
+                Tok::Combine,
+                Tok::CombineTypes,
+                Tok::Prefer,
+                Tok::Combine,
+                Tok::CombineTypes,
+                Tok::Slash
+            ]
+        );
+    }
 }
@@ -3,30 +3,94 @@
 #![feature(trait_upcasting)]
 #![feature(downcast_unchecked)]
 #![feature(assert_matches)]
+#![feature(test)]
 #![allow(clippy::type_complexity)]
 
 use std::io::Read;
 
 mod ast;
+mod compile;
 mod core;
+mod diag;
 mod eval;
 mod lex;
+mod vm;
 
 #[cfg(feature = "gc")]
 mod gc;
 
-use eval::{eval, Runtime, Scope};
+use eval::{eval, Budget, Runtime, RuntimeOptions, Scope};
 
 use crate::ast::Parser;
+use crate::compile::compile;
 use crate::lex::Lexer;
 
+const USAGE: &str = "usage: rhall [--ast-eval] [--dump-bytecode] [--sandbox] [--allow-env NAME]...
+             [--max-steps N] [--timeout-ms N] [--max-depth N] [file.dhall]
+
+Reads the program from standard input when no file is given. --sandbox
+comes with step and time limits, --max-steps and --timeout-ms override them.";
+
 fn main() {
+    // The AST-walking evaluator is kept around for comparison.
+    let mut ast_eval = false;
+    let mut dump_bytecode = false;
+    let mut options = RuntimeOptions::default();
+    // Applied last, so they override the limits of --sandbox in any order.
+    let (mut max_steps, mut timeout, mut max_depth) = (None, None, None);
+    let mut file = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| usage_error(&format!("{}: missing value", arg)))
+        };
+        match arg.as_str() {
+            "--ast-eval" => ast_eval = true,
+            "--dump-bytecode" => dump_bytecode = true,
+            "--sandbox" => options = RuntimeOptions::sandboxed(),
+            "--allow-env" => {
+                let name = value();
+                options.env_allowlist.get_or_insert_with(Vec::new).push(name)
+            }
+            "--max-steps" => max_steps = Some(number(&arg, value())),
+            "--timeout-ms" => {
+                let ms = number(&arg, value());
+                timeout = Some(std::time::Duration::from_millis(ms))
+            }
+            "--max-depth" => {
+                let n = number(&arg, value());
+                max_depth = Some(u32::try_from(n).unwrap_or_else(|_| {
+                    usage_error(&format!("{}: too large: {}", arg, n))
+                }))
+            }
+            _ if !arg.starts_with('-') && file.is_none() => file = Some(arg),
+            _ => usage_error(&format!("unknown argument: {}", arg)),
+        }
+    }
+    options.limits.max_steps = max_steps.or(options.limits.max_steps);
+    options.limits.timeout = timeout.or(options.limits.timeout);
+    options.limits.max_depth = max_depth.unwrap_or(options.limits.max_depth);
+    let limits = options.limits;
+
     let mut buf = String::new();
-    std::io::stdin()
-        .read_to_string(&mut buf)
-        .expect("I/O failure");
+    let filename = match &file {
+        Some(path) => {
+            buf = std::fs::read_to_string(path).unwrap_or_else(|e| {
+                eprintln!("{}: {}", path, e);
+                std::process::exit(1)
+            });
+            path.as_str()
+        }
+        None => {
+            std::io::stdin()
+                .read_to_string(&mut buf)
+                .expect("I/O failure");
+            "<stdin>"
+        }
+    };
 
-    let rt = Runtime::new();
+    let rt = Runtime::with_options(options);
     let node = {
         let mut rtref = rt.borrow_mut();
         let mut lexer = Lexer::new(buf.as_str(), 0, &mut rtref.string_pool);
@@ -34,7 +98,7 @@ fn main() {
         let mut node = match parser.parse_all() {
             Ok(node) => node,
             Err(e) => {
-                eprintln!("parsing failed: {:?}", e);
+                eprint!("{}", diag::render(&e, filename, &buf));
                 std::process::exit(1)
             }
         };
@@ -42,7 +106,7 @@ fn main() {
         let typ = match node.typecheck(&mut rtref, None) {
             Ok(t) => t,
             Err(e) => {
-                eprintln!("type-check failed: {:?}", e);
+                eprint!("{}", diag::render(&e, filename, &buf));
                 std::process::exit(1)
             }
         };
@@ -50,12 +114,36 @@ fn main() {
         node
     };
 
-    match eval(node.as_ref(), &Scope::from(rt)) {
+    let res = if ast_eval {
+        eval(node.as_ref(), &Scope::from(rt), &mut Budget::new(limits))
+    } else {
+        compile(node.as_ref(), &rt.borrow()).and_then(|main| {
+            if dump_bytecode {
+                print!("# BYTECODE:\n{}", main);
+            }
+            vm::run(&main, limits)
+        })
+    };
+    match res {
         Ok(val) => println!("{}", val),
-        Err(e) => eprintln!("{:?}", e),
+        Err(e) => {
+            eprint!("{}", diag::render(&e, filename, &buf));
+            std::process::exit(1)
+        }
     };
 }
 
+fn usage_error(msg: &str) -> ! {
+    eprintln!("{}\n{}", msg, USAGE);
+    std::process::exit(2)
+}
+
+fn number(arg: &str, value: String) -> u64 {
+    value
+        .parse()
+        .unwrap_or_else(|_| usage_error(&format!("{}: not a number: {}", arg, value)))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -66,7 +154,7 @@ mod test {
         /* Ugly hack to get the other tests to finish first: */
         std::thread::sleep(Duration::from_millis(100));
 
-        let rt = Runtime::new();
+        let rt = Runtime::with_options(RuntimeOptions::default());
 
         use std::path::PathBuf;
         let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -97,10 +185,15 @@ mod test {
                 (example, typ)
             };
 
-            let val = eval(&example, &Scope::from(rt.clone())).expect("evaluation failed");
+            let val = eval(&example, &Scope::from(rt.clone()), &mut Budget::default()).expect("evaluation failed");
             let res = format!("{}", val);
             assert_eq!(expected.trim(), res.trim());
             assert_eq!(typ.as_ref(), val.get_type().as_ref());
+
+            let main = compile(&example, &rt.borrow()).expect("compilation failed");
+            let val = vm::run(&main, eval::Limits::default()).expect("evaluation (VM) failed");
+            assert_eq!(expected.trim(), format!("{}", val).trim());
+            assert_eq!(typ.as_ref(), val.get_type().as_ref());
             path.pop();
         }
     }
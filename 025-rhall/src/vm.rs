@@ -0,0 +1,318 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    ast::{BinOp, Node},
+    core::{Error, SLoc, Type, Value},
+    eval::{binop, cast, invert, Budget, Limits},
+};
+
+/*
+ * The bytecode executed by the VM. Every function gets its own stack frame,
+ * locals (arguments and let-in bindings) live on the stack and are addressed
+ * relative to the frame base. Variables from enclosing functions are copied
+ * into the closure when it is created (only those that are actually used),
+ * a let-bound lambda refers to itself using `LoadSelf` so that recursive
+ * functions do not need a reference cycle.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instr {
+    Const(u32),
+    Load(u32),
+    LoadCapture(u32),
+    LoadSelf,
+    // Remove n values below the top of the stack (end of a let-in).
+    Slide(u32),
+    Invert,
+    BinOp(BinOp),
+    Call(u32),
+    Jump(u32),
+    JumpIfFalse(u32),
+    Return,
+    MakeClosure(u32),
+    MakeRecord(u32),
+    MakeRecordType(u32),
+    MakeForall(u32),
+    Field(u32),
+    Project(u32),
+    As,
+}
+
+#[derive(Debug, Default)]
+pub struct Function {
+    pub args: Vec<(Rc<str>, Rc<Type>)>,
+    pub rettyp: Option<Rc<Type>>,
+    // Only kept for printing closures.
+    pub body: Option<Rc<RefCell<Node>>>,
+    pub code: Vec<Instr>,
+    pub slocs: Vec<SLoc>,
+    pub ncaptures: u32,
+    pub consts: Vec<Value>,
+    pub functions: Vec<Rc<Function>>,
+    // Field names for `MakeRecord`/`MakeRecordType`.
+    pub fieldnames: Vec<Vec<Rc<str>>>,
+    // Field indices for `Project`.
+    pub projections: Vec<Vec<u32>>,
+    pub foralls: Vec<Vec<(Rc<str>, Rc<Type>)>>,
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub func: Rc<Function>,
+    pub captures: Vec<Value>,
+}
+
+struct Frame {
+    closure: Rc<Closure>,
+    pc: usize,
+    base: usize,
+}
+
+impl std::fmt::Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (pc, (instr, sloc)) in self.code.iter().zip(self.slocs.iter()).enumerate() {
+            writeln!(f, "{:4} {:3}:{:<3} {:?}", pc, sloc.line, sloc.col, instr)?;
+        }
+        for (i, func) in self.functions.iter().enumerate() {
+            writeln!(f, "function #{} ({} captures):", i, func.ncaptures)?;
+            write!(f, "{}", func)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn run(main: &Rc<Function>, limits: Limits) -> Result<Value, Error> {
+    let closure = Rc::new(Closure {
+        func: main.clone(),
+        captures: vec![],
+    });
+    call(&closure, vec![], &mut Budget::new(limits))
+}
+
+pub fn call(closure: &Rc<Closure>, args: Vec<Value>, budget: &mut Budget) -> Result<Value, Error> {
+    let mut stack = Vec::with_capacity(256);
+    stack.push(Value::Closure(closure.clone()));
+    stack.extend(args);
+    let mut frames = vec![Frame {
+        closure: closure.clone(),
+        pc: 0,
+        base: 1,
+    }];
+
+    loop {
+        let frame = frames.last_mut().unwrap();
+        let func = &frame.closure.func;
+        let pc = frame.pc;
+        frame.pc += 1;
+
+        budget.step(func.slocs[pc])?;
+        match func.code[pc] {
+            Instr::Const(idx) => stack.push(func.consts[idx as usize].clone()),
+            Instr::Load(slot) => stack.push(stack[frame.base + slot as usize].clone()),
+            Instr::LoadCapture(idx) => stack.push(frame.closure.captures[idx as usize].clone()),
+            Instr::LoadSelf => stack.push(Value::Closure(frame.closure.clone())),
+            Instr::Slide(n) => {
+                let top = stack.pop().unwrap();
+                stack.truncate(stack.len() - n as usize);
+                stack.push(top);
+            }
+            Instr::Invert => {
+                let value = stack.pop().unwrap();
+                stack.push(invert(func.slocs[pc], value)?);
+            }
+            Instr::BinOp(op) => {
+                let rhs = stack.pop().unwrap();
+                let lhs = stack.pop().unwrap();
+                stack.push(binop(func.slocs[pc], op, lhs, rhs)?);
+            }
+            Instr::Call(nargs) => {
+                let base = stack.len() - nargs as usize;
+                match &stack[base - 1] {
+                    Value::Closure(callee) => {
+                        let callee = callee.clone();
+                        frames.push(Frame {
+                            closure: callee,
+                            pc: 0,
+                            base,
+                        });
+                    }
+                    _ => {
+                        let args = stack.split_off(base);
+                        let callee = stack.pop().unwrap();
+                        stack.push(callee.apply(func.slocs[pc], args, budget)?);
+                    }
+                }
+            }
+            Instr::Jump(target) => frame.pc = target as usize,
+            Instr::JumpIfFalse(target) => match stack.pop().unwrap() {
+                Value::Bool(true) => {}
+                Value::Bool(false) => frame.pc = target as usize,
+                value => {
+                    return Err(Error::TypeError(
+                        func.slocs[pc],
+                        format!("condition of if-then-else is not a boolean: {}", value),
+                    ))
+                }
+            },
+            Instr::Return => {
+                let result = stack.pop().unwrap();
+                stack.truncate(frame.base - 1);
+                frames.pop();
+                if frames.is_empty() {
+                    return Ok(result);
+                }
+                stack.push(result);
+            }
+            Instr::MakeClosure(idx) => {
+                let func = func.functions[idx as usize].clone();
+                let captures = stack.split_off(stack.len() - func.ncaptures as usize);
+                stack.push(Value::Closure(Rc::new(Closure { func, captures })));
+            }
+            Instr::MakeRecord(idx) => {
+                let names = &func.fieldnames[idx as usize];
+                let values = stack.split_off(stack.len() - names.len());
+                stack.push(Value::Record(names.iter().cloned().zip(values).collect()));
+            }
+            Instr::MakeRecordType(idx) => {
+                let names = &func.fieldnames[idx as usize];
+                let types = stack.split_off(stack.len() - names.len());
+                stack.push(Value::Type(Rc::new(Type::Record(
+                    names
+                        .iter()
+                        .cloned()
+                        .zip(types.iter().map(|t| t.expect_type()))
+                        .collect(),
+                ))));
+            }
+            Instr::MakeForall(idx) => {
+                let rettyp = match stack.pop().unwrap() {
+                    Value::Type(t) => t,
+                    _ => return Err(Error::ExpectedType(func.slocs[pc])),
+                };
+                let args = func.foralls[idx as usize].clone();
+                stack.push(Value::Type(Rc::new(Type::Lambda(args, rettyp))));
+            }
+            Instr::Field(idx) => match stack.pop().unwrap() {
+                Value::Record(mut fields) if (idx as usize) < fields.len() => {
+                    stack.push(fields.swap_remove(idx as usize).1)
+                }
+                value => return Err(not_a_record(func.slocs[pc], &value)),
+            },
+            Instr::Project(idx) => match stack.pop().unwrap() {
+                Value::Record(fields) => {
+                    let projected = func.projections[idx as usize]
+                        .iter()
+                        .map(|i| fields.get(*i as usize).cloned())
+                        .collect::<Option<_>>();
+                    match projected {
+                        Some(projected) => stack.push(Value::Record(projected)),
+                        None => return Err(not_a_record(func.slocs[pc], &Value::Record(fields))),
+                    }
+                }
+                value => return Err(not_a_record(func.slocs[pc], &value)),
+            },
+            Instr::As => {
+                let t = stack.pop().unwrap().expect_type();
+                let value = stack.pop().unwrap();
+                stack.push(cast(func.slocs[pc], value, &t)?);
+            }
+        }
+    }
+}
+
+/* The type-check and compiler make sure field accesses fit the record. */
+fn not_a_record(sloc: SLoc, value: &Value) -> Error {
+    Error::TypeError(sloc, format!("not a record with the accessed fields: {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use super::*;
+    use crate::ast::Parser;
+    use crate::compile::compile;
+    use crate::eval::{eval, Budget, Runtime, RuntimeOptions, Scope};
+    use crate::lex::Lexer;
+
+    const FIB: &str =
+        "let fib: ∀(n: Int) -> Int = λ(n: Int) -> if n < 2 then n else fib(n - 1) + fib(n - 2) in fib(20)";
+
+    fn parse(rt: &Rc<RefCell<Runtime>>, input: &str) -> Box<Node> {
+        let mut rt = rt.borrow_mut();
+        let mut lexer = Lexer::new(input, 0, &mut rt.string_pool);
+        let mut parser = Parser::new(&mut lexer);
+        let mut node = parser.parse_all().expect("parsing failed");
+        node.typecheck(&mut rt, None).expect("type-check failed");
+        node
+    }
+
+    fn run_vm(input: &str) -> Value {
+        let rt = Runtime::with_options(RuntimeOptions::default());
+        let node = parse(&rt, input);
+        let main = compile(&node, &rt.borrow()).expect("compilation failed");
+        run(&main, Limits::default()).expect("evaluation failed")
+    }
+
+    #[test]
+    fn closures() {
+        let res = run_vm(
+            "let add = λ(x: Int) -> λ(y: Int) -> x + y
+             let a = 1
+             let b = 2
+             let add3 = add(a + b)
+             in add3(39)",
+        );
+        assert_eq!(res.to_string(), "42");
+
+        let res = run_vm(
+            "let x = 1
+             let f = λ(y: Int) -> x + y
+             let x = 100
+             in { a = f(1), b = (let x = 5 in x * f(x)), c = x }",
+        );
+        assert_eq!(res.to_string(), "{ a = 2, b = 30, c = 100 }");
+    }
+
+    #[test]
+    fn untyped_field_access() {
+        let rt = Runtime::with_options(RuntimeOptions::default());
+        let mut rtref = rt.borrow_mut();
+        let mut lexer = Lexer::new("{ a = 1 }.a", 0, &mut rtref.string_pool);
+        let node = Parser::new(&mut lexer).parse_all().expect("parsing failed");
+        assert!(matches!(compile(&node, &rtref), Err(Error::TypeError(..))));
+    }
+
+    #[test]
+    fn only_used_variables_are_captured() {
+        let rt = Runtime::with_options(RuntimeOptions::default());
+        let node = parse(
+            &rt,
+            "let a = 1 let b = 2 let c = 3 in λ(x: Int) -> λ(y: Int) -> x + y + c",
+        );
+        let main = compile(&node, &rt.borrow()).unwrap();
+        let outer = &main.functions[0];
+        let inner = &outer.functions[0];
+        assert_eq!(outer.ncaptures, 1); // c
+        assert_eq!(inner.ncaptures, 2); // c and x
+    }
+
+    #[test]
+    fn recursion() {
+        assert_eq!(run_vm(FIB).to_string(), "6765");
+    }
+
+    #[bench]
+    fn fib_eval(b: &mut test::Bencher) {
+        let rt = Runtime::with_options(RuntimeOptions::default());
+        let node = parse(&rt, FIB);
+        b.iter(|| eval(&node, &Scope::from(rt.clone()), &mut Budget::default()).unwrap());
+    }
+
+    #[bench]
+    fn fib_vm(b: &mut test::Bencher) {
+        let rt = Runtime::with_options(RuntimeOptions::default());
+        let node = parse(&rt, FIB);
+        let main = compile(&node, &rt.borrow()).unwrap();
+        b.iter(|| run(&main, Limits::default()).unwrap());
+    }
+}
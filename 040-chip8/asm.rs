@@ -0,0 +1,278 @@
+use crate::{Instr, VReg};
+use std::collections::HashMap;
+
+/// Where ROMs are loaded, so where the assembled code starts.
+pub(crate) const ORIGIN: u16 = 0x200;
+
+/// Assembles the syntax `Instr` is displayed in into a ROM.
+///
+/// Each line is any number of `label:`s followed by an instruction, a
+/// `db`/`dw` directive with a list of values, or a constant definition
+/// `name = value`. Values are numbers (`42`, `0x2A`, `0b101010`), labels
+/// and constants, added or subtracted. `;` starts a comment.
+///
+/// The first pass assigns the labels their addresses, the second encodes.
+/// Constants are evaluated where they are defined, so unlike labels they
+/// can only use what is defined above them.
+pub fn assemble(src: &str) -> Result<Vec<u8>, String> {
+    struct Line<'a> {
+        no: usize,
+        mnemonic: String,
+        operands: Vec<&'a str>,
+    }
+
+    let mut symbols: HashMap<&str, i64> = HashMap::new();
+    let mut lines = Vec::new();
+    let mut addr = ORIGIN as i64;
+    for (no, line) in src.lines().enumerate() {
+        let no = no + 1;
+        let err = |msg: String| format!("line {}: {}", no, msg);
+        let mut line = line.split(';').next().unwrap().trim();
+        while let Some((label, rest)) = line.split_once(':') {
+            let label = label.trim();
+            if !is_ident(label) {
+                break;
+            }
+            if symbols.insert(label, addr).is_some() {
+                return Err(err(format!("{} defined twice", label)));
+            }
+            line = rest.trim();
+        }
+        if line.is_empty() {
+            continue;
+        }
+        if let Some((name, value)) = line.split_once('=') {
+            let name = name.trim();
+            if is_ident(name) {
+                let value = eval(value, &symbols).map_err(err)?;
+                if symbols.insert(name, value).is_some() {
+                    return Err(err(format!("{} defined twice", name)));
+                }
+                continue;
+            }
+        }
+
+        let (mnemonic, operands) = match line.split_once(char::is_whitespace) {
+            Some((m, ops)) => (m, ops.split(',').map(str::trim).collect()),
+            None => (line, vec![]),
+        };
+        let mnemonic = mnemonic.to_ascii_uppercase();
+        addr += match mnemonic.as_str() {
+            "DB" => operands.len() as i64,
+            "DW" => operands.len() as i64 * 2,
+            _ if matches!(operands.get(1).map(|&o| operand(o)), Some(Operand::Long(_))) => 4,
+            _ => 2,
+        };
+        lines.push(Line {
+            no,
+            mnemonic,
+            operands,
+        });
+    }
+
+    let mut rom = Vec::new();
+    for line in lines {
+        let err = |msg: String| format!("line {}: {}", line.no, msg);
+        match line.mnemonic.as_str() {
+            "DB" => {
+                for op in line.operands {
+                    rom.push(number(op, 0xFF, &symbols).map_err(err)? as u8);
+                }
+            }
+            "DW" => {
+                for op in line.operands {
+                    let word = number(op, 0xFFFF, &symbols).map_err(err)?;
+                    rom.extend_from_slice(&word.to_be_bytes());
+                }
+            }
+            m => rom.extend(instr(m, &line.operands, &symbols).map_err(err)?.encode()),
+        }
+    }
+    Ok(rom)
+}
+
+fn is_ident(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Evaluates a sum of numbers and symbols.
+fn eval(expr: &str, symbols: &HashMap<&str, i64>) -> Result<i64, String> {
+    let mut sum = 0i64;
+    let mut sign = 1;
+    let mut expect_term = true;
+    let spaced = expr.replace('+', " + ").replace('-', " - ");
+    for tok in spaced.split_whitespace() {
+        match (tok, expect_term) {
+            ("+", _) => {}
+            ("-", _) => sign = -sign,
+            (_, false) => return Err(format!("missing + or - before {}", tok)),
+            (tok, true) => {
+                let value = if let Some(hex) = tok.strip_prefix("0x") {
+                    i64::from_str_radix(hex, 16).ok()
+                } else if let Some(bin) = tok.strip_prefix("0b") {
+                    i64::from_str_radix(bin, 2).ok()
+                } else if tok.starts_with(|c: char| c.is_ascii_digit()) {
+                    tok.parse().ok()
+                } else {
+                    symbols.get(tok).copied()
+                };
+                let Some(value) = value else {
+                    return Err(format!("unknown value {}", tok));
+                };
+                sum += sign * value;
+                sign = 1;
+                expect_term = false;
+                continue;
+            }
+        }
+        expect_term = true;
+    }
+    if expect_term {
+        return Err(format!("incomplete value {:?}", expr.trim()));
+    }
+    Ok(sum)
+}
+
+/// Evaluates `expr` and checks it fits in `0..=max`. Negative numbers are
+/// taken as two's complement.
+fn number(expr: &str, max: u16, symbols: &HashMap<&str, i64>) -> Result<u16, String> {
+    let value = eval(expr, symbols)?;
+    let min = -(max as i64 + 1) / 2;
+    if value < min || value > max as i64 {
+        return Err(format!("{} is out of range", expr.trim()));
+    }
+    Ok(value as u16 & max)
+}
+
+enum Operand<'a> {
+    V(VReg),
+    I,
+    AtI,
+    DT,
+    ST,
+    K,
+    F,
+    B,
+    HF,
+    R,
+    Long(&'a str),
+    Value(&'a str),
+}
+
+fn operand(op: &str) -> Operand<'_> {
+    match op.to_ascii_lowercase().as_str() {
+        "i" => Operand::I,
+        "[i]" => Operand::AtI,
+        "dt" => Operand::DT,
+        "st" => Operand::ST,
+        "k" => Operand::K,
+        "f" => Operand::F,
+        "b" => Operand::B,
+        "hf" => Operand::HF,
+        "r" => Operand::R,
+        lower => match lower.strip_prefix('v') {
+            Some(x) if x.len() == 1 => match u8::from_str_radix(x, 16) {
+                Ok(x) => Operand::V(x),
+                Err(_) => Operand::Value(op),
+            },
+            _ if lower.starts_with("long ") => Operand::Long(op[5..].trim()),
+            _ => Operand::Value(op),
+        },
+    }
+}
+
+fn instr(mnemonic: &str, operands: &[&str], symbols: &HashMap<&str, i64>) -> Result<Instr, String> {
+    use Operand::*;
+    let ops: Vec<Operand> = operands.iter().map(|&o| operand(o)).collect();
+    let addr = |e: &str| number(e, 0xFFF, symbols);
+    let byte = |e: &str| number(e, 0xFF, symbols).map(|b| b as u8);
+    let nibble = |e: &str| number(e, 0xF, symbols).map(|n| n as u8);
+    Ok(match (mnemonic, ops.as_slice()) {
+        ("SYS", [Value(a)]) => Instr::SYS { addr: addr(a)? },
+        ("CLS", []) => Instr::CLS,
+        ("RET", []) => Instr::RET,
+        ("JP", [Value(a)]) => Instr::JP { addr: addr(a)? },
+        ("JP", [V(0), Value(a)]) => Instr::JP_V0 { offset: addr(a)? },
+        ("CALL", [Value(a)]) => Instr::CALL { addr: addr(a)? },
+        ("LD", [V(dst), V(src)]) => Instr::MV {
+            dst: *dst,
+            src: *src,
+        },
+        ("LD", [V(dst), Value(b)]) => Instr::LD_IMM {
+            dst: *dst,
+            imm: byte(b)?,
+        },
+        ("LD", [I, Value(a)]) => Instr::LD_I { addr: addr(a)? },
+        ("LD", [I, Long(a)]) => Instr::LD_I_LONG {
+            addr: number(a, 0xFFFF, symbols)?,
+        },
+        ("LD", [V(dst), DT]) => Instr::LD_DT { dst: *dst },
+        ("LD", [V(dst), K]) => Instr::LD_K { dst: *dst },
+        ("LD", [F, V(digit)]) => Instr::LD_SPRITE { digit: *digit },
+        ("LD", [HF, V(digit)]) => Instr::LD_HF { digit: *digit },
+        ("LD", [B, V(num)]) => Instr::LD_BCD { num: *num },
+        ("LD", [AtI, V(upto)]) => Instr::LD_REGS_TO_I { upto: *upto },
+        ("LD", [V(upto), AtI]) => Instr::LD_I_TO_REGS { upto: *upto },
+        ("LD", [R, V(upto)]) => Instr::LD_REGS_TO_R { upto: *upto },
+        ("LD", [V(upto), R]) => Instr::LD_R_TO_REGS { upto: *upto },
+        ("LD", [DT, V(x)]) => Instr::SET_DT { x: *x },
+        ("LD", [ST, V(x)]) => Instr::SET_ST { x: *x },
+        ("SE", [V(x), V(y)]) => Instr::SE_REG { x: *x, y: *y },
+        ("SE", [V(x), Value(b)]) => Instr::SE_IMM {
+            x: *x,
+            imm: byte(b)?,
+        },
+        ("SNE", [V(x), V(y)]) => Instr::SNE_REG { x: *x, y: *y },
+        ("SNE", [V(x), Value(b)]) => Instr::SNE_IMM {
+            x: *x,
+            imm: byte(b)?,
+        },
+        ("OR", [V(x), V(y)]) => Instr::OR { x: *x, y: *y },
+        ("AND", [V(x), V(y)]) => Instr::AND { x: *x, y: *y },
+        ("XOR", [V(x), V(y)]) => Instr::XOR { x: *x, y: *y },
+        ("ADD", [V(x), V(y)]) => Instr::ADD { x: *x, y: *y },
+        ("ADD", [V(dst), Value(b)]) => Instr::ADD_IMM {
+            dst: *dst,
+            imm: byte(b)?,
+        },
+        ("ADD", [I, V(x)]) => Instr::ADD_I { x: *x },
+        ("SUB", [V(x), V(y)]) => Instr::SUB { x: *x, y: *y },
+        ("SUBN", [V(x), V(y)]) => Instr::SUBN { x: *x, y: *y },
+        ("SHR", [V(x)]) => Instr::SHR { x: *x, y: *x },
+        ("SHR", [V(x), V(y)]) => Instr::SHR { x: *x, y: *y },
+        ("SHL", [V(x)]) => Instr::SHL { x: *x, y: *x },
+        ("SHL", [V(x), V(y)]) => Instr::SHL { x: *x, y: *y },
+        ("RND", [V(dst), Value(b)]) => Instr::RND {
+            dst: *dst,
+            mask: byte(b)?,
+        },
+        ("DRW", [V(x), V(y), Value(n)]) => Instr::DRW {
+            x: *x,
+            y: *y,
+            n: nibble(n)?,
+        },
+        ("SKP", [V(x)]) => Instr::SKP { x: *x },
+        ("SKNP", [V(x)]) => Instr::SKNP { x: *x },
+        ("SCD", [Value(n)]) => Instr::SCD { n: nibble(n)? },
+        ("SCU", [Value(n)]) => Instr::SCU { n: nibble(n)? },
+        ("SCR", []) => Instr::SCR,
+        ("SCL", []) => Instr::SCL,
+        ("EXIT", []) => Instr::EXIT,
+        ("LOW", []) => Instr::LOW,
+        ("HIGH", []) => Instr::HIGH,
+        ("SAVE", [V(x), V(y)]) => Instr::SAVE_RANGE { x: *x, y: *y },
+        ("LOAD", [V(x), V(y)]) => Instr::LOAD_RANGE { x: *x, y: *y },
+        ("PLANE", [Value(n)]) => Instr::PLANE { mask: nibble(n)? },
+        ("AUDIO", []) => Instr::AUDIO,
+        ("PITCH", [V(x)]) => Instr::PITCH { x: *x },
+        _ => {
+            return Err(format!(
+                "invalid instruction {} {}",
+                mnemonic,
+                operands.join(", ")
+            ))
+        }
+    })
+}
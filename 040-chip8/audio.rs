@@ -0,0 +1,122 @@
+use std::io::{Seek, SeekFrom, Write};
+
+/// Timer ticks per second of emulated time, one frame of audio each.
+pub(crate) const TICKS_PER_SECOND: u32 = 60;
+
+/// Loudness of the tone, of the full -1.0 to 1.0 sample range.
+const VOLUME: f32 = 0.25;
+
+/// The pattern before XO-CHIP's `AUDIO` loads one: a square wave, 500 Hz at
+/// the default pitch.
+pub(crate) const SQUARE_WAVE: [u8; 16] = [0xF0; 16];
+
+/// Turns the buzzer state into samples. The sound is the 128 bit pattern
+/// of XO-CHIP played in a loop at `4000 * 2 ^ ((pitch - 64) / 48)` bits
+/// per second; the other modes only ever play the default square wave.
+pub struct Buzzer {
+    sample_rate: u32,
+    on: bool,
+    pattern: [u8; 16],
+    pitch: u8,
+    /// Position in the pattern, in bits.
+    phase: f64,
+    /// Samples owed to the next `frame` when a frame is not a whole number
+    /// of samples.
+    pending: f64,
+}
+
+impl Buzzer {
+    pub fn new(sample_rate: u32) -> Buzzer {
+        Buzzer {
+            sample_rate,
+            on: false,
+            pattern: SQUARE_WAVE,
+            pitch: 64,
+            phase: 0.,
+            pending: 0.,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_on(&mut self, on: bool) {
+        self.on = on;
+    }
+
+    pub fn set_pattern(&mut self, pattern: &[u8; 16], pitch: u8) {
+        self.pattern = *pattern;
+        self.pitch = pitch;
+    }
+
+    /// Fills `out` with the next samples, silence while the buzzer is off.
+    pub fn generate(&mut self, out: &mut [f32]) {
+        if !self.on {
+            out.fill(0.);
+            return;
+        }
+        let rate = 4000. * 2f64.powf((self.pitch as f64 - 64.) / 48.);
+        let step = rate / self.sample_rate as f64;
+        for sample in out {
+            let bit = self.phase as usize % 128;
+            let set = self.pattern[bit / 8] & (0x80 >> (bit % 8)) != 0;
+            *sample = if set { VOLUME } else { -VOLUME };
+            self.phase = (self.phase + step) % 128.;
+        }
+    }
+
+    /// Appends the samples of one timer tick to `out`.
+    pub fn frame(&mut self, out: &mut Vec<f32>) {
+        self.pending += self.sample_rate as f64 / TICKS_PER_SECOND as f64;
+        let n = self.pending as usize;
+        self.pending -= n as f64;
+        let start = out.len();
+        out.resize(start + n, 0.);
+        self.generate(&mut out[start..]);
+    }
+}
+
+/// Writes samples to a mono 16 bit WAV file as they come, the sizes in the
+/// header are filled in by `finish`.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    samples: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> std::io::Result<WavWriter<W>> {
+        out.write_all(b"RIFF\0\0\0\0WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?; // PCM
+        out.write_all(&1u16.to_le_bytes())?; // mono
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * 2).to_le_bytes())?;
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&16u16.to_le_bytes())?;
+        out.write_all(b"data\0\0\0\0")?;
+        Ok(WavWriter { out, samples: 0 })
+    }
+
+    pub fn write(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        let bytes: Vec<u8> = samples
+            .iter()
+            .flat_map(|s| ((s.clamp(-1., 1.) * i16::MAX as f32) as i16).to_le_bytes())
+            .collect();
+        self.out.write_all(&bytes)?;
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    /// Fills in the sizes and hands back the output.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        let data = self.samples * 2;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(36 + data).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&data.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
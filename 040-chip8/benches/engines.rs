@@ -0,0 +1,31 @@
+//! Cycles per second of each engine on tetris, with the headless UI so only
+//! the emulation is measured. Run with `cargo bench`, and add
+//! `--no-default-features` to leave out the clock read every cycle, which
+//! otherwise takes most of the time.
+
+use chip8::{Chip8State, Engine, HeadlessUI, Quirks};
+use std::time::Instant;
+
+const CYCLES: u64 = 2_000_000;
+
+fn main() {
+    let rom = std::fs::read("roms/tetris.ch8").unwrap();
+    for engine in [Engine::Interpreter, Engine::Blocks] {
+        let ui = HeadlessUI::new(1)
+            .press(0x6, 500, 700)
+            .press(0x4, 2000, 2200);
+        let mut c8 = Chip8State::new(Box::new(ui), &rom);
+        c8.set_quirks(Quirks::CHIP48);
+        c8.set_engine(engine);
+        let start = Instant::now();
+        let cycles = c8.run(CYCLES).unwrap();
+        let secs = start.elapsed().as_secs_f64();
+        println!(
+            "{:<12} {:>9} cycles in {:.3}s: {:>6.1} Mcycles/s",
+            format!("{:?}", engine),
+            cycles,
+            secs,
+            cycles as f64 / secs / 1e6
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{Instr, Machine, UI};
+use std::ops::Range;
+use std::rc::Rc;
+
+/// How `Chip8State` runs a program. Both engines run the same instructions
+/// with the same results, cycle for cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Engine {
+    /// Decodes each instruction every time it runs.
+    #[default]
+    Interpreter,
+    /// Decodes straight runs of code once into blocks and runs them from a
+    /// cache. Blocks are dropped when the program writes to them.
+    Blocks,
+}
+
+/// Most instructions in one block.
+const MAX_BLOCK: usize = 64;
+
+/// Instructions decoded from consecutive addresses. Only the last one can
+/// continue anywhere else than at the next.
+struct Block {
+    addrs: Vec<u16>,
+    instrs: Vec<Instr>,
+    /// The memory the instructions were decoded from.
+    bytes: Range<usize>,
+}
+
+pub(crate) struct BlockCache {
+    /// The block starting at each address.
+    blocks: Vec<Option<Rc<Block>>>,
+    /// How many blocks each byte of memory is decoded into, so writes to
+    /// data are told apart from writes to code cheaply.
+    code: Vec<u16>,
+    /// The block and index of the instruction that runs next, if the
+    /// program keeps going straight.
+    next: Option<(Rc<Block>, usize)>,
+}
+
+impl BlockCache {
+    pub(crate) fn new(memory: usize) -> BlockCache {
+        BlockCache {
+            blocks: vec![None; memory],
+            code: vec![0; memory],
+            next: None,
+        }
+    }
+
+    /// Runs the instruction at PC like `Machine::exec`.
+    pub(crate) fn exec(
+        &mut self,
+        machine: &mut Machine,
+        ui: &mut dyn UI,
+    ) -> Result<u16, &'static str> {
+        let pc = machine.pc;
+        let (block, i) = match self.next.take() {
+            Some((block, i)) if block.addrs[i] == pc => (block, i),
+            _ => (self.block(machine, pc)?, 0),
+        };
+        let instr = block.instrs[i];
+        let written = written(machine, instr);
+        let res = machine.execute(instr, ui);
+        if i + 1 < block.instrs.len() {
+            self.next = Some((block, i + 1));
+        }
+        if let Some(bytes) = written {
+            // Writes through `I` wrap around at the end of memory.
+            if bytes.end > self.code.len() {
+                self.invalidate(0..bytes.end - self.code.len());
+            }
+            self.invalidate(bytes);
+        }
+        res
+    }
+
+    /// Drops the blocks decoded from any of `bytes`.
+    pub(crate) fn invalidate(&mut self, bytes: Range<usize>) {
+        let bytes = bytes.start.min(self.code.len())..bytes.end.min(self.code.len());
+        if self.code[bytes.clone()].iter().all(|&n| n == 0) {
+            return;
+        }
+        // A block is at most `MAX_BLOCK` `LD I, long`s long.
+        for start in bytes.start.saturating_sub(MAX_BLOCK * 4)..bytes.end {
+            let hit = match &self.blocks[start] {
+                Some(b) => b.bytes.start < bytes.end && bytes.start < b.bytes.end,
+                None => false,
+            };
+            if hit {
+                let block = self.blocks[start].take().unwrap();
+                for n in &mut self.code[block.bytes.clone()] {
+                    *n -= 1;
+                }
+            }
+        }
+        self.next = None;
+    }
+
+    /// The block starting at `pc`, decoded now if it is not cached.
+    fn block(&mut self, machine: &Machine, pc: u16) -> Result<Rc<Block>, &'static str> {
+        if let Some(block) = &self.blocks[pc as usize] {
+            return Ok(block.clone());
+        }
+        let memory = &machine.memory;
+        let mut addrs = Vec::new();
+        let mut instrs = Vec::new();
+        let mut addr = pc as usize;
+        while instrs.len() < MAX_BLOCK {
+            // The first instruction fails like it would in the interpreter,
+            // anything after it is only decoded if it is sure to be code.
+            let instr = match Instr::decode(addr, memory) {
+                Ok(instr) => instr,
+                Err(e) if instrs.is_empty() => return Err(e),
+                Err(_) => break,
+            };
+            addrs.push(addr as u16);
+            instrs.push(instr);
+            addr += instr.size() as usize;
+            if ends_block(instr) || addr + 4 > memory.len() {
+                break;
+            }
+        }
+        let block = Rc::new(Block {
+            addrs,
+            instrs,
+            bytes: pc as usize..addr,
+        });
+        for n in &mut self.code[block.bytes.clone()] {
+            *n += 1;
+        }
+        self.blocks[pc as usize] = Some(block.clone());
+        Ok(block)
+    }
+}
+
+/// Whether the program may not continue at the instruction after `instr`:
+/// jumps, calls and skips, and the instructions that wait by running again.
+fn ends_block(instr: Instr) -> bool {
+    matches!(
+        instr,
+        Instr::SYS { .. }
+            | Instr::RET
+            | Instr::JP { .. }
+            | Instr::JP_V0 { .. }
+            | Instr::CALL { .. }
+            | Instr::SE_IMM { .. }
+            | Instr::SNE_IMM { .. }
+            | Instr::SE_REG { .. }
+            | Instr::SNE_REG { .. }
+            | Instr::SKP { .. }
+            | Instr::SKNP { .. }
+            | Instr::LD_K { .. }
+            | Instr::DRW { .. }
+            | Instr::EXIT
+    )
+}
+
+/// The memory `instr` is about to write to.
+fn written(machine: &Machine, instr: Instr) -> Option<Range<usize>> {
+    let len = match instr {
+        Instr::LD_BCD { .. } => 3,
+        Instr::LD_REGS_TO_I { upto } => upto as usize + 1,
+        Instr::SAVE_RANGE { x, y } => x.abs_diff(y) as usize + 1,
+        _ => return None,
+    };
+    let start = machine.idx_reg as usize;
+    Some(start..start + len)
+}
@@ -0,0 +1,30 @@
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (src_file, rom_file) = match args.as_slice() {
+        [src] => (src.clone(), format!("{}.ch8", src.trim_end_matches(".s"))),
+        [src, o, rom] if o == "-o" => (src.clone(), rom.clone()),
+        _ => {
+            eprintln!("usage: chip8-asm <file.s> [-o <file.ch8>]");
+            std::process::exit(2);
+        }
+    };
+
+    let src = match std::fs::read_to_string(&src_file) {
+        Ok(src) => src,
+        Err(e) => {
+            eprintln!("{}: {}", src_file, e);
+            std::process::exit(1);
+        }
+    };
+    let rom = match chip8::assemble(&src) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("{}: {}", src_file, e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = std::fs::write(&rom_file, &rom) {
+        eprintln!("{}: {}", rom_file, e);
+        std::process::exit(1);
+    }
+}
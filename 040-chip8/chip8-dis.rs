@@ -0,0 +1,15 @@
+fn main() {
+    let Some(rom_file) = std::env::args().nth(1) else {
+        eprintln!("usage: chip8-dis <file.ch8>");
+        std::process::exit(2);
+    };
+    let rom = match std::fs::read(&rom_file) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("{}: {}", rom_file, e);
+            std::process::exit(1);
+        }
+    };
+    println!("; {}, {} bytes", rom_file, rom.len());
+    print!("{}", chip8::disassemble(&rom));
+}
@@ -0,0 +1,156 @@
+use crate::{Chip8State, Instr, VReg};
+
+/// A register of the machine, for reading, writing and watching it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg {
+    V(VReg),
+    I,
+    PC,
+    DT,
+    ST,
+}
+
+/// What a watchpoint looks at: a byte of memory or a register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    Memory(u16),
+    Reg(Reg),
+}
+
+/// Why `Chip8State::run_until` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// The next instruction to run is at a breakpoint.
+    Breakpoint(u16),
+    /// The last instruction changed a watched value.
+    Watchpoint { watch: Watch, old: u16, new: u16 },
+    /// All the requested cycles ran.
+    Cycles,
+}
+
+impl Chip8State {
+    /// Runs a single instruction.
+    pub fn step(&mut self) -> Result<(), &'static str> {
+        self.cycle().map(|_| ())
+    }
+
+    /// Runs up to `n` cycles, stopping before an instruction at a
+    /// breakpoint and after one changing a watched value. The instruction
+    /// at PC runs even if it has a breakpoint, so calling this again after
+    /// a `Stop::Breakpoint` continues. Breakpoints are checked after each
+    /// cycle, so running one cycle at a time stops at them too.
+    pub fn run_until(&mut self, n: u64) -> Result<Stop, &'static str> {
+        for _ in 0..n {
+            let before: Vec<u16> = self.watchpoints.iter().map(|&w| self.watched(w)).collect();
+            self.cycle()?;
+            for (&watch, old) in self.watchpoints.iter().zip(before) {
+                let new = self.watched(watch);
+                if new != old {
+                    return Ok(Stop::Watchpoint { watch, old, new });
+                }
+            }
+            if self.breakpoints.contains(&self.machine.pc) {
+                return Ok(Stop::Breakpoint(self.machine.pc));
+            }
+        }
+        Ok(Stop::Cycles)
+    }
+
+    /// Adds a breakpoint at `pc`, returns false if there already was one.
+    pub fn add_breakpoint(&mut self, pc: u16) -> bool {
+        self.breakpoints.insert(pc)
+    }
+
+    /// Removes the breakpoint at `pc`, returns false if there was none.
+    pub fn remove_breakpoint(&mut self, pc: u16) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_watchpoint(&mut self, watch: Watch) {
+        if !self.watchpoints.contains(&watch) {
+            self.watchpoints.push(watch);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, watch: Watch) {
+        self.watchpoints.retain(|&w| w != watch);
+    }
+
+    fn watched(&self, watch: Watch) -> u16 {
+        match watch {
+            Watch::Memory(addr) => {
+                self.machine.memory[addr as usize % self.machine.memory.len()] as u16
+            }
+            Watch::Reg(reg) => self.reg(reg),
+        }
+    }
+
+    pub fn reg(&self, reg: Reg) -> u16 {
+        match reg {
+            Reg::V(x) => self.machine.v_regs[x as usize & 0xF] as u16,
+            Reg::I => self.machine.idx_reg,
+            Reg::PC => self.machine.pc,
+            Reg::DT => self.machine.delay_timer as u16,
+            Reg::ST => self.machine.sound_timer as u16,
+        }
+    }
+
+    /// Sets a register, the 8 bit ones to the low byte of `val`.
+    pub fn set_reg(&mut self, reg: Reg, val: u16) {
+        match reg {
+            Reg::V(x) => self.machine.v_regs[x as usize & 0xF] = val as u8,
+            Reg::I => self.machine.idx_reg = val,
+            Reg::PC => self.machine.pc = val,
+            Reg::DT => self.machine.delay_timer = val as u8,
+            Reg::ST => self.machine.sound_timer = val as u8,
+        }
+    }
+
+    /// Up to `len` bytes of memory starting at `addr`.
+    pub fn read_memory(&self, addr: u16, len: usize) -> &[u8] {
+        let start = (addr as usize).min(self.machine.memory.len());
+        let end = (start + len).min(self.machine.memory.len());
+        &self.machine.memory[start..end]
+    }
+
+    /// Writes `bytes` to memory at `addr`, dropping what does not fit.
+    pub fn write_memory(&mut self, addr: u16, bytes: &[u8]) {
+        let start = (addr as usize).min(self.machine.memory.len());
+        let end = (start + bytes.len()).min(self.machine.memory.len());
+        self.machine.memory[start..end].copy_from_slice(&bytes[..end - start]);
+        if let Some(blocks) = &mut self.blocks {
+            blocks.invalidate(start..end);
+        }
+    }
+
+    /// Return addresses, the innermost call last.
+    pub fn stack(&self) -> &[u16] {
+        &self.machine.stack
+    }
+
+    /// Decodes `count` instructions starting at `addr`, with their
+    /// addresses. Words that do not decode are shown as data.
+    pub fn disassemble(&self, addr: u16, count: usize) -> Vec<(u16, String)> {
+        let mut out = Vec::with_capacity(count);
+        let mut pc = addr as usize;
+        while out.len() < count && pc + 1 < self.machine.memory.len() {
+            match Instr::decode(pc, &self.machine.memory) {
+                Ok(instr) => {
+                    out.push((pc as u16, instr.to_string()));
+                    pc += instr.size() as usize;
+                }
+                Err(_) => {
+                    let word = ((self.machine.memory[pc] as u16) << 8)
+                        | self.machine.memory[pc + 1] as u16;
+                    out.push((pc as u16, format!("dw {:#06x}", word)));
+                    pc += 2;
+                }
+            }
+        }
+        out
+    }
+}
@@ -0,0 +1,137 @@
+use crate::asm::ORIGIN;
+use crate::Instr;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Label {
+    Sub,
+    Jump,
+    Data,
+}
+
+fn name(addr: usize, label: Label) -> String {
+    let prefix = match label {
+        Label::Sub => "sub",
+        Label::Jump => "l",
+        Label::Data => "d",
+    };
+    format!("{}_{:03x}", prefix, addr)
+}
+
+/// Turns a ROM back into source for `assemble`. Code is told apart from
+/// sprites and other data by following `JP`, `CALL` and skips from the
+/// entry point; everything not reached is written out with `db`. Jump and
+/// call targets and the addresses loaded into `I` get labels.
+pub fn disassemble(rom: &[u8]) -> String {
+    let start = ORIGIN as usize;
+    let end = start + rom.len();
+    // Only instructions that encode back to the same bytes, so the output
+    // assembles to the ROM again.
+    let instr_at = |addr: usize| -> Option<Instr> {
+        if addr < start || addr + 2 > end {
+            return None;
+        }
+        let bytes = &rom[addr - start..];
+        let instr = Instr::decode(0, bytes).ok()?;
+        let size = instr.size() as usize;
+        (size <= bytes.len() && instr.encode() == bytes[..size]).then_some(instr)
+    };
+
+    let mut code: BTreeMap<usize, Instr> = BTreeMap::new();
+    let mut labels: BTreeMap<usize, Label> = BTreeMap::new();
+    let mut todo = vec![start];
+    let mut label = |addr: u16, kind: Label, todo: &mut Vec<usize>| {
+        let addr = addr as usize;
+        if (start..end).contains(&addr) {
+            let old = labels.entry(addr).or_insert(kind);
+            if kind != Label::Data {
+                if *old == Label::Data {
+                    *old = kind;
+                }
+                todo.push(addr);
+            }
+        }
+    };
+    while let Some(mut addr) = todo.pop() {
+        while !code.contains_key(&addr) {
+            let Some(instr) = instr_at(addr) else {
+                break;
+            };
+            code.insert(addr, instr);
+            let next = addr + instr.size() as usize;
+            match instr {
+                Instr::JP { addr } => {
+                    label(addr, Label::Jump, &mut todo);
+                    break;
+                }
+                Instr::CALL { addr } => label(addr, Label::Sub, &mut todo),
+                Instr::LD_I { addr } | Instr::LD_I_LONG { addr } => {
+                    label(addr, Label::Data, &mut todo)
+                }
+                Instr::SE_IMM { .. }
+                | Instr::SNE_IMM { .. }
+                | Instr::SE_REG { .. }
+                | Instr::SNE_REG { .. }
+                | Instr::SKP { .. }
+                | Instr::SKNP { .. } => {
+                    // The skipped instruction is two bytes, or four if it is
+                    // `LD I, long` on XO-CHIP.
+                    todo.push(next + 2);
+                    if let Some(skipped) = instr_at(next) {
+                        todo.push(next + skipped.size() as usize);
+                    }
+                }
+                Instr::RET | Instr::EXIT | Instr::JP_V0 { .. } | Instr::SYS { .. } => break,
+                _ => {}
+            }
+            addr = next;
+        }
+    }
+
+    let operand = |addr: u16| match labels.get(&(addr as usize)) {
+        Some(&l) => name(addr as usize, l),
+        None => format!("{:#05x}", addr),
+    };
+    let mut out = String::new();
+    let mut data: Vec<String> = Vec::new();
+    let flush = |out: &mut String, data: &mut Vec<String>| {
+        if !data.is_empty() {
+            writeln!(out, "    db {}", data.join(", ")).unwrap();
+            data.clear();
+        }
+    };
+    let mut addr = start;
+    while addr < end {
+        if let Some(&l) = labels.get(&addr) {
+            flush(&mut out, &mut data);
+            writeln!(out, "{}:", name(addr, l)).unwrap();
+        }
+        // Instructions with a label inside are data, the label needs an
+        // address of its own.
+        let instr = code.get(&addr).filter(|i| {
+            let inner = addr + 1..addr + i.size() as usize;
+            labels.range(inner).next().is_none()
+        });
+        if let Some(instr) = instr {
+            flush(&mut out, &mut data);
+            let line = match *instr {
+                Instr::JP { addr } => format!("JP {}", operand(addr)),
+                Instr::CALL { addr } => format!("CALL {}", operand(addr)),
+                Instr::LD_I { addr } => format!("LD I, {}", operand(addr)),
+                Instr::LD_I_LONG { addr } => format!("LD I, long {}", operand(addr)),
+                instr => instr.to_string(),
+            };
+            writeln!(out, "    {}", line).unwrap();
+            addr += instr.size() as usize;
+        } else {
+            data.push(format!("{:#04x}", rom[addr - start]));
+            if data.len() == 8 {
+                flush(&mut out, &mut data);
+            }
+            addr += 1;
+        }
+    }
+    flush(&mut out, &mut data);
+    out
+}
@@ -0,0 +1,73 @@
+use crate::UI;
+
+/// A UI without a screen: keys are pressed and released at fixed cycles and
+/// `rnd` comes from a seeded xorshift, so a run is fully reproducible. Used
+/// by the ROM regression tests; the picture is read back with
+/// `Chip8State::snapshot_text` or `Chip8State::snapshot_pbm`.
+pub struct HeadlessUI {
+    /// `(cycle, key, pressed)`, sorted by cycle.
+    script: Vec<(u64, u8, bool)>,
+    next: usize,
+    keys: u16,
+    rng: u64,
+}
+
+impl HeadlessUI {
+    /// Timers tick once every `TICK` cycles, same rate as the terminal UI.
+    pub const TICK: u64 = 4;
+
+    pub fn new(seed: u64) -> HeadlessUI {
+        HeadlessUI {
+            script: Vec::new(),
+            next: 0,
+            keys: 0,
+            // xorshift gets stuck on zero.
+            rng: seed | 1,
+        }
+    }
+
+    /// Holds `key` down from cycle `from` until cycle `to`.
+    pub fn press(mut self, key: u8, from: u64, to: u64) -> HeadlessUI {
+        self.script.push((from, key & 0xF, true));
+        self.script.push((to, key & 0xF, false));
+        self.script.sort_by_key(|&(cycle, _, _)| cycle);
+        self
+    }
+}
+
+impl UI for HeadlessUI {
+    fn is_key_pressed(&mut self, key: u8) -> bool {
+        self.keys & (1 << (key & 0xF)) != 0
+    }
+
+    fn clear_screen(&mut self) {}
+
+    fn draw_pixel(&mut self, _x: usize, _y: usize, _val: bool) {}
+
+    fn update(&mut self, cycle: u64, _dt: std::time::Duration) -> Result<bool, &'static str> {
+        while let Some(&(at, key, pressed)) = self.script.get(self.next) {
+            if at > cycle {
+                break;
+            }
+            if pressed {
+                self.keys |= 1 << key;
+            } else {
+                self.keys &= !(1 << key);
+            }
+            self.next += 1;
+        }
+        Ok(cycle.is_multiple_of(Self::TICK))
+    }
+
+    fn rnd(&mut self) -> u8 {
+        xorshift(&mut self.rng)
+    }
+}
+
+/// The next byte of a xorshift generator, `state` must not be zero.
+pub(crate) fn xorshift(state: &mut u64) -> u8 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    (*state >> 32) as u8
+}
@@ -1,14 +1,58 @@
-#![feature(duration_constants)]
-
 use std::fmt::Debug;
 
 // http://devernay.free.fr/hacks/chip8/C8TECH10.HTM
 use rand::Rng;
 
+mod asm;
+mod audio;
+mod blocks;
+mod debug;
+mod dis;
+mod headless;
+mod machine;
+mod quirks;
+mod tas;
+
+pub use asm::assemble;
+pub use audio::{Buzzer, WavWriter};
+pub use blocks::Engine;
+pub use debug::{Reg, Stop, Watch};
+pub use dis::disassemble;
+pub use headless::HeadlessUI;
+pub use machine::Machine;
+pub use quirks::Quirks;
+pub use tas::TasUI;
+
 pub type VReg = u8;
 
+/// The machine being emulated. Each one extends the instruction set of the
+/// one before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Mode {
+    /// The original COSMAC VIP interpreter: 64x32 pixels, 4 KiB of memory.
+    #[default]
+    Chip8,
+    /// SUPER-CHIP 1.1: 128x64 hires mode, scrolling, big font, RPL flags.
+    SChip,
+    /// XO-CHIP: 64 KiB of memory, two bitplanes, audio patterns.
+    XoChip,
+}
+
+impl std::str::FromStr for Mode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Mode, &'static str> {
+        match s.to_ascii_lowercase().as_str() {
+            "chip8" | "chip-8" => Ok(Mode::Chip8),
+            "schip" | "superchip" | "super-chip" => Ok(Mode::SChip),
+            "xochip" | "xo-chip" => Ok(Mode::XoChip),
+            _ => Err("unknown mode, expected chip8, schip or xochip"),
+        }
+    }
+}
+
 #[allow(non_camel_case_types)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instr {
     SYS { addr: u16 },
     CLS,
@@ -45,27 +89,60 @@ pub enum Instr {
     DRW { x: VReg, y: VReg, n: u8 },
     SKP { x: VReg },
     SKNP { x: VReg },
+
+    // SUPER-CHIP
+    SCD { n: u8 },
+    SCR,
+    SCL,
+    EXIT,
+    LOW,
+    HIGH,
+    LD_HF { digit: VReg },
+    LD_REGS_TO_R { upto: VReg },
+    LD_R_TO_REGS { upto: VReg },
+
+    // XO-CHIP
+    SCU { n: u8 },
+    SAVE_RANGE { x: VReg, y: VReg },
+    LOAD_RANGE { x: VReg, y: VReg },
+    LD_I_LONG { addr: u16 },
+    PLANE { mask: u8 },
+    AUDIO,
+    PITCH { x: VReg },
 }
 
 impl Instr {
-    #[allow(dead_code)]
-    fn decode(addr: usize, mem: &[u8]) -> Result<Instr, &'static str> {
-        let b0 = mem[addr];
-        let b1 = mem[addr + 1];
+    pub fn decode(addr: usize, mem: &[u8]) -> Result<Instr, &'static str> {
+        let (b0, b1) = match mem.get(addr..addr + 2) {
+            Some(&[b0, b1]) => (b0, b1),
+            _ => return Err("instruction at the end of memory"),
+        };
         let nnn = (((b0 as u16) & 0xf) << 8) | (b1 as u16);
         let x = (b0 & 0xf) as VReg;
         let y = ((b1 >> 4) & 0xf) as VReg;
         Ok(match b0 >> 4 {
             0x0 => match nnn {
+                0x0C0..=0x0CF => Instr::SCD { n: b1 & 0xF },
+                0x0D0..=0x0DF => Instr::SCU { n: b1 & 0xF },
                 0x0E0 => Instr::CLS,
                 0x0EE => Instr::RET,
+                0x0FB => Instr::SCR,
+                0x0FC => Instr::SCL,
+                0x0FD => Instr::EXIT,
+                0x0FE => Instr::LOW,
+                0x0FF => Instr::HIGH,
                 nnn => Instr::SYS { addr: nnn },
             },
             0x1 => Instr::JP { addr: nnn },
             0x2 => Instr::CALL { addr: nnn },
             0x3 => Instr::SE_IMM { x, imm: b1 },
             0x4 => Instr::SNE_IMM { x, imm: b1 },
-            0x5 => Instr::SE_REG { x, y },
+            0x5 => match b1 & 0xf {
+                0x0 => Instr::SE_REG { x, y },
+                0x2 => Instr::SAVE_RANGE { x, y },
+                0x3 => Instr::LOAD_RANGE { x, y },
+                _ => return Err("invalid instruction with 0x5??? encoding."),
+            },
             0x6 => Instr::LD_IMM { dst: x, imm: b1 },
             0x7 => Instr::ADD_IMM { dst: x, imm: b1 },
             0x8 => match b1 & 0xf {
@@ -94,23 +171,125 @@ impl Instr {
                 _ => return Err("invalid instruction with encoding 0xE???"),
             },
             0xF => match b1 {
+                0x00 if x == 0 => match mem.get(addr + 2..addr + 4) {
+                    Some(&[hi, lo]) => Instr::LD_I_LONG {
+                        addr: ((hi as u16) << 8) | (lo as u16),
+                    },
+                    _ => return Err("'LD I, long' at the end of memory"),
+                },
+                0x01 => Instr::PLANE { mask: x },
+                0x02 if x == 0 => Instr::AUDIO,
                 0x07 => Instr::LD_DT { dst: x },
                 0x0A => Instr::LD_K { dst: x },
                 0x15 => Instr::SET_DT { x },
                 0x18 => Instr::SET_ST { x },
                 0x1E => Instr::ADD_I { x },
                 0x29 => Instr::LD_SPRITE { digit: x },
+                0x30 => Instr::LD_HF { digit: x },
                 0x33 => Instr::LD_BCD { num: x },
+                0x3A => Instr::PITCH { x },
                 0x55 => Instr::LD_REGS_TO_I { upto: x },
-                0x65 => Instr::LD_REGS_TO_I { upto: x },
+                0x65 => Instr::LD_I_TO_REGS { upto: x },
+                0x75 => Instr::LD_REGS_TO_R { upto: x },
+                0x85 => Instr::LD_R_TO_REGS { upto: x },
                 _ => return Err("invalid instruction with encoding 0xF???"),
             },
             _ => return Err("WTF? Impossible!"),
         })
     }
 
-    #[allow(dead_code)]
-    fn fmt(vreg: VReg, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    /// The first mode that has this instruction.
+    pub fn mode(&self) -> Mode {
+        match self {
+            Instr::SCD { .. }
+            | Instr::SCR
+            | Instr::SCL
+            | Instr::EXIT
+            | Instr::LOW
+            | Instr::HIGH
+            | Instr::LD_HF { .. }
+            | Instr::LD_REGS_TO_R { .. }
+            | Instr::LD_R_TO_REGS { .. } => Mode::SChip,
+            Instr::SCU { .. }
+            | Instr::SAVE_RANGE { .. }
+            | Instr::LOAD_RANGE { .. }
+            | Instr::LD_I_LONG { .. }
+            | Instr::PLANE { .. }
+            | Instr::AUDIO
+            | Instr::PITCH { .. } => Mode::XoChip,
+            _ => Mode::Chip8,
+        }
+    }
+
+    /// The inverse of `decode`.
+    pub fn encode(&self) -> Vec<u8> {
+        let x = |x: VReg| ((x & 0xF) as u16) << 8;
+        let y = |y: VReg| ((y & 0xF) as u16) << 4;
+        let word: u16 = match *self {
+            Instr::SYS { addr } => addr & 0xFFF,
+            Instr::CLS => 0x00E0,
+            Instr::RET => 0x00EE,
+            Instr::JP { addr } => 0x1000 | (addr & 0xFFF),
+            Instr::JP_V0 { offset } => 0xB000 | (offset & 0xFFF),
+            Instr::CALL { addr } => 0x2000 | (addr & 0xFFF),
+            Instr::MV { dst, src } => 0x8000 | x(dst) | y(src),
+            Instr::LD_IMM { dst, imm } => 0x6000 | x(dst) | imm as u16,
+            Instr::LD_I { addr } => 0xA000 | (addr & 0xFFF),
+            Instr::LD_DT { dst } => 0xF007 | x(dst),
+            Instr::LD_K { dst } => 0xF00A | x(dst),
+            Instr::LD_SPRITE { digit } => 0xF029 | x(digit),
+            Instr::LD_BCD { num } => 0xF033 | x(num),
+            Instr::LD_REGS_TO_I { upto } => 0xF055 | x(upto),
+            Instr::LD_I_TO_REGS { upto } => 0xF065 | x(upto),
+            Instr::SET_DT { x: vx } => 0xF015 | x(vx),
+            Instr::SET_ST { x: vx } => 0xF018 | x(vx),
+            Instr::SE_IMM { x: vx, imm } => 0x3000 | x(vx) | imm as u16,
+            Instr::SNE_IMM { x: vx, imm } => 0x4000 | x(vx) | imm as u16,
+            Instr::SE_REG { x: vx, y: vy } => 0x5000 | x(vx) | y(vy),
+            Instr::SNE_REG { x: vx, y: vy } => 0x9000 | x(vx) | y(vy),
+            Instr::OR { x: vx, y: vy } => 0x8001 | x(vx) | y(vy),
+            Instr::AND { x: vx, y: vy } => 0x8002 | x(vx) | y(vy),
+            Instr::XOR { x: vx, y: vy } => 0x8003 | x(vx) | y(vy),
+            Instr::ADD { x: vx, y: vy } => 0x8004 | x(vx) | y(vy),
+            Instr::ADD_IMM { dst, imm } => 0x7000 | x(dst) | imm as u16,
+            Instr::ADD_I { x: vx } => 0xF01E | x(vx),
+            Instr::SUB { x: vx, y: vy } => 0x8005 | x(vx) | y(vy),
+            Instr::SHR { x: vx, y: vy } => 0x8006 | x(vx) | y(vy),
+            Instr::SUBN { x: vx, y: vy } => 0x8007 | x(vx) | y(vy),
+            Instr::SHL { x: vx, y: vy } => 0x800E | x(vx) | y(vy),
+            Instr::RND { dst, mask } => 0xC000 | x(dst) | mask as u16,
+            Instr::DRW { x: vx, y: vy, n } => 0xD000 | x(vx) | y(vy) | (n & 0xF) as u16,
+            Instr::SKP { x: vx } => 0xE09E | x(vx),
+            Instr::SKNP { x: vx } => 0xE0A1 | x(vx),
+            Instr::SCD { n } => 0x00C0 | (n & 0xF) as u16,
+            Instr::SCR => 0x00FB,
+            Instr::SCL => 0x00FC,
+            Instr::EXIT => 0x00FD,
+            Instr::LOW => 0x00FE,
+            Instr::HIGH => 0x00FF,
+            Instr::LD_HF { digit } => 0xF030 | x(digit),
+            Instr::LD_REGS_TO_R { upto } => 0xF075 | x(upto),
+            Instr::LD_R_TO_REGS { upto } => 0xF085 | x(upto),
+            Instr::SCU { n } => 0x00D0 | (n & 0xF) as u16,
+            Instr::SAVE_RANGE { x: vx, y: vy } => 0x5002 | x(vx) | y(vy),
+            Instr::LOAD_RANGE { x: vx, y: vy } => 0x5003 | x(vx) | y(vy),
+            Instr::LD_I_LONG { addr } => return vec![0xF0, 0x00, (addr >> 8) as u8, addr as u8],
+            Instr::PLANE { mask } => 0xF001 | x(mask),
+            Instr::AUDIO => 0xF002,
+            Instr::PITCH { x: vx } => 0xF03A | x(vx),
+        };
+        word.to_be_bytes().to_vec()
+    }
+
+    /// Size in bytes, four for XO-CHIP's `LD I, long` and two otherwise.
+    pub fn size(&self) -> u16 {
+        match self {
+            Instr::LD_I_LONG { .. } => 4,
+            _ => 2,
+        }
+    }
+
+    fn fmt_vreg(vreg: VReg, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(match vreg {
             0x0 => "v0",
             0x1 => "v1",
@@ -127,107 +306,162 @@ impl Instr {
             0xC => "vC",
             0xD => "vD",
             0xE => "vE",
-            0xF => "F",
+            0xF => "vF",
             _ => "<invalid V reg.>",
         })
     }
 }
 
+/// Formats a `VReg` with `Instr::fmt_vreg`.
+struct V(VReg);
+
+impl std::fmt::Display for V {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Instr::fmt_vreg(self.0, f)
+    }
+}
+
+/// Assembly in the syntax of Cowgod's reference, plus the SCHIP and XO-CHIP
+/// additions.
+impl std::fmt::Display for Instr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Instr::SYS { addr } => write!(f, "SYS {:#05x}", addr),
+            Instr::CLS => write!(f, "CLS"),
+            Instr::RET => write!(f, "RET"),
+            Instr::JP { addr } => write!(f, "JP {:#05x}", addr),
+            Instr::JP_V0 { offset } => write!(f, "JP v0, {:#05x}", offset),
+            Instr::CALL { addr } => write!(f, "CALL {:#05x}", addr),
+            Instr::MV { dst, src } => write!(f, "LD {}, {}", V(dst), V(src)),
+            Instr::LD_IMM { dst, imm } => write!(f, "LD {}, {:#04x}", V(dst), imm),
+            Instr::LD_I { addr } => write!(f, "LD I, {:#05x}", addr),
+            Instr::LD_DT { dst } => write!(f, "LD {}, DT", V(dst)),
+            Instr::LD_K { dst } => write!(f, "LD {}, K", V(dst)),
+            Instr::LD_SPRITE { digit } => write!(f, "LD F, {}", V(digit)),
+            Instr::LD_BCD { num } => write!(f, "LD B, {}", V(num)),
+            Instr::LD_REGS_TO_I { upto } => write!(f, "LD [I], {}", V(upto)),
+            Instr::LD_I_TO_REGS { upto } => write!(f, "LD {}, [I]", V(upto)),
+            Instr::SET_DT { x } => write!(f, "LD DT, {}", V(x)),
+            Instr::SET_ST { x } => write!(f, "LD ST, {}", V(x)),
+            Instr::SE_IMM { x, imm } => write!(f, "SE {}, {:#04x}", V(x), imm),
+            Instr::SNE_IMM { x, imm } => write!(f, "SNE {}, {:#04x}", V(x), imm),
+            Instr::SE_REG { x, y } => write!(f, "SE {}, {}", V(x), V(y)),
+            Instr::SNE_REG { x, y } => write!(f, "SNE {}, {}", V(x), V(y)),
+            Instr::OR { x, y } => write!(f, "OR {}, {}", V(x), V(y)),
+            Instr::AND { x, y } => write!(f, "AND {}, {}", V(x), V(y)),
+            Instr::XOR { x, y } => write!(f, "XOR {}, {}", V(x), V(y)),
+            Instr::ADD { x, y } => write!(f, "ADD {}, {}", V(x), V(y)),
+            Instr::ADD_IMM { dst, imm } => write!(f, "ADD {}, {:#04x}", V(dst), imm),
+            Instr::ADD_I { x } => write!(f, "ADD I, {}", V(x)),
+            Instr::SUB { x, y } => write!(f, "SUB {}, {}", V(x), V(y)),
+            Instr::SHR { x, y } => write!(f, "SHR {}, {}", V(x), V(y)),
+            Instr::SUBN { x, y } => write!(f, "SUBN {}, {}", V(x), V(y)),
+            Instr::SHL { x, y } => write!(f, "SHL {}, {}", V(x), V(y)),
+            Instr::RND { dst, mask } => write!(f, "RND {}, {:#04x}", V(dst), mask),
+            Instr::DRW { x, y, n } => write!(f, "DRW {}, {}, {}", V(x), V(y), n),
+            Instr::SKP { x } => write!(f, "SKP {}", V(x)),
+            Instr::SKNP { x } => write!(f, "SKNP {}", V(x)),
+            Instr::SCD { n } => write!(f, "SCD {}", n),
+            Instr::SCR => write!(f, "SCR"),
+            Instr::SCL => write!(f, "SCL"),
+            Instr::EXIT => write!(f, "EXIT"),
+            Instr::LOW => write!(f, "LOW"),
+            Instr::HIGH => write!(f, "HIGH"),
+            Instr::LD_HF { digit } => write!(f, "LD HF, {}", V(digit)),
+            Instr::LD_REGS_TO_R { upto } => write!(f, "LD R, {}", V(upto)),
+            Instr::LD_R_TO_REGS { upto } => write!(f, "LD {}, R", V(upto)),
+            Instr::SCU { n } => write!(f, "SCU {}", n),
+            Instr::SAVE_RANGE { x, y } => write!(f, "SAVE {}, {}", V(x), V(y)),
+            Instr::LOAD_RANGE { x, y } => write!(f, "LOAD {}, {}", V(x), V(y)),
+            Instr::LD_I_LONG { addr } => write!(f, "LD I, long {:#06x}", addr),
+            Instr::PLANE { mask } => write!(f, "PLANE {}", mask),
+            Instr::AUDIO => write!(f, "AUDIO"),
+            Instr::PITCH { x } => write!(f, "PITCH {}", V(x)),
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub trait UI {
     fn is_key_pressed(&mut self, key: u8) -> bool;
     fn clear_screen(&mut self);
     fn draw_pixel(&mut self, x: usize, y: usize, val: bool);
+    /// Draws a pixel in one of four colors, a bitmask of the XO-CHIP planes
+    /// it is set in. UIs with only one color get by with `draw_pixel`.
+    fn draw_color(&mut self, x: usize, y: usize, color: u8) {
+        self.draw_pixel(x, y, color != 0)
+    }
+    /// Switches between 64x32 and 128x64 pixels. The screen is cleared
+    /// right after.
+    fn set_resolution(&mut self, _width: usize, _height: usize) {}
     fn update(&mut self, cycle: u64, dt: std::time::Duration) -> Result<bool, &'static str>;
+    /// The sound timer started or stopped, the buzzer sounds while it is
+    /// running.
+    fn buzzer(&mut self, _on: bool) {}
+    /// XO-CHIP changed what the buzzer plays, see `Buzzer`.
+    fn audio_pattern(&mut self, _pattern: &[u8; 16], _pitch: u8) {}
     fn rnd(&mut self) -> u8 {
         rand::thread_rng().gen()
     }
 }
 
-#[allow(dead_code)]
 pub struct Chip8State {
-    v_regs: [u8; 16],
-    memory: [u8; 0x1000],
-    display: [[bool; 64]; 32],
+    machine: Machine,
     pub ui: Box<dyn UI>,
-    pc: u16,
-    idx_reg: u16,
-    sound_timer: u8,
-    delay_timer: u8,
-    stack: Vec<u16>,
-    digit_sprites: [u16; 16],
     last_frame: std::time::Duration,
-    cycles: u64,
+    breakpoints: std::collections::BTreeSet<u16>,
+    watchpoints: Vec<Watch>,
+    /// Past states for `rewind`, the latest last.
+    rewind: std::collections::VecDeque<Machine>,
+    rewind_capacity: usize,
+    rewind_every: u64,
+    /// Decoded code for `Engine::Blocks`, none with `Engine::Interpreter`.
+    blocks: Option<blocks::BlockCache>,
+    /// Whether the UI was last told the buzzer is on.
+    buzzing: bool,
+    audio: Option<Buzzer>,
+    /// Generated by `audio` and not taken yet.
+    samples: Vec<f32>,
 }
 
-#[allow(dead_code)]
 impl Chip8State {
     pub fn new(ui: Box<dyn UI>, rom: &[u8]) -> Box<Chip8State> {
-        let mut ch8 = Box::new(Chip8State {
-            v_regs: [0; 16],
-            memory: [0; 0x1000],
-            display: [[false; 64]; 32],
+        Self::with_mode(ui, rom, Mode::Chip8)
+    }
+
+    pub fn with_mode(ui: Box<dyn UI>, rom: &[u8], mode: Mode) -> Box<Chip8State> {
+        Box::new(Chip8State {
+            machine: Machine::new(rom, mode),
             ui,
-            idx_reg: 0,
-            sound_timer: 0,
-            delay_timer: 0,
-            pc: 0x200,
-            stack: Vec::with_capacity(16),
-            digit_sprites: [0; 16],
             last_frame: if cfg!(feature = "time") {
                 std::time::SystemTime::UNIX_EPOCH.elapsed().unwrap()
             } else {
                 std::time::Duration::from_secs(0)
             },
-            cycles: 0,
-        });
-        fn store_digit(pos: usize, sprite: &[u8], memory: &mut [u8]) -> usize {
-            memory[pos..(sprite.len() + pos)].copy_from_slice(sprite);
-            pos + sprite.len()
-        }
-        let mut pos: usize = 0x050;
-        ch8.digit_sprites[0] = pos as u16;
-        pos = store_digit(pos, &[0xF0, 0x90, 0x90, 0x90, 0xF0], &mut ch8.memory);
-        ch8.digit_sprites[1] = pos as u16;
-        pos = store_digit(pos, &[0x20, 0x60, 0x20, 0x20, 0x70], &mut ch8.memory);
-        ch8.digit_sprites[2] = pos as u16;
-        pos = store_digit(pos, &[0xF0, 0x10, 0xF0, 0x80, 0xF0], &mut ch8.memory);
-        ch8.digit_sprites[3] = pos as u16;
-        pos = store_digit(pos, &[0xF0, 0x10, 0xF0, 0x10, 0xF0], &mut ch8.memory);
-        ch8.digit_sprites[4] = pos as u16;
-        pos = store_digit(pos, &[0x90, 0x90, 0xF0, 0x10, 0x10], &mut ch8.memory);
-        ch8.digit_sprites[5] = pos as u16;
-        pos = store_digit(pos, &[0xF0, 0x80, 0xF0, 0x10, 0xF0], &mut ch8.memory);
-        ch8.digit_sprites[6] = pos as u16;
-        pos = store_digit(pos, &[0xF0, 0x80, 0xF0, 0x90, 0xF0], &mut ch8.memory);
-        ch8.digit_sprites[7] = pos as u16;
-        pos = store_digit(pos, &[0xF0, 0x10, 0x20, 0x40, 0x40], &mut ch8.memory);
-        ch8.digit_sprites[8] = pos as u16;
-        pos = store_digit(pos, &[0xF0, 0x90, 0xF0, 0x90, 0xF0], &mut ch8.memory);
-        ch8.digit_sprites[9] = pos as u16;
-        pos = store_digit(pos, &[0xF0, 0x90, 0xF0, 0x10, 0xF0], &mut ch8.memory);
-        ch8.digit_sprites[10] = pos as u16;
-        pos = store_digit(pos, &[0xF0, 0x90, 0xF0, 0x90, 0x90], &mut ch8.memory);
-        ch8.digit_sprites[11] = pos as u16;
-        pos = store_digit(pos, &[0xE0, 0x90, 0xE0, 0x90, 0xE0], &mut ch8.memory);
-        ch8.digit_sprites[12] = pos as u16;
-        pos = store_digit(pos, &[0xF0, 0x80, 0x80, 0x80, 0xF0], &mut ch8.memory);
-        ch8.digit_sprites[13] = pos as u16;
-        pos = store_digit(pos, &[0xE0, 0x90, 0x90, 0x90, 0xE0], &mut ch8.memory);
-        ch8.digit_sprites[14] = pos as u16;
-        pos = store_digit(pos, &[0xF0, 0x80, 0xF0, 0x80, 0xF0], &mut ch8.memory);
-        ch8.digit_sprites[15] = pos as u16;
-        pos = store_digit(pos, &[0xF0, 0x80, 0xF0, 0x80, 0x80], &mut ch8.memory);
-        _ = pos;
-        for (i, b) in rom.iter().enumerate() {
-            ch8.memory[0x200 + i] = *b;
-        }
-        ch8
+            breakpoints: std::collections::BTreeSet::new(),
+            watchpoints: Vec::new(),
+            rewind: std::collections::VecDeque::new(),
+            rewind_capacity: 0,
+            rewind_every: 1,
+            blocks: None,
+            buzzing: false,
+            audio: None,
+            samples: Vec::new(),
+        })
     }
 
     pub fn cycle(&mut self) -> Result<std::time::Duration, &'static str> {
-        self.exec()?;
-        self.cycles = self.cycles.wrapping_add(1);
+        match &mut self.blocks {
+            Some(blocks) => blocks.exec(&mut self.machine, &mut *self.ui)?,
+            None => self.machine.exec(&mut *self.ui)?,
+        };
+        self.machine.cycles = self.machine.cycles.wrapping_add(1);
+        if self.rewind_capacity > 0 && self.machine.cycles.is_multiple_of(self.rewind_every) {
+            if self.rewind.len() == self.rewind_capacity {
+                self.rewind.pop_front();
+            }
+            self.rewind.push_back(self.machine.clone());
+        }
         let dt = if cfg!(feature = "time") {
             let now = std::time::SystemTime::UNIX_EPOCH.elapsed().unwrap();
             let dt = now - self.last_frame;
@@ -236,176 +470,787 @@ impl Chip8State {
         } else {
             std::time::Duration::from_secs(0)
         };
-        let timer_tick = self.ui.update(self.cycles, dt)?;
+        let timer_tick = self.ui.update(self.machine.cycles, dt)?;
         if timer_tick {
-            self.delay_timer = self.delay_timer.saturating_sub(1);
-            self.sound_timer = self.sound_timer.saturating_sub(1);
+            // The frame that just ended, with the timer it ran with.
+            if let Some(audio) = &mut self.audio {
+                audio.set_on(self.machine.sound_timer > 0);
+                audio.set_pattern(&self.machine.audio_pattern, self.machine.pitch);
+                audio.frame(&mut self.samples);
+            }
+            self.machine.tick();
+        }
+        let buzzing = self.machine.sound_timer > 0;
+        if buzzing != self.buzzing {
+            self.buzzing = buzzing;
+            self.ui.buzzer(buzzing);
         }
         Ok(dt)
     }
 
-    fn exec(&mut self) -> Result<u16, &'static str> {
-        let instr = Instr::decode(self.pc as usize, &self.memory)?;
-        // eprintln!("PC={:#08x}: INST={:?}", self.pc, &instr);
-        self.pc += 2;
-        match instr {
-            Instr::SYS { addr: _ } => return Err("unimplemented SYS instr."),
-            Instr::CLS => {
-                for i in 0..self.display.len() {
-                    let row = &mut self.display[i];
-                    for j in 0..row.len() {
-                        self.display[i][j] = false;
-                    }
-                }
-                self.ui.clear_screen()
-            }
-            Instr::RET => match self.stack.pop() {
-                Some(addr) => self.pc = addr,
-                None => return Err("ret from empty stack"),
-            },
-            Instr::JP { addr } => {
-                if self.pc - 2 == addr {
-                    return Err("busy wait");
-                } else {
-                    self.pc = addr
-                }
+    /// Runs up to `n` cycles and returns how many were run. A program parking
+    /// itself in a `JP` to its own address or calling `EXIT` is done, not
+    /// failed, so that ends the run early.
+    pub fn run(&mut self, n: u64) -> Result<u64, &'static str> {
+        for i in 0..n {
+            match self.cycle() {
+                Ok(_) => {}
+                Err("busy wait" | "exit") => return Ok(i),
+                Err(e) => return Err(e),
             }
-            Instr::JP_V0 { offset } => self.pc = self.v_regs[0] as u16 + offset,
-            Instr::CALL { addr } => {
-                self.stack.push(self.pc);
-                self.pc = addr;
-            }
-            Instr::MV { dst, src } => self.v_regs[dst as usize] = self.v_regs[src as usize],
-            Instr::LD_IMM { dst, imm } => self.v_regs[dst as usize] = imm,
-            Instr::LD_I { addr } => self.idx_reg = addr,
-            Instr::LD_DT { dst } => self.v_regs[dst as usize] = self.delay_timer,
-            Instr::LD_K { dst } => {
-                for key in 0..0xF {
-                    if self.ui.is_key_pressed(key) {
-                        self.v_regs[dst as usize] = key;
-                    }
-                }
-                // Retry later...
-                self.pc -= 2;
-            }
-            Instr::LD_SPRITE { digit } => {
-                if digit < 16 {
-                    self.idx_reg = self.digit_sprites[self.v_regs[digit as usize] as usize];
-                } else {
-                    return Err("'LD F, Vx' with a Vx out of bounds");
-                }
-            }
-            Instr::LD_BCD { num } => {
-                let num = self.v_regs[num as usize];
-                self.memory[self.idx_reg as usize] = num / 100;
-                self.memory[self.idx_reg as usize + 1] = num / 10;
-                self.memory[self.idx_reg as usize + 2] = num % 10;
-            }
-            Instr::LD_REGS_TO_I { upto } => {
-                let pos = self.idx_reg as usize;
-                for i in 0..=(upto as usize) {
-                    self.memory[pos + i] = self.v_regs[i];
-                }
-            }
-            Instr::LD_I_TO_REGS { upto } => {
-                let pos = self.idx_reg as usize;
-                for i in 0..=(upto as usize) {
-                    self.v_regs[i] = self.memory[pos + i];
-                }
-            }
-            Instr::SET_DT { x } => self.delay_timer = self.v_regs[x as usize],
-            Instr::SET_ST { x } => self.sound_timer = self.v_regs[x as usize],
-            Instr::SE_IMM { x, imm } => {
-                if self.v_regs[x as usize] == imm {
-                    self.pc += 2
-                }
-            }
-            Instr::SNE_IMM { x, imm } => {
-                if self.v_regs[x as usize] != imm {
-                    self.pc += 2
-                }
-            }
-            Instr::SE_REG { x, y } => {
-                if self.v_regs[x as usize] == self.v_regs[y as usize] {
-                    self.pc += 2
-                }
-            }
-            Instr::SNE_REG { x, y } => {
-                if self.v_regs[x as usize] != self.v_regs[y as usize] {
-                    self.pc += 2
-                }
-            }
-            Instr::OR { x, y } => self.v_regs[x as usize] |= self.v_regs[y as usize],
-            Instr::AND { x, y } => self.v_regs[x as usize] &= self.v_regs[y as usize],
-            Instr::XOR { x, y } => self.v_regs[x as usize] ^= self.v_regs[y as usize],
-            Instr::ADD { x, y } => {
-                let v1 = self.v_regs[x as usize] as u16;
-                let v2 = self.v_regs[y as usize] as u16;
-                let res = v1 + v2;
-                self.v_regs[x as usize] = (res & 0xFF) as u8;
-                self.v_regs[0xF] = (res > 0xFF) as u8;
-            }
-            Instr::ADD_IMM { dst, imm } => self.v_regs[dst as usize] += imm,
-            Instr::ADD_I { x } => self.idx_reg += self.v_regs[x as usize] as u16,
-            Instr::SUB { x, y } => {
-                let v1 = self.v_regs[x as usize] as u16;
-                let v2 = self.v_regs[y as usize] as u16;
-                self.v_regs[x as usize] = ((v1 - v2) & 0xFF) as u8;
-                self.v_regs[0xF] = (v1 > v2) as u8;
-            }
-            Instr::SHR { x, y: _ } => {
-                let v1 = self.v_regs[x as usize];
-                self.v_regs[0xF] = v1 & 0x1;
-                self.v_regs[x as usize] = v1 >> 1;
-            }
-            Instr::SUBN { x, y } => {
-                let v1 = self.v_regs[x as usize] as u16;
-                let v2 = self.v_regs[y as usize] as u16;
-                self.v_regs[x as usize] = ((v2 - v1) & 0xFF) as u8;
-                self.v_regs[0xF] = (v2 > v1) as u8;
-            }
-            Instr::SHL { x, y: _ } => {
-                let v1 = self.v_regs[x as usize];
-                self.v_regs[0xF] = ((v1 & 0x80) != 0) as u8;
-                self.v_regs[x as usize] = v1 << 1;
+        }
+        Ok(n)
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.machine.mode()
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.machine.quirks()
+    }
+
+    /// Overrides the quirks `with_mode` picked for the mode.
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.machine.set_quirks(quirks)
+    }
+
+    /// Width and height of the display in pixels.
+    pub fn resolution(&self) -> (usize, usize) {
+        self.machine.resolution()
+    }
+
+    /// See `Machine::snapshot_text`.
+    pub fn snapshot_text(&self) -> String {
+        self.machine.snapshot_text()
+    }
+
+    /// See `Machine::snapshot_pbm`.
+    pub fn snapshot_pbm(&self) -> String {
+        self.machine.snapshot_pbm()
+    }
+
+    /// The machine as a save state, see `Machine::save`.
+    pub fn save_state(&self) -> Vec<u8> {
+        self.machine.save()
+    }
+
+    /// Restores a save state and repaints the UI with it.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), &'static str> {
+        self.machine = Machine::load(state)?;
+        self.flush_blocks();
+        self.repaint();
+        Ok(())
+    }
+
+    /// Keeps the last `capacity` states, one every `every` cycles, to go
+    /// back to with `rewind`. A capacity of zero turns it off.
+    pub fn set_rewind(&mut self, capacity: usize, every: u64) {
+        self.rewind_capacity = capacity;
+        self.rewind_every = every.max(1);
+        while self.rewind.len() > capacity {
+            self.rewind.pop_front();
+        }
+    }
+
+    /// Goes back to the latest kept state and forgets it, so calling this
+    /// again goes further back. Returns false once there is none left.
+    pub fn rewind(&mut self) -> bool {
+        match self.rewind.pop_back() {
+            Some(machine) => {
+                self.machine = machine;
+                self.flush_blocks();
+                self.repaint();
+                true
             }
-            Instr::RND { dst, mask } => {
-                self.v_regs[dst as usize] = self.ui.rnd() & mask;
+            None => false,
+        }
+    }
+
+    /// Generates the sound at `rate` samples per second, one 60th of a
+    /// second every timer tick, to be picked up with `take_samples`. A rate
+    /// of zero turns it off.
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.audio = (rate > 0).then(|| Buzzer::new(rate));
+        self.samples.clear();
+    }
+
+    /// The samples generated since the last call.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn engine(&self) -> Engine {
+        match self.blocks {
+            Some(_) => Engine::Blocks,
+            None => Engine::Interpreter,
+        }
+    }
+
+    /// Switches engines, possibly in the middle of a run.
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.blocks = match engine {
+            Engine::Interpreter => None,
+            Engine::Blocks => Some(blocks::BlockCache::new(self.machine.memory.len())),
+        };
+    }
+
+    /// Forgets all decoded code, for when memory changed behind the
+    /// program's back.
+    fn flush_blocks(&mut self) {
+        if self.blocks.is_some() {
+            self.set_engine(Engine::Blocks);
+        }
+    }
+
+    fn repaint(&mut self) {
+        let (w, h) = self.machine.resolution();
+        self.ui.set_resolution(w, h);
+        self.ui.clear_screen();
+        self.machine.redraw(&mut *self.ui);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `rom` headless and compares the display against
+    /// `tests/<name>.txt`. With `CHIP8_BLESS` set, the file is rewritten
+    /// instead.
+    fn golden(name: &str, ui: HeadlessUI, quirks: Quirks, cycles: u64) {
+        let rom = std::fs::read(format!("roms/{}.ch8", name)).unwrap();
+        let mut c8 = Chip8State::new(Box::new(ui), &rom);
+        c8.set_quirks(quirks);
+        c8.run(cycles).unwrap();
+        let got = c8.snapshot_text();
+        let path = format!("tests/{}.txt", name);
+        if std::env::var_os("CHIP8_BLESS").is_some() {
+            std::fs::write(&path, &got).unwrap();
+        }
+        let want = std::fs::read_to_string(&path).unwrap();
+        assert!(got == want, "{} differs from {}:\n{}", name, path, got);
+    }
+
+    #[test]
+    fn chip8_logo() {
+        golden("1-chip8-logo", HeadlessUI::new(0), Quirks::COSMAC_VIP, 1000);
+    }
+
+    #[test]
+    fn ibm_logo() {
+        golden("2-ibm-logo", HeadlessUI::new(0), Quirks::COSMAC_VIP, 1000);
+    }
+
+    #[test]
+    fn opcodes() {
+        golden("opcodes", HeadlessUI::new(0), Quirks::COSMAC_VIP, 1000);
+        // The ROM checks itself: each of its three columns of six opcodes
+        // has to say "ok", a golden that records "no" is wrong.
+        const OK: [&str; 4] = ["###.#.#", "#.#.##.", "#.#.#.#", "###.#.#"];
+        let text = std::fs::read_to_string("tests/opcodes.txt").unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        for row in 0..6 {
+            for (col, x) in [10, 32, 52].into_iter().enumerate() {
+                let mark: Vec<&str> = (1..5).map(|y| &lines[row * 5 + y][x..x + 7]).collect();
+                assert_eq!(mark, OK, "the opcode in column {}, row {} failed", col, row);
             }
-            Instr::DRW { x, y, n } => {
-                let x = self.v_regs[x as usize] as usize;
-                let y = self.v_regs[y as usize] as usize;
-
-                let mut pixel_erased = false;
-                for row in 0..(n as usize) {
-                    let b = self.memory[self.idx_reg as usize + row];
-                    for col in 0..8 {
-                        let x = (x + col) % 64;
-                        let y = (y + row) % 32;
-                        if (b & (0x80u8 >> col)) == 0 {
-                            continue;
-                        }
-
-                        let prev_pixel = self.display[y][x];
-                        let new_pixel = !prev_pixel;
-                        pixel_erased |= !new_pixel;
-                        self.display[y][x] = new_pixel;
-                        self.ui.draw_pixel(x, y, new_pixel);
-                    }
-                }
-                self.v_regs[0xF] = pixel_erased as u8;
+        }
+    }
+
+    #[test]
+    fn tetris() {
+        // Move the first piece left, rotate and drop it.
+        let ui = HeadlessUI::new(42)
+            .press(0x5, 400, 440)
+            .press(0x4, 600, 640)
+            .press(0x1, 800, 840);
+        // Written for CHIP-48 on the HP48.
+        golden("tetris", ui, Quirks::CHIP48, 3000);
+    }
+
+    #[test]
+    fn headless_rnd_is_seeded() {
+        let bytes = |ui: &mut HeadlessUI| (0..64).map(|_| ui.rnd()).collect::<Vec<u8>>();
+        let (mut a, mut b, mut c) = (HeadlessUI::new(7), HeadlessUI::new(7), HeadlessUI::new(8));
+        let seq = bytes(&mut a);
+        // Same seed, same sequence, also past the first values.
+        assert_eq!(seq, bytes(&mut b));
+        assert_eq!(bytes(&mut a), bytes(&mut b));
+        assert_ne!(seq, bytes(&mut c));
+    }
+
+    #[test]
+    fn schip_hires_and_scroll() {
+        #[rustfmt::skip]
+        let rom = [
+            0x00, 0xFF, // HIGH
+            0x60, 0x00, // LD v0, 0
+            0x62, 0x08, // LD v2, 8
+            0xF2, 0x30, // LD HF, v2
+            0xD0, 0x0A, // DRW v0, v0, 10
+            0x00, 0xFB, // SCR
+            0x00, 0xFD, // EXIT
+        ];
+        let ui = Box::new(HeadlessUI::new(0));
+        let mut c8 = Chip8State::with_mode(ui, &rom, Mode::SChip);
+        assert_eq!(c8.run(100), Ok(6));
+        assert_eq!(c8.resolution(), (128, 64));
+        let text = c8.snapshot_text();
+        let rows: Vec<&str> = text.lines().collect();
+        assert_eq!(rows.len(), 64);
+        assert!(rows[0].starts_with("....########...."));
+        assert!(rows[2].starts_with("....##....##...."));
+        assert!(!rows[10].contains('#'));
+
+        let ui = Box::new(HeadlessUI::new(0));
+        let mut c8 = Chip8State::new(ui, &rom);
+        assert_eq!(c8.run(100), Err("instruction not available in this mode"));
+    }
+
+    #[test]
+    fn xochip_planes_and_long_load() {
+        #[rustfmt::skip]
+        let rom = [
+            0x60, 0x00, // LD v0, 0
+            0x30, 0x00, // SE v0, 0
+            0xF0, 0x00, 0x03, 0x00, // LD I, 0x300 (skipped)
+            0xF0, 0x00, 0x02, 0x14, // LD I, 0x214
+            0xF3, 0x01, // PLANE 3
+            0xD0, 0x01, // DRW v0, v0, 1
+            0x00, 0xFD, // EXIT
+            0x00, 0x00,
+            0xF0, 0x3C, // one row for each plane
+        ];
+        let ui = Box::new(HeadlessUI::new(0));
+        let mut c8 = Chip8State::with_mode(ui, &rom, Mode::XoChip);
+        assert_eq!(c8.run(100), Ok(5));
+        assert_eq!(c8.machine.idx_reg, 0x214);
+        assert!(c8.snapshot_text().starts_with("##3322.."));
+        assert!(c8.snapshot_pbm().contains("\n1 1 1 1 1 1 0 0 "));
+    }
+
+    #[test]
+    fn memory_wraps_around() {
+        #[rustfmt::skip]
+        let rom = [
+            0x60, 0x12, // LD v0, 0x12
+            0x61, 0x34, // LD v1, 0x34
+            0xF0, 0x00, 0xFF, 0xFF, // LD I, 0xFFFF
+            0xF1, 0x55, // LD [I], v1
+            0x60, 0x00, // LD v0, 0
+            0x61, 0x00, // LD v1, 0
+            0xF0, 0x00, 0xFF, 0xFF, // LD I, 0xFFFF
+            0xF1, 0x65, // LD v1, [I]
+            0xF0, 0x00, 0xFF, 0xFF, // LD I, 0xFFFF
+            0xF2, 0x33, // LD B, v2
+            0x52, 0x12, // SAVE v2 - v1
+            0x52, 0x13, // LOAD v2 - v1
+            0xF0, 0x02, // AUDIO
+            0xD0, 0x0F, // DRW v0, v0, 15
+            0x00, 0xFD, // EXIT
+        ];
+        let ui = Box::new(HeadlessUI::new(0));
+        let mut c8 = Chip8State::with_mode(ui, &rom, Mode::XoChip);
+        assert_eq!(c8.run(100), Ok(14));
+        assert_eq!(c8.machine.memory[0], 0x34);
+        assert_eq!(c8.machine.v_regs[..2], [0x12, 0x34]);
+
+        // Only the low nibble of Vx picks the font digit.
+        let rom = [0x60, 0xFF, 0xF0, 0x29, 0x12, 0x04]; // LD v0, 0xFF; LD F, v0
+        let mut c8 = Chip8State::new(Box::new(HeadlessUI::new(0)), &rom);
+        assert_eq!(c8.run(10), Ok(2));
+        assert_eq!(c8.machine.idx_reg, c8.machine.digit_sprites[0xF]);
+
+        // Running off the end of memory is an error, not a panic.
+        let mut rom = vec![0x1F, 0xFF]; // JP 0xFFF
+        rom.resize(0xE00, 0);
+        let mut c8 = Chip8State::new(Box::new(HeadlessUI::new(0)), &rom);
+        assert!(c8.run(10).is_err());
+    }
+
+    #[test]
+    fn wait_for_key() {
+        let rom = [
+            0xF3, 0x0A, // LD v3, K
+            0x12, 0x02, // JP 0x202
+        ];
+        let ui = Box::new(HeadlessUI::new(0));
+        let mut c8 = Chip8State::new(ui, &rom);
+        assert_eq!(c8.run(100), Ok(100));
+        assert_eq!(c8.reg(Reg::PC), 0x200);
+
+        let ui = Box::new(HeadlessUI::new(0).press(0xF, 50, 60));
+        let mut c8 = Chip8State::new(ui, &rom);
+        // Stops at the busy wait right after the key.
+        assert!(c8.run(100).unwrap() < 60);
+        assert_eq!(c8.reg(Reg::PC), 0x204);
+        assert_eq!(c8.machine.v_regs[3], 0xF);
+    }
+
+    fn run_with(rom: &[u8], quirks: Quirks, cycles: u64) -> (Box<Chip8State>, u64) {
+        let mut c8 = Chip8State::new(Box::new(HeadlessUI::new(0)), rom);
+        c8.set_quirks(quirks);
+        let n = c8.run(cycles).unwrap();
+        (c8, n)
+    }
+
+    #[test]
+    fn quirks() {
+        #[rustfmt::skip]
+        let rom = [
+            0x60, 0x05, // LD v0, 5
+            0x61, 0x03, // LD v1, 3
+            0x62, 0x08, // LD v2, 8
+            0x6F, 0x05, // LD vF, 5
+            0x80, 0x11, // OR v0, v1
+            0x83, 0xF0, // LD v3, vF
+            0x81, 0x26, // SHR v1, v2
+            0xA3, 0x00, // LD I, 0x300
+            0xF0, 0x55, // LD [I], v0
+            0xB3, 0x00, // JP v0, 0x300
+        ];
+        let (vip, _) = run_with(&rom, Quirks::COSMAC_VIP, 10);
+        assert_eq!(vip.machine.v_regs[3], 0);
+        assert_eq!(vip.machine.v_regs[1], 4);
+        assert_eq!(vip.machine.idx_reg, 0x301);
+        assert_eq!(vip.machine.pc, 0x307);
+
+        let (chip48, _) = run_with(&rom, Quirks::CHIP48, 10);
+        assert_eq!(chip48.machine.v_regs[3], 5);
+        assert_eq!(chip48.machine.v_regs[1], 1);
+        assert_eq!(chip48.machine.idx_reg, 0x300);
+        assert_eq!(chip48.machine.pc, 0x305);
+    }
+
+    #[test]
+    fn clipping_and_display_wait() {
+        #[rustfmt::skip]
+        let rom = [
+            0x60, 0x3E, // LD v0, 62
+            0xA2, 0x0A, // LD I, 0x20A
+            0xD0, 0x11, // DRW v0, v1, 1
+            0xD1, 0x11, // DRW v1, v1, 1
+            0x12, 0x08, // JP 0x208
+            0xFF,
+        ];
+        // The second draw waits for a tick.
+        let (vip, n) = run_with(&rom, Quirks::COSMAC_VIP, 100);
+        assert_eq!(n, 5);
+        let row = vip.snapshot_text().lines().next().unwrap().to_string();
+        assert_eq!(&row[..10], "########..");
+        assert_eq!(&row[60..], "..##");
+
+        let (xo, n) = run_with(&rom, Quirks::XOCHIP, 100);
+        assert_eq!(n, 4);
+        let row = xo.snapshot_text().lines().next().unwrap().to_string();
+        assert_eq!(&row[..10], "......##..");
+        assert_eq!(&row[60..], "..##");
+    }
+
+    #[test]
+    fn debugger() {
+        #[rustfmt::skip]
+        let rom = [
+            0x60, 0x05, // LD v0, 5
+            0xA3, 0x00, // LD I, 0x300
+            0xF0, 0x55, // LD [I], v0
+            0x70, 0x01, // ADD v0, 1
+            0x12, 0x06, // JP 0x206
+        ];
+        let mut c8 = Chip8State::new(Box::new(HeadlessUI::new(0)), &rom);
+        assert!(c8.add_breakpoint(0x204));
+        assert_eq!(c8.run_until(100), Ok(Stop::Breakpoint(0x204)));
+        assert_eq!(c8.reg(Reg::I), 0x300);
+
+        c8.add_watchpoint(Watch::Memory(0x300));
+        let stop = c8.run_until(100);
+        let watch = Watch::Memory(0x300);
+        assert_eq!(
+            stop,
+            Ok(Stop::Watchpoint {
+                watch,
+                old: 0,
+                new: 5
+            })
+        );
+        c8.remove_watchpoint(watch);
+
+        c8.add_watchpoint(Watch::Reg(Reg::V(0)));
+        let stop = c8.run_until(100);
+        let watch = Watch::Reg(Reg::V(0));
+        assert_eq!(
+            stop,
+            Ok(Stop::Watchpoint {
+                watch,
+                old: 5,
+                new: 6
+            })
+        );
+        assert_eq!(c8.run_until(1), Ok(Stop::Cycles));
+        assert_eq!(c8.reg(Reg::PC), 0x206);
+
+        c8.set_reg(Reg::V(0xF), 0x1FF);
+        assert_eq!(c8.reg(Reg::V(0xF)), 0xFF);
+        c8.write_memory(0x0FFE, &[1, 2, 3]);
+        assert_eq!(c8.read_memory(0x0FFE, 4), &[1, 2]);
+
+        let dis = c8.disassemble(0x200, 6);
+        let dis: Vec<String> = dis
+            .iter()
+            .map(|(a, i)| format!("{:#05x} {}", a, i))
+            .collect();
+        assert_eq!(
+            dis,
+            [
+                "0x200 LD v0, 0x05",
+                "0x202 LD I, 0x300",
+                "0x204 LD [I], v0",
+                "0x206 ADD v0, 0x01",
+                "0x208 JP 0x206",
+                "0x20a SYS 0x000",
+            ]
+        );
+    }
+
+    #[test]
+    fn breakpoint_one_cycle_at_a_time() {
+        // Like the `--debug` run loop does.
+        let rom = [
+            0x70, 0x01, // ADD v0, 1
+            0x12, 0x00, // JP 0x200
+        ];
+        let mut c8 = Chip8State::new(Box::new(HeadlessUI::new(0)), &rom);
+        c8.add_breakpoint(0x202);
+        for n in 1..=3 {
+            assert_eq!(c8.run_until(1), Ok(Stop::Breakpoint(0x202)));
+            assert_eq!(c8.reg(Reg::V(0)), n);
+            assert_eq!(c8.run_until(1), Ok(Stop::Cycles));
+        }
+    }
+
+    #[test]
+    fn encode_decode() {
+        for word in 0..=0xFFFFu16 {
+            let [hi, lo] = word.to_be_bytes();
+            if let Ok(instr) = Instr::decode(0, &[hi, lo, 0x12, 0x34]) {
+                let bytes = instr.encode();
+                assert_eq!(bytes[..2], [hi, lo], "{}", instr);
+                assert_eq!(bytes.len(), instr.size() as usize);
+                assert_eq!(assemble(&instr.to_string()), Ok(bytes), "{}", instr);
             }
-            Instr::SKP { x } => {
-                if self.ui.is_key_pressed(self.v_regs[x as usize]) {
-                    self.pc += 2
-                }
+        }
+    }
+
+    #[test]
+    fn decode_fx65() {
+        // The baseline decoded FX65 as FX55.
+        assert_eq!(
+            Instr::decode(0, &[0xF3, 0x65]),
+            Ok(Instr::LD_I_TO_REGS { upto: 3 })
+        );
+    }
+
+    #[test]
+    fn assembler() {
+        let src = "
+            SPRITE_H = 2
+            start:  LD I, sprite    ; comment
+                    DRW v0, v1, SPRITE_H
+                    CALL sub
+            loop:   JP loop
+            sub:    ADD v0, -1
+                    SHR vA
+                    RET
+            sprite: db 0b11000000, 0x3C
+                    dw start + 2
+        ";
+        #[rustfmt::skip]
+        let rom = [
+            0xA2, 0x0E,
+            0xD0, 0x12,
+            0x22, 0x08,
+            0x12, 0x06,
+            0x70, 0xFF,
+            0x8A, 0xA6,
+            0x00, 0xEE,
+            0xC0, 0x3C,
+            0x02, 0x02,
+        ];
+        assert_eq!(assemble(src), Ok(rom.to_vec()));
+        assert_eq!(
+            assemble("JP nowhere"),
+            Err("line 1: unknown value nowhere".to_string())
+        );
+        assert_eq!(
+            assemble("\nLD v0, 256"),
+            Err("line 2: 256 is out of range".to_string())
+        );
+        assert!(assemble("a: CLS\na: CLS").is_err());
+    }
+
+    #[test]
+    fn disassembler_round_trip() {
+        for entry in std::fs::read_dir("roms").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "ch8") {
+                let rom = std::fs::read(&path).unwrap();
+                let src = disassemble(&rom);
+                assert_eq!(assemble(&src).as_ref(), Ok(&rom), "{:?}", path);
             }
-            Instr::SKNP { x } => {
-                if !self.ui.is_key_pressed(self.v_regs[x as usize]) {
-                    self.pc += 2
+        }
+    }
+
+    #[test]
+    fn save_state_and_rewind() {
+        let rom = std::fs::read("roms/tetris.ch8").unwrap();
+        let ui = HeadlessUI::new(1).press(0x6, 500, 700);
+        let mut c8 = Chip8State::new(Box::new(ui), &rom);
+        c8.set_quirks(Quirks::CHIP48);
+        c8.set_rewind(4, 100);
+        c8.run(1000).unwrap();
+        let state = c8.save_state();
+        let screen = c8.snapshot_text();
+        c8.run(500).unwrap();
+        assert_ne!(c8.snapshot_text(), screen);
+
+        c8.load_state(&state).unwrap();
+        assert_eq!(c8.snapshot_text(), screen);
+        assert_eq!(c8.save_state(), state);
+        assert_eq!(Machine::load(&state).unwrap().quirks(), Quirks::CHIP48);
+
+        // Four states kept, from cycles 1200 to 1500.
+        for cycles in [1500, 1400, 1300, 1200] {
+            assert!(c8.rewind());
+            assert_eq!(c8.machine.cycles, cycles);
+        }
+        assert!(!c8.rewind());
+
+        assert_eq!(
+            Machine::load(&state[..20]).err(),
+            Some("save state is truncated")
+        );
+        let mut bad = state.clone();
+        bad[4] = 99;
+        assert_eq!(
+            Machine::load(&bad).err(),
+            Some("unsupported save state version")
+        );
+
+        // PC and I follow the magic, version, mode, quirks and V0-VF.
+        let mut bad = state.clone();
+        bad[23..25].copy_from_slice(&0xFFFFu16.to_be_bytes());
+        assert_eq!(
+            Machine::load(&bad).err(),
+            Some("address out of memory in save state")
+        );
+        let mut bad = state.clone();
+        bad[29..31].copy_from_slice(&17u16.to_be_bytes());
+        assert_eq!(
+            Machine::load(&bad).err(),
+            Some("stack too deep in save state")
+        );
+    }
+
+    #[test]
+    fn engines_in_lockstep() {
+        for path in std::fs::read_dir("roms").unwrap() {
+            let path = path.unwrap().path();
+            let rom = std::fs::read(&path).unwrap();
+            let ui = || {
+                Box::new(
+                    HeadlessUI::new(3)
+                        .press(0x6, 500, 700)
+                        .press(0x5, 900, 1000),
+                )
+            };
+            let mut interp = Chip8State::new(ui(), &rom);
+            let mut blocks = Chip8State::new(ui(), &rom);
+            blocks.set_engine(Engine::Blocks);
+            for cycle in 0..3000 {
+                let res = interp.cycle().map(|_| ());
+                assert_eq!(blocks.cycle().map(|_| ()), res, "{:?} {}", path, cycle);
+                assert!(
+                    interp.save_state() == blocks.save_state(),
+                    "{:?} {}",
+                    path,
+                    cycle
+                );
+                if res.is_err() {
+                    break;
                 }
             }
         }
-        Ok(self.pc)
+    }
+
+    #[test]
+    fn blocks_see_code_writes() {
+        let rom = assemble(
+            "
+                LD I, patch
+                LD v0, 0x61
+                LD v1, 0x2A
+                LD [I], v1
+            patch:
+                LD v1, 0x00     ; becomes LD v1, 0x2A
+            end:
+                JP end
+            ",
+        )
+        .unwrap();
+        let mut c8 = Chip8State::new(Box::new(HeadlessUI::new(0)), &rom);
+        c8.set_engine(Engine::Blocks);
+        assert_eq!(c8.run(100), Ok(5));
+        assert_eq!(c8.machine.v_regs[0x1], 0x2A);
+
+        // Same for the debugger writing code.
+        c8.set_reg(Reg::PC, 0x208);
+        c8.write_memory(0x208, &[0x61, 0x33]);
+        assert_eq!(c8.run(100), Ok(1));
+        assert_eq!(c8.machine.v_regs[0x1], 0x33);
+    }
+
+    #[test]
+    fn audio() {
+        let rom = assemble(
+            "
+                LD v0, 6
+                LD ST, v0
+            loop:
+                ADD v1, 1
+                JP loop
+            ",
+        )
+        .unwrap();
+        let mut c8 = Chip8State::new(Box::new(HeadlessUI::new(0)), &rom);
+        c8.set_sample_rate(6000);
+        c8.run(HeadlessUI::TICK * 20).unwrap();
+        let samples = c8.take_samples();
+        assert_eq!(samples.len(), 20 * 100);
+        // Six ticks of a 500 Hz square wave, then silence.
+        assert!(samples[..600].iter().all(|s| s.abs() == 0.25));
+        let high = samples[..600].iter().filter(|&&s| s > 0.).count();
+        assert!((295..=305).contains(&high), "{}", high);
+        let edges = samples[..600].windows(2).filter(|w| w[0] != w[1]).count();
+        assert!((99..=100).contains(&edges), "{}", edges);
+        assert!(samples[600..].iter().all(|&s| s == 0.));
+        assert!(c8.take_samples().is_empty());
+
+        let mut wav = WavWriter::new(std::io::Cursor::new(Vec::new()), 6000).unwrap();
+        wav.write(&samples).unwrap();
+        let wav = wav.finish().unwrap().into_inner();
+        assert_eq!(wav.len(), 44 + 4000);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(wav[4..8], (36u32 + 4000).to_le_bytes());
+        assert_eq!(wav[24..28], 6000u32.to_le_bytes());
+        assert_eq!(wav[40..44], 4000u32.to_le_bytes());
+        assert_eq!(wav[44..46], (i16::MAX / 4).to_le_bytes());
+
+        // XO-CHIP patterns, here all ones at a lower pitch.
+        let rom = assemble(
+            "
+                LD I, pattern
+                AUDIO
+                LD v0, 0
+                PITCH v0
+                LD v0, 2
+                LD ST, v0
+            loop:
+                ADD v1, 1
+                JP loop
+            pattern:
+                db 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF
+                db 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF
+            ",
+        )
+        .unwrap();
+        let mut c8 = Chip8State::with_mode(Box::new(HeadlessUI::new(0)), &rom, Mode::XoChip);
+        c8.set_sample_rate(6000);
+        c8.run(HeadlessUI::TICK * 4).unwrap();
+        let samples = c8.take_samples();
+        assert!(samples.contains(&0.25));
+        assert!(samples.iter().all(|&s| s == 0. || s == 0.25));
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let rom = std::fs::read("roms/tetris.ch8").unwrap();
+        let path = std::env::temp_dir().join(format!("chip8-{}.log", std::process::id()));
+        let ui = HeadlessUI::new(1)
+            .press(0x6, 500, 700)
+            .press(0x4, 1500, 1600)
+            .press(0x5, 2000, 2300);
+        let log = Box::new(std::fs::File::create(&path).unwrap());
+        let ui = TasUI::record(Box::new(ui), 42, Mode::Chip8, Quirks::CHIP48, &rom, log).unwrap();
+        let mut c8 = Chip8State::new(Box::new(ui), &rom);
+        c8.set_quirks(Quirks::CHIP48);
+        assert_eq!(c8.run(3000), Ok(3000));
+        let recorded = (c8.snapshot_text(), c8.save_state());
+        drop(c8);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(log.starts_with("chip8 input log v2 seed=42 mode=0 quirks=0d rom="));
+        assert_eq!(log.lines().count(), 1 + 3000 / HeadlessUI::TICK as usize);
+        assert!(log.lines().any(|l| l == "0040"));
+
+        // Another seed and no keys, all comes from the log.
+        let replay = |ui, mode, quirks, rom: &[u8]| TasUI::replay(ui, &log, mode, quirks, rom);
+        let ui = replay(
+            Box::new(HeadlessUI::new(7)),
+            Mode::Chip8,
+            Quirks::CHIP48,
+            &rom,
+        )
+        .unwrap();
+        let mut c8 = Chip8State::new(Box::new(ui), &rom);
+        c8.set_quirks(Quirks::CHIP48);
+        assert_eq!(c8.run(3000), Ok(3000));
+        assert_eq!((c8.snapshot_text(), c8.save_state()), recorded);
+        assert_eq!(c8.run(10), Err("end of the input log"));
+
+        // Replaying with other flags or another ROM would diverge.
+        let ui = || Box::new(HeadlessUI::new(0));
+        assert_eq!(
+            replay(ui(), Mode::SChip, Quirks::CHIP48, &rom).err(),
+            Some("input log was recorded in another mode")
+        );
+        assert_eq!(
+            replay(ui(), Mode::Chip8, Quirks::COSMAC_VIP, &rom).err(),
+            Some("input log was recorded with other quirks")
+        );
+        assert_eq!(
+            replay(ui(), Mode::Chip8, Quirks::CHIP48, &rom[1..]).err(),
+            Some("input log was recorded with another ROM")
+        );
+
+        let replay = |log| TasUI::replay(ui(), log, Mode::Chip8, Quirks::CHIP48, &rom);
+        assert_eq!(replay("0000\n").err(), Some("not an input log"));
+        assert_eq!(
+            replay("chip8 input log v1 seed=1\n0000\n").err(),
+            Some("not an input log")
+        );
+        let header = log.lines().next().unwrap();
+        assert_eq!(
+            replay(&format!("{}\nxyz\n", header)).err(),
+            Some("broken frame in input log")
+        );
+    }
+
+    #[test]
+    fn snapshot_pbm() {
+        let mut c8 = Chip8State::new(Box::new(HeadlessUI::new(0)), &[0xD0, 0x01]);
+        c8.machine.idx_reg = 0x50;
+        c8.run(1).unwrap();
+        let pbm = c8.snapshot_pbm();
+        let mut lines = pbm.lines();
+        assert_eq!(lines.next(), Some("P1"));
+        assert_eq!(lines.next(), Some("64 32"));
+        assert!(lines.next().unwrap().starts_with("1 1 1 1 0 0"));
+        assert_eq!(lines.count(), 31);
     }
 }
@@ -0,0 +1,667 @@
+use crate::audio::SQUARE_WAVE;
+use crate::{Instr, Mode, Quirks, VReg, UI};
+
+/// Where the 8x10 SUPER-CHIP digits go, right after the small ones.
+const BIG_FONT: usize = 0x0A0;
+
+#[rustfmt::skip]
+const BIG_DIGITS: [u8; 160] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+/// Return addresses the stack holds, as on the COSMAC VIP and the HP48.
+const STACK_DEPTH: usize = 16;
+
+/// Start of a saved `Machine`, followed by the format version. Bump the
+/// version whenever `save` changes, `load` refuses any other.
+const STATE_MAGIC: &[u8; 4] = b"C8ST";
+const STATE_VERSION: u8 = 1;
+
+/// The state of the emulated machine, everything a program can see:
+/// registers, memory, screen and timers. It runs against any `UI` passed to
+/// `exec`, so it can be saved and restored on its own, see `save` and
+/// `load`.
+#[derive(Clone)]
+#[allow(dead_code)]
+pub struct Machine {
+    pub(crate) mode: Mode,
+    pub(crate) quirks: Quirks,
+    pub(crate) v_regs: [u8; 16],
+    pub(crate) memory: Vec<u8>,
+    /// The planes each pixel is set in. Only the top left 64x32 are in use
+    /// outside of hires mode.
+    pub(crate) display: [[u8; 128]; 64],
+    pub(crate) hires: bool,
+    pub(crate) planes: u8,
+    pub(crate) rpl: [u8; 16],
+    pub(crate) audio_pattern: [u8; 16],
+    pub(crate) pitch: u8,
+    pub(crate) pc: u16,
+    pub(crate) idx_reg: u16,
+    pub(crate) sound_timer: u8,
+    pub(crate) delay_timer: u8,
+    pub(crate) stack: Vec<u16>,
+    pub(crate) digit_sprites: [u16; 16],
+    pub(crate) cycles: u64,
+    /// Set on a timer tick, cleared by `DRW` with `Quirks::display_wait`.
+    pub(crate) vblank: bool,
+}
+
+impl Machine {
+    pub fn new(rom: &[u8], mode: Mode) -> Machine {
+        let mut m = Machine {
+            mode,
+            quirks: Quirks::for_mode(mode),
+            v_regs: [0; 16],
+            memory: vec![
+                0;
+                if mode == Mode::XoChip {
+                    0x10000
+                } else {
+                    0x1000
+                }
+            ],
+            display: [[0; 128]; 64],
+            hires: false,
+            planes: 1,
+            rpl: [0; 16],
+            audio_pattern: SQUARE_WAVE,
+            pitch: 64,
+            idx_reg: 0,
+            sound_timer: 0,
+            delay_timer: 0,
+            pc: 0x200,
+            stack: Vec::with_capacity(STACK_DEPTH),
+            digit_sprites: [0; 16],
+            cycles: 0,
+            vblank: true,
+        };
+        fn store_digit(pos: usize, sprite: &[u8], memory: &mut [u8]) -> usize {
+            memory[pos..(sprite.len() + pos)].copy_from_slice(sprite);
+            pos + sprite.len()
+        }
+        let mut pos: usize = 0x050;
+        m.digit_sprites[0] = pos as u16;
+        pos = store_digit(pos, &[0xF0, 0x90, 0x90, 0x90, 0xF0], &mut m.memory);
+        m.digit_sprites[1] = pos as u16;
+        pos = store_digit(pos, &[0x20, 0x60, 0x20, 0x20, 0x70], &mut m.memory);
+        m.digit_sprites[2] = pos as u16;
+        pos = store_digit(pos, &[0xF0, 0x10, 0xF0, 0x80, 0xF0], &mut m.memory);
+        m.digit_sprites[3] = pos as u16;
+        pos = store_digit(pos, &[0xF0, 0x10, 0xF0, 0x10, 0xF0], &mut m.memory);
+        m.digit_sprites[4] = pos as u16;
+        pos = store_digit(pos, &[0x90, 0x90, 0xF0, 0x10, 0x10], &mut m.memory);
+        m.digit_sprites[5] = pos as u16;
+        pos = store_digit(pos, &[0xF0, 0x80, 0xF0, 0x10, 0xF0], &mut m.memory);
+        m.digit_sprites[6] = pos as u16;
+        pos = store_digit(pos, &[0xF0, 0x80, 0xF0, 0x90, 0xF0], &mut m.memory);
+        m.digit_sprites[7] = pos as u16;
+        pos = store_digit(pos, &[0xF0, 0x10, 0x20, 0x40, 0x40], &mut m.memory);
+        m.digit_sprites[8] = pos as u16;
+        pos = store_digit(pos, &[0xF0, 0x90, 0xF0, 0x90, 0xF0], &mut m.memory);
+        m.digit_sprites[9] = pos as u16;
+        pos = store_digit(pos, &[0xF0, 0x90, 0xF0, 0x10, 0xF0], &mut m.memory);
+        m.digit_sprites[10] = pos as u16;
+        pos = store_digit(pos, &[0xF0, 0x90, 0xF0, 0x90, 0x90], &mut m.memory);
+        m.digit_sprites[11] = pos as u16;
+        pos = store_digit(pos, &[0xE0, 0x90, 0xE0, 0x90, 0xE0], &mut m.memory);
+        m.digit_sprites[12] = pos as u16;
+        pos = store_digit(pos, &[0xF0, 0x80, 0x80, 0x80, 0xF0], &mut m.memory);
+        m.digit_sprites[13] = pos as u16;
+        pos = store_digit(pos, &[0xE0, 0x90, 0x90, 0x90, 0xE0], &mut m.memory);
+        m.digit_sprites[14] = pos as u16;
+        pos = store_digit(pos, &[0xF0, 0x80, 0xF0, 0x80, 0xF0], &mut m.memory);
+        m.digit_sprites[15] = pos as u16;
+        pos = store_digit(pos, &[0xF0, 0x80, 0xF0, 0x80, 0x80], &mut m.memory);
+        assert_eq!(pos, BIG_FONT);
+        store_digit(pos, &BIG_DIGITS, &mut m.memory);
+        for (i, b) in rom.iter().enumerate() {
+            m.memory[0x200 + i] = *b;
+        }
+        m
+    }
+
+    /// Serializes the machine into the save state format `load` reads.
+    pub fn save(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.memory.len() + 128 * 64 + 96);
+        out.extend_from_slice(STATE_MAGIC);
+        out.push(STATE_VERSION);
+        out.push(self.mode as u8);
+        out.push(self.quirks.to_bits());
+        out.extend_from_slice(&self.v_regs);
+        out.extend_from_slice(&self.pc.to_be_bytes());
+        out.extend_from_slice(&self.idx_reg.to_be_bytes());
+        out.push(self.delay_timer);
+        out.push(self.sound_timer);
+        out.extend_from_slice(&(self.stack.len() as u16).to_be_bytes());
+        for addr in &self.stack {
+            out.extend_from_slice(&addr.to_be_bytes());
+        }
+        out.push(self.hires as u8);
+        out.push(self.planes);
+        out.extend_from_slice(&self.rpl);
+        out.extend_from_slice(&self.audio_pattern);
+        out.push(self.pitch);
+        out.push(self.vblank as u8);
+        out.extend_from_slice(&self.cycles.to_be_bytes());
+        out.extend_from_slice(&(self.memory.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.memory);
+        for row in &self.display {
+            out.extend_from_slice(row);
+        }
+        out
+    }
+
+    /// Reads a machine back from what `save` wrote.
+    pub fn load(state: &[u8]) -> Result<Machine, &'static str> {
+        let mut r = Reader(state);
+        if r.bytes(4)? != STATE_MAGIC {
+            return Err("not a save state");
+        }
+        if r.u8()? != STATE_VERSION {
+            return Err("unsupported save state version");
+        }
+        let mode = match r.u8()? {
+            0 => Mode::Chip8,
+            1 => Mode::SChip,
+            2 => Mode::XoChip,
+            _ => return Err("invalid mode in save state"),
+        };
+        let mut m = Machine::new(&[], mode);
+        m.quirks = Quirks::from_bits(r.u8()?);
+        m.v_regs.copy_from_slice(r.bytes(16)?);
+        // Anything the next instructions would trip over is rejected here,
+        // so a corrupted state fails to load instead of panicking later.
+        let len = m.memory.len();
+        m.pc = r.addr(len)?;
+        m.idx_reg = r.addr(len)?;
+        m.delay_timer = r.u8()?;
+        m.sound_timer = r.u8()?;
+        let depth = r.u16()? as usize;
+        if depth > STACK_DEPTH {
+            return Err("stack too deep in save state");
+        }
+        for _ in 0..depth {
+            m.stack.push(r.addr(len)?);
+        }
+        m.hires = r.u8()? != 0;
+        m.planes = r.u8()?;
+        if m.planes > 0b11 {
+            return Err("invalid plane mask in save state");
+        }
+        m.rpl.copy_from_slice(r.bytes(16)?);
+        m.audio_pattern.copy_from_slice(r.bytes(16)?);
+        m.pitch = r.u8()?;
+        m.vblank = r.u8()? != 0;
+        m.cycles = u64::from_be_bytes(r.bytes(8)?.try_into().unwrap());
+        let len = u32::from_be_bytes(r.bytes(4)?.try_into().unwrap()) as usize;
+        if len != m.memory.len() {
+            return Err("memory size in save state does not match the mode");
+        }
+        m.memory.copy_from_slice(r.bytes(len)?);
+        for row in m.display.iter_mut() {
+            row.copy_from_slice(r.bytes(128)?);
+            if row.iter().any(|&planes| planes > 0b11) {
+                return Err("invalid pixel in save state");
+            }
+        }
+        if !r.0.is_empty() {
+            return Err("trailing bytes after save state");
+        }
+        Ok(m)
+    }
+
+    /// Counts down the timers, 60 times a second.
+    pub(crate) fn tick(&mut self) {
+        self.vblank = true;
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Width and height of the display in pixels.
+    pub fn resolution(&self) -> (usize, usize) {
+        if self.hires {
+            (128, 64)
+        } else {
+            (64, 32)
+        }
+    }
+
+    /// The rows of the display in use, each pixel a bitmask of the planes
+    /// it is set in.
+    fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        let (w, h) = self.resolution();
+        self.display[..h].iter().map(move |row| &row[..w])
+    }
+
+    /// The display as text, one line per row, `#` for a set pixel. Pixels
+    /// set only in the second plane or in both show as `2` and `3`.
+    pub fn snapshot_text(&self) -> String {
+        let mut s = String::new();
+        for row in self.rows() {
+            s.extend(row.iter().map(|&p| match p {
+                0 => '.',
+                1 => '#',
+                p => char::from(b'0' + p),
+            }));
+            s.push('\n');
+        }
+        s
+    }
+
+    /// The display as a plain (P1) PBM image, any plane counting as set.
+    pub fn snapshot_pbm(&self) -> String {
+        let (w, h) = self.resolution();
+        let mut s = format!("P1\n{} {}\n", w, h);
+        for row in self.rows() {
+            let row: Vec<&str> = row
+                .iter()
+                .map(|&p| if p != 0 { "1" } else { "0" })
+                .collect();
+            s.push_str(&row.join(" "));
+            s.push('\n');
+        }
+        s
+    }
+
+    fn set_pixel(&mut self, ui: &mut dyn UI, x: usize, y: usize, color: u8) {
+        self.display[y][x] = color;
+        ui.draw_color(x, y, color);
+    }
+
+    /// Sends the whole display to the UI again, after it moved.
+    pub(crate) fn redraw(&mut self, ui: &mut dyn UI) {
+        let (w, h) = self.resolution();
+        for y in 0..h {
+            for x in 0..w {
+                ui.draw_color(x, y, self.display[y][x]);
+            }
+        }
+    }
+
+    /// Moves the selected planes by `dx`, `dy` pixels, shifting in blanks.
+    fn scroll(&mut self, ui: &mut dyn UI, dx: isize, dy: isize) {
+        let (w, h) = self.resolution();
+        let old = self.display;
+        for y in 0..h {
+            for x in 0..w {
+                let (sx, sy) = (x as isize - dx, y as isize - dy);
+                let moved = if (0..w as isize).contains(&sx) && (0..h as isize).contains(&sy) {
+                    old[sy as usize][sx as usize]
+                } else {
+                    0
+                };
+                self.display[y][x] = (old[y][x] & !self.planes) | (moved & self.planes);
+            }
+        }
+        self.redraw(ui);
+    }
+
+    fn set_hires(&mut self, ui: &mut dyn UI, hires: bool) {
+        self.hires = hires;
+        self.display = [[0; 128]; 64];
+        let (w, h) = self.resolution();
+        ui.set_resolution(w, h);
+        ui.clear_screen();
+    }
+
+    /// Skips the next instruction, which on XO-CHIP may be the four byte
+    /// `LD I, long`.
+    fn skip(&mut self) {
+        let pc = self.pc as usize;
+        let long = self.mode == Mode::XoChip && self.memory.get(pc..pc + 2) == Some(&[0xF0, 0x00]);
+        self.pc += if long { 4 } else { 2 };
+    }
+
+    /// The byte at `addr` as seen through `I`: accesses past the end of
+    /// memory wrap around to the start, as `I` can point anywhere.
+    fn peek(&self, addr: usize) -> u8 {
+        self.memory[addr % self.memory.len()]
+    }
+
+    fn poke(&mut self, addr: usize, val: u8) {
+        let len = self.memory.len();
+        self.memory[addr % len] = val;
+    }
+
+    /// The registers `Vx` to `Vy` for XO-CHIP's range loads and stores,
+    /// counting down if `x > y`.
+    fn reg_range(x: VReg, y: VReg) -> Vec<usize> {
+        let (x, y) = (x as usize, y as usize);
+        if x <= y {
+            (x..=y).collect()
+        } else {
+            (y..=x).rev().collect()
+        }
+    }
+
+    pub(crate) fn exec(&mut self, ui: &mut dyn UI) -> Result<u16, &'static str> {
+        let instr = Instr::decode(self.pc as usize, &self.memory)?;
+        self.execute(instr, ui)
+    }
+
+    /// Runs `instr` as if it was decoded at PC.
+    pub(crate) fn execute(&mut self, instr: Instr, ui: &mut dyn UI) -> Result<u16, &'static str> {
+        if instr.mode() > self.mode {
+            return Err("instruction not available in this mode");
+        }
+        self.pc += 2;
+        match instr {
+            Instr::SYS { addr: _ } => return Err("unimplemented SYS instr."),
+            Instr::CLS => {
+                for row in self.display.iter_mut() {
+                    for p in row.iter_mut() {
+                        *p &= !self.planes;
+                    }
+                }
+                if self.rows().all(|row| row.iter().all(|&p| p == 0)) {
+                    ui.clear_screen()
+                } else {
+                    self.redraw(ui)
+                }
+            }
+            Instr::RET => match self.stack.pop() {
+                Some(addr) => self.pc = addr,
+                None => return Err("ret from empty stack"),
+            },
+            Instr::JP { addr } => {
+                if self.pc - 2 == addr {
+                    return Err("busy wait");
+                } else {
+                    self.pc = addr
+                }
+            }
+            Instr::JP_V0 { offset } => {
+                let x = if self.quirks.jump { offset >> 8 } else { 0 };
+                self.pc = self.v_regs[x as usize] as u16 + offset
+            }
+            Instr::CALL { addr } => {
+                if self.stack.len() == STACK_DEPTH {
+                    return Err("stack overflow");
+                }
+                self.stack.push(self.pc);
+                self.pc = addr;
+            }
+            Instr::MV { dst, src } => self.v_regs[dst as usize] = self.v_regs[src as usize],
+            Instr::LD_IMM { dst, imm } => self.v_regs[dst as usize] = imm,
+            Instr::LD_I { addr } => self.idx_reg = addr,
+            Instr::LD_DT { dst } => self.v_regs[dst as usize] = self.delay_timer,
+            Instr::LD_K { dst } => match (0..16).find(|&key| ui.is_key_pressed(key)) {
+                Some(key) => self.v_regs[dst as usize] = key,
+                // Retry later...
+                None => self.pc -= 2,
+            },
+            Instr::LD_SPRITE { digit } => {
+                self.idx_reg = self.digit_sprites[(self.v_regs[digit as usize] & 0xF) as usize]
+            }
+            Instr::LD_BCD { num } => {
+                let num = self.v_regs[num as usize];
+                let pos = self.idx_reg as usize;
+                self.poke(pos, num / 100);
+                self.poke(pos + 1, num / 10 % 10);
+                self.poke(pos + 2, num % 10);
+            }
+            Instr::LD_REGS_TO_I { upto } => {
+                let pos = self.idx_reg as usize;
+                for i in 0..=(upto as usize) {
+                    self.poke(pos + i, self.v_regs[i]);
+                }
+                if self.quirks.load_store {
+                    self.idx_reg = self.idx_reg.wrapping_add(upto as u16 + 1);
+                }
+            }
+            Instr::LD_I_TO_REGS { upto } => {
+                let pos = self.idx_reg as usize;
+                for i in 0..=(upto as usize) {
+                    self.v_regs[i] = self.peek(pos + i);
+                }
+                if self.quirks.load_store {
+                    self.idx_reg = self.idx_reg.wrapping_add(upto as u16 + 1);
+                }
+            }
+            Instr::SET_DT { x } => self.delay_timer = self.v_regs[x as usize],
+            Instr::SET_ST { x } => self.sound_timer = self.v_regs[x as usize],
+            Instr::SE_IMM { x, imm } => {
+                if self.v_regs[x as usize] == imm {
+                    self.skip()
+                }
+            }
+            Instr::SNE_IMM { x, imm } => {
+                if self.v_regs[x as usize] != imm {
+                    self.skip()
+                }
+            }
+            Instr::SE_REG { x, y } => {
+                if self.v_regs[x as usize] == self.v_regs[y as usize] {
+                    self.skip()
+                }
+            }
+            Instr::SNE_REG { x, y } => {
+                if self.v_regs[x as usize] != self.v_regs[y as usize] {
+                    self.skip()
+                }
+            }
+            Instr::OR { x, y } => {
+                self.v_regs[x as usize] |= self.v_regs[y as usize];
+                if self.quirks.vf_reset {
+                    self.v_regs[0xF] = 0;
+                }
+            }
+            Instr::AND { x, y } => {
+                self.v_regs[x as usize] &= self.v_regs[y as usize];
+                if self.quirks.vf_reset {
+                    self.v_regs[0xF] = 0;
+                }
+            }
+            Instr::XOR { x, y } => {
+                self.v_regs[x as usize] ^= self.v_regs[y as usize];
+                if self.quirks.vf_reset {
+                    self.v_regs[0xF] = 0;
+                }
+            }
+            Instr::ADD { x, y } => {
+                let v1 = self.v_regs[x as usize] as u16;
+                let v2 = self.v_regs[y as usize] as u16;
+                let res = v1 + v2;
+                self.v_regs[x as usize] = (res & 0xFF) as u8;
+                self.v_regs[0xF] = (res > 0xFF) as u8;
+            }
+            Instr::ADD_IMM { dst, imm } => self.v_regs[dst as usize] += imm,
+            Instr::ADD_I { x } => {
+                self.idx_reg = self.idx_reg.wrapping_add(self.v_regs[x as usize] as u16)
+            }
+            Instr::SUB { x, y } => {
+                let v1 = self.v_regs[x as usize] as u16;
+                let v2 = self.v_regs[y as usize] as u16;
+                self.v_regs[x as usize] = ((v1 - v2) & 0xFF) as u8;
+                self.v_regs[0xF] = (v1 > v2) as u8;
+            }
+            Instr::SHR { x, y } => {
+                let v1 = self.v_regs[if self.quirks.shift { x } else { y } as usize];
+                self.v_regs[0xF] = v1 & 0x1;
+                self.v_regs[x as usize] = v1 >> 1;
+            }
+            Instr::SUBN { x, y } => {
+                let v1 = self.v_regs[x as usize] as u16;
+                let v2 = self.v_regs[y as usize] as u16;
+                self.v_regs[x as usize] = ((v2 - v1) & 0xFF) as u8;
+                self.v_regs[0xF] = (v2 > v1) as u8;
+            }
+            Instr::SHL { x, y } => {
+                let v1 = self.v_regs[if self.quirks.shift { x } else { y } as usize];
+                self.v_regs[0xF] = ((v1 & 0x80) != 0) as u8;
+                self.v_regs[x as usize] = v1 << 1;
+            }
+            Instr::RND { dst, mask } => {
+                self.v_regs[dst as usize] = ui.rnd() & mask;
+            }
+            Instr::DRW { x, y, n } => {
+                if self.quirks.display_wait && !self.hires {
+                    if !self.vblank {
+                        // Retry after the next tick.
+                        self.pc -= 2;
+                        return Ok(self.pc);
+                    }
+                    self.vblank = false;
+                }
+                let (w, h) = self.resolution();
+                let x = self.v_regs[x as usize] as usize % w;
+                let y = self.v_regs[y as usize] as usize % h;
+                // SCHIP draws 16x16 sprites, two bytes per row, for `n == 0`.
+                let (rows, cols) = if n == 0 && self.mode >= Mode::SChip {
+                    (16, 16)
+                } else {
+                    (n as usize, 8)
+                };
+
+                // With both XO-CHIP planes selected, the sprite for the
+                // second plane follows the one for the first.
+                let mut addr = self.idx_reg as usize;
+                let mut pixel_erased = false;
+                for plane in [1u8, 2] {
+                    if self.planes & plane == 0 {
+                        continue;
+                    }
+                    for row in 0..rows {
+                        let b = if cols == 16 {
+                            let pos = addr + row * 2;
+                            ((self.peek(pos) as u16) << 8) | (self.peek(pos + 1) as u16)
+                        } else {
+                            (self.peek(addr + row) as u16) << 8
+                        };
+                        for col in 0..cols {
+                            if (b & (0x8000u16 >> col)) == 0 {
+                                continue;
+                            }
+                            if self.quirks.clipping && (x + col >= w || y + row >= h) {
+                                continue;
+                            }
+                            let x = (x + col) % w;
+                            let y = (y + row) % h;
+
+                            let prev_pixel = self.display[y][x];
+                            pixel_erased |= prev_pixel & plane != 0;
+                            self.set_pixel(ui, x, y, prev_pixel ^ plane);
+                        }
+                    }
+                    addr += rows * cols / 8;
+                }
+                self.v_regs[0xF] = pixel_erased as u8;
+            }
+            Instr::SKP { x } => {
+                if ui.is_key_pressed(self.v_regs[x as usize]) {
+                    self.skip()
+                }
+            }
+            Instr::SKNP { x } => {
+                if !ui.is_key_pressed(self.v_regs[x as usize]) {
+                    self.skip()
+                }
+            }
+            Instr::SCD { n } => self.scroll(ui, 0, n as isize),
+            Instr::SCU { n } => self.scroll(ui, 0, -(n as isize)),
+            Instr::SCR => self.scroll(ui, 4, 0),
+            Instr::SCL => self.scroll(ui, -4, 0),
+            Instr::EXIT => return Err("exit"),
+            Instr::LOW => self.set_hires(ui, false),
+            Instr::HIGH => self.set_hires(ui, true),
+            Instr::LD_HF { digit } => {
+                self.idx_reg = (BIG_FONT + (self.v_regs[digit as usize] & 0xF) as usize * 10) as u16
+            }
+            Instr::LD_REGS_TO_R { upto } => {
+                let n = upto as usize + 1;
+                self.rpl[..n].copy_from_slice(&self.v_regs[..n]);
+            }
+            Instr::LD_R_TO_REGS { upto } => {
+                let n = upto as usize + 1;
+                self.v_regs[..n].copy_from_slice(&self.rpl[..n]);
+            }
+            Instr::SAVE_RANGE { x, y } => {
+                let pos = self.idx_reg as usize;
+                for (i, r) in Self::reg_range(x, y).into_iter().enumerate() {
+                    self.poke(pos + i, self.v_regs[r]);
+                }
+            }
+            Instr::LOAD_RANGE { x, y } => {
+                let pos = self.idx_reg as usize;
+                for (i, r) in Self::reg_range(x, y).into_iter().enumerate() {
+                    self.v_regs[r] = self.peek(pos + i);
+                }
+            }
+            Instr::LD_I_LONG { addr } => {
+                self.idx_reg = addr;
+                self.pc += 2;
+            }
+            Instr::PLANE { mask } => self.planes = mask & 0b11,
+            Instr::AUDIO => {
+                let pos = self.idx_reg as usize;
+                for i in 0..16 {
+                    self.audio_pattern[i] = self.peek(pos + i);
+                }
+                ui.audio_pattern(&self.audio_pattern, self.pitch);
+            }
+            Instr::PITCH { x } => {
+                self.pitch = self.v_regs[x as usize];
+                ui.audio_pattern(&self.audio_pattern, self.pitch);
+            }
+        }
+        Ok(self.pc)
+    }
+}
+
+/// Reads a save state front to back.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], &'static str> {
+        if self.0.len() < n {
+            return Err("save state is truncated");
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, &'static str> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    /// An address into a memory of `len` bytes.
+    fn addr(&mut self, len: usize) -> Result<u16, &'static str> {
+        match self.u16()? {
+            addr if (addr as usize) < len => Ok(addr),
+            _ => Err("address out of memory in save state"),
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use crate::Mode;
+
+/// How to run the opcodes that interpreters disagree on. ROMs are written
+/// against one interpreter, so pick the preset matching it.
+///
+/// See https://github.com/Timendus/chip8-test-suite#quirks-test for what
+/// each preset does and why.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// `SHR`/`SHL` shift `Vx` in place instead of shifting `Vy` into `Vx`.
+    pub shift: bool,
+    /// `LD [I], Vx` and `LD Vx, [I]` leave `I` pointing past the last
+    /// register.
+    pub load_store: bool,
+    /// `JP V0, nnn` is `Bxnn` and jumps to `xnn + Vx`.
+    pub jump: bool,
+    /// Sprites are cut off at the screen edges instead of wrapping around.
+    pub clipping: bool,
+    /// `OR`, `AND` and `XOR` set VF to zero.
+    pub vf_reset: bool,
+    /// `DRW` in lores mode waits for the next timer tick, like the VIP
+    /// waiting for the vertical blank.
+    pub display_wait: bool,
+}
+
+impl Quirks {
+    pub const COSMAC_VIP: Quirks = Quirks {
+        shift: false,
+        load_store: true,
+        jump: false,
+        clipping: true,
+        vf_reset: true,
+        display_wait: true,
+    };
+
+    /// CHIP-48 increments `I` by one less on loads and stores; that is
+    /// rare enough to be counted as no increment.
+    pub const CHIP48: Quirks = Quirks {
+        shift: true,
+        load_store: false,
+        jump: true,
+        clipping: true,
+        vf_reset: false,
+        display_wait: false,
+    };
+
+    pub const SCHIP: Quirks = Quirks {
+        shift: true,
+        load_store: false,
+        jump: true,
+        clipping: true,
+        vf_reset: false,
+        display_wait: false,
+    };
+
+    pub const XOCHIP: Quirks = Quirks {
+        shift: false,
+        load_store: true,
+        jump: false,
+        clipping: false,
+        vf_reset: false,
+        display_wait: false,
+    };
+
+    /// The preset of the interpreter that introduced `mode`.
+    pub fn for_mode(mode: Mode) -> Quirks {
+        match mode {
+            Mode::Chip8 => Quirks::COSMAC_VIP,
+            Mode::SChip => Quirks::SCHIP,
+            Mode::XoChip => Quirks::XOCHIP,
+        }
+    }
+
+    /// One bit per quirk, in field order, for save states.
+    pub(crate) fn to_bits(self) -> u8 {
+        [
+            self.shift,
+            self.load_store,
+            self.jump,
+            self.clipping,
+            self.vf_reset,
+            self.display_wait,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (i, &q)| bits | (q as u8) << i)
+    }
+
+    pub(crate) fn from_bits(bits: u8) -> Quirks {
+        let bit = |i: u8| bits & (1 << i) != 0;
+        Quirks {
+            shift: bit(0),
+            load_store: bit(1),
+            jump: bit(2),
+            clipping: bit(3),
+            vf_reset: bit(4),
+            display_wait: bit(5),
+        }
+    }
+}
+
+impl std::str::FromStr for Quirks {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Quirks, &'static str> {
+        match s.to_ascii_lowercase().as_str() {
+            "vip" | "cosmac-vip" => Ok(Quirks::COSMAC_VIP),
+            "chip48" | "chip-48" => Ok(Quirks::CHIP48),
+            "schip" | "superchip" | "super-chip" => Ok(Quirks::SCHIP),
+            "xochip" | "xo-chip" => Ok(Quirks::XOCHIP),
+            _ => Err("unknown quirks, expected vip, chip48, schip or xochip"),
+        }
+    }
+}
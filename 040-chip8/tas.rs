@@ -0,0 +1,182 @@
+use crate::headless::xorshift;
+use crate::{Mode, Quirks, UI};
+use std::io::Write;
+
+/// First line of an input log, followed by the seed and then the mode,
+/// quirks and ROM it was recorded with, which a replay has to match.
+const LOG_HEADER: &str = "chip8 input log v2 seed=";
+
+/// Wraps a UI to make runs reproducible: `rnd` comes from a seeded
+/// xorshift instead of the UI, and keys can be recorded to an input log and
+/// played back from it.
+///
+/// Keys are taken once per frame, at each timer tick, and held for the
+/// whole frame, so the log has one line per frame with the keys down as a
+/// hex bitmask. Replaying a log over a UI that ticks at the same cycles,
+/// which all of them do, draws the same frames as the recording.
+pub struct TasUI {
+    ui: Box<dyn UI>,
+    rng: u64,
+    input: Input,
+    /// The keys of the current frame, bit `k` for key `k`.
+    keys: u16,
+}
+
+enum Input {
+    /// Keys straight from the UI, only `rnd` is seeded.
+    Live,
+    Record(Box<dyn Write>),
+    Replay {
+        frames: Vec<u16>,
+        next: usize,
+    },
+}
+
+impl TasUI {
+    pub fn seeded(ui: Box<dyn UI>, seed: u64) -> TasUI {
+        TasUI {
+            ui,
+            // xorshift gets stuck on zero.
+            rng: seed | 1,
+            input: Input::Live,
+            keys: 0,
+        }
+    }
+
+    /// Records the keys of every frame to `log`, which is written as the
+    /// program runs. `mode`, `quirks` and `rom` are those of the run, for
+    /// `replay` to check.
+    pub fn record(
+        ui: Box<dyn UI>,
+        seed: u64,
+        mode: Mode,
+        quirks: Quirks,
+        rom: &[u8],
+        mut log: Box<dyn Write>,
+    ) -> std::io::Result<TasUI> {
+        writeln!(
+            log,
+            "{}{} {}",
+            LOG_HEADER,
+            seed,
+            run_fields(mode, quirks, rom).join(" ")
+        )?;
+        Ok(TasUI {
+            input: Input::Record(log),
+            ..TasUI::seeded(ui, seed)
+        })
+    }
+
+    /// Plays back a log written by `record`, `update` fails once it ran out
+    /// of frames. The keys of `ui` are ignored. Fails if the log was recorded
+    /// with another mode, other quirks or another ROM, as it would diverge.
+    pub fn replay(
+        ui: Box<dyn UI>,
+        log: &str,
+        mode: Mode,
+        quirks: Quirks,
+        rom: &[u8],
+    ) -> Result<TasUI, &'static str> {
+        let mut lines = log.lines();
+        let mut header = lines
+            .next()
+            .and_then(|l| l.strip_prefix(LOG_HEADER))
+            .ok_or("not an input log")?
+            .split_whitespace();
+        let seed = header
+            .next()
+            .and_then(|s| s.parse().ok())
+            .ok_or("not an input log")?;
+        let errors = [
+            "input log was recorded in another mode",
+            "input log was recorded with other quirks",
+            "input log was recorded with another ROM",
+        ];
+        for (field, err) in run_fields(mode, quirks, rom).iter().zip(errors) {
+            match header.next() {
+                Some(f) if f == field => {}
+                Some(f) if f.split('=').next() == field.split('=').next() => return Err(err),
+                _ => return Err("not an input log"),
+            }
+        }
+        let frames = lines
+            .map(|l| u16::from_str_radix(l.trim(), 16))
+            .collect::<Result<_, _>>()
+            .map_err(|_| "broken frame in input log")?;
+        Ok(TasUI {
+            input: Input::Replay { frames, next: 0 },
+            ..TasUI::seeded(ui, seed)
+        })
+    }
+}
+
+/// The `key=value` fields of the log header after the seed.
+fn run_fields(mode: Mode, quirks: Quirks, rom: &[u8]) -> [String; 3] {
+    // 64-bit FNV-1a, enough to tell ROMs apart.
+    let hash = rom.iter().fold(0xcbf29ce484222325u64, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    [
+        format!("mode={}", mode as u8),
+        format!("quirks={:02x}", quirks.to_bits()),
+        format!("rom={:016x}", hash),
+    ]
+}
+
+impl UI for TasUI {
+    fn is_key_pressed(&mut self, key: u8) -> bool {
+        match self.input {
+            Input::Live => self.ui.is_key_pressed(key),
+            _ => self.keys & (1 << (key & 0xF)) != 0,
+        }
+    }
+
+    fn clear_screen(&mut self) {
+        self.ui.clear_screen()
+    }
+
+    fn draw_pixel(&mut self, x: usize, y: usize, val: bool) {
+        self.ui.draw_pixel(x, y, val)
+    }
+
+    fn draw_color(&mut self, x: usize, y: usize, color: u8) {
+        self.ui.draw_color(x, y, color)
+    }
+
+    fn set_resolution(&mut self, width: usize, height: usize) {
+        self.ui.set_resolution(width, height)
+    }
+
+    fn update(&mut self, cycle: u64, dt: std::time::Duration) -> Result<bool, &'static str> {
+        let timer_tick = self.ui.update(cycle, dt)?;
+        if timer_tick {
+            match &mut self.input {
+                Input::Live => {}
+                Input::Record(log) => {
+                    self.keys = (0..16)
+                        .filter(|&k| self.ui.is_key_pressed(k))
+                        .fold(0, |keys, k| keys | 1 << k);
+                    writeln!(log, "{:04x}", self.keys)
+                        .map_err(|_| "could not write the input log")?;
+                }
+                Input::Replay { frames, next } => {
+                    self.keys = *frames.get(*next).ok_or("end of the input log")?;
+                    *next += 1;
+                }
+            }
+        }
+        Ok(timer_tick)
+    }
+
+    fn rnd(&mut self) -> u8 {
+        xorshift(&mut self.rng)
+    }
+
+    fn buzzer(&mut self, on: bool) {
+        self.ui.buzzer(on)
+    }
+
+    fn audio_pattern(&mut self, pattern: &[u8; 16], pitch: u8) {
+        self.ui.audio_pattern(pattern, pitch)
+    }
+}
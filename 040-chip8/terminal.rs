@@ -3,9 +3,35 @@
 use crossterm::{execute, queue, QueueableCommand};
 use std::io::Write;
 
+/// Colors of the XO-CHIP plane combinations, `Reset` being the background.
+const COLORS: [crossterm::style::Color; 4] = [
+    crossterm::style::Color::Reset,
+    crossterm::style::Color::White,
+    crossterm::style::Color::Yellow,
+    crossterm::style::Color::Red,
+];
+
+/// Of the `--record-audio` WAV file.
+const SAMPLE_RATE: u32 = 44100;
+
+/// Keys for the emulator itself rather than the program, handed from
+/// `TerminalUI::update` to the main loop.
+#[derive(Debug, Clone, Copy)]
+enum Hotkey {
+    Pause,
+    Save,
+    Load,
+    Rewind,
+}
+
 struct TerminalUI {
     stdout: std::io::Stdout,
     keys: [u32; 16],
+    /// In hires mode two pixel rows share a line of half blocks, so drawing
+    /// one needs the color of the other.
+    pixels: [[u8; 128]; 64],
+    hires: bool,
+    hotkey: std::rc::Rc<std::cell::Cell<Option<Hotkey>>>,
 }
 
 impl TerminalUI {
@@ -52,25 +78,62 @@ impl chip8::UI for TerminalUI {
         )
         .unwrap();
         self.draw_border().unwrap();
+        self.pixels = [[0; 128]; 64];
     }
 
     fn draw_pixel(&mut self, x: usize, y: usize, val: bool) {
+        self.draw_color(x, y, val as u8)
+    }
+
+    fn draw_color(&mut self, x: usize, y: usize, color: u8) {
+        use crossterm::style::*;
+        self.pixels[y][x] = color;
+        if !self.hires {
+            queue!(
+                self.stdout,
+                crossterm::cursor::MoveTo((x * 2) as u16 + 1, y as u16 + 1),
+                SetForegroundColor(COLORS[color as usize & 3]),
+                Print(if color != 0 { "██" } else { "  " }),
+                ResetColor
+            )
+            .unwrap();
+            return;
+        }
+
+        let top = self.pixels[y & !1][x] as usize & 3;
+        let bottom = self.pixels[y | 1][x] as usize & 3;
+        let (c, fg, bg) = match (top, bottom) {
+            (0, 0) => (' ', Color::Reset, Color::Reset),
+            (0, b) => ('▄', COLORS[b], Color::Reset),
+            (t, b) => ('▀', COLORS[t], COLORS[b]),
+        };
         queue!(
             self.stdout,
-            crossterm::cursor::MoveTo((x * 2) as u16 + 1, y as u16 + 1)
-        )
-        .unwrap();
-        queue!(
-            self.stdout,
-            crossterm::style::Print(if val { "██" } else { "  " })
+            crossterm::cursor::MoveTo(x as u16 + 1, (y / 2) as u16 + 1),
+            SetForegroundColor(fg),
+            SetBackgroundColor(bg),
+            Print(c),
+            ResetColor
         )
         .unwrap();
     }
 
+    fn set_resolution(&mut self, width: usize, _height: usize) {
+        // Both resolutions fit the same border, hires just packs more in.
+        self.hires = width > 64;
+    }
+
     fn is_key_pressed(&mut self, key: u8) -> bool {
         self.keys[(key & 0xF) as usize] > 0
     }
 
+    /// No audio device, the terminal bell will have to do.
+    fn buzzer(&mut self, on: bool) {
+        if on {
+            queue!(self.stdout, crossterm::style::Print('\x07')).unwrap();
+        }
+    }
+
     fn update(&mut self, cycle: u64, dt: std::time::Duration) -> Result<bool, &'static str> {
         const F: u32 = 4;
         const KEY_PRESSED_FOR: u32 = 10;
@@ -103,6 +166,10 @@ impl chip8::UI for TerminalUI {
                     KeyCode::Char('x') => self.keys[0x0] = KEY_PRESSED_FOR,
                     KeyCode::Char('c') => self.keys[0xB] = KEY_PRESSED_FOR,
                     KeyCode::Char('v') => self.keys[0xF] = KEY_PRESSED_FOR,
+                    KeyCode::F(2) => self.hotkey.set(Some(Hotkey::Save)),
+                    KeyCode::F(3) => self.hotkey.set(Some(Hotkey::Load)),
+                    KeyCode::F(4) => self.hotkey.set(Some(Hotkey::Rewind)),
+                    KeyCode::F(6) => self.hotkey.set(Some(Hotkey::Pause)),
 
                     KeyCode::Esc
                     | KeyCode::Backspace
@@ -117,43 +184,284 @@ impl chip8::UI for TerminalUI {
             }
         }
 
-        Ok(cycle % (F as u64) == 0)
+        Ok(cycle.is_multiple_of(F as u64))
     }
 }
 
+/// Draws the debugger panel right of the screen: registers, stack, the
+/// code around PC and the keys to drive it.
+fn draw_debugger(c8: &chip8::Chip8State, status: &str) -> Result<(), std::io::Error> {
+    use chip8::Reg;
+    use crossterm::cursor::MoveTo;
+    use crossterm::style::Print;
+    const COL: u16 = 64 * 2 + 3;
+    let mut lines = vec![
+        format!(
+            "PC {:#05x}  I {:#05x}  DT {:3}  ST {:3}",
+            c8.reg(Reg::PC),
+            c8.reg(Reg::I),
+            c8.reg(Reg::DT),
+            c8.reg(Reg::ST)
+        ),
+        String::new(),
+    ];
+    for row in 0..4 {
+        let regs: Vec<String> = (0..4)
+            .map(|col| row * 4 + col)
+            .map(|x| format!("v{:X} {:#04x}", x, c8.reg(Reg::V(x))))
+            .collect();
+        lines.push(regs.join("  "));
+    }
+    lines.push(String::new());
+    let stack: Vec<String> = c8.stack().iter().map(|a| format!("{:#05x}", a)).collect();
+    lines.push(format!("stack [{}]", stack.join(" ")));
+    lines.push(String::new());
+
+    let pc = c8.reg(Reg::PC);
+    let breakpoints: Vec<u16> = c8.breakpoints().collect();
+    for (addr, instr) in c8.disassemble(pc.saturating_sub(2 * 8), 18) {
+        let mark = if addr == pc { '>' } else { ' ' };
+        let bp = if breakpoints.contains(&addr) {
+            '*'
+        } else {
+            ' '
+        };
+        lines.push(format!("{}{} {:#05x}  {}", bp, mark, addr, instr));
+    }
+    lines.push(String::new());
+    lines.push(status.to_string());
+    lines.push("space/F10 step  F5 run  F6 pause  F9 breakpoint  Esc quit".to_string());
+    lines.push("F2 save  F3 load  F4 rewind".to_string());
+
+    let mut stdout = std::io::stdout();
+    for (row, line) in lines.iter().enumerate() {
+        queue!(
+            stdout,
+            MoveTo(COL, row as u16),
+            Print(format!("{:<60}", line))
+        )?;
+    }
+    stdout.flush()
+}
+
+/// Saves, loads or rewinds and says how that went, refused while `tas`
+/// records or replays an input log.
+fn handle_hotkey(c8: &mut chip8::Chip8State, key: Hotkey, state_file: &str, tas: bool) -> String {
+    match key {
+        Hotkey::Pause => "paused".to_string(),
+        _ if tas => "no save states while recording or replaying".to_string(),
+        Hotkey::Save => match std::fs::write(state_file, c8.save_state()) {
+            Ok(()) => format!("saved to {}", state_file),
+            Err(e) => format!("{}: {}", state_file, e),
+        },
+        Hotkey::Load => match std::fs::read(state_file) {
+            Ok(state) => match c8.load_state(&state) {
+                Ok(()) => format!("loaded {}", state_file),
+                Err(e) => format!("{}: {}", state_file, e),
+            },
+            Err(e) => format!("{}: {}", state_file, e),
+        },
+        Hotkey::Rewind if c8.rewind() => "rewound".to_string(),
+        Hotkey::Rewind => "nothing left to rewind".to_string(),
+    }
+}
+
+/// Prints `msg` on the line below the screen.
+fn show_status(msg: &str) -> Result<(), std::io::Error> {
+    use crossterm::terminal::{Clear, ClearType};
+    let mut stdout = std::io::stdout();
+    queue!(
+        stdout,
+        crossterm::cursor::MoveTo(0, 34),
+        Clear(ClearType::CurrentLine),
+        crossterm::style::Print(msg)
+    )?;
+    stdout.flush()
+}
+
 fn main() {
     let ws = crossterm::terminal::window_size().unwrap();
     if ws.rows <= 32 || ws.columns <= 64 * 2 {
         eprintln!("terminal too small! rows={}, cols={}", ws.rows, ws.columns);
     }
 
-    let rom_file = std::env::args().nth(1).unwrap_or("rom.ch8".to_string());
+    let mut mode = chip8::Mode::Chip8;
+    let mut quirks: Option<chip8::Quirks> = None;
+    let mut rom_file = "rom.ch8".to_string();
+    let mut debug = false;
+    let mut wav_file: Option<String> = None;
+    let mut seed: Option<u64> = None;
+    let mut record: Option<String> = None;
+    let mut replay: Option<String> = None;
+    for arg in std::env::args().skip(1) {
+        let parsed = if arg == "--debug" {
+            debug = true;
+            Ok(())
+        } else if let Some(m) = arg.strip_prefix("--mode=") {
+            m.parse().map(|m| mode = m)
+        } else if let Some(q) = arg.strip_prefix("--quirks=") {
+            q.parse().map(|q| quirks = Some(q))
+        } else if let Some(f) = arg.strip_prefix("--record-audio=") {
+            wav_file = Some(f.to_string());
+            Ok(())
+        } else if let Some(s) = arg.strip_prefix("--seed=") {
+            s.parse()
+                .map(|s| seed = Some(s))
+                .map_err(|_| "expected a number")
+        } else if let Some(f) = arg.strip_prefix("--record=") {
+            record = Some(f.to_string());
+            Ok(())
+        } else if let Some(f) = arg.strip_prefix("--replay=") {
+            replay = Some(f.to_string());
+            Ok(())
+        } else {
+            rom_file = arg.clone();
+            Ok(())
+        };
+        if let Err(e) = parsed {
+            eprintln!("{}: {}", arg, e);
+            std::process::exit(2);
+        }
+    }
+    let quirks = quirks.unwrap_or(chip8::Quirks::for_mode(mode));
     println!(
-        "hi! (terminal-size={}x{}, rom={:?})",
-        ws.columns, ws.rows, rom_file
+        "hi! (terminal-size={}x{}, rom={:?}, mode={:?}, quirks={:?})",
+        ws.columns, ws.rows, rom_file, mode, quirks
     );
     let rom: Vec<u8> = match std::fs::read(&rom_file) {
         Ok(buf) => buf,
         Err(e) => {
-            eprintln!("{}: {}", &rom_file, e);
+            eprintln!("{}: {}", rom_file, e);
             std::process::exit(1);
         }
     };
 
+    let state_file = format!("{}.state", rom_file);
+    let mut wav = wav_file.map(|f| {
+        let file = std::fs::File::create(&f)
+            .and_then(|file| chip8::WavWriter::new(std::io::BufWriter::new(file), SAMPLE_RATE));
+        file.unwrap_or_else(|e| {
+            eprintln!("{}: {}", f, e);
+            std::process::exit(1);
+        })
+    });
+    let tas = record.is_some() || replay.is_some();
     let err = {
+        let hotkey = std::rc::Rc::new(std::cell::Cell::new(None));
         let ui = Box::new(TerminalUI {
             stdout: std::io::stdout(),
             keys: [0; 16],
+            pixels: [[0; 128]; 64],
+            hires: false,
+            hotkey: hotkey.clone(),
         });
-        let mut c8 = chip8::Chip8State::new(ui, &rom);
+        let fail = |file: &str, e: String| -> ! {
+            eprintln!("{}: {}", file, e);
+            std::process::exit(1);
+        };
+        let ui: Box<dyn chip8::UI> = if let Some(f) = &replay {
+            let log = std::fs::read_to_string(f).unwrap_or_else(|e| fail(f, e.to_string()));
+            match chip8::TasUI::replay(ui, &log, mode, quirks, &rom) {
+                Ok(ui) => Box::new(ui),
+                Err(e) => fail(f, e.to_string()),
+            }
+        } else if let Some(f) = &record {
+            let seed = seed.unwrap_or_else(rand::random);
+            let ui = std::fs::File::create(f).and_then(|file| {
+                let log = Box::new(std::io::BufWriter::new(file));
+                chip8::TasUI::record(ui, seed, mode, quirks, &rom, log)
+            });
+            Box::new(ui.unwrap_or_else(|e| fail(f, e.to_string())))
+        } else if let Some(seed) = seed {
+            Box::new(chip8::TasUI::seeded(ui, seed))
+        } else {
+            ui
+        };
+        let mut c8 = chip8::Chip8State::with_mode(ui, &rom, mode);
+        c8.set_quirks(quirks);
+        // About a minute back, ten states a second.
+        c8.set_rewind(600, 24);
+        if wav.is_some() {
+            c8.set_sample_rate(SAMPLE_RATE);
+        }
         crossterm::terminal::enable_raw_mode().unwrap();
         c8.ui.clear_screen();
-        loop {
-            match c8.cycle() {
-                Ok(_) => continue,
-                Err(e) => break e,
+        let err = if !debug {
+            loop {
+                if let Err(e) = c8.cycle() {
+                    break e;
+                }
+                if let Some(wav) = &mut wav {
+                    wav.write(&c8.take_samples()).unwrap();
+                }
+                match hotkey.take() {
+                    None | Some(Hotkey::Pause) => {}
+                    Some(key) => {
+                        show_status(&handle_hotkey(&mut c8, key, &state_file, tas)).unwrap()
+                    }
+                }
+            }
+        } else {
+            use crossterm::event::{read, Event, KeyCode};
+            let mut status = "paused".to_string();
+            let mut paused = true;
+            loop {
+                if !paused {
+                    match c8.run_until(1) {
+                        Ok(chip8::Stop::Cycles) => match hotkey.take() {
+                            None => continue,
+                            Some(Hotkey::Pause) => status = "paused".to_string(),
+                            Some(key) => {
+                                status = handle_hotkey(&mut c8, key, &state_file, tas);
+                                draw_debugger(&c8, &status).unwrap();
+                                continue;
+                            }
+                        },
+                        Ok(stop) => status = format!("{:?}", stop),
+                        Err(e) => break e,
+                    }
+                    paused = true;
+                }
+                draw_debugger(&c8, &status).unwrap();
+                if let Event::Key(e) = read().unwrap() {
+                    match e.code {
+                        KeyCode::Char(' ') | KeyCode::F(10) => {
+                            if let Err(e) = c8.step() {
+                                break e;
+                            }
+                            status = "step".to_string();
+                        }
+                        KeyCode::F(5) => {
+                            status = "running".to_string();
+                            paused = false;
+                            draw_debugger(&c8, &status).unwrap();
+                        }
+                        KeyCode::F(9) => {
+                            let pc = c8.reg(chip8::Reg::PC);
+                            if !c8.remove_breakpoint(pc) {
+                                c8.add_breakpoint(pc);
+                            }
+                        }
+                        KeyCode::F(2) => {
+                            status = handle_hotkey(&mut c8, Hotkey::Save, &state_file, tas)
+                        }
+                        KeyCode::F(3) => {
+                            status = handle_hotkey(&mut c8, Hotkey::Load, &state_file, tas)
+                        }
+                        KeyCode::F(4) => {
+                            status = handle_hotkey(&mut c8, Hotkey::Rewind, &state_file, tas)
+                        }
+                        KeyCode::Esc => break "Bye!",
+                        _ => {}
+                    }
+                }
             }
+        };
+        if let Some(mut wav) = wav {
+            wav.write(&c8.take_samples()).unwrap();
+            wav.finish().unwrap();
         }
+        err
     };
 
     execute!(std::io::stdout(), crossterm::cursor::MoveTo(0, 34)).unwrap();
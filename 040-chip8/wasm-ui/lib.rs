@@ -1,14 +1,63 @@
 mod utils;
 
 use chip8;
+use std::cell::Cell;
+use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 
+/// Fill styles of the XO-CHIP plane combinations, 0 being cleared.
+const PALETTE: [&str; 4] = ["", "white", "yellow", "red"];
+
+/// States kept to rewind: a minute of history at ten states a second.
+const REWIND_STATES: usize = 600;
+
+/// The CHIP-8 key at each `KeyboardEvent.code`, the left of the keyboard
+/// laid out like the COSMAC VIP keypad:
+///
+/// ```text
+/// 1 2 3 4      1 2 3 C
+/// Q W E R  ->  4 5 6 D
+/// A S D F      7 8 9 E
+/// Z X C V      A 0 B F
+/// ```
+///
+/// Codes are physical keys, so this is the same on any keyboard layout.
+const KEYMAP: [(&str, u8); 16] = [
+    ("Digit1", 0x1),
+    ("Digit2", 0x2),
+    ("Digit3", 0x3),
+    ("Digit4", 0xC),
+    ("KeyQ", 0x4),
+    ("KeyW", 0x5),
+    ("KeyE", 0x6),
+    ("KeyR", 0xD),
+    ("KeyA", 0x7),
+    ("KeyS", 0x8),
+    ("KeyD", 0x9),
+    ("KeyF", 0xE),
+    ("KeyZ", 0xA),
+    ("KeyX", 0x0),
+    ("KeyC", 0xB),
+    ("KeyV", 0xF),
+];
+
+/// What the wrapper tells the UI between cycles.
+#[derive(Default)]
+struct Input {
+    /// One bit per key held down.
+    keys: Cell<u16>,
+    /// The cycle running is the last of a frame, so the timers tick after
+    /// it.
+    frame_end: Cell<bool>,
+}
+
 #[wasm_bindgen]
 pub struct WebIU {
     width: u32,
     height: u32,
     pixel_size: f64,
     ctx: web_sys::CanvasRenderingContext2d,
+    input: Rc<Input>,
 }
 
 impl chip8::UI for WebIU {
@@ -18,7 +67,13 @@ impl chip8::UI for WebIU {
     }
 
     fn draw_pixel(&mut self, x: usize, y: usize, val: bool) {
-        if val {
+        self.draw_color(x, y, val as u8)
+    }
+
+    fn draw_color(&mut self, x: usize, y: usize, color: u8) {
+        if color != 0 {
+            self.ctx
+                .set_fill_style(&JsValue::from_str(PALETTE[color as usize & 3]));
             self.ctx.fill_rect(
                 (x as f64) * self.pixel_size,
                 (y as f64) * self.pixel_size,
@@ -35,31 +90,116 @@ impl chip8::UI for WebIU {
         }
     }
 
+    fn set_resolution(&mut self, width: usize, _height: usize) {
+        self.pixel_size = self.width as f64 / width as f64;
+    }
+
     fn update(&mut self, _cycle: u64, _dt: std::time::Duration) -> Result<bool, &'static str> {
-        Ok(true)
+        Ok(self.input.frame_end.get())
     }
 
-    // TODO:...
-    fn is_key_pressed(&mut self, _key: u8) -> bool {
-        false
+    fn is_key_pressed(&mut self, key: u8) -> bool {
+        self.input.keys.get() & (1 << (key & 0xF)) != 0
     }
 }
 
 #[wasm_bindgen]
 pub struct Chip8JSWrapper {
     c8: Box<chip8::Chip8State>,
+    input: Rc<Input>,
 }
 
 #[wasm_bindgen]
 impl Chip8JSWrapper {
+    /// Runs a single cycle as a whole frame, timers included.
     pub fn tick(&mut self) -> Result<(), String> {
+        self.input.frame_end.set(true);
         self.c8.cycle().map(|_| ()).map_err(|e| e.to_string())
     }
+
+    /// Runs one 60th of a second: `cycles_per_frame` cycles, then a timer
+    /// tick. Call it 60 times a second whatever the display refresh rate.
+    /// Returns false once the program is done, see `Chip8State::run`.
+    pub fn run_frame(&mut self, cycles_per_frame: u32) -> Result<bool, String> {
+        let n = cycles_per_frame.max(1);
+        // One state every six frames, whatever the speed is set to.
+        self.c8.set_rewind(REWIND_STATES, 6 * n as u64);
+        for i in 0..n {
+            self.input.frame_end.set(i + 1 == n);
+            match self.c8.cycle() {
+                Ok(_) => {}
+                Err("busy wait" | "exit") => return Ok(false),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Presses the CHIP-8 key at a `KeyboardEvent.code`, returns false for
+    /// codes that are not one, see `KEYMAP`.
+    pub fn key_down(&mut self, code: &str) -> bool {
+        self.set_key(code, true)
+    }
+
+    pub fn key_up(&mut self, code: &str) -> bool {
+        self.set_key(code, false)
+    }
+
+    /// The keys held down, key `k` being bit `k`.
+    pub fn keys(&self) -> u16 {
+        self.input.keys.get()
+    }
+
+    fn set_key(&mut self, code: &str, down: bool) -> bool {
+        let Some(&(_, key)) = KEYMAP.iter().find(|&&(c, _)| c == code) else {
+            return false;
+        };
+        let keys = self.input.keys.get();
+        self.input.keys.set(if down {
+            keys | 1 << key
+        } else {
+            keys & !(1 << key)
+        });
+        true
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        self.c8.save_state()
+    }
+
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        Ok(self.c8.load_state(state)?)
+    }
+
+    /// Steps back about a tenth of a second, false once there is no
+    /// history left.
+    pub fn rewind(&mut self) -> bool {
+        self.c8.rewind()
+    }
+
+    /// Generates sound at `rate` samples per second, the `AudioContext`
+    /// sample rate. Zero turns it off.
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.c8.set_sample_rate(rate)
+    }
+
+    /// The samples generated since the last call, to be queued as an
+    /// `AudioBuffer`.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.c8.take_samples()
+    }
+
+    /// Picks a quirks preset by name, see `chip8::Quirks`.
+    pub fn set_quirks(&mut self, preset: &str) -> Result<(), String> {
+        self.c8.set_quirks(preset.parse()?);
+        Ok(())
+    }
 }
 
 #[wasm_bindgen]
-pub fn init_chip8(pixel_size: f64, rom: &[u8]) -> Chip8JSWrapper {
+pub fn init_chip8(pixel_size: f64, rom: &[u8], mode: &str) -> Result<Chip8JSWrapper, String> {
     utils::set_panic_hook();
+    let mode: chip8::Mode = mode.parse()?;
 
     let document = web_sys::window().unwrap().document().unwrap();
     let canvas = document.get_element_by_id("chip8").unwrap();
@@ -77,14 +217,16 @@ pub fn init_chip8(pixel_size: f64, rom: &[u8]) -> Chip8JSWrapper {
         .dyn_into::<web_sys::CanvasRenderingContext2d>()
         .unwrap();
 
-    ctx.set_fill_style(&JsValue::from_str("white"));
-
+    let input = Rc::new(Input::default());
     let ui = Box::new(WebIU {
         pixel_size,
         width: canvas.width(),
         height: canvas.height(),
         ctx,
+        input: input.clone(),
     });
-    let c8 = chip8::Chip8State::new(ui, rom);
-    Chip8JSWrapper { c8 }
+    let mut c8 = chip8::Chip8State::with_mode(ui, rom, mode);
+    // `run_frame` sets the interval from the speed it runs at.
+    c8.set_rewind(REWIND_STATES, 60);
+    Ok(Chip8JSWrapper { c8, input })
 }
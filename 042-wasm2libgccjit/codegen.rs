@@ -0,0 +1,1389 @@
+/*
+ * Translation of a decoded module to native code with libgccjit.
+ *
+ * Every Wasm function becomes an internal gcc function `wasm_func_<n>`, and
+ * every exported one gets an exported wrapper named after the export, with
+ * anything but ASCII letters and digits replaced by `_`. Exports that would
+ * share a symbol, or take one of the `wasm_` names below, are rejected. The
+ * state shared with the host lives in exported symbols of the generated
+ * code, so the same code works when it is run in memory and when it is
+ * written to a shared object (see `runtime.rs` for the host side):
+ *
+ *   void *wasm_instance;                     passed back to all callbacks
+ *   void (*wasm_trap_handler)(int32_t);      called with a `Trap`, must not return
+ *   void (*wasm_host_call)(void *, int32_t, uint64_t *);
+ *                                            calls imported function n, the slots
+ *                                            hold the arguments and then the results
+ *   uint8_t *wasm_memory;                    memory 0,
+ *   uint64_t wasm_memory_size;               and its size in bytes
+ *   int32_t (*wasm_memory_grow)(void *, int32_t pages);
+ *   uint64_t *wasm_table_<n>;                table n,
+ *   uint32_t wasm_table_<n>_size;            and its number of elements
+ *   int32_t (*wasm_table_grow)(void *, int32_t table, int32_t n, uint64_t init);
+ *   <type> wasm_global_<n>;                  global n (imported ones are set by the host)
+ *   void wasm_init(void);                    initializes the globals and segments,
+ *                                            then runs the start function
+ *
+ * References are 64 bit integers, 0 is null and functions are their index
+ * plus one. Values are passed to the host as their bit patterns, zero
+ * extended to 64 bits. Memory is accessed little endian, as on the host.
+ *
+ * The operand stack only exists at compile time: every value pushed is
+ * stored in a fresh local, the branches of a block assign its results to
+ * a set of locals shared by all of them, and gcc cleans this up.
+ */
+
+use gccjit::{BinaryOp, Block, ComparisonOp, Context, FunctionType, GlobalKind, LValue,
+             OptimizationLevel, OutputKind, RValue, ToLValue, ToRValue, UnaryOp};
+
+use crate::{BlockType, DataMode, ElementInit, ElementMode, Error, ImportDesc, Instr, MemOp, Module, Op,
+            Result, Trap, Type};
+
+#[derive(Clone, Copy)]
+struct Types<'ctx> {
+    void: gccjit::Type<'ctx>,
+    bool: gccjit::Type<'ctx>,
+    i8: gccjit::Type<'ctx>,
+    u8: gccjit::Type<'ctx>,
+    i16: gccjit::Type<'ctx>,
+    u16: gccjit::Type<'ctx>,
+    i32: gccjit::Type<'ctx>,
+    u32: gccjit::Type<'ctx>,
+    i64: gccjit::Type<'ctx>,
+    u64: gccjit::Type<'ctx>,
+    f32: gccjit::Type<'ctx>,
+    f64: gccjit::Type<'ctx>,
+    ptr: gccjit::Type<'ctx>,
+}
+
+impl<'ctx> Types<'ctx> {
+    fn new(ctx: &'ctx Context<'ctx>) -> Self {
+        Types {
+            void: ctx.new_type::<()>(),
+            bool: ctx.new_type::<bool>(),
+            i8: ctx.new_type::<i8>(),
+            u8: ctx.new_type::<u8>(),
+            i16: ctx.new_type::<i16>(),
+            u16: ctx.new_type::<u16>(),
+            i32: ctx.new_type::<i32>(),
+            u32: ctx.new_type::<u32>(),
+            i64: ctx.new_type::<i64>(),
+            u64: ctx.new_type::<u64>(),
+            f32: ctx.new_type::<f32>(),
+            f64: ctx.new_type::<f64>(),
+            ptr: ctx.new_type::<u8>().make_pointer(),
+        }
+    }
+
+    fn of(&self, t: &Type) -> gccjit::Type<'ctx> {
+        match t {
+            Type::I32 => self.i32,
+            Type::I64 => self.i64,
+            Type::F32 => self.f32,
+            Type::F64 => self.f64,
+            Type::FuncRef | Type::ExternRef => self.u64,
+            Type::Func(..) => unreachable!("function types are not value types"),
+        }
+    }
+
+    fn result(&self, rets: &[Type]) -> Result<gccjit::Type<'ctx>> {
+        match rets {
+            [] => Ok(self.void),
+            [t] => Ok(self.of(t)),
+            _ => Err(Error::Unsupported("functions with more than one return value".to_string()))
+        }
+    }
+}
+
+/// A global array and the global holding its current length.
+type Array<'ctx> = (LValue<'ctx>, LValue<'ctx>);
+
+struct CodeGen<'a, 'ctx> {
+    m: &'a Module,
+    ctx: &'ctx Context<'ctx>,
+    types: Types<'ctx>,
+    functions: Vec<gccjit::Function<'ctx>>,
+    globals: Vec<LValue<'ctx>>,
+    instance: LValue<'ctx>,
+    trap_handler: LValue<'ctx>,
+    host_call: LValue<'ctx>,
+    memory: Array<'ctx>,
+    memory_grow: LValue<'ctx>,
+    tables: Vec<Array<'ctx>>,
+    table_grow: LValue<'ctx>,
+    data: Vec<Array<'ctx>>,
+    elements: Vec<Array<'ctx>>,
+    /// The address and canonical type index of every function, for `call_indirect`.
+    function_table: LValue<'ctx>,
+    function_types: LValue<'ctx>,
+    /// Export names and the symbols of their wrappers.
+    exports: Vec<(String, String)>,
+}
+
+impl<'a, 'ctx> CodeGen<'a, 'ctx> {
+    fn new(m: &'a Module, ctx: &'ctx Context<'ctx>) -> Result<Self> {
+        let t = Types::new(ctx);
+        let global = |ty, name: &str| ctx.new_global(None, GlobalKind::Exported, ty, name);
+        let array = |ty, len: usize, name: &str| {
+            let ty = ctx.new_array_type(None, ty, len.max(1) as u64);
+            ctx.new_global(None, GlobalKind::Internal, ty, name)
+        };
+
+        let handler_type = ctx.new_function_pointer_type(None, t.void, &[t.i32], false);
+        let host_call_type = ctx.new_function_pointer_type(None, t.void, &[t.ptr, t.i32, t.u64.make_pointer()], false);
+        let memory_grow_type = ctx.new_function_pointer_type(None, t.i32, &[t.ptr, t.i32], false);
+        let table_grow_type = ctx.new_function_pointer_type(None, t.i32, &[t.ptr, t.i32, t.i32, t.u64], false);
+        let num_functions = m.num_functions();
+        let mut cg = CodeGen {
+            m,
+            ctx,
+            types: t,
+            functions: vec![],
+            globals: vec![],
+            instance: global(t.ptr, "wasm_instance"),
+            trap_handler: global(handler_type, "wasm_trap_handler"),
+            host_call: global(host_call_type, "wasm_host_call"),
+            memory: (global(t.ptr, "wasm_memory"), global(t.u64, "wasm_memory_size")),
+            memory_grow: global(memory_grow_type, "wasm_memory_grow"),
+            tables: (0..m.num_tables())
+                .map(|i| (global(t.u64.make_pointer(), &format!("wasm_table_{}", i)),
+                          global(t.u32, &format!("wasm_table_{}_size", i))))
+                .collect(),
+            table_grow: global(table_grow_type, "wasm_table_grow"),
+            data: m.data.iter().enumerate()
+                .map(|(i, d)| {
+                    let bytes = array(t.u8, d.bytes.len(), &format!("wasm_data_{}", i));
+                    bytes.global_set_initializer(&d.bytes);
+                    (bytes, global(t.u32, &format!("wasm_data_{}_size", i)))
+                })
+                .collect(),
+            elements: m.elements.iter().enumerate()
+                .map(|(i, e)| {
+                    let len = match &e.init {
+                        ElementInit::Funcs(funcs) => funcs.len(),
+                        ElementInit::Exprs(exprs) => exprs.len(),
+                    };
+                    (array(t.u64, len, &format!("wasm_elem_{}", i)), global(t.u32, &format!("wasm_elem_{}_size", i)))
+                })
+                .collect(),
+            function_table: array(t.ptr, num_functions, "wasm_functions"),
+            function_types: array(t.i32, num_functions, "wasm_function_types"),
+            exports: vec![],
+        };
+
+        for import in &m.imports {
+            if let ImportDesc::Global(ty, _) = &import.desc {
+                let name = format!("wasm_global_{}", cg.globals.len());
+                cg.globals.push(global(t.of(ty), &name));
+            }
+        }
+        for (ty, _, _) in &m.globals {
+            let name = format!("wasm_global_{}", cg.globals.len());
+            cg.globals.push(global(t.of(ty), &name));
+        }
+        for idx in 0..num_functions {
+            let (args, rets) = &m.function_types[m.function_type_index(idx).unwrap()];
+            let params: Vec<_> = args.iter().enumerate()
+                .map(|(i, ty)| ctx.new_parameter(None, t.of(ty), format!("p{}", i)))
+                .collect();
+            let name = format!("wasm_func_{}", idx);
+            let f = ctx.new_function(None, FunctionType::Internal, t.result(rets)?, &params, &name, false);
+            cg.functions.push(f);
+        }
+        Ok(cg)
+    }
+
+    fn trap(&self, block: Block<'ctx>, trap: Trap) {
+        let code = self.ctx.new_rvalue_from_int(self.types.i32, trap as i32);
+        block.add_eval(None, self.ctx.new_call_through_ptr(None, self.trap_handler.to_rvalue(), &[code]));
+        block.add_eval(None, self.ctx.new_call(None, self.ctx.get_builtin_function("__builtin_trap"), &[]));
+    }
+
+    /// The index of the first type equal to type `t`, which is what
+    /// `call_indirect` compares.
+    fn canonical_type(&self, t: usize) -> i32 {
+        let types = &self.m.function_types;
+        types.iter().position(|other| *other == types[t]).unwrap() as i32
+    }
+
+    fn const_expr(&self, expr: &[Instr]) -> RValue<'ctx> {
+        let (ctx, t) = (self.ctx, self.types);
+        match &expr[0] {
+            Instr::I32Const(c) => ctx.new_rvalue_from_int(t.i32, *c),
+            Instr::I64Const(c) => ctx.new_rvalue_from_long(t.i64, *c),
+            Instr::F32Const(c) => ctx.new_bitcast(None, ctx.new_rvalue_from_int(t.i32, c.to_bits() as i32), t.f32),
+            Instr::F64Const(c) => ctx.new_bitcast(None, ctx.new_rvalue_from_long(t.i64, c.to_bits() as i64), t.f64),
+            Instr::GlobalGet(idx) => self.globals[*idx as usize].to_rvalue(),
+            Instr::RefNull(_) => ctx.new_rvalue_zero(t.u64),
+            Instr::RefFunc(idx) => ctx.new_rvalue_from_long(t.u64, *idx as i64 + 1),
+            instr => unreachable!("{} in a validated constant expression", instr)
+        }
+    }
+
+    /// Imported functions pass their arguments on to the host.
+    fn import(&self, idx: usize) {
+        let (ctx, t) = (self.ctx, self.types);
+        let f = self.functions[idx];
+        let (args, rets) = &self.m.function_types[self.m.function_type_index(idx).unwrap()];
+        let slots = f.new_local(None, ctx.new_array_type(None, t.u64, args.len().max(rets.len()).max(1) as u64), "slots");
+        let slot = |i: usize| ctx.new_array_access(None, slots.to_rvalue(), ctx.new_rvalue_from_int(t.i32, i as i32));
+        let block = f.new_block("entry");
+        for (i, arg) in args.iter().enumerate() {
+            let value = f.get_param(i as i32).to_rvalue();
+            let bits = match arg {
+                Type::I32 => ctx.new_cast(None, value, t.u32),
+                Type::F32 => ctx.new_bitcast(None, value, t.u32),
+                Type::F64 => ctx.new_bitcast(None, value, t.u64),
+                _ => value,
+            };
+            block.add_assignment(None, slot(i), ctx.new_cast(None, bits, t.u64));
+        }
+        let code = ctx.new_rvalue_from_int(t.i32, idx as i32);
+        let args = [self.instance.to_rvalue(), code, slot(0).get_address(None)];
+        block.add_eval(None, ctx.new_call_through_ptr(None, self.host_call.to_rvalue(), &args));
+        match rets.first() {
+            None => block.end_with_void_return(None),
+            Some(ret) => {
+                let bits = slot(0).to_rvalue();
+                let value = match ret {
+                    Type::F32 => ctx.new_bitcast(None, ctx.new_cast(None, bits, t.u32), t.f32),
+                    Type::F64 => ctx.new_bitcast(None, bits, t.f64),
+                    ty => ctx.new_cast(None, bits, t.of(ty)),
+                };
+                block.end_with_return(None, value);
+            }
+        }
+    }
+
+    /// Instantiation in the order of the spec: globals, tables, memory, start.
+    fn init(&self) {
+        let (ctx, t) = (self.ctx, self.types);
+        let f = ctx.new_function(None, FunctionType::Exported, t.void, &[], "wasm_init", false);
+        let mut g = FuncGen::new(self, f, None);
+
+        let imported = self.globals.len() - self.m.globals.len();
+        for (i, (_, _, expr)) in self.m.globals.iter().enumerate() {
+            g.cur().add_assignment(None, self.globals[imported + i], self.const_expr(expr));
+        }
+        for (i, func) in self.functions.iter().enumerate() {
+            let i32 = |n: i64| ctx.new_rvalue_from_long(t.i32, n);
+            let address = ctx.new_cast(None, func.get_address(None), t.ptr);
+            g.cur().add_assignment(None, ctx.new_array_access(None, self.function_table.to_rvalue(), i32(i as i64)), address);
+            let ty = self.canonical_type(self.m.function_type_index(i).unwrap());
+            g.cur().add_assignment(None, ctx.new_array_access(None, self.function_types.to_rvalue(), i32(i as i64)), i32(ty as i64));
+        }
+
+        for (i, element) in self.m.elements.iter().enumerate() {
+            let (values, size) = self.elements[i];
+            let exprs: Vec<RValue> = match &element.init {
+                ElementInit::Funcs(funcs) => funcs.iter().map(|f| ctx.new_rvalue_from_long(t.u64, *f as i64 + 1)).collect(),
+                ElementInit::Exprs(exprs) => exprs.iter().map(|e| self.const_expr(e)).collect(),
+            };
+            for (j, value) in exprs.iter().enumerate() {
+                g.cur().add_assignment(None, ctx.new_array_access(None, values.to_rvalue(), g.int(t.i32, j as i64)), *value);
+            }
+            g.cur().add_assignment(None, size, g.int(t.u32, exprs.len() as i64));
+            match &element.mode {
+                ElementMode::Active(table, offset) => {
+                    g.stack.push(self.const_expr(offset));
+                    g.stack.push(g.int(t.i32, 0));
+                    g.stack.push(g.int(t.i32, exprs.len() as i64));
+                    g.table_init(i, *table);
+                    g.cur().add_assignment(None, size, g.int(t.u32, 0));
+                }
+                ElementMode::Declarative => g.cur().add_assignment(None, size, g.int(t.u32, 0)),
+                ElementMode::Passive => {}
+            }
+        }
+
+        for (i, data) in self.m.data.iter().enumerate() {
+            let size = self.data[i].1;
+            g.cur().add_assignment(None, size, g.int(t.u32, data.bytes.len() as i64));
+            if let DataMode::Active(_, offset) = &data.mode {
+                g.stack.push(self.const_expr(offset));
+                g.stack.push(g.int(t.i32, 0));
+                g.stack.push(g.int(t.i32, data.bytes.len() as i64));
+                g.memory_init(i);
+                g.cur().add_assignment(None, size, g.int(t.u32, 0));
+            }
+        }
+
+        if let Some(start) = self.m.start {
+            g.cur().add_eval(None, ctx.new_call(None, self.functions[start], &[]));
+        }
+        g.cur().end_with_void_return(None);
+    }
+
+    fn exports(&mut self) -> Result<()> {
+        for (name, kind, idx) in &self.m.exports {
+            if *kind != crate::Export::Func {
+                continue;
+            }
+            let t = self.m.function_type_index(*idx).unwrap();
+            let (args, rets) = &self.m.function_types[t];
+            let params: Vec<_> = args.iter().enumerate()
+                .map(|(i, t)| self.ctx.new_parameter(None, self.types.of(t), format!("p{}", i)))
+                .collect();
+            let symbol: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+            if symbol.is_empty() || symbol.starts_with(|c: char| c.is_ascii_digit()) || symbol.starts_with("wasm_") {
+                return Err(Error::Unsupported(format!("export {:?} as the symbol {:?}", name, symbol)));
+            }
+            if let Some((other, _)) = self.exports.iter().find(|(_, s)| *s == symbol) {
+                return Err(Error::Unsupported(format!("exports {:?} and {:?} both as the symbol {}", other, name, symbol)));
+            }
+            let f = self.ctx.new_function(None, FunctionType::Exported, self.types.result(rets)?, &params, &symbol, false);
+            let block = f.new_block("entry");
+            let args: Vec<_> = params.iter().map(|p| p.to_rvalue()).collect();
+            let res = self.ctx.new_call(None, self.functions[*idx], &args);
+            if rets.is_empty() {
+                block.add_eval(None, res);
+                block.end_with_void_return(None);
+            } else {
+                block.end_with_return(None, res);
+            }
+            self.exports.push((name.clone(), symbol));
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq)]
+enum FrameKind { Block, Loop, If }
+
+struct Frame<'ctx> {
+    kind: FrameKind,
+    /// Stack height without the block parameters.
+    height: usize,
+    params: Vec<LValue<'ctx>>,
+    results: Vec<LValue<'ctx>>,
+    /// The loop header, the only target of branches to a loop.
+    header: Option<Block<'ctx>>,
+    /// Where the block continues, created when first needed.
+    after: Option<Block<'ctx>>,
+    /// The else branch of an `if` that has not been reached yet.
+    otherwise: Option<Block<'ctx>>,
+}
+
+struct FuncGen<'a, 'b, 'ctx> {
+    cg: &'b CodeGen<'a, 'ctx>,
+    ctx: &'ctx Context<'ctx>,
+    t: Types<'ctx>,
+    func: gccjit::Function<'ctx>,
+    return_type: Option<gccjit::Type<'ctx>>,
+    locals: Vec<LValue<'ctx>>,
+    stack: Vec<RValue<'ctx>>,
+    frames: Vec<Frame<'ctx>>,
+    /// The block code is emitted to, `None` after a branch or `unreachable`.
+    block: Option<Block<'ctx>>,
+    /// Nesting depth of the blocks inside unreachable code.
+    dead: usize,
+    temps: usize,
+}
+
+impl<'a, 'b, 'ctx> FuncGen<'a, 'b, 'ctx> {
+    fn new(cg: &'b CodeGen<'a, 'ctx>, func: gccjit::Function<'ctx>, return_type: Option<gccjit::Type<'ctx>>) -> Self {
+        FuncGen {
+            cg,
+            ctx: cg.ctx,
+            t: cg.types,
+            func,
+            return_type,
+            locals: vec![],
+            stack: vec![],
+            frames: vec![],
+            block: Some(func.new_block("entry")),
+            dead: 0,
+            temps: 0,
+        }
+    }
+
+    fn cur(&self) -> Block<'ctx> {
+        self.block.expect("code emitted to an unreachable block")
+    }
+
+    fn local(&mut self, ty: gccjit::Type<'ctx>) -> LValue<'ctx> {
+        self.temps += 1;
+        self.func.new_local(None, ty, format!("t{}", self.temps))
+    }
+
+    fn new_block(&mut self) -> Block<'ctx> {
+        self.temps += 1;
+        self.func.new_block(format!("b{}", self.temps))
+    }
+
+    /// Evaluate `value` now.
+    fn tmp(&mut self, value: RValue<'ctx>) -> RValue<'ctx> {
+        let local = self.local(value.get_type());
+        self.cur().add_assignment(None, local, value);
+        local.to_rvalue()
+    }
+
+    /// The stack only holds locals, so values are evaluated in order.
+    fn push(&mut self, value: RValue<'ctx>) {
+        let value = self.tmp(value);
+        self.stack.push(value);
+    }
+
+    /// `Module::decode` type-checks function bodies, and unreachable code is
+    /// skipped, so the operand is always there.
+    fn pop(&mut self) -> RValue<'ctx> {
+        self.stack.pop().expect("operand stack underflow in a validated function")
+    }
+
+    fn pop2(&mut self) -> (RValue<'ctx>, RValue<'ctx>) {
+        let b = self.pop();
+        let a = self.pop();
+        (a, b)
+    }
+
+    fn int(&self, ty: gccjit::Type<'ctx>, value: i64) -> RValue<'ctx> {
+        self.ctx.new_rvalue_from_long(ty, value)
+    }
+
+    fn float(&self, ty: gccjit::Type<'ctx>, value: f64) -> RValue<'ctx> {
+        self.ctx.new_rvalue_from_double(ty, value)
+    }
+
+    fn cast(&self, value: RValue<'ctx>, ty: gccjit::Type<'ctx>) -> RValue<'ctx> {
+        self.ctx.new_cast(None, value, ty)
+    }
+
+    fn bitcast(&self, value: RValue<'ctx>, ty: gccjit::Type<'ctx>) -> RValue<'ctx> {
+        self.ctx.new_bitcast(None, value, ty)
+    }
+
+    fn cmp(&self, op: ComparisonOp, a: RValue<'ctx>, b: RValue<'ctx>) -> RValue<'ctx> {
+        self.ctx.new_comparison(None, op, a, b)
+    }
+
+    fn binop(&self, op: BinaryOp, ty: gccjit::Type<'ctx>, a: RValue<'ctx>, b: RValue<'ctx>) -> RValue<'ctx> {
+        self.ctx.new_binary_op(None, op, ty, a, b)
+    }
+
+    fn builtin(&self, name: &str, args: &[RValue<'ctx>]) -> RValue<'ctx> {
+        let f = self.ctx.get_builtin_function(name);
+        // Builtins use the C types, which are not necessarily our fixed size ones.
+        let args: Vec<_> = args.iter().enumerate()
+            .map(|(i, a)| self.cast(*a, f.get_param(i as i32).to_rvalue().get_type()))
+            .collect();
+        self.ctx.new_call(None, f, &args)
+    }
+
+    /// The value of the first case whose condition holds, or `default`.
+    /// Only the selected value is evaluated.
+    fn cases(&mut self, cases: &[(RValue<'ctx>, RValue<'ctx>)], default: RValue<'ctx>) -> RValue<'ctx> {
+        let res = self.local(default.get_type());
+        let join = self.new_block();
+        for (cond, value) in cases {
+            let (yes, no) = (self.new_block(), self.new_block());
+            self.cur().end_with_conditional(None, *cond, yes, no);
+            yes.add_assignment(None, res, self.cast(*value, default.get_type()));
+            yes.end_with_jump(None, join);
+            self.block = Some(no);
+        }
+        self.cur().add_assignment(None, res, default);
+        self.cur().end_with_jump(None, join);
+        self.block = Some(join);
+        res.to_rvalue()
+    }
+
+    fn trap_if(&mut self, cond: RValue<'ctx>, trap: Trap) {
+        let (yes, no) = (self.new_block(), self.new_block());
+        self.cur().end_with_conditional(None, cond, yes, no);
+        self.cg.trap(yes, trap);
+        yes.end_with_jump(None, no);
+        self.block = Some(no);
+    }
+
+    fn block_type(&self, bt: &BlockType) -> (Vec<Type>, Vec<Type>) {
+        match bt {
+            BlockType::Empty => (vec![], vec![]),
+            BlockType::Value(t) => (vec![], vec![t.clone()]),
+            BlockType::Type(idx) => self.cg.m.function_types[*idx as usize].clone(),
+        }
+    }
+
+    fn enter(&mut self, kind: FrameKind, bt: &BlockType) {
+        let cond = if kind == FrameKind::If { Some(self.pop()) } else { None };
+        let (params, results) = self.block_type(bt);
+        let params: Vec<_> = params.iter().map(|t| self.local(self.t.of(t))).collect();
+        let results: Vec<_> = results.iter().map(|t| self.local(self.t.of(t))).collect();
+        let height = self.stack.len() - params.len();
+        // The parameters are passed in locals, as a branch to a loop or the
+        // else branch of an `if` needs them again.
+        for (local, value) in params.iter().zip(&self.stack[height..]) {
+            self.cur().add_assignment(None, *local, *value);
+        }
+        self.stack.truncate(height);
+        let mut frame = Frame { kind, height, params, results, header: None, after: None, otherwise: None };
+        if let Some(cond) = cond {
+            let (then, otherwise) = (self.new_block(), self.new_block());
+            let cond = self.cmp(ComparisonOp::NotEquals, cond, self.int(self.t.i32, 0));
+            self.cur().end_with_conditional(None, cond, then, otherwise);
+            frame.otherwise = Some(otherwise);
+            self.block = Some(then);
+        } else if frame.kind == FrameKind::Loop {
+            let header = self.new_block();
+            self.cur().end_with_jump(None, header);
+            frame.header = Some(header);
+            self.block = Some(header);
+        }
+        self.stack.extend(frame.params.iter().map(|p| p.to_rvalue()));
+        self.frames.push(frame);
+    }
+
+    /// Leave the block of frame `idx` with `values` as its results.
+    fn exit(&mut self, idx: usize, values: &[RValue<'ctx>]) {
+        let after = match self.frames[idx].after {
+            Some(after) => after,
+            None => {
+                let after = self.new_block();
+                self.frames[idx].after = Some(after);
+                after
+            }
+        };
+        let block = self.cur();
+        for (local, value) in self.frames[idx].results.iter().zip(values) {
+            block.add_assignment(None, *local, *value);
+        }
+        block.end_with_jump(None, after);
+        self.block = None;
+    }
+
+    /// Pass the values on top of the stack to the block `depth` levels up
+    /// and jump there, the current block is done afterwards.
+    fn branch(&mut self, depth: u32) {
+        let idx = self.frames.len() - 1 - depth as usize;
+        let frame = &self.frames[idx];
+        if idx == 0 {
+            self.ret();
+        } else if let Some(header) = frame.header {
+            let block = self.cur();
+            let top = &self.stack[self.stack.len() - frame.params.len()..];
+            for (local, value) in frame.params.iter().zip(top) {
+                block.add_assignment(None, *local, *value);
+            }
+            block.end_with_jump(None, header);
+            self.block = None;
+        } else {
+            let top = self.stack[self.stack.len() - frame.results.len()..].to_vec();
+            self.exit(idx, &top);
+        }
+    }
+
+    fn ret(&mut self) {
+        let block = self.cur();
+        match self.return_type {
+            Some(_) => block.end_with_return(None, *self.stack.last().unwrap()),
+            None => block.end_with_void_return(None),
+        }
+        self.block = None;
+    }
+
+    /// The end of a block, `else` is the end of the then branch.
+    fn end(&mut self, is_else: bool) {
+        let idx = self.frames.len() - 1;
+        if self.block.is_some() {
+            if idx == 0 {
+                return self.ret();
+            }
+            let top = self.stack[self.stack.len() - self.frames[idx].results.len()..].to_vec();
+            self.exit(idx, &top);
+        }
+        self.stack.truncate(self.frames[idx].height);
+        let params: Vec<_> = self.frames[idx].params.iter().map(|p| p.to_rvalue()).collect();
+        if let Some(otherwise) = self.frames[idx].otherwise.take() {
+            self.block = Some(otherwise);
+            if is_else {
+                self.stack.extend(params);
+                return;
+            }
+            // An `if` without `else` passes its parameters on:
+            self.exit(idx, &params);
+        }
+        let frame = self.frames.pop().unwrap();
+        self.block = frame.after;
+        if self.block.is_some() {
+            self.stack.extend(frame.results.iter().map(|r| r.to_rvalue()));
+        }
+    }
+
+    /// An i32 operand as unsigned 64 bit value.
+    fn u64(&mut self, value: RValue<'ctx>) -> RValue<'ctx> {
+        let value = self.cast(self.cast(value, self.t.u32), self.t.u64);
+        self.tmp(value)
+    }
+
+    /// Trap unless `start + len <= limit`, all three are unsigned.
+    fn check_range(&mut self, start: RValue<'ctx>, len: RValue<'ctx>, limit: RValue<'ctx>, trap: Trap) {
+        let t = self.t;
+        let end = self.binop(BinaryOp::Plus, t.u64, start, len);
+        self.trap_if(self.cmp(ComparisonOp::GreaterThan, end, self.cast(limit, t.u64)), trap);
+    }
+
+    /// The address of element `i` of an array, which has been checked.
+    fn element(&self, array: Array<'ctx>, i: RValue<'ctx>) -> RValue<'ctx> {
+        self.ctx.new_array_access(None, array.0.to_rvalue(), i).get_address(None)
+    }
+
+    fn table_entry(&mut self, table: usize, i: RValue<'ctx>) -> LValue<'ctx> {
+        let (base, size) = self.cg.tables[table];
+        let i = self.u64(i);
+        self.check_range(i, self.int(self.t.u64, 1), size.to_rvalue(), Trap::OutOfBoundsTableAccess);
+        self.ctx.new_array_access(None, base.to_rvalue(), i)
+    }
+
+    fn memory_access(&mut self, op: MemOp, offset: u32) {
+        let t = self.t;
+        let name = op.name();
+        let size = 1i64 << op.natural_alignment();
+        let (ty, full) = match &name[..3] {
+            "i32" => (t.i32, 4),
+            "i64" => (t.i64, 8),
+            "f32" => (t.f32, 4),
+            _ => (t.f64, 8),
+        };
+        let narrow = match (size, name.ends_with("_s")) {
+            _ if size == full => ty,
+            (1, true) => t.i8,
+            (1, false) => t.u8,
+            (2, true) => t.i16,
+            (2, false) => t.u16,
+            (_, true) => t.i32,
+            (_, false) => t.u32,
+        };
+        let value = if op.is_store() { Some(self.pop()) } else { None };
+        let addr = self.pop();
+        let addr = self.u64(addr);
+        let ea = self.tmp(self.binop(BinaryOp::Plus, t.u64, addr, self.int(t.u64, offset as i64)));
+        let n = self.int(t.u64, size);
+        self.check_range(ea, n, self.cg.memory.1.to_rvalue(), Trap::OutOfBoundsMemoryAccess);
+        // memcpy as the address does not have to be aligned:
+        let local = self.local(narrow);
+        let p = self.element(self.cg.memory, ea);
+        match value {
+            Some(value) => {
+                self.cur().add_assignment(None, local, self.cast(value, narrow));
+                let copy = self.builtin("__builtin_memcpy", &[p, local.get_address(None), n]);
+                self.cur().add_eval(None, copy);
+            }
+            None => {
+                let copy = self.builtin("__builtin_memcpy", &[local.get_address(None), p, n]);
+                self.cur().add_eval(None, copy);
+                self.push(self.cast(local.to_rvalue(), ty));
+            }
+        }
+    }
+
+    /// `memory.copy` and `table.copy`, the operands are `d s n`.
+    fn copy(&mut self, dst: Array<'ctx>, src: Array<'ctx>, size: i64, trap: Trap) {
+        let t = self.t;
+        let n = self.pop();
+        let s = self.pop();
+        let d = self.pop();
+        let (d, s, n) = (self.u64(d), self.u64(s), self.u64(n));
+        self.check_range(s, n, src.1.to_rvalue(), trap);
+        self.check_range(d, n, dst.1.to_rvalue(), trap);
+        let bytes = self.binop(BinaryOp::Mult, t.u64, n, self.int(t.u64, size));
+        let copy = self.builtin("__builtin_memmove", &[self.element(dst, d), self.element(src, s), bytes]);
+        self.cur().add_eval(None, copy);
+    }
+
+    fn memory_init(&mut self, data: usize) {
+        let segment = self.cg.data[data];
+        self.copy(self.cg.memory, segment, 1, Trap::OutOfBoundsMemoryAccess);
+    }
+
+    fn table_init(&mut self, elem: usize, table: usize) {
+        let segment = self.cg.elements[elem];
+        self.copy(self.cg.tables[table], segment, 8, Trap::OutOfBoundsTableAccess);
+    }
+
+    fn table_fill(&mut self, table: usize) {
+        let t = self.t;
+        let n = self.pop();
+        let value = self.pop();
+        let d = self.pop();
+        let (d, n) = (self.u64(d), self.u64(n));
+        self.check_range(d, n, self.cg.tables[table].1.to_rvalue(), Trap::OutOfBoundsTableAccess);
+        let i = self.local(t.u64);
+        self.cur().add_assignment(None, i, self.int(t.u64, 0));
+        let (head, body, exit) = (self.new_block(), self.new_block(), self.new_block());
+        self.cur().end_with_jump(None, head);
+        head.end_with_conditional(None, self.cmp(ComparisonOp::LessThan, i.to_rvalue(), n), body, exit);
+        let entry = self.binop(BinaryOp::Plus, t.u64, d, i.to_rvalue());
+        body.add_assignment(None, self.ctx.new_array_access(None, self.cg.tables[table].0.to_rvalue(), entry), value);
+        body.add_assignment(None, i, self.binop(BinaryOp::Plus, t.u64, i.to_rvalue(), self.int(t.u64, 1)));
+        body.end_with_jump(None, head);
+        self.block = Some(exit);
+    }
+
+    fn call_indirect(&mut self, ty: usize, table: usize) -> Result<()> {
+        let t = self.t;
+        let i = self.pop();
+        let (args, rets) = self.cg.m.function_types[ty].clone();
+        let params: Vec<_> = args.iter().map(|a| t.of(a)).collect();
+        let ptr_type = self.ctx.new_function_pointer_type(None, t.result(&rets)?, &params, false);
+        let args = self.stack.split_off(self.stack.len() - args.len());
+
+        let (base, size) = self.cg.tables[table];
+        let i = self.u64(i);
+        self.check_range(i, self.int(t.u64, 1), size.to_rvalue(), Trap::UndefinedElement);
+        let entry = self.tmp(self.ctx.new_array_access(None, base.to_rvalue(), i).to_rvalue());
+        self.trap_if(self.cmp(ComparisonOp::Equals, entry, self.int(t.u64, 0)), Trap::UninitializedElement);
+        let f = self.tmp(self.binop(BinaryOp::Minus, t.u64, entry, self.int(t.u64, 1)));
+        let actual = self.ctx.new_array_access(None, self.cg.function_types.to_rvalue(), f).to_rvalue();
+        let expected = self.int(t.i32, self.cg.canonical_type(ty) as i64);
+        self.trap_if(self.cmp(ComparisonOp::NotEquals, actual, expected), Trap::IndirectCallTypeMismatch);
+
+        let code = self.ctx.new_array_access(None, self.cg.function_table.to_rvalue(), f).to_rvalue();
+        let res = self.ctx.new_call_through_ptr(None, self.cast(code, ptr_type), &args);
+        if rets.is_empty() {
+            self.cur().add_eval(None, res);
+        } else {
+            self.push(res);
+        }
+        Ok(())
+    }
+
+    fn instr(&mut self, instr: &Instr) -> Result<()> {
+        if self.block.is_none() {
+            // Skip unreachable code up to the end of the current block.
+            match instr {
+                Instr::Block(_) | Instr::Loop(_) | Instr::If(_) => self.dead += 1,
+                Instr::Else if self.dead == 0 => self.end(true),
+                Instr::End if self.dead == 0 => self.end(false),
+                Instr::End => self.dead -= 1,
+                _ => {}
+            }
+            return Ok(());
+        }
+
+        let t = self.t;
+        match instr {
+            Instr::Unreachable => {
+                let block = self.cur();
+                self.cg.trap(block, Trap::Unreachable);
+                // The block still needs a terminator, the trap handler does not return.
+                match self.return_type {
+                    Some(ty) => block.end_with_return(None, self.ctx.new_rvalue_zero(ty)),
+                    None => block.end_with_void_return(None),
+                }
+                self.block = None;
+            }
+            Instr::NOp => {}
+            Instr::Block(bt) => self.enter(FrameKind::Block, bt),
+            Instr::Loop(bt) => self.enter(FrameKind::Loop, bt),
+            Instr::If(bt) => self.enter(FrameKind::If, bt),
+            Instr::Else => self.end(true),
+            Instr::End => self.end(false),
+            Instr::Br(depth) => self.branch(*depth),
+            Instr::BrIf(depth) => {
+                let cond = self.pop();
+                let (taken, cont) = (self.new_block(), self.new_block());
+                let cond = self.cmp(ComparisonOp::NotEquals, cond, self.int(t.i32, 0));
+                self.cur().end_with_conditional(None, cond, taken, cont);
+                self.block = Some(taken);
+                self.branch(*depth);
+                self.block = Some(cont);
+            }
+            Instr::BrTable(labels, default) => {
+                let idx = self.pop();
+                let block = self.cur();
+                let mut targets: Vec<(u32, Block<'ctx>)> = vec![];
+                let mut target = |this: &mut Self, depth: u32| {
+                    if let Some((_, b)) = targets.iter().find(|(d, _)| *d == depth) {
+                        return *b;
+                    }
+                    let b = this.new_block();
+                    this.block = Some(b);
+                    this.branch(depth);
+                    targets.push((depth, b));
+                    b
+                };
+                let mut cases = vec![];
+                for (i, depth) in labels.iter().enumerate() {
+                    let dest = target(self, *depth);
+                    let i = self.int(t.i32, i as i64);
+                    cases.push(self.ctx.new_case(i, i, dest));
+                }
+                let default = target(self, *default);
+                block.end_with_switch(None, idx, default, &cases);
+                self.block = None;
+            }
+            Instr::Return => self.ret(),
+            Instr::Call(idx) => {
+                let (args, rets) = &self.cg.m.function_types[self.cg.m.function_type_index(*idx as usize).unwrap()];
+                let args = self.stack.split_off(self.stack.len() - args.len());
+                let res = self.ctx.new_call(None, self.cg.functions[*idx as usize], &args);
+                if rets.is_empty() {
+                    self.cur().add_eval(None, res);
+                } else {
+                    self.push(res);
+                }
+            }
+
+            Instr::CallIndirect(ty, table) => self.call_indirect(*ty as usize, *table as usize)?,
+
+            Instr::RefNull(_) => self.push(self.int(t.u64, 0)),
+            Instr::RefIsNull => {
+                let a = self.pop();
+                let null = self.int(t.u64, 0);
+                self.push(self.cast(self.cmp(ComparisonOp::Equals, a, null), t.i32));
+            }
+            Instr::RefFunc(_) => {
+                let value = self.cg.const_expr(std::slice::from_ref(instr));
+                self.push(value);
+            }
+
+            Instr::Drop => { self.pop(); }
+            Instr::Select | Instr::SelectT(_) => {
+                let cond = self.pop();
+                let (a, b) = self.pop2();
+                let cond = self.cmp(ComparisonOp::NotEquals, cond, self.int(t.i32, 0));
+                let res = self.cases(&[(cond, a)], b);
+                self.stack.push(res);
+            }
+
+            Instr::LocalGet(idx) => self.push(self.locals[*idx as usize].to_rvalue()),
+            Instr::LocalSet(idx) => {
+                let value = self.pop();
+                self.cur().add_assignment(None, self.locals[*idx as usize], value);
+            }
+            Instr::LocalTee(idx) => {
+                let value = *self.stack.last().unwrap();
+                self.cur().add_assignment(None, self.locals[*idx as usize], value);
+            }
+            Instr::GlobalGet(idx) => self.push(self.cg.globals[*idx as usize].to_rvalue()),
+            Instr::GlobalSet(idx) => {
+                let value = self.pop();
+                self.cur().add_assignment(None, self.cg.globals[*idx as usize], value);
+            }
+
+            Instr::I32Const(_) | Instr::I64Const(_) | Instr::F32Const(_) | Instr::F64Const(_) => {
+                let value = self.cg.const_expr(std::slice::from_ref(instr));
+                self.push(value);
+            }
+            Instr::Op(op) => self.op(*op),
+
+
+            Instr::TableGet(table) => {
+                let i = self.pop();
+                let entry = self.table_entry(*table as usize, i);
+                self.push(entry.to_rvalue());
+            }
+            Instr::TableSet(table) => {
+                let value = self.pop();
+                let i = self.pop();
+                let entry = self.table_entry(*table as usize, i);
+                self.cur().add_assignment(None, entry, value);
+            }
+            Instr::TableSize(table) => {
+                let size = self.cg.tables[*table as usize].1;
+                self.push(self.cast(size.to_rvalue(), t.i32));
+            }
+            Instr::TableGrow(table) => {
+                let (init, n) = self.pop2();
+                let args = [self.cg.instance.to_rvalue(), self.int(t.i32, *table as i64), n, init];
+                self.push(self.ctx.new_call_through_ptr(None, self.cg.table_grow.to_rvalue(), &args));
+            }
+            Instr::TableFill(table) => self.table_fill(*table as usize),
+            Instr::TableCopy(dst, src) => {
+                let (dst, src) = (self.cg.tables[*dst as usize], self.cg.tables[*src as usize]);
+                self.copy(dst, src, 8, Trap::OutOfBoundsTableAccess);
+            }
+            Instr::TableInit(elem, table) => self.table_init(*elem as usize, *table as usize),
+            Instr::ElemDrop(elem) => {
+                let size = self.cg.elements[*elem as usize].1;
+                self.cur().add_assignment(None, size, self.int(t.u32, 0));
+            }
+
+            Instr::Mem(op, arg) => self.memory_access(*op, arg.offset),
+            Instr::MemorySize => {
+                let size = self.binop(BinaryOp::RShift, t.u64, self.cg.memory.1.to_rvalue(), self.int(t.u64, 16));
+                self.push(self.cast(size, t.i32));
+            }
+            Instr::MemoryGrow => {
+                let delta = self.pop();
+                let args = [self.cg.instance.to_rvalue(), delta];
+                self.push(self.ctx.new_call_through_ptr(None, self.cg.memory_grow.to_rvalue(), &args));
+            }
+            Instr::MemoryInit(data) => self.memory_init(*data as usize),
+            Instr::DataDrop(data) => {
+                let size = self.cg.data[*data as usize].1;
+                self.cur().add_assignment(None, size, self.int(t.u32, 0));
+            }
+            Instr::MemoryCopy => self.copy(self.cg.memory, self.cg.memory, 1, Trap::OutOfBoundsMemoryAccess),
+            Instr::MemoryFill => {
+                let n = self.pop();
+                let value = self.pop();
+                let d = self.pop();
+                let (d, n) = (self.u64(d), self.u64(n));
+                self.check_range(d, n, self.cg.memory.1.to_rvalue(), Trap::OutOfBoundsMemoryAccess);
+                let fill = self.builtin("__builtin_memset", &[self.element(self.cg.memory, d), value, n]);
+                self.cur().add_eval(None, fill);
+            }
+        }
+        Ok(())
+    }
+
+    fn op(&mut self, op: Op) {
+        use Op::*;
+        let t = self.t;
+        let name = op.name();
+        let (ty, uty, bits) = if name.starts_with("i32") {
+            (t.i32, t.u32, 32)
+        } else if name.starts_with("i64") {
+            (t.i64, t.u64, 64)
+        } else if name.starts_with("f32") {
+            (t.f32, t.f32, 32)
+        } else {
+            (t.f64, t.f64, 64)
+        };
+
+        match op {
+            I32Eqz | I64Eqz => {
+                let a = self.pop();
+                let res = self.cmp(ComparisonOp::Equals, a, self.int(ty, 0));
+                self.push(self.cast(res, t.i32));
+            }
+            I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU | I32GeS | I32GeU
+                | I64Eq | I64Ne | I64LtS | I64LtU | I64GtS | I64GtU | I64LeS | I64LeU | I64GeS | I64GeU
+                | F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge
+                | F64Eq | F64Ne | F64Lt | F64Gt | F64Le | F64Ge => {
+                let (mut a, mut b) = self.pop2();
+                if name.ends_with("_u") {
+                    (a, b) = (self.cast(a, uty), self.cast(b, uty));
+                }
+                let cmp = match &name[4..6] {
+                    "eq" => ComparisonOp::Equals,
+                    "ne" => ComparisonOp::NotEquals,
+                    "lt" => ComparisonOp::LessThan,
+                    "gt" => ComparisonOp::GreaterThan,
+                    "le" => ComparisonOp::LessThanEquals,
+                    _ => ComparisonOp::GreaterThanEquals,
+                };
+                let res = self.cmp(cmp, a, b);
+                self.push(self.cast(res, t.i32));
+            }
+
+            I32Clz | I64Clz => {
+                let a = self.pop();
+                let zero = self.cmp(ComparisonOp::Equals, a, self.int(ty, 0));
+                let clz = self.builtin(if bits == 32 { "__builtin_clz" } else { "__builtin_clzll" }, &[a]);
+                let res = self.cases(&[(zero, self.int(ty, bits))], self.cast(clz, ty));
+                self.stack.push(res);
+            }
+            I32Ctz | I64Ctz => {
+                let a = self.pop();
+                let zero = self.cmp(ComparisonOp::Equals, a, self.int(ty, 0));
+                let ctz = self.builtin(if bits == 32 { "__builtin_ctz" } else { "__builtin_ctzll" }, &[a]);
+                let res = self.cases(&[(zero, self.int(ty, bits))], self.cast(ctz, ty));
+                self.stack.push(res);
+            }
+            I32Popcnt | I64Popcnt => {
+                let a = self.pop();
+                let res = self.builtin(if bits == 32 { "__builtin_popcount" } else { "__builtin_popcountll" }, &[a]);
+                self.push(self.cast(res, ty));
+            }
+            // Signed overflow is undefined in C, it wraps for unsigned types:
+            I32Add | I32Sub | I32Mul | I64Add | I64Sub | I64Mul => {
+                let (a, b) = self.pop2();
+                let op = match &name[4..] {
+                    "add" => BinaryOp::Plus,
+                    "sub" => BinaryOp::Minus,
+                    _ => BinaryOp::Mult,
+                };
+                let res = self.binop(op, uty, self.cast(a, uty), self.cast(b, uty));
+                self.push(self.cast(res, ty));
+            }
+            I32DivS | I64DivS => {
+                let (a, b) = self.pop2();
+                self.trap_if(self.cmp(ComparisonOp::Equals, b, self.int(ty, 0)), Trap::IntegerDivideByZero);
+                let min = self.cmp(ComparisonOp::Equals, a, self.int(ty, if bits == 32 { i32::MIN as i64 } else { i64::MIN }));
+                let minus_one = self.cmp(ComparisonOp::Equals, b, self.int(ty, -1));
+                self.trap_if(self.binop(BinaryOp::LogicalAnd, t.bool, min, minus_one), Trap::IntegerOverflow);
+                self.push(self.binop(BinaryOp::Divide, ty, a, b));
+            }
+            I32RemS | I64RemS => {
+                let (a, b) = self.pop2();
+                self.trap_if(self.cmp(ComparisonOp::Equals, b, self.int(ty, 0)), Trap::IntegerDivideByZero);
+                // MIN % -1 overflows in C, but is 0 in Wasm:
+                let minus_one = self.cmp(ComparisonOp::Equals, b, self.int(ty, -1));
+                let rem = self.binop(BinaryOp::Modulo, ty, a, b);
+                let res = self.cases(&[(minus_one, self.int(ty, 0))], rem);
+                self.stack.push(res);
+            }
+            I32DivU | I32RemU | I64DivU | I64RemU => {
+                let (a, b) = self.pop2();
+                self.trap_if(self.cmp(ComparisonOp::Equals, b, self.int(ty, 0)), Trap::IntegerDivideByZero);
+                let op = if name.contains("div") { BinaryOp::Divide } else { BinaryOp::Modulo };
+                let res = self.binop(op, uty, self.cast(a, uty), self.cast(b, uty));
+                self.push(self.cast(res, ty));
+            }
+            I32And | I32Or | I32Xor | I64And | I64Or | I64Xor => {
+                let (a, b) = self.pop2();
+                let op = match &name[4..] {
+                    "and" => BinaryOp::BitwiseAnd,
+                    "or" => BinaryOp::BitwiseOr,
+                    _ => BinaryOp::BitwiseXor,
+                };
+                self.push(self.binop(op, ty, a, b));
+            }
+            I32Shl | I32ShrS | I32ShrU | I64Shl | I64ShrS | I64ShrU => {
+                let (a, b) = self.pop2();
+                let count = self.binop(BinaryOp::BitwiseAnd, ty, b, self.int(ty, bits - 1));
+                let res = match op {
+                    I32Shl | I64Shl => self.cast(self.binop(BinaryOp::LShift, uty, self.cast(a, uty), self.cast(count, uty)), ty),
+                    I32ShrS | I64ShrS => self.binop(BinaryOp::RShift, ty, a, count),
+                    _ => self.cast(self.binop(BinaryOp::RShift, uty, self.cast(a, uty), self.cast(count, uty)), ty),
+                };
+                self.push(res);
+            }
+            I32Rotl | I32Rotr | I64Rotl | I64Rotr => {
+                let (a, b) = self.pop2();
+                let a = self.cast(a, uty);
+                let count = self.cast(self.binop(BinaryOp::BitwiseAnd, ty, b, self.int(ty, bits - 1)), uty);
+                let other = self.binop(BinaryOp::Minus, uty, self.int(uty, bits), count);
+                let other = self.binop(BinaryOp::BitwiseAnd, uty, other, self.int(uty, bits - 1));
+                let (left, right) = if name.ends_with("rotl") { (count, other) } else { (other, count) };
+                let res = self.binop(BinaryOp::BitwiseOr, uty,
+                    self.binop(BinaryOp::LShift, uty, a, left),
+                    self.binop(BinaryOp::RShift, uty, a, right));
+                self.push(self.cast(res, ty));
+            }
+
+            F32Abs | F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt
+                | F64Abs | F64Ceil | F64Floor | F64Trunc | F64Nearest | F64Sqrt => {
+                let a = self.pop();
+                let f = match &name[4..] {
+                    "abs" => "fabs",
+                    "nearest" => "nearbyint",
+                    f => f,
+                };
+                let suffix = if bits == 32 { "f" } else { "" };
+                let res = self.builtin(&format!("__builtin_{}{}", f, suffix), &[a]);
+                self.push(self.cast(res, ty));
+            }
+            F32Neg | F64Neg => {
+                let a = self.pop();
+                self.push(self.ctx.new_unary_op(None, UnaryOp::Minus, ty, a));
+            }
+            F32Add | F32Sub | F32Mul | F32Div | F64Add | F64Sub | F64Mul | F64Div => {
+                let (a, b) = self.pop2();
+                let op = match &name[4..] {
+                    "add" => BinaryOp::Plus,
+                    "sub" => BinaryOp::Minus,
+                    "mul" => BinaryOp::Mult,
+                    _ => BinaryOp::Divide,
+                };
+                self.push(self.binop(op, ty, a, b));
+            }
+            F32Min | F32Max | F64Min | F64Max => {
+                let (a, b) = self.pop2();
+                let is_min = name.ends_with("min");
+                let int = if bits == 32 { t.u32 } else { t.u64 };
+                // -0 and +0 compare equal, the sign bit decides:
+                let bits_op = if is_min { BinaryOp::BitwiseOr } else { BinaryOp::BitwiseAnd };
+                let zeros = self.bitcast(self.binop(bits_op, int, self.bitcast(a, int), self.bitcast(b, int)), ty);
+                let cases = [
+                    (self.cmp(ComparisonOp::NotEquals, a, a), a),
+                    (self.cmp(ComparisonOp::NotEquals, b, b), b),
+                    (self.cmp(ComparisonOp::Equals, a, b), zeros),
+                    (self.cmp(if is_min { ComparisonOp::LessThan } else { ComparisonOp::GreaterThan }, a, b), a),
+                ];
+                let res = self.cases(&cases, b);
+                self.stack.push(res);
+            }
+            F32Copysign | F64Copysign => {
+                let (a, b) = self.pop2();
+                let res = self.builtin(if bits == 32 { "__builtin_copysignf" } else { "__builtin_copysign" }, &[a, b]);
+                self.push(self.cast(res, ty));
+            }
+
+            I32WrapI64 => {
+                let a = self.pop();
+                self.push(self.cast(a, t.i32));
+            }
+            I32TruncF32S | I32TruncF32U | I32TruncF64S | I32TruncF64U
+                | I64TruncF32S | I64TruncF32U | I64TruncF64S | I64TruncF64U
+                | I32TruncSatF32S | I32TruncSatF32U | I32TruncSatF64S | I32TruncSatF64U
+                | I64TruncSatF32S | I64TruncSatF32U | I64TruncSatF64S | I64TruncSatF64U => {
+                let a = self.pop();
+                let signed = name.ends_with("_s");
+                let from = a.get_type();
+                let mantissa = if name.contains("f32") { 24 } else { 53 };
+                // The range of values that truncate to a representable integer:
+                let (lo, lo_cmp, hi) = if !signed {
+                    (-1.0, ComparisonOp::GreaterThan, 2f64.powi(bits as i32))
+                } else if mantissa > bits {
+                    (-(2f64.powi(bits as i32 - 1)) - 1.0, ComparisonOp::GreaterThan, 2f64.powi(bits as i32 - 1))
+                } else {
+                    (-(2f64.powi(bits as i32 - 1)), ComparisonOp::GreaterThanEquals, 2f64.powi(bits as i32 - 1))
+                };
+                let above_lo = self.cmp(lo_cmp, a, self.float(from, lo));
+                let below_hi = self.cmp(ComparisonOp::LessThan, a, self.float(from, hi));
+                let value = self.cast(self.cast(a, if signed { ty } else { uty }), ty);
+                if name.contains("sat") {
+                    let (min, max) = match (signed, bits) {
+                        (true, 32) => (i32::MIN as i64, i32::MAX as i64),
+                        (true, _) => (i64::MIN, i64::MAX),
+                        (false, 32) => (0, u32::MAX as i32 as i64),
+                        (false, _) => (0, -1),
+                    };
+                    let cases = [
+                        (self.cmp(ComparisonOp::NotEquals, a, a), self.int(ty, 0)),
+                        (self.ctx.new_unary_op(None, UnaryOp::LogicalNegate, t.bool, above_lo), self.int(ty, min)),
+                        (self.ctx.new_unary_op(None, UnaryOp::LogicalNegate, t.bool, below_hi), self.int(ty, max)),
+                    ];
+                    let res = self.cases(&cases, value);
+                    self.stack.push(res);
+                } else {
+                    self.trap_if(self.cmp(ComparisonOp::NotEquals, a, a), Trap::InvalidConversion);
+                    let in_range = self.binop(BinaryOp::LogicalAnd, t.bool, above_lo, below_hi);
+                    self.trap_if(self.ctx.new_unary_op(None, UnaryOp::LogicalNegate, t.bool, in_range), Trap::IntegerOverflow);
+                    self.push(value);
+                }
+            }
+            I64ExtendI32S => {
+                let a = self.pop();
+                self.push(self.cast(a, t.i64));
+            }
+            I64ExtendI32U => {
+                let a = self.pop();
+                self.push(self.cast(self.cast(a, t.u32), t.i64));
+            }
+            F32ConvertI32S | F32ConvertI64S | F64ConvertI32S | F64ConvertI64S | F32DemoteF64 | F64PromoteF32 => {
+                let a = self.pop();
+                self.push(self.cast(a, ty));
+            }
+            F32ConvertI32U | F64ConvertI32U => {
+                let a = self.pop();
+                self.push(self.cast(self.cast(a, t.u32), ty));
+            }
+            F32ConvertI64U | F64ConvertI64U => {
+                let a = self.pop();
+                self.push(self.cast(self.cast(a, t.u64), ty));
+            }
+            I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32 | F64ReinterpretI64 => {
+                let a = self.pop();
+                self.push(self.bitcast(a, ty));
+            }
+            I32Extend8S | I32Extend16S | I64Extend8S | I64Extend16S | I64Extend32S => {
+                let a = self.pop();
+                let narrow = if name.ends_with("8_s") { t.i8 } else if name.ends_with("16_s") { t.i16 } else { t.i32 };
+                self.push(self.cast(self.cast(a, narrow), ty));
+            }
+        }
+    }
+}
+
+impl<'a, 'ctx> CodeGen<'a, 'ctx> {
+    fn function(&self, f: &crate::Function) -> Result<()> {
+        let func = self.functions[f.index];
+        let mut g = FuncGen::new(self, func, f.returns.first().map(|t| self.types.of(t)));
+        for i in 0..f.arguments.len() {
+            g.locals.push(func.get_param(i as i32).to_lvalue());
+        }
+        for (n, t) in &f.locals {
+            let ty = self.types.of(t);
+            for _ in 0..*n {
+                let local = g.local(ty);
+                g.cur().add_assignment(None, local, self.ctx.new_rvalue_zero(ty));
+                g.locals.push(local);
+            }
+        }
+        // Branches to the function block return directly.
+        g.frames.push(Frame {
+            kind: FrameKind::Block, height: 0, params: vec![], results: vec![], header: None, after: None, otherwise: None
+        });
+        for instr in &f.body {
+            g.instr(instr)?;
+        }
+        Ok(())
+    }
+}
+
+fn translate<'ctx>(m: &Module, ctx: &'ctx Context<'ctx>) -> Result<Vec<(String, String)>> {
+    if m.imports.iter().any(|i| matches!(i.desc, ImportDesc::Table(..) | ImportDesc::Memory(_))) {
+        return Err(Error::Unsupported("imported tables and memories".to_string()));
+    }
+    ctx.set_optimization_level(OptimizationLevel::Standard);
+    let mut cg = CodeGen::new(m, ctx)?;
+    for idx in 0..m.num_functions() - m.functions.len() {
+        cg.import(idx);
+    }
+    for f in &m.functions {
+        cg.function(f)?;
+    }
+    cg.init();
+    cg.exports()?;
+    Ok(cg.exports)
+}
+
+fn check(ctx: &Context) -> Result<()> {
+    match ctx.get_first_error() {
+        Ok(Some(msg)) => Err(Error::Compile(msg.to_string())),
+        _ => Ok(())
+    }
+}
+
+/// Write the translated module as a shared object, see the top of this
+/// file for the symbols the host has to set up.
+pub fn compile_to_file(m: &Module, path: &str) -> Result<()> {
+    let ctx = Context::default();
+    translate(m, &ctx)?;
+    ctx.compile_to_file(OutputKind::DynamicLibrary, path);
+    check(&ctx)
+}
+
+extern "C" fn default_trap_handler(code: i32) {
+    match Trap::from_code(code) {
+        Some(trap) => eprintln!("wasm trap: {}", trap),
+        None => eprintln!("wasm trap: {}", code),
+    }
+    std::process::abort();
+}
+
+/// A module translated to native code in memory.
+pub struct Jit {
+    result: gccjit::CompileResult,
+    exports: Vec<(String, String)>,
+}
+
+impl Jit {
+    pub fn compile(m: &Module) -> Result<Jit> {
+        let ctx = Context::default();
+        let exports = translate(m, &ctx)?;
+        let result = ctx.compile();
+        check(&ctx)?;
+        let jit = Jit { result, exports };
+        unsafe {
+            let handler = jit.result.get_global("wasm_trap_handler") as *mut extern "C" fn(i32);
+            *handler = default_trap_handler;
+        }
+        Ok(jit)
+    }
+
+    /// The address of the exported function `name`.
+    pub fn export(&self, name: &str) -> Option<*const ()> {
+        let (_, symbol) = self.exports.iter().find(|(export, _)| export == name)?;
+        Some(self.result.get_function(symbol) as *const ())
+    }
+
+    /// The address of a symbol of the generated code, like `wasm_global_0`.
+    pub fn symbol(&self, name: &str) -> *mut () {
+        self.result.get_global(name)
+    }
+
+    /// Initialize the globals and segments, then run the start function.
+    ///
+    /// # Safety
+    /// The callbacks, memory and tables have to be set up, see `Instance`.
+    pub unsafe fn init(&self) {
+        let init: extern "C" fn() = std::mem::transmute(self.result.get_function("wasm_init"));
+        init();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{code, module, name, vec};
+
+    #[test]
+    fn id() {
+        let f = std::fs::File::open("./tests/id.wasm").unwrap();
+        let m = Module::parse(&mut std::io::BufReader::new(f)).unwrap();
+        let jit = Jit::compile(&m).unwrap();
+        unsafe { jit.init() };
+        let id: extern "C" fn(i32) -> i32 = unsafe { std::mem::transmute(jit.export("id").unwrap()) };
+        assert_eq!(id(42), 42);
+        assert_eq!(id(-1), -1);
+    }
+
+    #[test]
+    fn control_flow() {
+        let fac = [
+            0x42, 1, 0x21, 1,                   // acc = 1
+            0x02, 0x40,                         // block
+            0x03, 0x40,                         // loop
+            0x20, 0, 0x50, 0x0d, 1,             // br_if 1 (n == 0)
+            0x20, 1, 0x20, 0, 0x7e, 0x21, 1,    // acc *= n
+            0x20, 0, 0x42, 1, 0x7d, 0x21, 0,    // n -= 1
+            0x0c, 0,                            // br 0
+            0x0b, 0x0b,
+            0x20, 1, 0x0b,
+        ];
+        let switch = [
+            0x02, 0x40, 0x02, 0x40, 0x02, 0x40,
+            0x20, 0, 0x0e, 2, 0, 1, 2,          // br_table 0 1 2
+            0x0b, 0x41, 10, 0x0f,               // case 0: return 10
+            0x0b, 0x41, 20, 0x0f,               // case 1: return 20
+            0x0b, 0x41, 30, 0x0b,               // default: 30
+        ];
+        let abs = [
+            0x20, 0, 0x41, 0, 0x48,             // n < 0
+            0x04, 0x7f, 0x41, 0, 0x20, 0, 0x6b, // if (result i32) 0 - n
+            0x05, 0x20, 0, 0x0b,                // else n
+            0x0b,
+        ];
+        let min = [0x20, 0, 0x20, 1, 0xa4, 0x0b];
+        let trunc = [0x20, 0, 0xfc, 0x02, 0x0b];
+        let bytes = module(&[
+            (1, vec(&[
+                vec![0x60, 1, 0x7e, 1, 0x7e],
+                vec![0x60, 1, 0x7f, 1, 0x7f],
+                vec![0x60, 2, 0x7c, 0x7c, 1, 0x7c],
+                vec![0x60, 1, 0x7c, 1, 0x7f],
+            ])),
+            (3, vec(&[vec![0], vec![1], vec![1], vec![2], vec![3]])),
+            (7, vec(&[
+                [name("fac"), vec![0x00, 0]].concat(),
+                [name("switch"), vec![0x00, 1]].concat(),
+                [name("abs"), vec![0x00, 2]].concat(),
+                [name("min"), vec![0x00, 3]].concat(),
+                [name("trunc"), vec![0x00, 4]].concat(),
+            ])),
+            (10, vec(&[
+                code(&[1, 1, 0x7e], &fac), code(&[0], &switch), code(&[0], &abs),
+                code(&[0], &min), code(&[0], &trunc),
+            ])),
+        ]);
+        let m = Module::decode(&bytes).unwrap();
+        let jit = Jit::compile(&m).unwrap();
+        unsafe {
+            jit.init();
+            let fac: extern "C" fn(i64) -> i64 = std::mem::transmute(jit.export("fac").unwrap());
+            assert_eq!(fac(10), 3628800);
+            let switch: extern "C" fn(i32) -> i32 = std::mem::transmute(jit.export("switch").unwrap());
+            assert_eq!([0, 1, 2, 7].map(|n| switch(n)), [10, 20, 30, 30]);
+            let abs: extern "C" fn(i32) -> i32 = std::mem::transmute(jit.export("abs").unwrap());
+            assert_eq!([-5, 5, i32::MIN].map(|n| abs(n)), [5, 5, i32::MIN]);
+            let min: extern "C" fn(f64, f64) -> f64 = std::mem::transmute(jit.export("min").unwrap());
+            assert_eq!(min(1.0, -2.0), -2.0);
+            assert!(min(f64::NAN, 1.0).is_nan());
+            assert!(min(0.0, -0.0).is_sign_negative());
+            let trunc: extern "C" fn(f64) -> i32 = std::mem::transmute(jit.export("trunc").unwrap());
+            assert_eq!([1e10, -1e10, f64::NAN, -3.9].map(|x| trunc(x)), [i32::MAX, i32::MIN, 0, -3]);
+        }
+    }
+
+    #[test]
+    fn export_symbols() {
+        let with_exports = |names: &[&str]| {
+            let exports: Vec<_> = names.iter().map(|n| [name(n), vec![0x00, 0]].concat()).collect();
+            let bytes = module(&[
+                (1, vec(&[vec![0x60, 0, 0]])),
+                (3, vec(&[vec![0]])),
+                (7, vec(&exports)),
+                (10, vec(&[code(&[0], &[0x0b])])),
+            ]);
+            Module::decode(&bytes).unwrap()
+        };
+        assert!(Jit::compile(&with_exports(&["run", "run.now"])).is_ok());
+        for names in [&["a-b", "a_b"][..], &["wasm_init"], &["wasm.memory"], &["1up"], &[""]] {
+            assert!(matches!(Jit::compile(&with_exports(names)), Err(Error::Unsupported(_))), "{:?}", names);
+        }
+    }
+}
@@ -0,0 +1,926 @@
+/*
+ * A straightforward interpreter over the decoded instructions, used as a
+ * reference for the generated code and where libgccjit is not available.
+ *
+ * Values are kept as the same 64 bit patterns the host functions see:
+ * 32 bit integers zero extended, floats as their bits and references as 0
+ * for null and the function index + 1 for functions. Branch targets are
+ * found once per function, before it first runs.
+ */
+
+use std::rc::Rc;
+
+use crate::runtime::{link, Caller, HostFunc};
+use crate::{wasi, BlockType, DataMode, ElementInit, ElementMode, Error, Export, Instr, MemOp, Module, Op,
+            Result, Trap, MAX_PAGES, PAGE_SIZE};
+
+/// Calls nested deeper than this trap, before the native stack overflows.
+const MAX_DEPTH: usize = 512;
+
+/// Conversion from and to the bit pattern of a value.
+trait Value {
+    fn from_bits(bits: u64) -> Self;
+    fn bits(self) -> u64;
+}
+
+impl Value for u64 {
+    fn from_bits(bits: u64) -> Self { bits }
+    fn bits(self) -> u64 { self }
+}
+
+impl Value for i64 {
+    fn from_bits(bits: u64) -> Self { bits as i64 }
+    fn bits(self) -> u64 { self as u64 }
+}
+
+impl Value for u32 {
+    fn from_bits(bits: u64) -> Self { bits as u32 }
+    fn bits(self) -> u64 { self as u64 }
+}
+
+impl Value for i32 {
+    fn from_bits(bits: u64) -> Self { bits as i32 }
+    fn bits(self) -> u64 { self as u32 as u64 }
+}
+
+impl Value for f32 {
+    fn from_bits(bits: u64) -> Self { f32::from_bits(bits as u32) }
+    fn bits(self) -> u64 { self.to_bits() as u64 }
+}
+
+impl Value for f64 {
+    fn from_bits(bits: u64) -> Self { f64::from_bits(bits) }
+    fn bits(self) -> u64 { self.to_bits() }
+}
+
+/// Conditions and comparison results are i32.
+impl Value for bool {
+    fn from_bits(bits: u64) -> Self { bits as u32 != 0 }
+    fn bits(self) -> u64 { self as u64 }
+}
+
+/// The operand stack of a function. `Module::decode` type-checks function
+/// bodies, which guarantees the operands are there and have the expected type.
+struct Stack(Vec<u64>);
+
+impl Stack {
+    fn pop<T: Value>(&mut self) -> T {
+        T::from_bits(self.0.pop().unwrap())
+    }
+
+    fn push<T: Value>(&mut self, v: T) {
+        self.0.push(v.bits())
+    }
+}
+
+struct Label {
+    /// The number of values a branch to the label takes along.
+    arity: usize,
+    height: usize,
+    /// Where execution continues after a branch.
+    target: usize,
+    is_loop: bool,
+}
+
+/// A module instance, with its own memory, tables, globals and WASI state.
+pub struct Interpreter<'m> {
+    m: &'m Module,
+    imports: Vec<HostFunc>,
+    /// For every function defined by the module and every `block`, `loop`,
+    /// `if` and `else`, the position of its `else` or `end`.
+    targets: Vec<Rc<Vec<usize>>>,
+    globals: Vec<u64>,
+    memory: Vec<u8>,
+    max_pages: usize,
+    /// The elements and maximum size of every table.
+    tables: Vec<(Vec<u64>, usize)>,
+    /// Element and data segments, empty once dropped.
+    elements: Vec<Vec<u64>>,
+    data: Vec<&'m [u8]>,
+    depth: usize,
+    pub wasi: wasi::Wasi,
+}
+
+impl<'m> Interpreter<'m> {
+    /// Instantiate a module: initialize its tables and memory from the
+    /// active segments and run its start function, if any.
+    pub fn new(m: &'m Module, wasi: wasi::Wasi) -> Result<Interpreter<'m>> {
+        let imports = link(m)?;
+        let (min, max) = m.memory_ranges.first().copied().unwrap_or((0, Some(0)));
+        let mut interp = Interpreter {
+            m,
+            imports,
+            targets: m.functions.iter().map(|f| Rc::new(targets(&f.body))).collect(),
+            globals: vec![],
+            memory: vec![0; min * PAGE_SIZE],
+            max_pages: max.unwrap_or(MAX_PAGES),
+            tables: m.tables.iter()
+                .map(|(_, (min, max))| (vec![0; *min], max.unwrap_or(u32::MAX as usize)))
+                .collect(),
+            elements: vec![],
+            data: m.data.iter().map(|d| d.bytes.as_slice()).collect(),
+            depth: 0,
+            wasi,
+        };
+        for (_, _, expr) in &m.globals {
+            let v = interp.const_expr(expr);
+            interp.globals.push(v);
+        }
+        interp.elements = m.elements.iter().map(|e| match &e.init {
+            ElementInit::Funcs(funcs) => funcs.iter().map(|f| *f as u64 + 1).collect(),
+            ElementInit::Exprs(exprs) => exprs.iter().map(|expr| interp.const_expr(expr)).collect(),
+        }).collect();
+
+        for (i, e) in m.elements.iter().enumerate() {
+            match &e.mode {
+                ElementMode::Active(table, offset) => {
+                    let d = interp.const_expr(offset) as u32;
+                    let n = interp.elements[i].len() as u32;
+                    interp.table_init(i, *table, d, 0, n)?;
+                }
+                ElementMode::Declarative => {}
+                ElementMode::Passive => continue,
+            }
+            interp.elements[i].clear();
+        }
+        for (i, d) in m.data.iter().enumerate() {
+            if let DataMode::Active(_, offset) = &d.mode {
+                let dst = interp.const_expr(offset) as u32;
+                interp.memory_init(i, dst, 0, d.bytes.len() as u32)?;
+                interp.data[i] = &[];
+            }
+        }
+        if let Some(start) = m.start {
+            interp.invoke(start, vec![])?;
+        }
+        Ok(interp)
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Call the exported function `name`, the arguments and results are bit
+    /// patterns as for host functions.
+    pub fn call(&mut self, name: &str, args: &[u64]) -> Result<Vec<u64>> {
+        let func = self.m.exports.iter()
+            .find(|(export, kind, _)| export == name && *kind == Export::Func)
+            .map(|(_, _, idx)| *idx)
+            .ok_or_else(|| Error::Link(format!("no function {:?} exported", name)))?;
+        let (params, _) = self.function_type(func);
+        if args.len() != params.len() {
+            return Err(Error::Link(format!("{} takes {} arguments, not {}", name, params.len(), args.len())));
+        }
+        self.invoke(func, args.to_vec())
+    }
+
+    /// Run the `_start` function of a WASI command and return its exit status.
+    pub fn run(&mut self) -> Result<i32> {
+        match self.call("_start", &[]) {
+            Ok(_) => Ok(0),
+            Err(Error::Exit(code)) => Ok(code),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn function_type(&self, func: usize) -> &'m (Vec<crate::Type>, Vec<crate::Type>) {
+        &self.m.function_types[self.m.function_type_index(func).unwrap()]
+    }
+
+    fn block_type(&self, bt: &BlockType) -> (usize, usize) {
+        match bt {
+            BlockType::Empty => (0, 0),
+            BlockType::Value(_) => (0, 1),
+            BlockType::Type(idx) => {
+                let (params, results) = &self.m.function_types[*idx as usize];
+                (params.len(), results.len())
+            }
+        }
+    }
+
+    fn const_expr(&self, expr: &[Instr]) -> u64 {
+        match &expr[0] {
+            Instr::I32Const(c) => c.bits(),
+            Instr::I64Const(c) => c.bits(),
+            Instr::F32Const(c) => c.bits(),
+            Instr::F64Const(c) => c.bits(),
+            Instr::RefNull(_) => 0,
+            Instr::RefFunc(idx) => *idx as u64 + 1,
+            Instr::GlobalGet(idx) => self.globals[*idx as usize],
+            instr => unreachable!("{} in a constant expression", instr)
+        }
+    }
+
+    fn invoke(&mut self, func: usize, mut args: Vec<u64>) -> Result<Vec<u64>> {
+        if func < self.imports.len() {
+            let (params, results) = self.function_type(func);
+            args.resize(params.len().max(results.len()).max(1), 0);
+            let mut caller = Caller { memory: &mut self.memory, wasi: &mut self.wasi };
+            self.imports[func](&mut caller, &mut args)?;
+            args.truncate(results.len());
+            return Ok(args);
+        }
+        if self.depth == MAX_DEPTH {
+            return Err(Trap::CallStackExhausted.into());
+        }
+        let idx = func - self.imports.len();
+        for (n, _) in &self.m.functions[idx].locals {
+            args.resize(args.len() + n, 0);
+        }
+        self.depth += 1;
+        let res = self.execute(idx, args);
+        self.depth -= 1;
+        res
+    }
+
+    /// Pop the arguments of `func` and push its results.
+    fn call_function(&mut self, s: &mut Stack, func: usize) -> Result<()> {
+        let (params, _) = self.function_type(func);
+        let args = s.0.split_off(s.0.len() - params.len());
+        let results = self.invoke(func, args)?;
+        s.0.extend(results);
+        Ok(())
+    }
+
+    fn execute(&mut self, idx: usize, mut locals: Vec<u64>) -> Result<Vec<u64>> {
+        let m = self.m;
+        let f = &m.functions[idx];
+        let body = &f.body;
+        let targets = self.targets[idx].clone();
+        let mut s = Stack(vec![]);
+        // Branching to the function body returns:
+        let mut labels = vec![Label { arity: f.returns.len(), height: 0, target: body.len(), is_loop: false }];
+        let mut pc = 0;
+        while pc < body.len() {
+            let instr = &body[pc];
+            pc += 1;
+            match instr {
+                Instr::Unreachable => return Err(Trap::Unreachable.into()),
+                Instr::NOp => {}
+                Instr::Block(bt) => {
+                    let (params, results) = self.block_type(bt);
+                    let target = targets[pc - 1] + 1;
+                    labels.push(Label { arity: results, height: s.0.len() - params, target, is_loop: false });
+                }
+                Instr::Loop(bt) => {
+                    let (params, _) = self.block_type(bt);
+                    labels.push(Label { arity: params, height: s.0.len() - params, target: pc, is_loop: true });
+                }
+                Instr::If(bt) => {
+                    let cond: bool = s.pop();
+                    let (params, results) = self.block_type(bt);
+                    let other = targets[pc - 1];
+                    let has_else = body[other] == Instr::Else;
+                    let end = if has_else { targets[other] } else { other };
+                    labels.push(Label { arity: results, height: s.0.len() - params, target: end + 1, is_loop: false });
+                    if !cond {
+                        // Without `else`, continue at the `end` to pop the label.
+                        pc = if has_else { other + 1 } else { other };
+                    }
+                }
+                Instr::Else => pc = targets[pc - 1],
+                Instr::End => {
+                    labels.pop();
+                }
+                Instr::Br(l) => pc = branch(&mut s, &mut labels, *l),
+                Instr::BrIf(l) => {
+                    if s.pop() {
+                        pc = branch(&mut s, &mut labels, *l);
+                    }
+                }
+                Instr::BrTable(ls, default) => {
+                    let i: u32 = s.pop();
+                    pc = branch(&mut s, &mut labels, *ls.get(i as usize).unwrap_or(default));
+                }
+                Instr::Return => {
+                    let l = labels.len() as u32 - 1;
+                    pc = branch(&mut s, &mut labels, l);
+                }
+                Instr::Call(func) => self.call_function(&mut s, *func as usize)?,
+                Instr::CallIndirect(t, table) => {
+                    let i: u32 = s.pop();
+                    let entry = *self.tables[*table as usize].0.get(i as usize).ok_or(Trap::UndefinedElement)?;
+                    if entry == 0 {
+                        return Err(Trap::UninitializedElement.into());
+                    }
+                    let func = entry as usize - 1;
+                    if *self.function_type(func) != m.function_types[*t as usize] {
+                        return Err(Trap::IndirectCallTypeMismatch.into());
+                    }
+                    self.call_function(&mut s, func)?;
+                }
+
+                Instr::RefNull(_) => s.push(0u64),
+                Instr::RefIsNull => {
+                    let r: u64 = s.pop();
+                    s.push(r == 0);
+                }
+                Instr::RefFunc(idx) => s.push(*idx as u64 + 1),
+
+                Instr::Drop => {
+                    s.pop::<u64>();
+                }
+                Instr::Select | Instr::SelectT(_) => {
+                    let cond: bool = s.pop();
+                    let b: u64 = s.pop();
+                    let a: u64 = s.pop();
+                    s.push(if cond { a } else { b });
+                }
+
+                Instr::LocalGet(idx) => s.push(locals[*idx as usize]),
+                Instr::LocalSet(idx) => locals[*idx as usize] = s.pop(),
+                Instr::LocalTee(idx) => locals[*idx as usize] = *s.0.last().unwrap(),
+                Instr::GlobalGet(idx) => s.push(self.globals[*idx as usize]),
+                Instr::GlobalSet(idx) => self.globals[*idx as usize] = s.pop(),
+
+                Instr::TableGet(table) => {
+                    let i: u32 = s.pop();
+                    let v = *self.tables[*table as usize].0.get(i as usize).ok_or(Trap::OutOfBoundsTableAccess)?;
+                    s.push(v);
+                }
+                Instr::TableSet(table) => {
+                    let v: u64 = s.pop();
+                    let i: u32 = s.pop();
+                    *self.tables[*table as usize].0.get_mut(i as usize).ok_or(Trap::OutOfBoundsTableAccess)? = v;
+                }
+                Instr::TableInit(elem, table) => {
+                    let (n, src, dst) = (s.pop(), s.pop(), s.pop());
+                    self.table_init(*elem as usize, *table as usize, dst, src, n)?;
+                }
+                Instr::ElemDrop(elem) => self.elements[*elem as usize].clear(),
+                Instr::TableCopy(dst_table, src_table) => {
+                    let (n, src, dst): (u32, u32, u32) = (s.pop(), s.pop(), s.pop());
+                    let src = range(self.tables[*src_table as usize].0.len(), src, n, Trap::OutOfBoundsTableAccess)?;
+                    let dst = range(self.tables[*dst_table as usize].0.len(), dst, n, Trap::OutOfBoundsTableAccess)?;
+                    let entries = self.tables[*src_table as usize].0[src].to_vec();
+                    self.tables[*dst_table as usize].0[dst].copy_from_slice(&entries);
+                }
+                Instr::TableGrow(table) => {
+                    let n: u32 = s.pop();
+                    let init: u64 = s.pop();
+                    let (entries, max) = &mut self.tables[*table as usize];
+                    s.push(grow(entries, *max, n as usize, init).map_or(-1, |old| old as i32));
+                }
+                Instr::TableSize(table) => s.push(self.tables[*table as usize].0.len() as u32),
+                Instr::TableFill(table) => {
+                    let n: u32 = s.pop();
+                    let v: u64 = s.pop();
+                    let i: u32 = s.pop();
+                    let entries = &mut self.tables[*table as usize].0;
+                    let r = range(entries.len(), i, n, Trap::OutOfBoundsTableAccess)?;
+                    entries[r].fill(v);
+                }
+
+                Instr::Mem(op, arg) => {
+                    let size = 1 << op.natural_alignment();
+                    if op.is_store() {
+                        let v: u64 = s.pop();
+                        let addr: u32 = s.pop();
+                        let r = address(self.memory.len(), addr, arg.offset, size)?;
+                        self.memory[r].copy_from_slice(&v.to_le_bytes()[..size]);
+                    } else {
+                        let addr: u32 = s.pop();
+                        let r = address(self.memory.len(), addr, arg.offset, size)?;
+                        let mut bytes = [0; 8];
+                        bytes[..size].copy_from_slice(&self.memory[r]);
+                        let v = u64::from_le_bytes(bytes);
+                        s.0.push(match op {
+                            MemOp::I32Load8S => (v as i8 as i32).bits(),
+                            MemOp::I32Load16S => (v as i16 as i32).bits(),
+                            MemOp::I64Load8S => v as i8 as u64,
+                            MemOp::I64Load16S => v as i16 as u64,
+                            MemOp::I64Load32S => v as i32 as u64,
+                            _ => v
+                        });
+                    }
+                }
+                Instr::MemorySize => s.push((self.memory.len() / PAGE_SIZE) as u32),
+                Instr::MemoryGrow => {
+                    let n: u32 = s.pop();
+                    let old = grow(&mut self.memory, self.max_pages * PAGE_SIZE, n as usize * PAGE_SIZE, 0);
+                    s.push(old.map_or(-1, |old| (old / PAGE_SIZE) as i32));
+                }
+                Instr::MemoryInit(data) => {
+                    let (n, src, dst) = (s.pop(), s.pop(), s.pop());
+                    self.memory_init(*data as usize, dst, src, n)?;
+                }
+                Instr::DataDrop(data) => self.data[*data as usize] = &[],
+                Instr::MemoryCopy => {
+                    let (n, src, dst): (u32, u32, u32) = (s.pop(), s.pop(), s.pop());
+                    let src = range(self.memory.len(), src, n, Trap::OutOfBoundsMemoryAccess)?;
+                    let dst = range(self.memory.len(), dst, n, Trap::OutOfBoundsMemoryAccess)?;
+                    self.memory.copy_within(src, dst.start);
+                }
+                Instr::MemoryFill => {
+                    let n: u32 = s.pop();
+                    let v: u32 = s.pop();
+                    let dst: u32 = s.pop();
+                    let r = range(self.memory.len(), dst, n, Trap::OutOfBoundsMemoryAccess)?;
+                    self.memory[r].fill(v as u8);
+                }
+
+                Instr::I32Const(c) => s.push(*c),
+                Instr::I64Const(c) => s.push(*c),
+                Instr::F32Const(c) => s.push(*c),
+                Instr::F64Const(c) => s.push(*c),
+                Instr::Op(op) => numeric(&mut s, *op)?,
+            }
+        }
+        Ok(s.0.split_off(s.0.len() - f.returns.len()))
+    }
+
+    fn table_init(&mut self, elem: usize, table: usize, dst: u32, src: u32, n: u32) -> Result<()> {
+        let src = range(self.elements[elem].len(), src, n, Trap::OutOfBoundsTableAccess)?;
+        let dst = range(self.tables[table].0.len(), dst, n, Trap::OutOfBoundsTableAccess)?;
+        self.tables[table].0[dst].copy_from_slice(&self.elements[elem][src]);
+        Ok(())
+    }
+
+    fn memory_init(&mut self, data: usize, dst: u32, src: u32, n: u32) -> Result<()> {
+        let src = range(self.data[data].len(), src, n, Trap::OutOfBoundsMemoryAccess)?;
+        let dst = range(self.memory.len(), dst, n, Trap::OutOfBoundsMemoryAccess)?;
+        self.memory[dst].copy_from_slice(&self.data[data][src]);
+        Ok(())
+    }
+}
+
+/// The position of the `else` or `end` of every block, `else` included.
+fn targets(body: &[Instr]) -> Vec<usize> {
+    let mut targets = vec![0; body.len()];
+    let mut open = vec![];
+    for (pc, instr) in body.iter().enumerate() {
+        match instr {
+            Instr::Block(_) | Instr::Loop(_) | Instr::If(_) => open.push(pc),
+            Instr::Else => {
+                targets[open.pop().unwrap()] = pc;
+                open.push(pc);
+            }
+            // The `end` of the function has no block:
+            Instr::End => if let Some(start) = open.pop() {
+                targets[start] = pc;
+            }
+            _ => {}
+        }
+    }
+    targets
+}
+
+/// Branch to label `l` and return where execution continues.
+fn branch(s: &mut Stack, labels: &mut Vec<Label>, l: u32) -> usize {
+    let idx = labels.len() - 1 - l as usize;
+    let label = &labels[idx];
+    s.0.drain(label.height..s.0.len() - label.arity);
+    let target = label.target;
+    labels.truncate(if label.is_loop { idx + 1 } else { idx });
+    target
+}
+
+/// The `n` entries at `start` of something `len` entries long.
+fn range(len: usize, start: u32, n: u32, trap: Trap) -> Result<std::ops::Range<usize>> {
+    let end = start as usize + n as usize;
+    if end > len {
+        return Err(trap.into());
+    }
+    Ok(start as usize..end)
+}
+
+/// The bytes accessed by a load or store.
+fn address(len: usize, addr: u32, offset: u32, size: usize) -> Result<std::ops::Range<usize>> {
+    let start = addr as usize + offset as usize;
+    if start + size > len {
+        return Err(Trap::OutOfBoundsMemoryAccess.into());
+    }
+    Ok(start..start + size)
+}
+
+/// Grow a table or memory by `n` entries and return the old size.
+fn grow<T: Clone>(v: &mut Vec<T>, max: usize, n: usize, init: T) -> Option<usize> {
+    let old = v.len();
+    if old + n > max || v.try_reserve_exact(n).is_err() {
+        return None;
+    }
+    v.resize(old + n, init);
+    Some(old)
+}
+
+fn nonzero<T: Default + PartialEq>(b: T) -> Result<T> {
+    if b == T::default() {
+        return Err(Trap::IntegerDivideByZero.into());
+    }
+    Ok(b)
+}
+
+/// Truncate a float towards zero, trapping unless the result is in
+/// [min, max).
+fn trunc(x: f64, min: f64, max: f64) -> Result<f64> {
+    if x.is_nan() {
+        return Err(Trap::InvalidConversion.into());
+    }
+    let t = x.trunc();
+    if t < min || t >= max {
+        return Err(Trap::IntegerOverflow.into());
+    }
+    Ok(t)
+}
+
+macro_rules! min_max {
+    ($t:ty, $min:ident, $max:ident) => {
+        /// NaN if either operand is, and -0 is less than 0.
+        fn $min(a: $t, b: $t) -> $t {
+            if a.is_nan() || b.is_nan() {
+                <$t>::NAN
+            } else if a == b {
+                if a.is_sign_negative() { a } else { b }
+            } else {
+                a.min(b)
+            }
+        }
+
+        fn $max(a: $t, b: $t) -> $t {
+            if a.is_nan() || b.is_nan() {
+                <$t>::NAN
+            } else if a == b {
+                if a.is_sign_positive() { a } else { b }
+            } else {
+                a.max(b)
+            }
+        }
+    }
+}
+
+min_max!(f32, f32_min, f32_max);
+min_max!(f64, f64_min, f64_max);
+
+macro_rules! unary {
+    ($s:ident, $t:ty, |$a:ident| $e:expr) => {{
+        let $a: $t = $s.pop();
+        $s.push($e)
+    }}
+}
+
+macro_rules! binary {
+    ($s:ident, $t:ty, |$a:ident, $b:ident| $e:expr) => {{
+        let $b: $t = $s.pop();
+        let $a: $t = $s.pop();
+        $s.push($e)
+    }}
+}
+
+const I32_MIN: f64 = -2147483648.0;
+const I32_END: f64 = 2147483648.0;
+const U32_END: f64 = 4294967296.0;
+const I64_MIN: f64 = -9223372036854775808.0;
+const I64_END: f64 = 9223372036854775808.0;
+const U64_END: f64 = 18446744073709551616.0;
+
+fn numeric(s: &mut Stack, op: Op) -> Result<()> {
+    use Op::*;
+    match op {
+        I32Eqz => unary!(s, i32, |a| a == 0),
+        I32Eq => binary!(s, i32, |a, b| a == b),
+        I32Ne => binary!(s, i32, |a, b| a != b),
+        I32LtS => binary!(s, i32, |a, b| a < b),
+        I32LtU => binary!(s, u32, |a, b| a < b),
+        I32GtS => binary!(s, i32, |a, b| a > b),
+        I32GtU => binary!(s, u32, |a, b| a > b),
+        I32LeS => binary!(s, i32, |a, b| a <= b),
+        I32LeU => binary!(s, u32, |a, b| a <= b),
+        I32GeS => binary!(s, i32, |a, b| a >= b),
+        I32GeU => binary!(s, u32, |a, b| a >= b),
+
+        I64Eqz => unary!(s, i64, |a| a == 0),
+        I64Eq => binary!(s, i64, |a, b| a == b),
+        I64Ne => binary!(s, i64, |a, b| a != b),
+        I64LtS => binary!(s, i64, |a, b| a < b),
+        I64LtU => binary!(s, u64, |a, b| a < b),
+        I64GtS => binary!(s, i64, |a, b| a > b),
+        I64GtU => binary!(s, u64, |a, b| a > b),
+        I64LeS => binary!(s, i64, |a, b| a <= b),
+        I64LeU => binary!(s, u64, |a, b| a <= b),
+        I64GeS => binary!(s, i64, |a, b| a >= b),
+        I64GeU => binary!(s, u64, |a, b| a >= b),
+
+        F32Eq => binary!(s, f32, |a, b| a == b),
+        F32Ne => binary!(s, f32, |a, b| a != b),
+        F32Lt => binary!(s, f32, |a, b| a < b),
+        F32Gt => binary!(s, f32, |a, b| a > b),
+        F32Le => binary!(s, f32, |a, b| a <= b),
+        F32Ge => binary!(s, f32, |a, b| a >= b),
+        F64Eq => binary!(s, f64, |a, b| a == b),
+        F64Ne => binary!(s, f64, |a, b| a != b),
+        F64Lt => binary!(s, f64, |a, b| a < b),
+        F64Gt => binary!(s, f64, |a, b| a > b),
+        F64Le => binary!(s, f64, |a, b| a <= b),
+        F64Ge => binary!(s, f64, |a, b| a >= b),
+
+        I32Clz => unary!(s, i32, |a| a.leading_zeros()),
+        I32Ctz => unary!(s, i32, |a| a.trailing_zeros()),
+        I32Popcnt => unary!(s, i32, |a| a.count_ones()),
+        I32Add => binary!(s, i32, |a, b| a.wrapping_add(b)),
+        I32Sub => binary!(s, i32, |a, b| a.wrapping_sub(b)),
+        I32Mul => binary!(s, i32, |a, b| a.wrapping_mul(b)),
+        I32DivS => binary!(s, i32, |a, b| a.checked_div(nonzero(b)?).ok_or(Trap::IntegerOverflow)?),
+        I32DivU => binary!(s, u32, |a, b| a / nonzero(b)?),
+        I32RemS => binary!(s, i32, |a, b| a.wrapping_rem(nonzero(b)?)),
+        I32RemU => binary!(s, u32, |a, b| a % nonzero(b)?),
+        I32And => binary!(s, i32, |a, b| a & b),
+        I32Or => binary!(s, i32, |a, b| a | b),
+        I32Xor => binary!(s, i32, |a, b| a ^ b),
+        I32Shl => binary!(s, i32, |a, b| a.wrapping_shl(b as u32)),
+        I32ShrS => binary!(s, i32, |a, b| a.wrapping_shr(b as u32)),
+        I32ShrU => binary!(s, u32, |a, b| a.wrapping_shr(b)),
+        I32Rotl => binary!(s, u32, |a, b| a.rotate_left(b)),
+        I32Rotr => binary!(s, u32, |a, b| a.rotate_right(b)),
+
+        I64Clz => unary!(s, i64, |a| a.leading_zeros() as u64),
+        I64Ctz => unary!(s, i64, |a| a.trailing_zeros() as u64),
+        I64Popcnt => unary!(s, i64, |a| a.count_ones() as u64),
+        I64Add => binary!(s, i64, |a, b| a.wrapping_add(b)),
+        I64Sub => binary!(s, i64, |a, b| a.wrapping_sub(b)),
+        I64Mul => binary!(s, i64, |a, b| a.wrapping_mul(b)),
+        I64DivS => binary!(s, i64, |a, b| a.checked_div(nonzero(b)?).ok_or(Trap::IntegerOverflow)?),
+        I64DivU => binary!(s, u64, |a, b| a / nonzero(b)?),
+        I64RemS => binary!(s, i64, |a, b| a.wrapping_rem(nonzero(b)?)),
+        I64RemU => binary!(s, u64, |a, b| a % nonzero(b)?),
+        I64And => binary!(s, i64, |a, b| a & b),
+        I64Or => binary!(s, i64, |a, b| a | b),
+        I64Xor => binary!(s, i64, |a, b| a ^ b),
+        I64Shl => binary!(s, i64, |a, b| a.wrapping_shl(b as u32)),
+        I64ShrS => binary!(s, i64, |a, b| a.wrapping_shr(b as u32)),
+        I64ShrU => binary!(s, u64, |a, b| a.wrapping_shr(b as u32)),
+        I64Rotl => binary!(s, u64, |a, b| a.rotate_left((b % 64) as u32)),
+        I64Rotr => binary!(s, u64, |a, b| a.rotate_right((b % 64) as u32)),
+
+        F32Abs => unary!(s, f32, |a| a.abs()),
+        F32Neg => unary!(s, f32, |a| -a),
+        F32Ceil => unary!(s, f32, |a| a.ceil()),
+        F32Floor => unary!(s, f32, |a| a.floor()),
+        F32Trunc => unary!(s, f32, |a| a.trunc()),
+        F32Nearest => unary!(s, f32, |a| a.round_ties_even()),
+        F32Sqrt => unary!(s, f32, |a| a.sqrt()),
+        F32Add => binary!(s, f32, |a, b| a + b),
+        F32Sub => binary!(s, f32, |a, b| a - b),
+        F32Mul => binary!(s, f32, |a, b| a * b),
+        F32Div => binary!(s, f32, |a, b| a / b),
+        F32Min => binary!(s, f32, |a, b| f32_min(a, b)),
+        F32Max => binary!(s, f32, |a, b| f32_max(a, b)),
+        F32Copysign => binary!(s, f32, |a, b| a.copysign(b)),
+
+        F64Abs => unary!(s, f64, |a| a.abs()),
+        F64Neg => unary!(s, f64, |a| -a),
+        F64Ceil => unary!(s, f64, |a| a.ceil()),
+        F64Floor => unary!(s, f64, |a| a.floor()),
+        F64Trunc => unary!(s, f64, |a| a.trunc()),
+        F64Nearest => unary!(s, f64, |a| a.round_ties_even()),
+        F64Sqrt => unary!(s, f64, |a| a.sqrt()),
+        F64Add => binary!(s, f64, |a, b| a + b),
+        F64Sub => binary!(s, f64, |a, b| a - b),
+        F64Mul => binary!(s, f64, |a, b| a * b),
+        F64Div => binary!(s, f64, |a, b| a / b),
+        F64Min => binary!(s, f64, |a, b| f64_min(a, b)),
+        F64Max => binary!(s, f64, |a, b| f64_max(a, b)),
+        F64Copysign => binary!(s, f64, |a, b| a.copysign(b)),
+
+        I32WrapI64 => unary!(s, i64, |a| a as i32),
+        I32TruncF32S => unary!(s, f32, |a| trunc(a as f64, I32_MIN, I32_END)? as i32),
+        I32TruncF32U => unary!(s, f32, |a| trunc(a as f64, 0.0, U32_END)? as u32),
+        I32TruncF64S => unary!(s, f64, |a| trunc(a, I32_MIN, I32_END)? as i32),
+        I32TruncF64U => unary!(s, f64, |a| trunc(a, 0.0, U32_END)? as u32),
+        I64ExtendI32S => unary!(s, i32, |a| a as i64),
+        I64ExtendI32U => unary!(s, u32, |a| a as u64),
+        I64TruncF32S => unary!(s, f32, |a| trunc(a as f64, I64_MIN, I64_END)? as i64),
+        I64TruncF32U => unary!(s, f32, |a| trunc(a as f64, 0.0, U64_END)? as u64),
+        I64TruncF64S => unary!(s, f64, |a| trunc(a, I64_MIN, I64_END)? as i64),
+        I64TruncF64U => unary!(s, f64, |a| trunc(a, 0.0, U64_END)? as u64),
+        F32ConvertI32S => unary!(s, i32, |a| a as f32),
+        F32ConvertI32U => unary!(s, u32, |a| a as f32),
+        F32ConvertI64S => unary!(s, i64, |a| a as f32),
+        F32ConvertI64U => unary!(s, u64, |a| a as f32),
+        F32DemoteF64 => unary!(s, f64, |a| a as f32),
+        F64ConvertI32S => unary!(s, i32, |a| a as f64),
+        F64ConvertI32U => unary!(s, u32, |a| a as f64),
+        F64ConvertI64S => unary!(s, i64, |a| a as f64),
+        F64ConvertI64U => unary!(s, u64, |a| a as f64),
+        F64PromoteF32 => unary!(s, f32, |a| a as f64),
+        // The bit patterns stay the same:
+        I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32 | F64ReinterpretI64 => {}
+
+        I32Extend8S => unary!(s, i32, |a| a as i8 as i32),
+        I32Extend16S => unary!(s, i32, |a| a as i16 as i32),
+        I64Extend8S => unary!(s, i64, |a| a as i8 as i64),
+        I64Extend16S => unary!(s, i64, |a| a as i16 as i64),
+        I64Extend32S => unary!(s, i64, |a| a as i32 as i64),
+
+        // Rust casts saturate, and turn NaN into 0:
+        I32TruncSatF32S => unary!(s, f32, |a| a as i32),
+        I32TruncSatF32U => unary!(s, f32, |a| a as u32),
+        I32TruncSatF64S => unary!(s, f64, |a| a as i32),
+        I32TruncSatF64U => unary!(s, f64, |a| a as u32),
+        I64TruncSatF32S => unary!(s, f32, |a| a as i64),
+        I64TruncSatF32U => unary!(s, f32, |a| a as u64),
+        I64TruncSatF64S => unary!(s, f64, |a| a as i64),
+        I64TruncSatF64U => unary!(s, f64, |a| a as u64),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{code, module, name, vec};
+    use crate::wasi::tests::Output;
+
+    fn exports(names: &[&str]) -> Vec<u8> {
+        let exports: Vec<Vec<u8>> = names.iter().enumerate()
+            .map(|(i, n)| [name(n), vec![0x00, i as u8]].concat())
+            .collect();
+        vec(&exports)
+    }
+
+    fn trap(res: Result<Vec<u64>>) -> Trap {
+        match res {
+            Err(Error::Trap(trap)) => trap,
+            res => panic!("expected a trap, got {:?}", res)
+        }
+    }
+
+    #[test]
+    fn control_flow() {
+        let fac = [
+            0x20, 0, 0x50,                      // n == 0
+            0x04, 0x7e, 0x42, 1,                // if (result i64) 1
+            0x05, 0x20, 0, 0x20, 0, 0x42, 1, 0x7d, 0x10, 0, 0x7e, // else n * fac(n - 1)
+            0x0b, 0x0b,
+        ];
+        let switch = [
+            0x02, 0x40, 0x02, 0x40, 0x02, 0x40,
+            0x20, 0, 0x0e, 2, 0, 1, 2,          // br_table 0 1 2
+            0x0b, 0x41, 10, 0x0f,               // case 0: return 10
+            0x0b, 0x41, 20, 0x0f,               // case 1: return 20
+            0x0b, 0x41, 30, 0x0b,               // default: 30
+        ];
+        let clamp = [
+            0x20, 0, 0x41, 0xe4, 0x00, 0x4a,    // n > 100
+            0x04, 0x40, 0x41, 0xe4, 0x00, 0x21, 0, 0x0b, // if n = 100
+            0x20, 0, 0x0b,
+        ];
+        let sum = [
+            0x03, 0x40,                         // loop
+            0x20, 1, 0x20, 0, 0x6a, 0x21, 1,    // acc += n
+            0x20, 0, 0x41, 1, 0x6b, 0x22, 0,    // n -= 1
+            0x0d, 0,                            // br_if 0 (n != 0)
+            0x0b, 0x20, 1, 0x0b,
+        ];
+        // The 1 below the branch operand is dropped:
+        let carry = [0x02, 0x7f, 0x41, 1, 0x41, 2, 0x0c, 0, 0x0b, 0x0b];
+        let bytes = module(&[
+            (1, vec(&[vec![0x60, 1, 0x7e, 1, 0x7e], vec![0x60, 1, 0x7f, 1, 0x7f]])),
+            (3, vec(&[vec![0], vec![1], vec![1], vec![1], vec![1]])),
+            (7, exports(&["fac", "switch", "clamp", "sum", "carry"])),
+            (10, vec(&[
+                code(&[0], &fac), code(&[0], &switch), code(&[0], &clamp),
+                code(&[1, 1, 0x7f], &sum), code(&[0], &carry),
+            ])),
+        ]);
+        let m = Module::decode(&bytes).unwrap();
+        let mut interp = Interpreter::new(&m, wasi::Wasi::default()).unwrap();
+        assert_eq!(interp.call("fac", &[10]).unwrap(), vec![3628800]);
+        let switch: Vec<u64> = [0, 1, 2, 7].iter().map(|n| interp.call("switch", &[*n]).unwrap()[0]).collect();
+        assert_eq!(switch, vec![10, 20, 30, 30]);
+        assert_eq!(interp.call("clamp", &[5]).unwrap(), vec![5]);
+        assert_eq!(interp.call("clamp", &[500]).unwrap(), vec![100]);
+        assert_eq!(interp.call("sum", &[100]).unwrap(), vec![5050]);
+        assert_eq!(interp.call("carry", &[0]).unwrap(), vec![2]);
+        assert!(matches!(interp.call("missing", &[]), Err(Error::Link(_))));
+        assert!(matches!(interp.call("fac", &[]), Err(Error::Link(_))));
+    }
+
+    #[test]
+    fn traps_and_memory() {
+        let fill_copy = [
+            0x41, 0, 0x41, 0xab, 0x01, 0x41, 4, 0xfc, 0x0b, 0, // memory.fill 0 0xab 4
+            0x41, 8, 0x41, 0, 0x41, 4, 0xfc, 0x0a, 0, 0,       // memory.copy 8 0 4
+            0x41, 8, 0x28, 2, 0, 0x0b,                         // i32.load 8
+        ];
+        let bytes = module(&[
+            (1, vec(&[vec![0x60, 2, 0x7f, 0x7f, 1, 0x7f], vec![0x60, 1, 0x7f, 1, 0x7f], vec![0x60, 0, 0]])),
+            (3, vec(&[vec![0], vec![1], vec![1], vec![2], vec![1], vec![1], vec![2]])),
+            (4, vec(&[vec![0x70, 0x00, 3]])),
+            (5, vec(&[vec![0x01, 1, 2]])),
+            (7, exports(&["div", "load", "dispatch", "recurse", "grow", "fill_copy", "unreachable"])),
+            (9, vec(&[vec![0x00, 0x41, 0, 0x0b, 2, 1, 0]])),
+            (10, vec(&[
+                code(&[0], &[0x20, 0, 0x20, 1, 0x6d, 0x0b]),
+                code(&[0], &[0x20, 0, 0x28, 2, 0, 0x0b]),
+                code(&[0], &[0x20, 0, 0x20, 0, 0x11, 1, 0, 0x0b]),
+                code(&[0], &[0x10, 3, 0x0b]),
+                code(&[0], &[0x20, 0, 0x40, 0, 0x0b]),
+                code(&[0], &fill_copy),
+                code(&[0], &[0x00, 0x0b]),
+            ])),
+        ]);
+        let m = Module::decode(&bytes).unwrap();
+        let mut interp = Interpreter::new(&m, wasi::Wasi::default()).unwrap();
+        let minus_one = -1i32 as u32 as u64;
+
+        assert_eq!(interp.call("div", &[7, 2]).unwrap(), vec![3]);
+        assert_eq!(trap(interp.call("div", &[1, 0])), Trap::IntegerDivideByZero);
+        assert_eq!(trap(interp.call("div", &[i32::MIN as u32 as u64, minus_one])), Trap::IntegerOverflow);
+        assert_eq!(interp.call("load", &[65532]).unwrap(), vec![0]);
+        assert_eq!(trap(interp.call("load", &[65533])), Trap::OutOfBoundsMemoryAccess);
+
+        assert_eq!(interp.call("dispatch", &[0]).unwrap(), vec![0]);
+        assert_eq!(trap(interp.call("dispatch", &[1])), Trap::IndirectCallTypeMismatch);
+        assert_eq!(trap(interp.call("dispatch", &[2])), Trap::UninitializedElement);
+        assert_eq!(trap(interp.call("dispatch", &[3])), Trap::UndefinedElement);
+        assert_eq!(trap(interp.call("recurse", &[])), Trap::CallStackExhausted);
+        assert_eq!(trap(interp.call("unreachable", &[])), Trap::Unreachable);
+
+        assert_eq!(interp.call("fill_copy", &[0]).unwrap(), vec![0xabababab]);
+        assert_eq!(interp.call("grow", &[1]).unwrap(), vec![1]);
+        assert_eq!(interp.call("grow", &[1]).unwrap(), vec![minus_one]);
+        assert_eq!(interp.memory().len(), 2 * PAGE_SIZE);
+        assert_eq!(interp.call("load", &[65533]).unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn wasi() {
+        let m = Module::decode(&crate::runtime::tests::hello()).unwrap();
+        let out = Output::default();
+        let mut wasi = wasi::Wasi::default();
+        wasi.stdout = Box::new(out.clone());
+        let mut interp = Interpreter::new(&m, wasi).unwrap();
+        assert_eq!(interp.run().unwrap(), 0);
+        assert_eq!(out.0.borrow().as_slice(), b"hello\n");
+
+        let bytes = module(&[
+            (1, vec(&[vec![0x60, 1, 0x7f, 0], vec![0x60, 0, 0]])),
+            (2, vec(&[[name("wasi_snapshot_preview1"), name("proc_exit"), vec![0x00, 0]].concat()])),
+            (3, vec(&[vec![1]])),
+            (7, vec(&[[name("_start"), vec![0x00, 1]].concat()])),
+            (10, vec(&[code(&[0], &[0x41, 3, 0x10, 0, 0x00, 0x0b])])),
+        ]);
+        let m = Module::decode(&bytes).unwrap();
+        let mut interp = Interpreter::new(&m, wasi::Wasi::default()).unwrap();
+        assert_eq!(interp.run().unwrap(), 3);
+    }
+
+    /// Compare the results of the interpreter and the generated code for the
+    /// binary operators, on inputs that do not trap.
+    #[cfg(feature = "jit")]
+    #[test]
+    fn compiled_and_interpreted() {
+        // (opcode, type index), i32 operators wrap their operands and
+        // extend their result.
+        let mut ops: Vec<(u8, usize)> = vec![];
+        ops.extend((0x46..=0x4f).chain(0x6a..=0x78).map(|op| (op, 0)));
+        ops.extend((0x51..=0x5a).chain(0x7c..=0x8a).map(|op| (op, 1)));
+        ops.extend((0x92..=0x98).map(|op| (op, 2)));
+        ops.extend((0xa0..=0xa6).map(|op| (op, 3)));
+        let body = |op: u8, typ: usize| match typ {
+            0 => vec![0x20, 0, 0xa7, 0x20, 1, 0xa7, op, 0xad, 0x0b],
+            1 if op <= 0x5a => vec![0x20, 0, 0x20, 1, op, 0xad, 0x0b],
+            1 | 3 => vec![0x20, 0, 0x20, 1, op, 0x0b],
+            _ => vec![0x20, 0, 0xb6, 0x20, 1, 0xb6, op, 0xbb, 0x0b],
+        };
+        let names: Vec<String> = ops.iter().map(|(op, _)| format!("op{:x}", op)).collect();
+        let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+        let bytes = module(&[
+            (1, vec(&[vec![0x60, 2, 0x7e, 0x7e, 1, 0x7e], vec![0x60, 2, 0x7c, 0x7c, 1, 0x7c]])),
+            (3, vec(&ops.iter().map(|(_, typ)| vec![(*typ >= 2) as u8]).collect::<Vec<_>>())),
+            (7, exports(&names)),
+            (10, vec(&ops.iter().map(|(op, typ)| code(&[0], &body(*op, *typ))).collect::<Vec<_>>())),
+        ]);
+        let m = Module::decode(&bytes).unwrap();
+        let mut interp = Interpreter::new(&m, wasi::Wasi::default()).unwrap();
+        let jit = crate::Jit::compile(&m).unwrap();
+        unsafe { jit.init() };
+
+        let ints: Vec<u64> = [0, 1, 2, 7, -1, -7, 31, 32, 33, 63, 64, i32::MAX as i64, i32::MIN as i64,
+                              u32::MAX as i64, i64::MAX, i64::MIN, 0x123456789abcdef]
+            .iter().map(|n| *n as u64).collect();
+        let floats: Vec<u64> = [0.0, -0.0, 1.5, -2.25, 1e300, -1e-300, f64::INFINITY, f64::NAN, f32::MAX as f64]
+            .iter().map(|x| f64::to_bits(*x)).collect();
+        for ((op, typ), name) in ops.iter().zip(&names) {
+            let f = jit.export(name).unwrap();
+            let inputs = if *typ >= 2 { &floats } else { &ints };
+            for a in inputs {
+                for b in inputs {
+                    let Ok(expected) = interp.call(name, &[*a, *b]) else { continue };
+                    let got = unsafe {
+                        if *typ >= 2 {
+                            let f: extern "C" fn(f64, f64) -> f64 = std::mem::transmute(f);
+                            f(f64::from_bits(*a), f64::from_bits(*b)).to_bits()
+                        } else {
+                            let f: extern "C" fn(u64, u64) -> u64 = std::mem::transmute(f);
+                            f(*a, *b)
+                        }
+                    };
+                    let nan = |bits: u64| *typ >= 2 && f64::from_bits(bits).is_nan();
+                    assert!(got == expected[0] || (nan(got) && nan(expected[0])),
+                        "{:x} {:x} {:x}: {:x} != {:x}", op, a, b, got, expected[0]);
+                }
+            }
+        }
+    }
+}
@@ -1,8 +1,22 @@
 #![feature(buf_read_has_data_left)]
 
+#[cfg(feature = "jit")]
+mod codegen;
+mod interp;
+mod runtime;
+mod wasi;
+mod wat;
+
+#[cfg(feature = "jit")]
+pub use codegen::{compile_to_file, Jit};
+pub use interp::Interpreter;
+#[cfg(feature = "jit")]
+pub use runtime::Instance;
+pub use runtime::{link, Caller, Exit, HostFunc};
+pub use wasi::Wasi;
+
 #[allow(clippy::upper_case_acronyms)]
-#[allow(dead_code)]
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 #[repr(u8)]
 enum SectionID {
 	CUSTOM     = 0,
@@ -35,170 +49,1413 @@ impl std::fmt::Display for SectionID {
 
 impl std::convert::TryFrom<u8> for SectionID {
     type Error = ();
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        if value < 12 {
-            return Err(());
+    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+        use SectionID::*;
+        Ok(match value {
+            0 => CUSTOM,   1 => TYPE,     2 => IMPORT,   3 => FUNCTION,
+            4 => TABLE,    5 => MEMORY,   6 => GLOBAL,   7 => EXPORT,
+            8 => START,    9 => ELEMENT, 10 => CODE,    11 => DATA,
+            12 => DATACOUNT,
+            _ => return Err(())
+        })
+    }
+}
+
+impl SectionID {
+    /// The position in which the (non-custom) sections have to appear, the
+    /// data count section was added later and comes before the code.
+    fn order(self) -> u8 {
+        match self {
+            SectionID::DATACOUNT => SectionID::ELEMENT as u8 * 2 + 1,
+            id => id as u8 * 2,
+        }
+    }
+}
+
+/// Errors decoding, translating, linking or running a module.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Invalid(usize, String),
+    /// A valid module using something the translation does not handle yet.
+    Unsupported(String),
+    /// libgccjit failed to compile the generated code.
+    Compile(String),
+    /// An import that cannot be resolved.
+    Link(String),
+    /// Execution of the module trapped.
+    Trap(Trap),
+    /// The module called `proc_exit`.
+    Exit(i32),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Invalid(offset, msg) => write!(f, "at offset 0x{:x}: {}", offset, msg),
+            Error::Unsupported(what) => write!(f, "unsupported: {}", what),
+            Error::Compile(msg) => write!(f, "libgccjit: {}", msg),
+            Error::Link(msg) => write!(f, "link: {}", msg),
+            Error::Trap(trap) => write!(f, "trap: {}", trap),
+            Error::Exit(code) => write!(f, "exit status {}", code),
         }
-        unsafe { std::mem::transmute(value) }
     }
 }
 
-#[allow(dead_code)]
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<Trap> for Error {
+    fn from(trap: Trap) -> Self {
+        Error::Trap(trap)
+    }
+}
+
+impl From<Exit> for Error {
+    fn from(Exit(code): Exit) -> Self {
+        Error::Exit(code)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The reasons execution of a module can be aborted, the discriminant is
+/// what the generated code passes to the trap handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum Trap {
+    Unreachable = 0,
+    IntegerDivideByZero = 1,
+    IntegerOverflow = 2,
+    InvalidConversion = 3,
+    OutOfBoundsMemoryAccess = 4,
+    OutOfBoundsTableAccess = 5,
+    UndefinedElement = 6,
+    IndirectCallTypeMismatch = 7,
+    UninitializedElement = 8,
+    /// Only raised by the interpreter, the generated code simply overflows
+    /// the native stack.
+    CallStackExhausted = 9,
+}
+
+impl Trap {
+    pub fn from_code(code: i32) -> Option<Trap> {
+        use Trap::*;
+        [Unreachable, IntegerDivideByZero, IntegerOverflow, InvalidConversion,
+         OutOfBoundsMemoryAccess, OutOfBoundsTableAccess, UndefinedElement,
+         IndirectCallTypeMismatch, UninitializedElement, CallStackExhausted].get(code as usize).copied()
+    }
+}
+
+impl std::fmt::Display for Trap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Trap::Unreachable => "unreachable",
+            Trap::IntegerDivideByZero => "integer divide by zero",
+            Trap::IntegerOverflow => "integer overflow",
+            Trap::InvalidConversion => "invalid conversion to integer",
+            Trap::OutOfBoundsMemoryAccess => "out of bounds memory access",
+            Trap::OutOfBoundsTableAccess => "out of bounds table access",
+            Trap::UndefinedElement => "undefined element",
+            Trap::IndirectCallTypeMismatch => "indirect call type mismatch",
+            Trap::UninitializedElement => "uninitialized element",
+            Trap::CallStackExhausted => "call stack exhausted",
+        })
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
-enum Type {
+pub enum Type {
     I32,
     I64,
     F32,
     F64,
+    FuncRef,
+    ExternRef,
     Func(Vec<Type>, Vec<Type>)
 }
 
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::I32 => f.write_str("i32"),
+            Type::I64 => f.write_str("i64"),
+            Type::F32 => f.write_str("f32"),
+            Type::F64 => f.write_str("f64"),
+            Type::FuncRef => f.write_str("funcref"),
+            Type::ExternRef => f.write_str("externref"),
+            Type::Func(args, rets) => {
+                f.write_str("(func")?;
+                for arg in args {
+                    write!(f, " (param {})", arg)?;
+                }
+                for ret in rets {
+                    write!(f, " (result {})", ret)?;
+                }
+                f.write_str(")")
+            }
+        }
+    }
+}
+
 impl Type {
-    fn parse(r: &mut dyn std::io::BufRead) -> std::io::Result<Type> {
-        let mut buf = [0u8; 1];
-        r.read_exact(&mut buf)?;
-        match buf[0] {
+    fn parse(r: &mut Reader) -> Result<Type> {
+        let offset = r.pos;
+        match r.byte()? {
             0x60 => {
-                let mut pair: [Option<Vec<Type>>; 2] = [None, None];
-                for i in 0..2 {
-                    let n = read_unsigned_leb128(r)?.0 as usize;
-                    let mut v = vec![];
-                    for _ in 0..n {
-                        v.push(Self::parse(r)?);
-                    }
-                    pair[i] = Some(v);
-                }
-                Ok(Type::Func(pair[0].take().unwrap(), pair[1].take().unwrap()))
+                let args = r.vec(Self::parse_value)?;
+                let rets = r.vec(Self::parse_value)?;
+                Ok(Type::Func(args, rets))
             }
-            0x7F => Ok(Type::I32),
-            0x7E => Ok(Type::I64),
-            0x7D => Ok(Type::F32),
-            0x7C => Ok(Type::F64),
-            b => Err(std::io::Error::other(format!("unknown type: {:?}", b)))
+            b => Self::from_byte(b).ok_or_else(|| Error::Invalid(offset, format!("unknown type: 0x{:x}", b)))
+        }
+    }
+
+    fn parse_value(r: &mut Reader) -> Result<Type> {
+        let offset = r.pos;
+        let b = r.byte()?;
+        Self::from_byte(b).ok_or_else(|| Error::Invalid(offset, format!("unknown value type: 0x{:x}", b)))
+    }
+
+    fn parse_ref(r: &mut Reader) -> Result<Type> {
+        let offset = r.pos;
+        match r.byte()? {
+            0x70 => Ok(Type::FuncRef),
+            0x6F => Ok(Type::ExternRef),
+            b => Err(Error::Invalid(offset, format!("unknown reference type: 0x{:x}", b)))
         }
     }
+
+    pub fn is_ref(&self) -> bool {
+        matches!(self, Type::FuncRef | Type::ExternRef)
+    }
+
+    /// The number types by their name in instruction names.
+    fn from_name(name: &str) -> Option<Type> {
+        match name {
+            "i32" => Some(Type::I32),
+            "i64" => Some(Type::I64),
+            "f32" => Some(Type::F32),
+            "f64" => Some(Type::F64),
+            _ => None
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<Type> {
+        match b {
+            0x7F => Some(Type::I32),
+            0x7E => Some(Type::I64),
+            0x7D => Some(Type::F32),
+            0x7C => Some(Type::F64),
+            0x70 => Some(Type::FuncRef),
+            0x6F => Some(Type::ExternRef),
+            _ => None
+        }
+    }
+}
+
+/// Minimum and optional maximum size, in pages for memories.
+pub type Limits = (usize, Option<usize>);
+
+pub const PAGE_SIZE: usize = 65536;
+pub(crate) const MAX_PAGES: usize = 65536;
+
+fn parse_limits(r: &mut Reader) -> Result<Limits> {
+    let offset = r.pos;
+    let limits = match r.byte()? {
+        0x00 => (r.u32()? as usize, None),
+        0x01 => (r.u32()? as usize, Some(r.u32()? as usize)),
+        b => return Err(Error::Invalid(offset, format!("expected 0x00 or 0x01 before limits, not 0x{:x}", b)))
+    };
+    if limits.1.is_some_and(|max| max < limits.0) {
+        return Err(Error::Invalid(offset, "size minimum must not be greater than maximum".to_string()));
+    }
+    Ok(limits)
 }
 
-#[allow(dead_code)]
 #[derive(Debug, Clone, PartialEq)]
-struct Function {
-    index: usize,
-    size: usize,
-    arguments: Vec<Type>,
-    returns: Vec<Type>,
-    locals: Vec<(usize, Type)>,
-    body: Vec<Instr>
+pub struct Function {
+    /// The index in the function index space (imported functions come first).
+    pub index: usize,
+    pub size: usize,
+    pub arguments: Vec<Type>,
+    pub returns: Vec<Type>,
+    /// Runs of locals: (count, type).
+    pub locals: Vec<(usize, Type)>,
+    pub body: Vec<Instr>
 }
 
-#[allow(dead_code)]
-#[derive(Debug)]
-enum Export { Func, Table, Memory, Global }
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Export { Func, Table, Memory, Global }
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportDesc {
+    Func(usize),
+    Table(Type, Limits),
+    Memory(Limits),
+    Global(Type, bool),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Import {
+    pub module: String,
+    pub name: String,
+    pub desc: ImportDesc,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ElementMode {
+    Passive,
+    Active(usize, Vec<Instr>),
+    Declarative,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ElementInit {
+    Funcs(Vec<usize>),
+    Exprs(Vec<Vec<Instr>>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Element {
+    pub mode: ElementMode,
+    pub typ: Type,
+    pub init: ElementInit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DataMode {
+    Passive,
+    Active(usize, Vec<Instr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Data {
+    pub mode: DataMode,
+    pub bytes: Vec<u8>,
+}
 
-#[allow(dead_code)]
 #[derive(Debug)]
 pub struct Module {
-    version: u32,
-    function_types: Vec<(Vec<Type>, Vec<Type>)>,
-    function_type_indexes: Vec<usize>,
-    memory_ranges: Vec<(usize, Option<usize>)>,
-    custom_sections: Vec<(usize, String, Vec<u8>)>,
-    globals: Vec<(Type, bool, Vec<Instr>)>,
-    exports: Vec<(String, Export, usize)>,
-    functions: Vec<Function>
+    pub version: u32,
+    pub function_types: Vec<(Vec<Type>, Vec<Type>)>,
+    pub imports: Vec<Import>,
+    /// Type indexes of the functions defined in the module (not imported).
+    pub function_type_indexes: Vec<usize>,
+    pub tables: Vec<(Type, Limits)>,
+    pub memory_ranges: Vec<Limits>,
+    pub custom_sections: Vec<(usize, String, Vec<u8>)>,
+    pub globals: Vec<(Type, bool, Vec<Instr>)>,
+    pub exports: Vec<(String, Export, usize)>,
+    pub start: Option<usize>,
+    pub elements: Vec<Element>,
+    pub data_count: Option<usize>,
+    pub data: Vec<Data>,
+    pub functions: Vec<Function>
+}
+
+/*
+ * The module is decoded from memory, which makes it easy to report errors
+ * with the offset they occured at and to check section and function sizes.
+ */
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn err<T>(&self, msg: impl Into<String>) -> Result<T> {
+        Err(Error::Invalid(self.pos, msg.into()))
+    }
+
+    fn eof(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        match self.bytes.get(self.pos) {
+            Some(b) => {
+                self.pos += 1;
+                Ok(*b)
+            }
+            None => self.err("unexpected end of input")
+        }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() - self.pos < n {
+            return self.err("unexpected end of input");
+        }
+        self.pos += n;
+        Ok(&self.bytes[self.pos - n..self.pos])
+    }
+
+    fn unsigned(&mut self, bits: u32) -> Result<u64> {
+        let offset = self.pos;
+        let mut res: u64 = 0;
+        let mut shift: u32 = 0;
+        loop {
+            let b = self.byte()?;
+            if shift >= bits || (bits - shift < 7 && (b & 0x7f) >> (bits - shift) != 0) {
+                return Err(Error::Invalid(offset, "integer too large".to_string()));
+            }
+            res |= (b as u64 & 0x7f) << shift;
+            if (b & 0x80) == 0 {
+                return Ok(res)
+            }
+            shift += 7;
+        }
+    }
+
+    fn signed(&mut self, bits: u32) -> Result<i64> {
+        let offset = self.pos;
+        let mut res: i64 = 0;
+        let mut shift: u32 = 0;
+        loop {
+            let b = self.byte()?;
+            if shift >= bits {
+                return Err(Error::Invalid(offset, "integer too large".to_string()));
+            }
+            if bits - shift < 7 {
+                // The unused bits have to be a sign extension of the last used one:
+                let unused = (b as i8) << 1 >> (bits - shift);
+                if b & 0x80 != 0 || (unused != 0 && unused != -1) {
+                    return Err(Error::Invalid(offset, "integer too large".to_string()));
+                }
+            }
+            res |= (b as i64 & 0x7f) << shift;
+            shift += 7;
+            if (b & 0x80) == 0 {
+                return Ok(if b & 0x40 != 0 && shift < 64 {
+                    res | (-1 << shift)
+                } else {
+                    res
+                })
+            }
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(self.unsigned(32)? as u32)
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        let offset = self.pos;
+        let bytes = self.bytes(len)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| Error::Invalid(offset, "malformed UTF-8 encoding".to_string()))
+    }
+
+    fn vec<T>(&mut self, mut f: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+        let n = self.u32()? as usize;
+        // Do not trust n for the allocation, every element is at least a byte:
+        let mut v = Vec::with_capacity(n.min(self.bytes.len() - self.pos));
+        for _ in 0..n {
+            v.push(f(self)?);
+        }
+        Ok(v)
+    }
 }
 
 impl Module {
-    pub fn parse(r: &mut dyn std::io::BufRead) -> std::io::Result<Module> {
-        let mut buf = [0x0u8; 8];
-        r.read_exact(&mut buf)?;
-        if buf != [b'\0', b'a', b's', b'm', 1u8, 0u8, 0u8, 0u8] {
-            return Err(std::io::Error::other("bad magic number or version"))
+    pub fn parse(r: &mut dyn std::io::BufRead) -> Result<Module> {
+        let mut bytes = vec![];
+        while r.has_data_left()? {
+            let buf = r.fill_buf()?;
+            bytes.extend_from_slice(buf);
+            let n = buf.len();
+            r.consume(n);
+        }
+        Self::decode(&bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Module> {
+        let mut r = Reader { bytes, pos: 0 };
+        if r.bytes(4).ok() != Some(b"\0asm") {
+            return Err(Error::Invalid(0, "bad magic number".to_string()))
+        }
+        let version = u32::from_le_bytes(r.bytes(4)?.try_into().unwrap());
+        if version != 1 {
+            return Err(Error::Invalid(4, format!("unsupported version: {}", version)))
         }
 
         let mut m = Module {
-            version: 1,
+            version,
             function_types: vec![],
+            imports: vec![],
             function_type_indexes: vec![],
+            tables: vec![],
             memory_ranges: vec![],
             custom_sections: vec![],
             globals: vec![],
             exports: vec![],
+            start: None,
+            elements: vec![],
+            data_count: None,
+            data: vec![],
             functions: vec![]
         };
 
-        while r.has_data_left()? {
-            m.parse_section(r)?
+        let mut last: Option<SectionID> = None;
+        let mut code_seen = false;
+        while !r.eof() {
+            let offset = r.pos;
+            let id = r.byte()?;
+            let id = SectionID::try_from(id)
+                .map_err(|_| Error::Invalid(offset, format!("unknown section ID {}", id)))?;
+            if id != SectionID::CUSTOM {
+                if last.is_some_and(|last| last.order() >= id.order()) {
+                    return Err(Error::Invalid(offset, format!("unexpected {} section", id)));
+                }
+                last = Some(id);
+            }
+            code_seen |= id == SectionID::CODE;
+
+            let size = r.u32()? as usize;
+            let start = r.pos;
+            let end = start.checked_add(size).filter(|end| *end <= bytes.len())
+                .ok_or_else(|| Error::Invalid(offset, "section extends past the end of the module".to_string()))?;
+            let mut section = Reader { bytes: &bytes[..end], pos: start };
+            m.parse_section(id, &mut section)?;
+            if section.pos != end {
+                return Err(Error::Invalid(section.pos, format!("{} section size mismatch", id)));
+            }
+            r.pos = end;
         }
 
+        if !code_seen && !m.function_type_indexes.is_empty() {
+            return Err(Error::Invalid(r.pos, "function and code section have inconsistent lengths".to_string()));
+        }
+        if m.data_count.is_some_and(|n| n != m.data.len()) {
+            return Err(Error::Invalid(r.pos, "data count and data section have inconsistent lengths".to_string()));
+        }
         Ok(m)
     }
 
-    fn parse_section(&mut self, r: &mut dyn std::io::BufRead) -> std::io::Result<()> {
-        let mut buf = [0x0u8; 1];
-        r.read_exact(&mut buf[0..1])?;
-        let size = read_unsigned_leb128(r)?.0 as usize;
+    /// Number of functions including the imported ones.
+    pub fn num_functions(&self) -> usize {
+        self.imported(|d| matches!(d, ImportDesc::Func(_))) + self.function_type_indexes.len()
+    }
+
+    pub fn num_globals(&self) -> usize {
+        self.imported(|d| matches!(d, ImportDesc::Global(..))) + self.globals.len()
+    }
 
-        if buf[0] == (SectionID::CUSTOM as u8) {
-            let (key_len, n) = read_unsigned_leb128(r)?;
-            let mut key_buf = vec![0u8; key_len as usize];
-            r.read_exact(&mut key_buf[0..key_len as usize])?;
-            let val_len = size - (n + key_len as usize);
-            let mut val_buf = vec![0u8; val_len];
-            r.read_exact(&mut val_buf[0..val_len])?;
-            self.custom_sections.push((size,
-                String::from_utf8(key_buf)
-                    .map_err(std::io::Error::other)?, val_buf));
-            return Ok(())
+    pub fn num_tables(&self) -> usize {
+        self.imported(|d| matches!(d, ImportDesc::Table(..))) + self.tables.len()
+    }
+
+    pub fn num_memories(&self) -> usize {
+        self.imported(|d| matches!(d, ImportDesc::Memory(_))) + self.memory_ranges.len()
+    }
+
+    fn imported(&self, f: impl Fn(&ImportDesc) -> bool) -> usize {
+        self.imports.iter().filter(|i| f(&i.desc)).count()
+    }
+
+    /// The type index of a function from the function index space.
+    pub fn function_type_index(&self, func: usize) -> Option<usize> {
+        let imported = self.imports.iter().filter_map(|i| match i.desc {
+            ImportDesc::Func(t) => Some(t),
+            _ => None
+        });
+        imported.chain(self.function_type_indexes.iter().copied()).nth(func)
+    }
+
+    /// The type of a global from the global index space and whether it is mutable.
+    pub fn global_type(&self, global: usize) -> Option<(Type, bool)> {
+        let imported = self.imports.iter().filter_map(|i| match &i.desc {
+            ImportDesc::Global(t, m) => Some((t.clone(), *m)),
+            _ => None
+        });
+        imported.chain(self.globals.iter().map(|(t, m, _)| (t.clone(), *m))).nth(global)
+    }
+
+    /// The element type of a table from the table index space.
+    pub fn table_type(&self, table: usize) -> Option<Type> {
+        let imported = self.imports.iter().filter_map(|i| match &i.desc {
+            ImportDesc::Table(t, _) => Some(t.clone()),
+            _ => None
+        });
+        imported.chain(self.tables.iter().map(|(t, _)| t.clone())).nth(table)
+    }
+
+    /// The parameter and result types of a block.
+    pub fn block_type(&self, bt: &BlockType) -> (Vec<Type>, Vec<Type>) {
+        match bt {
+            BlockType::Empty => (vec![], vec![]),
+            BlockType::Value(t) => (vec![], vec![t.clone()]),
+            BlockType::Type(idx) => self.function_types[*idx as usize].clone(),
         }
+    }
 
-        if buf[0] == (SectionID::TYPE as u8) {
-            assert!(self.function_types.is_empty());
-            let n = read_unsigned_leb128(r)?.0 as usize;
-            for _ in 0 ..n {
-                match Type::parse(r)? {
-                    Type::Func(a, b) => 
-                        self.function_types.push((a, b)),
-                    t => return Err(std::io::Error::other(
-                        format!("expected a function type, not {:?}", t)))
+    fn parse_section(&mut self, id: SectionID, r: &mut Reader) -> Result<()> {
+        match id {
+            SectionID::CUSTOM => {
+                let start = r.pos;
+                let name = r.name()?;
+                let data = r.bytes(r.bytes.len() - r.pos)?.to_vec();
+                self.custom_sections.push((r.pos - start, name, data));
+            }
+            SectionID::TYPE => {
+                self.function_types = r.vec(|r| match Type::parse(r)? {
+                    Type::Func(a, b) => Ok((a, b)),
+                    t => r.err(format!("expected a function type, not {}", t))
+                })?;
+            }
+            SectionID::IMPORT => {
+                self.imports = r.vec(|r| {
+                    let module = r.name()?;
+                    let name = r.name()?;
+                    let offset = r.pos;
+                    let desc = match r.byte()? {
+                        0x00 => ImportDesc::Func(self.type_index(r)?),
+                        0x01 => ImportDesc::Table(Type::parse_ref(r)?, parse_limits(r)?),
+                        0x02 => ImportDesc::Memory(parse_memory(r)?),
+                        0x03 => {
+                            let (t, m) = parse_global_type(r)?;
+                            ImportDesc::Global(t, m)
+                        }
+                        b => return Err(Error::Invalid(offset, format!("unknown import kind: 0x{:x}", b)))
+                    };
+                    Ok(Import { module, name, desc })
+                })?;
+            }
+            SectionID::FUNCTION => {
+                self.function_type_indexes = r.vec(|r| self.type_index(r))?;
+            }
+            SectionID::TABLE => {
+                self.tables = r.vec(|r| Ok((Type::parse_ref(r)?, parse_limits(r)?)))?;
+            }
+            SectionID::MEMORY => {
+                let offset = r.pos;
+                self.memory_ranges = r.vec(parse_memory)?;
+                if self.num_memories() > 1 {
+                    return Err(Error::Invalid(offset, "multiple memories".to_string()));
+                }
+            }
+            SectionID::GLOBAL => {
+                let n = r.u32()?;
+                for _ in 0..n {
+                    let (t, m) = parse_global_type(r)?;
+                    let expr = self.parse_const_expr(r, &t)?;
+                    self.globals.push((t, m, expr));
+                }
+            }
+            SectionID::EXPORT => {
+                let n = r.u32()?;
+                for _ in 0..n {
+                    let offset = r.pos;
+                    let name = r.name()?;
+                    if self.exports.iter().any(|(other, _, _)| *other == name) {
+                        return Err(Error::Invalid(offset, format!("duplicate export name {:?}", name)));
+                    }
+                    let kind_offset = r.pos;
+                    let (kind, max) = match r.byte()? {
+                        0x00 => (Export::Func, self.num_functions()),
+                        0x01 => (Export::Table, self.num_tables()),
+                        0x02 => (Export::Memory, self.num_memories()),
+                        0x03 => (Export::Global, self.num_globals()),
+                        b => return Err(Error::Invalid(kind_offset, format!("unknown export kind: 0x{:x}", b)))
+                    };
+                    let idx = index(r, max, "export")?;
+                    self.exports.push((name, kind, idx));
+                }
+            }
+            SectionID::START => {
+                let offset = r.pos;
+                let idx = index(r, self.num_functions(), "function")?;
+                let t = self.function_type_index(idx).unwrap();
+                if !self.function_types[t].0.is_empty() || !self.function_types[t].1.is_empty() {
+                    return Err(Error::Invalid(offset, "start function must not take or return values".to_string()));
+                }
+                self.start = Some(idx);
+            }
+            SectionID::ELEMENT => {
+                let n = r.u32()?;
+                for _ in 0..n {
+                    let element = self.parse_element(r)?;
+                    self.elements.push(element);
+                }
+            }
+            SectionID::DATACOUNT => {
+                self.data_count = Some(r.u32()? as usize);
+            }
+            SectionID::CODE => {
+                let offset = r.pos;
+                let n = r.u32()? as usize;
+                if n != self.function_type_indexes.len() {
+                    return Err(Error::Invalid(offset, "function and code section have inconsistent lengths".to_string()));
+                }
+                let imported = self.num_functions() - n;
+                for i in 0..n {
+                    let f = self.parse_function(imported + i, r)?;
+                    self.functions.push(f);
+                }
+            }
+            SectionID::DATA => {
+                let n = r.u32()?;
+                for _ in 0..n {
+                    let offset = r.pos;
+                    let mode = match r.u32()? {
+                        0 => DataMode::Active(0, self.parse_const_expr(r, &Type::I32)?),
+                        1 => DataMode::Passive,
+                        2 => {
+                            let mem = index(r, self.num_memories(), "memory")?;
+                            DataMode::Active(mem, self.parse_const_expr(r, &Type::I32)?)
+                        }
+                        flags => return Err(Error::Invalid(offset, format!("unknown data segment kind: {}", flags)))
+                    };
+                    if let DataMode::Active(0, _) = mode {
+                        if self.num_memories() == 0 {
+                            return Err(Error::Invalid(offset, "unknown memory 0".to_string()));
+                        }
+                    }
+                    let len = r.u32()? as usize;
+                    let bytes = r.bytes(len)?.to_vec();
+                    self.data.push(Data { mode, bytes });
                 }
             }
-            return Ok(())
         }
+        Ok(())
+    }
 
-        if buf[0] == (SectionID::FUNCTION as u8) {
-            assert!(self.function_type_indexes.is_empty());
-            let n = read_unsigned_leb128(r)?.0 as usize;
-            for _ in 0 ..n {
-                let idx = read_unsigned_leb128(r)?.0 as usize;
-                assert!(idx < self.function_types.len());
-                self.function_type_indexes.push(idx);
+    fn type_index(&self, r: &mut Reader) -> Result<usize> {
+        index(r, self.function_types.len(), "type")
+    }
+
+    fn parse_element(&self, r: &mut Reader) -> Result<Element> {
+        let offset = r.pos;
+        let flags = r.u32()?;
+        if flags > 7 {
+            return Err(Error::Invalid(offset, format!("unknown element segment kind: {}", flags)));
+        }
+
+        // Bit 0: passive or declarative, bit 1: explicit table index or
+        // declarative, bit 2: expressions instead of function indexes.
+        let mode = match flags & 0b011 {
+            0b000 => ElementMode::Active(0, self.parse_const_expr(r, &Type::I32)?),
+            0b010 => {
+                let table = index(r, self.num_tables(), "table")?;
+                ElementMode::Active(table, self.parse_const_expr(r, &Type::I32)?)
+            }
+            0b001 => ElementMode::Passive,
+            _ => ElementMode::Declarative,
+        };
+        if let ElementMode::Active(0, _) = mode {
+            if self.num_tables() == 0 {
+                return Err(Error::Invalid(offset, "unknown table 0".to_string()));
             }
-            return Ok(())
         }
 
-        if buf[0] == (SectionID::MEMORY as u8) {
+        let explicit_type = flags & 0b011 != 0;
+        if flags & 0b100 == 0 {
+            if explicit_type {
+                let kind_offset = r.pos;
+                if r.byte()? != 0x00 {
+                    return Err(Error::Invalid(kind_offset, "unknown element kind".to_string()));
+                }
+            }
+            let funcs = r.vec(|r| index(r, self.num_functions(), "function"))?;
+            Ok(Element { mode, typ: Type::FuncRef, init: ElementInit::Funcs(funcs) })
+        } else {
+            let typ = if explicit_type { Type::parse_ref(r)? } else { Type::FuncRef };
+            let exprs = r.vec(|r| self.parse_const_expr(r, &typ))?;
+            Ok(Element { mode, typ, init: ElementInit::Exprs(exprs) })
+        }
+    }
 
+    /// Constant expressions initialize globals and give the offsets of
+    /// active element and data segments.
+    fn parse_const_expr(&self, r: &mut Reader, typ: &Type) -> Result<Vec<Instr>> {
+        let offset = r.pos;
+        let mut expr = vec![];
+        loop {
+            let instr_offset = r.pos;
+            let instr = Instr::parse(r)?;
+            let t = match &instr {
+                Instr::I32Const(_) => Type::I32,
+                Instr::I64Const(_) => Type::I64,
+                Instr::F32Const(_) => Type::F32,
+                Instr::F64Const(_) => Type::F64,
+                Instr::RefNull(t) => t.clone(),
+                Instr::RefFunc(idx) => {
+                    if *idx as usize >= self.num_functions() {
+                        return Err(Error::Invalid(instr_offset, format!("unknown function {}", idx)));
+                    }
+                    Type::FuncRef
+                }
+                Instr::GlobalGet(idx) => match self.global_type(*idx as usize) {
+                    Some((t, false)) => t,
+                    Some((_, true)) => return Err(Error::Invalid(instr_offset,
+                        "constant expression required, global is mutable".to_string())),
+                    None => return Err(Error::Invalid(instr_offset, format!("unknown global {}", idx)))
+                },
+                Instr::End => break,
+                _ => return Err(Error::Invalid(instr_offset, "constant expression required".to_string()))
+            };
+            if !expr.is_empty() || t != *typ {
+                return Err(Error::Invalid(offset, format!("type mismatch in constant expression, expected {}", typ)));
+            }
+            expr.push(instr);
         }
+        if expr.is_empty() {
+            return Err(Error::Invalid(offset, format!("type mismatch in constant expression, expected {}", typ)));
+        }
+        expr.push(Instr::End);
+        Ok(expr)
+    }
+
+    fn parse_function(&self, index: usize, r: &mut Reader) -> Result<Function> {
+        let size = r.u32()? as usize;
+        let start = r.pos;
+        let end = start.checked_add(size).filter(|end| *end <= r.bytes.len())
+            .ok_or_else(|| Error::Invalid(start, "function body extends past the end of the section".to_string()))?;
+        let mut body = Reader { bytes: &r.bytes[..end], pos: start };
+        r.pos = end;
+        let r = &mut body;
+        let (arguments, returns) = self.function_types[self.function_type_index(index).unwrap()].clone();
 
-        Err(std::io::Error::other(format!("unknown section ID {:?}", buf[0])))
+        let offset = r.pos;
+        let locals = r.vec(|r| Ok((r.u32()? as usize, Type::parse_value(r)?)))?;
+        let num_locals = locals.iter().try_fold(arguments.len(), |acc, (n, _)| acc.checked_add(*n))
+            .filter(|n| *n <= u32::MAX as usize)
+            .ok_or_else(|| Error::Invalid(offset, "too many locals".to_string()))?;
+
+        let mut f = Function { index, size, arguments, returns, locals, body: vec![] };
+        let mut operands = Operands::new(&f);
+        while !operands.frames.is_empty() {
+            let offset = r.pos;
+            if r.eof() {
+                return r.err("function body does not end with `end`");
+            }
+            let instr = Instr::parse(r)?;
+            self.validate(&instr, num_locals, operands.frames.len())
+                .and_then(|()| self.check_operands(&mut operands, &instr))
+                .map_err(|msg| Error::Invalid(offset, msg))?;
+            f.body.push(instr);
+        }
+        if !r.eof() {
+            return r.err("function body size mismatch");
+        }
+        Ok(f)
+    }
+
+    /// Check the indexes used by an instruction.
+    fn validate(&self, instr: &Instr, num_locals: usize, depth: usize) -> std::result::Result<(), String> {
+        let check = |idx: u32, max: usize, what: &str| match (idx as usize) < max {
+            true => Ok(()),
+            false => Err(format!("unknown {} {}", what, idx))
+        };
+        let block_type = |bt: &BlockType| match bt {
+            BlockType::Type(idx) => check(*idx, self.function_types.len(), "type"),
+            _ => Ok(())
+        };
+        let data = |idx: u32| match self.data_count {
+            Some(n) => check(idx, n, "data segment"),
+            None => Err("data count section required".to_string())
+        };
+        let memory = || check(0, self.num_memories(), "memory");
+
+        match instr {
+            Instr::Block(bt) | Instr::Loop(bt) | Instr::If(bt) => block_type(bt),
+            Instr::Br(l) | Instr::BrIf(l) => check(*l, depth, "label"),
+            Instr::BrTable(ls, l) => ls.iter().chain(std::iter::once(l)).try_for_each(|l| check(*l, depth, "label")),
+            Instr::Call(idx) | Instr::RefFunc(idx) => check(*idx, self.num_functions(), "function"),
+            Instr::CallIndirect(t, table) => {
+                check(*t, self.function_types.len(), "type")?;
+                check(*table, self.num_tables(), "table")
+            }
+            Instr::LocalGet(idx) | Instr::LocalSet(idx) | Instr::LocalTee(idx) => check(*idx, num_locals, "local"),
+            Instr::GlobalGet(idx) => check(*idx, self.num_globals(), "global"),
+            Instr::GlobalSet(idx) => {
+                check(*idx, self.num_globals(), "global")?;
+                match self.global_type(*idx as usize) {
+                    Some((_, true)) => Ok(()),
+                    _ => Err(format!("global {} is immutable", idx))
+                }
+            }
+            Instr::TableGet(t) | Instr::TableSet(t) | Instr::TableGrow(t) | Instr::TableSize(t)
+                | Instr::TableFill(t) => check(*t, self.num_tables(), "table"),
+            Instr::TableInit(e, t) => {
+                check(*e, self.elements.len(), "element segment")?;
+                check(*t, self.num_tables(), "table")
+            }
+            Instr::ElemDrop(e) => check(*e, self.elements.len(), "element segment"),
+            Instr::TableCopy(dst, src) => {
+                check(*dst, self.num_tables(), "table")?;
+                check(*src, self.num_tables(), "table")
+            }
+            Instr::Mem(op, arg) => {
+                memory()?;
+                if arg.align > op.natural_alignment() {
+                    return Err(format!("alignment of {} must not be larger than natural", op.name()));
+                }
+                Ok(())
+            }
+            Instr::MemorySize | Instr::MemoryGrow | Instr::MemoryCopy | Instr::MemoryFill => memory(),
+            Instr::MemoryInit(idx) => {
+                memory()?;
+                data(*idx)
+            }
+            Instr::DataDrop(idx) => data(*idx),
+            _ => Ok(())
+        }
+    }
+
+    /// Check the types an instruction pops from and pushes onto the operand
+    /// stack, its indexes have already been checked by `validate`.
+    fn check_operands(&self, s: &mut Operands, instr: &Instr) -> std::result::Result<(), String> {
+        let table = |idx: &u32| self.table_type(*idx as usize).unwrap();
+        match instr {
+            Instr::Unreachable => s.unreachable(),
+            Instr::NOp | Instr::ElemDrop(_) | Instr::DataDrop(_) => {}
+            Instr::Block(bt) | Instr::Loop(bt) | Instr::If(bt) => {
+                let (params, results) = self.block_type(bt);
+                let kind = match instr {
+                    Instr::Block(_) => Kind::Block,
+                    Instr::Loop(_) => Kind::Loop,
+                    _ => {
+                        s.pop(&Type::I32)?;
+                        Kind::If
+                    }
+                };
+                s.pop_all(&params)?;
+                s.enter(kind, params, results);
+            }
+            Instr::Else => {
+                if s.frames.last().unwrap().kind != Kind::If {
+                    return Err("`else` without `if`".to_string());
+                }
+                let frame = s.exit()?;
+                s.enter(Kind::Else, frame.params, frame.results);
+            }
+            Instr::End => {
+                let frame = s.exit()?;
+                if frame.kind == Kind::If && frame.params != frame.results {
+                    return Err("type mismatch: `if` without `else` must leave its parameters".to_string());
+                }
+                s.push_all(&frame.results);
+            }
+            Instr::Br(l) => {
+                s.pop_all(&s.label(*l))?;
+                s.unreachable();
+            }
+            Instr::BrIf(l) => {
+                s.pop(&Type::I32)?;
+                let types = s.label(*l);
+                s.pop_all(&types)?;
+                s.push_all(&types);
+            }
+            Instr::BrTable(ls, l) => {
+                s.pop(&Type::I32)?;
+                let types = s.label(*l);
+                for l in ls {
+                    let other = s.label(*l);
+                    if other.len() != types.len() {
+                        return Err("type mismatch: br_table targets have different arities".to_string());
+                    }
+                    let values = s.pop_all(&other)?;
+                    s.values.extend(values);
+                }
+                s.pop_all(&types)?;
+                s.unreachable();
+            }
+            Instr::Return => {
+                s.pop_all(&s.returns.clone())?;
+                s.unreachable();
+            }
+            Instr::Call(idx) => {
+                let (params, results) = &self.function_types[self.function_type_index(*idx as usize).unwrap()];
+                s.pop_all(params)?;
+                s.push_all(results);
+            }
+            Instr::CallIndirect(t, idx) => {
+                if table(idx) != Type::FuncRef {
+                    return Err(format!("type mismatch: call_indirect through a table of {}", table(idx)));
+                }
+                let (params, results) = &self.function_types[*t as usize];
+                s.pop(&Type::I32)?;
+                s.pop_all(params)?;
+                s.push_all(results);
+            }
+            Instr::RefNull(t) => s.push(t.clone()),
+            Instr::RefIsNull => {
+                if let Some(t) = s.pop_any()?.filter(|t| !t.is_ref()) {
+                    return Err(format!("type mismatch: expected a reference, found {}", t));
+                }
+                s.push(Type::I32);
+            }
+            Instr::RefFunc(_) => s.push(Type::FuncRef),
+            Instr::Drop => {
+                s.pop_any()?;
+            }
+            Instr::Select => {
+                s.pop(&Type::I32)?;
+                let (a, b) = (s.pop_any()?, s.pop_any()?);
+                if let Some(t) = a.iter().chain(&b).find(|t| t.is_ref()) {
+                    return Err(format!("type mismatch: select without a type on {}", t));
+                }
+                if let (Some(a), Some(b)) = (&a, &b) {
+                    if a != b {
+                        return Err(format!("type mismatch: select on {} and {}", b, a));
+                    }
+                }
+                s.values.push(a.or(b));
+            }
+            Instr::SelectT(types) => {
+                let [t] = types.as_slice() else {
+                    return Err("select must have exactly one result type".to_string());
+                };
+                s.pop(&Type::I32)?;
+                s.pop_all(&[t.clone(), t.clone()])?;
+                s.push(t.clone());
+            }
+            Instr::LocalGet(idx) => s.push(s.local(*idx)),
+            Instr::LocalSet(idx) => {
+                s.pop(&s.local(*idx))?;
+            }
+            Instr::LocalTee(idx) => {
+                s.pop(&s.local(*idx))?;
+                s.push(s.local(*idx));
+            }
+            Instr::GlobalGet(idx) => s.push(self.global_type(*idx as usize).unwrap().0),
+            Instr::GlobalSet(idx) => {
+                s.pop(&self.global_type(*idx as usize).unwrap().0)?;
+            }
+            Instr::TableGet(idx) => {
+                s.pop(&Type::I32)?;
+                s.push(table(idx));
+            }
+            Instr::TableSet(idx) => {
+                s.pop_all(&[Type::I32, table(idx)])?;
+            }
+            Instr::TableInit(e, idx) => {
+                if self.elements[*e as usize].typ != table(idx) {
+                    return Err(format!("type mismatch: table.init of {} into a table of {}",
+                        self.elements[*e as usize].typ, table(idx)));
+                }
+                s.pop_all(&[Type::I32, Type::I32, Type::I32])?;
+            }
+            Instr::TableCopy(dst, src) => {
+                if table(dst) != table(src) {
+                    return Err(format!("type mismatch: table.copy of {} into a table of {}", table(src), table(dst)));
+                }
+                s.pop_all(&[Type::I32, Type::I32, Type::I32])?;
+            }
+            Instr::TableGrow(idx) => {
+                s.pop_all(&[table(idx), Type::I32])?;
+                s.push(Type::I32);
+            }
+            Instr::TableSize(_) | Instr::MemorySize => s.push(Type::I32),
+            Instr::TableFill(idx) => {
+                s.pop_all(&[Type::I32, table(idx), Type::I32])?;
+            }
+            Instr::Mem(op, _) if op.is_store() => {
+                s.pop_all(&[Type::I32, op.value_type()])?;
+            }
+            Instr::Mem(op, _) => {
+                s.pop(&Type::I32)?;
+                s.push(op.value_type());
+            }
+            Instr::MemoryGrow => {
+                s.pop(&Type::I32)?;
+                s.push(Type::I32);
+            }
+            Instr::MemoryInit(_) | Instr::MemoryCopy | Instr::MemoryFill => {
+                s.pop_all(&[Type::I32, Type::I32, Type::I32])?;
+            }
+            Instr::I32Const(_) => s.push(Type::I32),
+            Instr::I64Const(_) => s.push(Type::I64),
+            Instr::F32Const(_) => s.push(Type::F32),
+            Instr::F64Const(_) => s.push(Type::F64),
+            Instr::Op(op) => {
+                let (params, result) = op.signature();
+                s.pop_all(&params)?;
+                s.push(result);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind { Function, Block, Loop, If, Else }
+
+/// A block open during validation.
+struct Frame {
+    kind: Kind,
+    params: Vec<Type>,
+    results: Vec<Type>,
+    /// Height of the operand stack at the start of the block.
+    height: usize,
+    /// Set after an unconditional branch: the stack below is unknown.
+    unreachable: bool,
+}
+
+/*
+ * The operand and control stacks of the validation algorithm in the
+ * appendix of the spec. Values popped below the height of an unreachable
+ * block are unknown (`None`) and match any type.
+ */
+struct Operands {
+    values: Vec<Option<Type>>,
+    frames: Vec<Frame>,
+    /// Runs of locals, arguments included: (index after the run, type).
+    locals: Vec<(u64, Type)>,
+    returns: Vec<Type>,
+}
+
+impl Operands {
+    fn new(f: &Function) -> Operands {
+        let mut end = 0;
+        let args = f.arguments.iter().map(|t| (1, t));
+        let locals = args.chain(f.locals.iter().map(|(n, t)| (*n, t))).map(|(n, t)| {
+            end += n as u64;
+            (end, t.clone())
+        }).collect();
+        let mut s = Operands { values: vec![], frames: vec![], locals, returns: f.returns.clone() };
+        s.enter(Kind::Function, vec![], f.returns.clone());
+        s
+    }
+
+    fn local(&self, idx: u32) -> Type {
+        let run = self.locals.partition_point(|(end, _)| *end <= idx as u64);
+        self.locals[run].1.clone()
+    }
+
+    /// The types a branch to label `l` takes along.
+    fn label(&self, l: u32) -> Vec<Type> {
+        let frame = &self.frames[self.frames.len() - 1 - l as usize];
+        match frame.kind {
+            Kind::Loop => frame.params.clone(),
+            _ => frame.results.clone(),
+        }
+    }
+
+    fn push(&mut self, t: Type) {
+        self.values.push(Some(t));
+    }
+
+    fn push_all(&mut self, types: &[Type]) {
+        self.values.extend(types.iter().cloned().map(Some));
+    }
+
+    fn pop_any(&mut self) -> std::result::Result<Option<Type>, String> {
+        let frame = self.frames.last().unwrap();
+        if self.values.len() > frame.height {
+            Ok(self.values.pop().unwrap())
+        } else if frame.unreachable {
+            Ok(None)
+        } else {
+            Err("type mismatch: operand stack underflow".to_string())
+        }
+    }
+
+    fn pop(&mut self, expected: &Type) -> std::result::Result<Option<Type>, String> {
+        match self.pop_any()? {
+            Some(t) if t != *expected => Err(format!("type mismatch: expected {}, found {}", expected, t)),
+            t => Ok(t),
+        }
+    }
+
+    /// Pops values of the given types, the last one first.
+    fn pop_all(&mut self, types: &[Type]) -> std::result::Result<Vec<Option<Type>>, String> {
+        let mut values = types.iter().rev().map(|t| self.pop(t)).collect::<std::result::Result<Vec<_>, _>>()?;
+        values.reverse();
+        Ok(values)
+    }
+
+    fn enter(&mut self, kind: Kind, params: Vec<Type>, results: Vec<Type>) {
+        let height = self.values.len();
+        self.push_all(&params);
+        self.frames.push(Frame { kind, params, results, height, unreachable: false });
+    }
+
+    fn exit(&mut self) -> std::result::Result<Frame, String> {
+        let results = self.frames.last().unwrap().results.clone();
+        self.pop_all(&results)?;
+        if self.values.len() > self.frames.last().unwrap().height {
+            return Err("type mismatch: values left on the operand stack at the end of the block".to_string());
+        }
+        Ok(self.frames.pop().unwrap())
+    }
+
+    /// The rest of the block cannot be reached.
+    fn unreachable(&mut self) {
+        let frame = self.frames.last_mut().unwrap();
+        self.values.truncate(frame.height);
+        frame.unreachable = true;
+    }
+}
+
+fn parse_memory(r: &mut Reader) -> Result<Limits> {
+    let offset = r.pos;
+    let limits = parse_limits(r)?;
+    if limits.0 > MAX_PAGES || limits.1.is_some_and(|max| max > MAX_PAGES) {
+        return Err(Error::Invalid(offset, "memory size must be at most 65536 pages (4GiB)".to_string()));
+    }
+    Ok(limits)
+}
+
+fn parse_global_type(r: &mut Reader) -> Result<(Type, bool)> {
+    let t = Type::parse_value(r)?;
+    let offset = r.pos;
+    match r.byte()? {
+        0x00 => Ok((t, false)),
+        0x01 => Ok((t, true)),
+        b => Err(Error::Invalid(offset, format!("malformed mutability: 0x{:x}", b)))
+    }
+}
+
+fn index(r: &mut Reader, max: usize, what: &str) -> Result<usize> {
+    let offset = r.pos;
+    let idx = r.u32()? as usize;
+    if idx >= max {
+        return Err(Error::Invalid(offset, format!("unknown {} {}", what, idx)));
+    }
+    Ok(idx)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockType {
+    Empty,
+    Value(Type),
+    Type(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemArg {
+    pub align: u32,
+    pub offset: u32,
+}
+
+/*
+ * The instructions without immediates, and the loads and stores, are only
+ * distinguished by their opcode (prefixed ones are 0xFC00 | opcode).
+ */
+macro_rules! opcodes {
+    ($enum:ident { $($name:ident = $opc:expr, $text:expr;)* }) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum $enum { $($name,)* }
+
+        impl $enum {
+            fn from_opcode(opc: u32) -> Option<$enum> {
+                match opc {
+                    $($opc => Some($enum::$name),)*
+                    _ => None
+                }
+            }
+
+            pub fn opcode(self) -> u32 {
+                match self { $($enum::$name => $opc,)* }
+            }
+
+            pub fn name(self) -> &'static str {
+                match self { $($enum::$name => $text,)* }
+            }
+        }
+    }
+}
+
+opcodes!(Op {
+    I32Eqz = 0x45, "i32.eqz"; I32Eq = 0x46, "i32.eq"; I32Ne = 0x47, "i32.ne";
+    I32LtS = 0x48, "i32.lt_s"; I32LtU = 0x49, "i32.lt_u"; I32GtS = 0x4A, "i32.gt_s";
+    I32GtU = 0x4B, "i32.gt_u"; I32LeS = 0x4C, "i32.le_s"; I32LeU = 0x4D, "i32.le_u";
+    I32GeS = 0x4E, "i32.ge_s"; I32GeU = 0x4F, "i32.ge_u";
+
+    I64Eqz = 0x50, "i64.eqz"; I64Eq = 0x51, "i64.eq"; I64Ne = 0x52, "i64.ne";
+    I64LtS = 0x53, "i64.lt_s"; I64LtU = 0x54, "i64.lt_u"; I64GtS = 0x55, "i64.gt_s";
+    I64GtU = 0x56, "i64.gt_u"; I64LeS = 0x57, "i64.le_s"; I64LeU = 0x58, "i64.le_u";
+    I64GeS = 0x59, "i64.ge_s"; I64GeU = 0x5A, "i64.ge_u";
+
+    F32Eq = 0x5B, "f32.eq"; F32Ne = 0x5C, "f32.ne"; F32Lt = 0x5D, "f32.lt";
+    F32Gt = 0x5E, "f32.gt"; F32Le = 0x5F, "f32.le"; F32Ge = 0x60, "f32.ge";
+    F64Eq = 0x61, "f64.eq"; F64Ne = 0x62, "f64.ne"; F64Lt = 0x63, "f64.lt";
+    F64Gt = 0x64, "f64.gt"; F64Le = 0x65, "f64.le"; F64Ge = 0x66, "f64.ge";
+
+    I32Clz = 0x67, "i32.clz"; I32Ctz = 0x68, "i32.ctz"; I32Popcnt = 0x69, "i32.popcnt";
+    I32Add = 0x6A, "i32.add"; I32Sub = 0x6B, "i32.sub"; I32Mul = 0x6C, "i32.mul";
+    I32DivS = 0x6D, "i32.div_s"; I32DivU = 0x6E, "i32.div_u"; I32RemS = 0x6F, "i32.rem_s";
+    I32RemU = 0x70, "i32.rem_u"; I32And = 0x71, "i32.and"; I32Or = 0x72, "i32.or";
+    I32Xor = 0x73, "i32.xor"; I32Shl = 0x74, "i32.shl"; I32ShrS = 0x75, "i32.shr_s";
+    I32ShrU = 0x76, "i32.shr_u"; I32Rotl = 0x77, "i32.rotl"; I32Rotr = 0x78, "i32.rotr";
+
+    I64Clz = 0x79, "i64.clz"; I64Ctz = 0x7A, "i64.ctz"; I64Popcnt = 0x7B, "i64.popcnt";
+    I64Add = 0x7C, "i64.add"; I64Sub = 0x7D, "i64.sub"; I64Mul = 0x7E, "i64.mul";
+    I64DivS = 0x7F, "i64.div_s"; I64DivU = 0x80, "i64.div_u"; I64RemS = 0x81, "i64.rem_s";
+    I64RemU = 0x82, "i64.rem_u"; I64And = 0x83, "i64.and"; I64Or = 0x84, "i64.or";
+    I64Xor = 0x85, "i64.xor"; I64Shl = 0x86, "i64.shl"; I64ShrS = 0x87, "i64.shr_s";
+    I64ShrU = 0x88, "i64.shr_u"; I64Rotl = 0x89, "i64.rotl"; I64Rotr = 0x8A, "i64.rotr";
+
+    F32Abs = 0x8B, "f32.abs"; F32Neg = 0x8C, "f32.neg"; F32Ceil = 0x8D, "f32.ceil";
+    F32Floor = 0x8E, "f32.floor"; F32Trunc = 0x8F, "f32.trunc"; F32Nearest = 0x90, "f32.nearest";
+    F32Sqrt = 0x91, "f32.sqrt"; F32Add = 0x92, "f32.add"; F32Sub = 0x93, "f32.sub";
+    F32Mul = 0x94, "f32.mul"; F32Div = 0x95, "f32.div"; F32Min = 0x96, "f32.min";
+    F32Max = 0x97, "f32.max"; F32Copysign = 0x98, "f32.copysign";
+
+    F64Abs = 0x99, "f64.abs"; F64Neg = 0x9A, "f64.neg"; F64Ceil = 0x9B, "f64.ceil";
+    F64Floor = 0x9C, "f64.floor"; F64Trunc = 0x9D, "f64.trunc"; F64Nearest = 0x9E, "f64.nearest";
+    F64Sqrt = 0x9F, "f64.sqrt"; F64Add = 0xA0, "f64.add"; F64Sub = 0xA1, "f64.sub";
+    F64Mul = 0xA2, "f64.mul"; F64Div = 0xA3, "f64.div"; F64Min = 0xA4, "f64.min";
+    F64Max = 0xA5, "f64.max"; F64Copysign = 0xA6, "f64.copysign";
+
+    I32WrapI64 = 0xA7, "i32.wrap_i64";
+    I32TruncF32S = 0xA8, "i32.trunc_f32_s"; I32TruncF32U = 0xA9, "i32.trunc_f32_u";
+    I32TruncF64S = 0xAA, "i32.trunc_f64_s"; I32TruncF64U = 0xAB, "i32.trunc_f64_u";
+    I64ExtendI32S = 0xAC, "i64.extend_i32_s"; I64ExtendI32U = 0xAD, "i64.extend_i32_u";
+    I64TruncF32S = 0xAE, "i64.trunc_f32_s"; I64TruncF32U = 0xAF, "i64.trunc_f32_u";
+    I64TruncF64S = 0xB0, "i64.trunc_f64_s"; I64TruncF64U = 0xB1, "i64.trunc_f64_u";
+    F32ConvertI32S = 0xB2, "f32.convert_i32_s"; F32ConvertI32U = 0xB3, "f32.convert_i32_u";
+    F32ConvertI64S = 0xB4, "f32.convert_i64_s"; F32ConvertI64U = 0xB5, "f32.convert_i64_u";
+    F32DemoteF64 = 0xB6, "f32.demote_f64";
+    F64ConvertI32S = 0xB7, "f64.convert_i32_s"; F64ConvertI32U = 0xB8, "f64.convert_i32_u";
+    F64ConvertI64S = 0xB9, "f64.convert_i64_s"; F64ConvertI64U = 0xBA, "f64.convert_i64_u";
+    F64PromoteF32 = 0xBB, "f64.promote_f32";
+    I32ReinterpretF32 = 0xBC, "i32.reinterpret_f32"; I64ReinterpretF64 = 0xBD, "i64.reinterpret_f64";
+    F32ReinterpretI32 = 0xBE, "f32.reinterpret_i32"; F64ReinterpretI64 = 0xBF, "f64.reinterpret_i64";
+
+    I32Extend8S = 0xC0, "i32.extend8_s"; I32Extend16S = 0xC1, "i32.extend16_s";
+    I64Extend8S = 0xC2, "i64.extend8_s"; I64Extend16S = 0xC3, "i64.extend16_s";
+    I64Extend32S = 0xC4, "i64.extend32_s";
+
+    I32TruncSatF32S = 0xFC00, "i32.trunc_sat_f32_s"; I32TruncSatF32U = 0xFC01, "i32.trunc_sat_f32_u";
+    I32TruncSatF64S = 0xFC02, "i32.trunc_sat_f64_s"; I32TruncSatF64U = 0xFC03, "i32.trunc_sat_f64_u";
+    I64TruncSatF32S = 0xFC04, "i64.trunc_sat_f32_s"; I64TruncSatF32U = 0xFC05, "i64.trunc_sat_f32_u";
+    I64TruncSatF64S = 0xFC06, "i64.trunc_sat_f64_s"; I64TruncSatF64U = 0xFC07, "i64.trunc_sat_f64_u";
+});
+
+opcodes!(MemOp {
+    I32Load = 0x28, "i32.load"; I64Load = 0x29, "i64.load";
+    F32Load = 0x2A, "f32.load"; F64Load = 0x2B, "f64.load";
+    I32Load8S = 0x2C, "i32.load8_s"; I32Load8U = 0x2D, "i32.load8_u";
+    I32Load16S = 0x2E, "i32.load16_s"; I32Load16U = 0x2F, "i32.load16_u";
+    I64Load8S = 0x30, "i64.load8_s"; I64Load8U = 0x31, "i64.load8_u";
+    I64Load16S = 0x32, "i64.load16_s"; I64Load16U = 0x33, "i64.load16_u";
+    I64Load32S = 0x34, "i64.load32_s"; I64Load32U = 0x35, "i64.load32_u";
+    I32Store = 0x36, "i32.store"; I64Store = 0x37, "i64.store";
+    F32Store = 0x38, "f32.store"; F64Store = 0x39, "f64.store";
+    I32Store8 = 0x3A, "i32.store8"; I32Store16 = 0x3B, "i32.store16";
+    I64Store8 = 0x3C, "i64.store8"; I64Store16 = 0x3D, "i64.store16";
+    I64Store32 = 0x3E, "i64.store32";
+});
+
+impl MemOp {
+    /// log2 of the access size in bytes.
+    pub fn natural_alignment(self) -> u32 {
+        let (typ, op) = self.name().split_once('.').unwrap();
+        if op.contains('8') {
+            0
+        } else if op.contains("16") {
+            1
+        } else if op.contains("32") || typ.ends_with("32") {
+            2
+        } else {
+            3
+        }
+    }
+
+    pub fn is_store(self) -> bool {
+        self.opcode() >= MemOp::I32Store.opcode()
+    }
+
+    /// The type of the value loaded or stored.
+    pub fn value_type(self) -> Type {
+        Type::from_name(self.name().split_once('.').unwrap().0).unwrap()
+    }
+}
+
+impl Op {
+    /// The operand types and the result type.
+    pub fn signature(self) -> (Vec<Type>, Type) {
+        let (typ, op) = self.name().split_once('.').unwrap();
+        let typ = Type::from_name(typ).unwrap();
+        // Conversions name the type they convert from:
+        if let Some(from) = op.split('_').find_map(Type::from_name) {
+            return (vec![from], typ);
+        }
+        match op.split('_').next().unwrap() {
+            "eqz" => (vec![typ], Type::I32),
+            "eq" | "ne" | "lt" | "gt" | "le" | "ge" => (vec![typ.clone(), typ], Type::I32),
+            "clz" | "ctz" | "popcnt" | "abs" | "neg" | "ceil" | "floor" | "trunc" | "nearest" | "sqrt"
+                | "extend8" | "extend16" | "extend32" => (vec![typ.clone()], typ),
+            _ => (vec![typ.clone(), typ.clone()], typ),
+        }
     }
 }
 
-#[allow(dead_code)]
 #[derive(Clone, PartialEq)]
 pub enum Instr {
     Unreachable,
     NOp,
+    Block(BlockType),
+    Loop(BlockType),
+    If(BlockType),
+    Else,
+    End,
+    Br(u32),
+    BrIf(u32),
+    BrTable(Vec<u32>, u32),
+    Return,
+    Call(u32),
+    // Type index and table index:
+    CallIndirect(u32, u32),
+
+    RefNull(Type),
+    RefIsNull,
+    RefFunc(u32),
+
+    Drop,
+    Select,
+    SelectT(Vec<Type>),
+
     LocalGet(u32),
     LocalSet(u32),
     LocalTee(u32),
     GlobalGet(u32),
     GlobalSet(u32),
+
+    TableGet(u32),
+    TableSet(u32),
+    // Element segment and table index:
+    TableInit(u32, u32),
+    ElemDrop(u32),
+    // Destination and source table:
+    TableCopy(u32, u32),
+    TableGrow(u32),
+    TableSize(u32),
+    TableFill(u32),
+
+    Mem(MemOp, MemArg),
+    MemorySize,
+    MemoryGrow,
+    MemoryInit(u32),
+    DataDrop(u32),
+    MemoryCopy,
+    MemoryFill,
+
     I32Const(i32),
     I64Const(i64),
     F32Const(f32),
     F64Const(f64),
-    End
+    Op(Op),
+}
+
+impl std::fmt::Display for BlockType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockType::Empty => Ok(()),
+            BlockType::Value(t) => write!(f, " (result {})", t),
+            BlockType::Type(idx) => write!(f, " (type {})", idx),
+        }
+    }
 }
 
 impl std::fmt::Display for Instr {
@@ -207,16 +1464,70 @@ impl std::fmt::Display for Instr {
         match self {
             Unreachable => f.write_str("(unreachable)"),
             NOp => f.write_str("(nop)"),
+            Block(bt) => write!(f, "(block{})", bt),
+            Loop(bt) => write!(f, "(loop{})", bt),
+            If(bt) => write!(f, "(if{})", bt),
+            Else => f.write_str("(else)"),
+            End => f.write_str("(end)"),
+            Br(l) => write!(f, "(br {})", l),
+            BrIf(l) => write!(f, "(br_if {})", l),
+            BrTable(ls, l) => {
+                f.write_str("(br_table")?;
+                for l in ls {
+                    write!(f, " {}", l)?;
+                }
+                write!(f, " {})", l)
+            }
+            Return => f.write_str("(return)"),
+            Call(idx) => write!(f, "(call {})", idx),
+            CallIndirect(t, table) => write!(f, "(call_indirect {} (type {}))", table, t),
+            RefNull(t) => write!(f, "(ref.null {})", if *t == Type::FuncRef { "func" } else { "extern" }),
+            RefIsNull => f.write_str("(ref.is_null)"),
+            RefFunc(idx) => write!(f, "(ref.func {})", idx),
+            Drop => f.write_str("(drop)"),
+            Select => f.write_str("(select)"),
+            SelectT(ts) => {
+                f.write_str("(select")?;
+                for t in ts {
+                    write!(f, " (result {})", t)?;
+                }
+                f.write_str(")")
+            }
             LocalGet(idx) => write!(f, "(local.get {})", idx),
             LocalSet(idx) => write!(f, "(local.set {})", idx),
-            LocalTee(idx) => write!(f, "(local.tree {})", idx),
+            LocalTee(idx) => write!(f, "(local.tee {})", idx),
             GlobalGet(idx) => write!(f, "(global.get {})", idx),
             GlobalSet(idx) => write!(f, "(global.set {})", idx),
+            TableGet(idx) => write!(f, "(table.get {})", idx),
+            TableSet(idx) => write!(f, "(table.set {})", idx),
+            TableInit(e, t) => write!(f, "(table.init {} {})", t, e),
+            ElemDrop(e) => write!(f, "(elem.drop {})", e),
+            TableCopy(dst, src) => write!(f, "(table.copy {} {})", dst, src),
+            TableGrow(idx) => write!(f, "(table.grow {})", idx),
+            TableSize(idx) => write!(f, "(table.size {})", idx),
+            TableFill(idx) => write!(f, "(table.fill {})", idx),
+            Mem(op, arg) => {
+                f.write_str("(")?;
+                f.write_str(op.name())?;
+                if arg.offset != 0 {
+                    write!(f, " offset={}", arg.offset)?;
+                }
+                if arg.align != op.natural_alignment() {
+                    write!(f, " align={}", 1u64 << arg.align)?;
+                }
+                f.write_str(")")
+            }
+            MemorySize => f.write_str("(memory.size)"),
+            MemoryGrow => f.write_str("(memory.grow)"),
+            MemoryInit(idx) => write!(f, "(memory.init {})", idx),
+            DataDrop(idx) => write!(f, "(data.drop {})", idx),
+            MemoryCopy => f.write_str("(memory.copy)"),
+            MemoryFill => f.write_str("(memory.fill)"),
             I32Const(c) => write!(f, "(i32.const {:x})", c),
             I64Const(c) => write!(f, "(i64.const {:x})", c),
             F32Const(c) => write!(f, "(f32.const {})", c),
             F64Const(c) => write!(f, "(f64.const {})", c),
-            End => f.write_str("(end)"),
+            Op(op) => write!(f, "({})", op.name()),
         }
     }
 }
@@ -228,67 +1539,190 @@ impl std::fmt::Debug for Instr {
 }
 
 impl Instr {
-    fn parse(r: &mut dyn std::io::BufRead) -> std::io::Result<Instr> {
-        let mut buf: [u8; 1] = [0];
-        r.read_exact(&mut buf)?;
-        Ok(match buf[0] {
+    fn parse(r: &mut Reader) -> Result<Instr> {
+        let offset = r.pos;
+        let opc = r.byte()?;
+        Ok(match opc {
             0x00 => Instr::Unreachable,
             0x01 => Instr::NOp,
+            0x02 => Instr::Block(parse_block_type(r)?),
+            0x03 => Instr::Loop(parse_block_type(r)?),
+            0x04 => Instr::If(parse_block_type(r)?),
+            0x05 => Instr::Else,
+            0x0b => Instr::End,
+            0x0c => Instr::Br(r.u32()?),
+            0x0d => Instr::BrIf(r.u32()?),
+            0x0e => {
+                let labels = r.vec(|r| r.u32())?;
+                Instr::BrTable(labels, r.u32()?)
+            }
+            0x0f => Instr::Return,
+            0x10 => Instr::Call(r.u32()?),
+            0x11 => {
+                let t = r.u32()?;
+                Instr::CallIndirect(t, r.u32()?)
+            }
 
-            0x20 => Instr::LocalGet(read_unsigned_leb128(r)?.0 as u32),
-            0x21 => Instr::LocalSet(read_unsigned_leb128(r)?.0 as u32),
-            0x22 => Instr::LocalTee(read_unsigned_leb128(r)?.0 as u32),
-            0x23 => Instr::GlobalGet(read_unsigned_leb128(r)?.0 as u32),
-            0x24 => Instr::GlobalSet(read_unsigned_leb128(r)?.0 as u32),
+            0x1a => Instr::Drop,
+            0x1b => Instr::Select,
+            0x1c => Instr::SelectT(r.vec(Type::parse_value)?),
 
-            0x41 => Instr::I32Const(read_signed_leb128(r, 32)? as i32),
-            0x42 => Instr::I64Const(read_signed_leb128(r, 64)? as i64),
+            0x20 => Instr::LocalGet(r.u32()?),
+            0x21 => Instr::LocalSet(r.u32()?),
+            0x22 => Instr::LocalTee(r.u32()?),
+            0x23 => Instr::GlobalGet(r.u32()?),
+            0x24 => Instr::GlobalSet(r.u32()?),
+            0x25 => Instr::TableGet(r.u32()?),
+            0x26 => Instr::TableSet(r.u32()?),
 
-            0x0b => Instr::End,
+            0x28..=0x3e => {
+                let op = MemOp::from_opcode(opc as u32).unwrap();
+                let align = r.u32()?;
+                if align >= 32 {
+                    return Err(Error::Invalid(offset, "malformed memop alignment".to_string()));
+                }
+                Instr::Mem(op, MemArg { align, offset: r.u32()? })
+            }
+            0x3f | 0x40 => {
+                if r.byte()? != 0x00 {
+                    return Err(Error::Invalid(offset + 1, "zero byte expected".to_string()));
+                }
+                if opc == 0x3f { Instr::MemorySize } else { Instr::MemoryGrow }
+            }
 
-            opc => return Err(std::io::Error::other(format!("unimplemented instruction opcode: {:x}", opc)))
+            0x41 => Instr::I32Const(r.signed(32)? as i32),
+            0x42 => Instr::I64Const(r.signed(64)?),
+            0x43 => Instr::F32Const(f32::from_le_bytes(r.bytes(4)?.try_into().unwrap())),
+            0x44 => Instr::F64Const(f64::from_le_bytes(r.bytes(8)?.try_into().unwrap())),
+
+            0xd0 => Instr::RefNull(Type::parse_ref(r)?),
+            0xd1 => Instr::RefIsNull,
+            0xd2 => Instr::RefFunc(r.u32()?),
+
+            0xfc => {
+                let sub = r.u32()?;
+                match sub {
+                    0..=7 => Instr::Op(Op::from_opcode(0xfc00 | sub).unwrap()),
+                    8 => {
+                        let data = r.u32()?;
+                        if r.byte()? != 0x00 {
+                            return Err(Error::Invalid(r.pos - 1, "zero byte expected".to_string()));
+                        }
+                        Instr::MemoryInit(data)
+                    }
+                    9 => Instr::DataDrop(r.u32()?),
+                    10 => {
+                        if r.bytes(2)? != [0x00, 0x00] {
+                            return Err(Error::Invalid(r.pos - 2, "zero byte expected".to_string()));
+                        }
+                        Instr::MemoryCopy
+                    }
+                    11 => {
+                        if r.byte()? != 0x00 {
+                            return Err(Error::Invalid(r.pos - 1, "zero byte expected".to_string()));
+                        }
+                        Instr::MemoryFill
+                    }
+                    12 => {
+                        let elem = r.u32()?;
+                        Instr::TableInit(elem, r.u32()?)
+                    }
+                    13 => Instr::ElemDrop(r.u32()?),
+                    14 => {
+                        let dst = r.u32()?;
+                        Instr::TableCopy(dst, r.u32()?)
+                    }
+                    15 => Instr::TableGrow(r.u32()?),
+                    16 => Instr::TableSize(r.u32()?),
+                    17 => Instr::TableFill(r.u32()?),
+                    _ => return Err(Error::Invalid(offset, format!("unknown instruction opcode: fc {:x}", sub)))
+                }
+            }
+
+            opc => match Op::from_opcode(opc as u32) {
+                Some(op) => Instr::Op(op),
+                None => return Err(Error::Invalid(offset, format!("unknown instruction opcode: {:x}", opc)))
+            }
         })
     }
 }
 
-fn read_unsigned_leb128(r: &mut dyn std::io::BufRead) -> std::io::Result<(u64, usize)> {
-    let mut res: u64 = 0;
-    let mut shift: u64 = 0;
-    let mut bytes: usize = 0;
-    loop {
-        let mut buf: [u8; 1] = [0];
-        r.read_exact(&mut buf)?;
-        bytes += 1;
-        res |= (buf[0] as u64 & 0x7f) << shift;
-        if (buf[0] & 0x80) == 0 {
-            return Ok((res, bytes))
+fn parse_block_type(r: &mut Reader) -> Result<BlockType> {
+    let offset = r.pos;
+    match r.bytes.get(r.pos) {
+        Some(0x40) => {
+            r.pos += 1;
+            Ok(BlockType::Empty)
         }
-        shift += 7;
-    }
-}
-
-fn read_signed_leb128(r: &mut dyn std::io::BufRead, bits: i64) -> std::io::Result<i64> {
-    let mut res: i64 = 0;
-    let mut shift: i64 = 0;
-    loop {
-        let mut buf: [u8; 1] = [0];
-        r.read_exact(&mut buf)?;
-        res |= (buf[0] as i64 & 0x7f) << shift;
-        if (buf[0] & 0x80) == 0 {
-            return Ok(if buf[0] & 0x40 != 0 && shift < bits {
-                res | (-1 << shift)
-            } else {
-                res
-            })
+        Some(b) if Type::from_byte(*b).is_some() => {
+            r.pos += 1;
+            Ok(BlockType::Value(Type::from_byte(*b).unwrap()))
+        }
+        _ => match r.signed(33)? {
+            idx if idx >= 0 => Ok(BlockType::Type(idx as u32)),
+            _ => Err(Error::Invalid(offset, "malformed block type".to_string()))
         }
-        shift += 7;
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
+    pub(crate) fn leb(mut n: u64) -> Vec<u8> {
+        let mut v = vec![];
+        loop {
+            let b = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                v.push(b);
+                return v;
+            }
+            v.push(b | 0x80);
+        }
+    }
+
+    pub(crate) fn name(s: &str) -> Vec<u8> {
+        let mut v = leb(s.len() as u64);
+        v.extend_from_slice(s.as_bytes());
+        v
+    }
+
+    /// A module from (section-id, contents) pairs.
+    pub(crate) fn module(sections: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        let mut v = b"\0asm\x01\0\0\0".to_vec();
+        for (id, contents) in sections {
+            v.push(*id);
+            v.extend(leb(contents.len() as u64));
+            v.extend(contents);
+        }
+        v
+    }
+
+    /// A code section entry from locals and body.
+    pub(crate) fn code(locals: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut f = locals.to_vec();
+        f.extend_from_slice(body);
+        let mut v = leb(f.len() as u64);
+        v.extend(f);
+        v
+    }
+
+    pub(crate) fn vec(items: &[Vec<u8>]) -> Vec<u8> {
+        let mut v = leb(items.len() as u64);
+        for item in items {
+            v.extend(item);
+        }
+        v
+    }
+
+    fn invalid(bytes: &[u8]) -> (usize, String) {
+        match Module::decode(bytes) {
+            Err(Error::Invalid(offset, msg)) => (offset, msg),
+            res => panic!("expected an error, got {:?}", res)
+        }
+    }
+
     #[test]
     fn parse_id_module() {
         let p = std::path::Path::new("./tests/id.wasm");
@@ -296,6 +1730,168 @@ mod tests {
         let mut buffered = std::io::BufReader::new(f);
         let m = Module::parse(&mut buffered).unwrap();
 
-        println!("{:?}", m);
+        assert_eq!(m.function_types, vec![(vec![], vec![]), (vec![Type::I32], vec![Type::I32])]);
+        assert_eq!(m.function_type_indexes, vec![0, 1]);
+        assert_eq!(m.memory_ranges, vec![(2, None)]);
+        assert_eq!(m.globals.len(), 10);
+        assert_eq!(m.globals[0], (Type::I32, true, vec![Instr::I32Const(66560), Instr::End]));
+        assert_eq!(m.exports[1], ("__wasm_call_ctors".to_string(), Export::Func, 0));
+        assert_eq!(m.exports[2], ("id".to_string(), Export::Func, 1));
+        assert_eq!(m.functions[1].body, vec![Instr::LocalGet(0), Instr::End]);
+        assert_eq!(m.functions[1].arguments, vec![Type::I32]);
+        let names: Vec<&str> = m.custom_sections.iter().map(|(_, n, _)| n.as_str()).collect();
+        assert_eq!(names, vec!["name", "producers", "target_features"]);
+    }
+
+    #[test]
+    fn all_sections() {
+        let bytes = module(&[
+            (1, vec(&[vec![0x60, 1, 0x7f, 1, 0x7f], vec![0x60, 0, 0]])),
+            (2, vec(&[
+                [name("env"), name("f"), vec![0x00, 0]].concat(),
+                [name("env"), name("g"), vec![0x03, 0x7e, 0]].concat(),
+            ])),
+            (3, vec(&[vec![0], vec![1]])),
+            (4, vec(&[vec![0x70, 0x01, 1, 2]])),
+            (5, vec(&[vec![0x01, 1, 16]])),
+            (6, vec(&[vec![0x7f, 1, 0x41, 0x7f, 0x0b], vec![0x7e, 0, 0x23, 0, 0x0b]])),
+            (7, vec(&[[name("run"), vec![0x00, 1]].concat(), [name("mem"), vec![0x02, 0]].concat()])),
+            (8, vec![2]),
+            (9, vec(&[vec![0x00, 0x41, 0, 0x0b, 2, 1, 2], vec![0x05, 0x70, 1, 0xd2, 0, 0x0b]])),
+            (12, vec![2]),
+            (10, vec(&[
+                code(&[1, 1, 0x7f], &[0x20, 0, 0x20, 1, 0x6a, 0x0b]),
+                code(&[0], &[0x0b]),
+            ])),
+            (11, vec(&[
+                [vec![0x00, 0x41, 8, 0x0b], name("hello")].concat(),
+                [vec![0x01], name("passive")].concat(),
+            ])),
+        ]);
+        let m = Module::decode(&bytes).unwrap();
+        assert_eq!(m.imports[0], Import {
+            module: "env".to_string(), name: "f".to_string(), desc: ImportDesc::Func(0) });
+        assert_eq!(m.imports[1].desc, ImportDesc::Global(Type::I64, false));
+        assert_eq!(m.num_functions(), 3);
+        assert_eq!(m.functions[0].index, 1);
+        assert_eq!(m.functions[0].locals, vec![(1, Type::I32)]);
+        assert_eq!(m.functions[0].body, vec![
+            Instr::LocalGet(0), Instr::LocalGet(1), Instr::Op(Op::I32Add), Instr::End]);
+        assert_eq!(m.tables, vec![(Type::FuncRef, (1, Some(2)))]);
+        assert_eq!(m.memory_ranges, vec![(1, Some(16))]);
+        assert_eq!(m.globals[0], (Type::I32, true, vec![Instr::I32Const(-1), Instr::End]));
+        assert_eq!(m.global_type(2), Some((Type::I64, false)));
+        assert_eq!(m.exports, vec![("run".to_string(), Export::Func, 1), ("mem".to_string(), Export::Memory, 0)]);
+        assert_eq!(m.start, Some(2));
+        assert_eq!(m.elements[0], Element {
+            mode: ElementMode::Active(0, vec![Instr::I32Const(0), Instr::End]),
+            typ: Type::FuncRef,
+            init: ElementInit::Funcs(vec![1, 2]),
+        });
+        assert_eq!(m.elements[1].mode, ElementMode::Passive);
+        assert_eq!(m.elements[1].init, ElementInit::Exprs(vec![vec![Instr::RefFunc(0), Instr::End]]));
+        assert_eq!(m.data_count, Some(2));
+        assert_eq!(m.data[0], Data {
+            mode: DataMode::Active(0, vec![Instr::I32Const(8), Instr::End]),
+            bytes: b"hello".to_vec(),
+        });
+        assert_eq!(m.data[1].mode, DataMode::Passive);
+    }
+
+    #[test]
+    fn instructions() {
+        let body = [
+            0x02, 0x40,                         // block
+            0x03, 0x7f,                         // loop (result i32)
+            0x41, 0x80, 0x80, 0x80, 0x80, 0x78, // i32.const -2^31
+            0x04, 0x00,                         // if (type 0)
+            0x42, 0x7f,                         // i64.const -1
+            0x1a,                               // drop
+            0x05,                               // else
+            0x0b,                               // end
+            0x41, 0x00,                         // i32.const 0
+            0x0e, 2, 0, 1, 1,                   // br_table 0 1 1
+            0x0b,                               // end
+            0x1a,                               // drop
+            0x0b,                               // end
+            0x41, 0x00, 0x28, 0x02, 0x10,       // i32.const 0, i32.load offset=16
+            0x41, 0x00, 0x2c, 0x00, 0x00,       // i32.const 0, i32.load8_s
+            0x71, 0x1a,                         // i32.and, drop
+            0x43, 0x00, 0x00, 0x80, 0x3f,       // f32.const 1
+            0xfc, 0x00, 0x1a,                   // i32.trunc_sat_f32_s, drop
+            0x3f, 0x00, 0x40, 0x00, 0x1a,       // memory.size, memory.grow, drop
+            0x41, 0, 0x41, 0, 0x41, 0, 0xfc, 0x0b, 0x00, // memory.fill
+            0x0b,
+        ];
+        let bytes = module(&[
+            (1, vec(&[vec![0x60, 0, 0]])),
+            (3, vec(&[vec![0]])),
+            (5, vec(&[vec![0x00, 1]])),
+            (10, vec(&[code(&[0], &body)])),
+        ]);
+        let m = Module::decode(&bytes).unwrap();
+        let body: Vec<String> = m.functions[0].body.iter().map(|i| i.to_string()).collect();
+        assert_eq!(body, vec![
+            "(block)", "(loop (result i32))", "(i32.const 80000000)", "(if (type 0))",
+            "(i64.const ffffffffffffffff)", "(drop)", "(else)", "(end)", "(i32.const 0)",
+            "(br_table 0 1 1)", "(end)", "(drop)", "(end)", "(i32.const 0)", "(i32.load offset=16)",
+            "(i32.const 0)", "(i32.load8_s)", "(i32.and)", "(drop)", "(f32.const 1)",
+            "(i32.trunc_sat_f32_s)", "(drop)", "(memory.size)", "(memory.grow)", "(drop)",
+            "(i32.const 0)", "(i32.const 0)", "(i32.const 0)", "(memory.fill)", "(end)",
+        ]);
+    }
+
+    #[test]
+    fn errors() {
+        let types = (1, vec(&[vec![0x60, 0, 0]]));
+        let funcs = (3, vec(&[vec![0]]));
+
+        assert_eq!(invalid(b"\0asn\x01\0\0\0"), (0, "bad magic number".to_string()));
+        assert_eq!(invalid(&module(&[types.clone(), funcs.clone()])),
+            (18, "function and code section have inconsistent lengths".to_string()));
+        assert_eq!(invalid(&module(&[(5, vec(&[vec![0x00, 1]])), types.clone()])),
+            (13, "unexpected type section".to_string()));
+        assert_eq!(invalid(&module(&[(3, vec(&[vec![1]]))])), (11, "unknown type 1".to_string()));
+        assert_eq!(invalid(&module(&[(13, vec![])])), (8, "unknown section ID 13".to_string()));
+        assert_eq!(invalid(&module(&[(5, vec![1, 0x00, 1, 0])])),
+            (13, "memory section size mismatch".to_string()));
+
+        let func = |body: &[u8]| module(&[types.clone(), funcs.clone(), (10, vec(&[code(&[0], body)]))]);
+        // The body starts at offset 23:
+        assert_eq!(invalid(&func(&[0x01, 0xff, 0x0b])), (24, "unknown instruction opcode: ff".to_string()));
+        assert_eq!(invalid(&func(&[0x20, 0, 0x0b])), (23, "unknown local 0".to_string()));
+        assert_eq!(invalid(&func(&[0x0c, 1, 0x0b])), (23, "unknown label 1".to_string()));
+        assert_eq!(invalid(&func(&[0x05, 0x0b])), (23, "`else` without `if`".to_string()));
+        assert_eq!(invalid(&func(&[0x01])), (24, "function body does not end with `end`".to_string()));
+        assert_eq!(invalid(&func(&[0x0b, 0x01])), (24, "function body size mismatch".to_string()));
+        assert_eq!(invalid(&func(&[0x41, 0x80, 0x80, 0x80, 0x80, 0x10, 0x0b])),
+            (24, "integer too large".to_string()));
+        assert_eq!(invalid(&func(&[0x41, 0, 0x28, 2, 0, 0x0b])), (25, "unknown memory 0".to_string()));
+
+        let with_memory = module(&[types.clone(), funcs.clone(), (5, vec(&[vec![0x00, 1]])),
+            (10, vec(&[code(&[0], &[0x41, 0, 0x28, 3, 0, 0x1a, 0x0b])]))]);
+        assert_eq!(invalid(&with_memory), (30, "alignment of i32.load must not be larger than natural".to_string()));
+
+        // Operand types, the body still starts at offset 23:
+        assert_eq!(invalid(&func(&[0x6a, 0x0b])), (23, "type mismatch: operand stack underflow".to_string()));
+        assert_eq!(invalid(&func(&[0x42, 1, 0x41, 1, 0x6a, 0x1a, 0x0b])),
+            (27, "type mismatch: expected i32, found i64".to_string()));
+        assert_eq!(invalid(&func(&[0x02, 0x7f, 0x0b, 0x1a, 0x0b])),
+            (25, "type mismatch: operand stack underflow".to_string()));
+        assert_eq!(invalid(&func(&[0x02, 0x40, 0x41, 0, 0x0b, 0x0b])),
+            (27, "type mismatch: values left on the operand stack at the end of the block".to_string()));
+        assert_eq!(invalid(&func(&[0x41, 0, 0x04, 0x7f, 0x41, 1, 0x0b, 0x1a, 0x0b])),
+            (29, "type mismatch: `if` without `else` must leave its parameters".to_string()));
+        assert_eq!(invalid(&func(&[0x02, 0x7f, 0x41, 0, 0x41, 0, 0x0e, 1, 0, 1, 0x0b, 0x1a, 0x0b])),
+            (29, "type mismatch: br_table targets have different arities".to_string()));
+
+        // A `() -> i32` function, one byte more of types moves the body to 24:
+        let returns_i32 = |body: &[u8]| module(&[(1, vec(&[vec![0x60, 0, 1, 0x7f]])), funcs.clone(),
+            (10, vec(&[code(&[0], body)]))]);
+        assert_eq!(invalid(&returns_i32(&[0x6a, 0x0b])), (24, "type mismatch: operand stack underflow".to_string()));
+        assert_eq!(invalid(&returns_i32(&[0x42, 1, 0x0b])),
+            (26, "type mismatch: expected i32, found i64".to_string()));
+        assert_eq!(invalid(&returns_i32(&[0x0b])), (24, "type mismatch: operand stack underflow".to_string()));
+        assert!(Module::decode(&returns_i32(&[0x00, 0x0b])).is_ok());
     }
 }
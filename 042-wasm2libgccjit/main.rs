@@ -0,0 +1,37 @@
+/*
+ * Command line interface:
+ *
+ *   wasm2libgcc dump [--fold] <file.wasm>   print a module in the text format
+ */
+
+use std::process::ExitCode;
+
+use wasm2libgcc::Module;
+
+fn usage() -> ExitCode {
+    eprintln!("usage: wasm2libgcc dump [--fold] <file.wasm>");
+    ExitCode::from(2)
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (fold, path) = match args[..] {
+        ["dump", path] => (false, path),
+        ["dump", "--fold", path] => (true, path),
+        _ => return usage()
+    };
+    let m = match std::fs::read(path).map_err(wasm2libgcc::Error::from).and_then(|bytes| Module::decode(&bytes)) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if fold {
+        println!("{:#}", m);
+    } else {
+        println!("{}", m);
+    }
+    ExitCode::SUCCESS
+}
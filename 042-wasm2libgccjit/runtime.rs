@@ -0,0 +1,282 @@
+/*
+ * The host side of translated modules: linear memory, tables and the
+ * imported functions, which are resolved to WASI.
+ *
+ * The memory and tables are owned by the `Instance`, the generated code
+ * sees them through the `wasm_memory` and `wasm_table_<n>` symbols, which
+ * are updated whenever they grow and move. Accesses are bounds checked by
+ * the generated code.
+ */
+
+use crate::{wasi, Error, ImportDesc, Module, Result};
+#[cfg(feature = "jit")]
+use crate::{Jit, PAGE_SIZE, MAX_PAGES};
+
+/// What a host function sees of the instance that called it.
+pub struct Caller<'a> {
+    pub memory: &'a mut [u8],
+    pub wasi: &'a mut wasi::Wasi,
+}
+
+/// The module called `proc_exit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exit(pub i32);
+
+/// An imported function. The slots hold the arguments as bit patterns
+/// zero extended to 64 bits and are overwritten with the results.
+pub type HostFunc = fn(&mut Caller, &mut [u64]) -> std::result::Result<(), Exit>;
+
+/// Resolve the imports of a module, only WASI functions can be imported.
+pub fn link(m: &Module) -> Result<Vec<HostFunc>> {
+    m.imports.iter().map(|import| {
+        let name = format!("{}.{}", import.module, import.name);
+        let ImportDesc::Func(t) = import.desc else {
+            return Err(Error::Link(format!("cannot import {}, only functions are supported", name)));
+        };
+        let (f, typ) = match import.module.as_str() {
+            wasi::MODULE => wasi::lookup(&import.name),
+            _ => None
+        }.ok_or_else(|| Error::Link(format!("unknown import {}", name)))?;
+        if typ != m.function_types[t] {
+            return Err(Error::Link(format!("incompatible import type for {}", name)));
+        }
+        Ok(f)
+    }).collect()
+}
+
+#[cfg(feature = "jit")]
+type HostCall = extern "C" fn(*mut Instance, i32, *mut u64);
+#[cfg(feature = "jit")]
+type MemoryGrow = extern "C" fn(*mut Instance, i32) -> i32;
+#[cfg(feature = "jit")]
+type TableGrow = extern "C" fn(*mut Instance, i32, i32, u64) -> i32;
+
+/// A translated module with its memory, tables and WASI state.
+#[cfg(feature = "jit")]
+pub struct Instance {
+    jit: Jit,
+    /// The imported functions and the number of slots they use.
+    imports: Vec<(HostFunc, usize)>,
+    memory: Vec<u8>,
+    max_pages: usize,
+    /// The elements and maximum size of every table.
+    tables: Vec<(Vec<u64>, usize)>,
+    start: bool,
+    pub wasi: wasi::Wasi,
+}
+
+#[cfg(feature = "jit")]
+impl Instance {
+    /// Compile and instantiate a module: this runs its start function, if
+    /// any, and traps abort the process.
+    pub fn new(m: &Module, wasi: wasi::Wasi) -> Result<Box<Instance>> {
+        let imports = link(m)?.into_iter().enumerate().map(|(i, f)| {
+            let (args, rets) = &m.function_types[m.function_type_index(i).unwrap()];
+            (f, args.len().max(rets.len()).max(1))
+        }).collect();
+        let jit = Jit::compile(m)?;
+        let (min, max) = m.memory_ranges.first().copied().unwrap_or((0, Some(0)));
+        let start = m.exports.iter().any(|(name, kind, idx)| {
+            name == "_start" && *kind == crate::Export::Func
+                && m.function_types[m.function_type_index(*idx).unwrap()] == (vec![], vec![])
+        });
+
+        // Boxed, as the generated code keeps a pointer to the instance.
+        let mut instance = Box::new(Instance {
+            jit,
+            imports,
+            memory: vec![0; min * PAGE_SIZE],
+            max_pages: max.unwrap_or(MAX_PAGES),
+            tables: m.tables.iter()
+                .map(|(_, (min, max))| (vec![0; *min], max.unwrap_or(u32::MAX as usize)))
+                .collect(),
+            start,
+            wasi,
+        });
+        unsafe {
+            let ptr: *mut Instance = &mut *instance;
+            *instance.symbol::<*mut Instance>("wasm_instance") = ptr;
+            *instance.symbol::<HostCall>("wasm_host_call") = host_call;
+            *instance.symbol::<MemoryGrow>("wasm_memory_grow") = memory_grow;
+            *instance.symbol::<TableGrow>("wasm_table_grow") = table_grow;
+            instance.update_memory();
+            for i in 0..instance.tables.len() {
+                instance.update_table(i);
+            }
+            instance.jit.init();
+        }
+        Ok(instance)
+    }
+
+    fn symbol<T>(&self, name: &str) -> *mut T {
+        self.jit.symbol(name) as *mut T
+    }
+
+    unsafe fn update_memory(&mut self) {
+        *self.symbol::<*mut u8>("wasm_memory") = self.memory.as_mut_ptr();
+        *self.symbol::<u64>("wasm_memory_size") = self.memory.len() as u64;
+    }
+
+    unsafe fn update_table(&mut self, i: usize) {
+        *self.symbol::<*mut u64>(&format!("wasm_table_{}", i)) = self.tables[i].0.as_mut_ptr();
+        *self.symbol::<u32>(&format!("wasm_table_{}_size", i)) = self.tables[i].0.len() as u32;
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// The address of the exported function `name`, to be called with the
+    /// C calling convention.
+    pub fn export(&self, name: &str) -> Option<*const ()> {
+        self.jit.export(name)
+    }
+
+    /// Run the `_start` function of a WASI command. `proc_exit` exits the
+    /// process, there is no way to unwind the generated code.
+    pub fn run(&self) -> Result<()> {
+        if !self.start {
+            return Err(Error::Link("no `_start` function exported".to_string()));
+        }
+        let start: extern "C" fn() = unsafe { std::mem::transmute(self.export("_start").unwrap()) };
+        start();
+        Ok(())
+    }
+}
+
+#[cfg(feature = "jit")]
+extern "C" fn host_call(instance: *mut Instance, idx: i32, slots: *mut u64) {
+    let instance = unsafe { &mut *instance };
+    let (f, n) = instance.imports[idx as usize];
+    let slots = unsafe { std::slice::from_raw_parts_mut(slots, n) };
+    let mut caller = Caller { memory: &mut instance.memory, wasi: &mut instance.wasi };
+    if let Err(Exit(code)) = f(&mut caller, slots) {
+        std::process::exit(code);
+    }
+}
+
+#[cfg(feature = "jit")]
+extern "C" fn memory_grow(instance: *mut Instance, delta: i32) -> i32 {
+    let instance = unsafe { &mut *instance };
+    let old = instance.memory.len() / PAGE_SIZE;
+    let new = old + delta as u32 as usize;
+    if new > instance.max_pages || instance.memory.try_reserve_exact((new - old) * PAGE_SIZE).is_err() {
+        return -1;
+    }
+    instance.memory.resize(new * PAGE_SIZE, 0);
+    unsafe { instance.update_memory() };
+    old as i32
+}
+
+#[cfg(feature = "jit")]
+extern "C" fn table_grow(instance: *mut Instance, table: i32, n: i32, init: u64) -> i32 {
+    let instance = unsafe { &mut *instance };
+    let (elements, max) = &mut instance.tables[table as usize];
+    let old = elements.len();
+    let new = old + n as u32 as usize;
+    if new > *max || elements.try_reserve_exact(new - old).is_err() {
+        return -1;
+    }
+    elements.resize(new, init);
+    unsafe { instance.update_table(table as usize) };
+    old as i32
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::tests::{code, module, name, vec};
+    #[cfg(feature = "jit")]
+    use crate::wasi::tests::Output;
+
+    /// A WASI command writing "hello" to stdout.
+    pub(crate) fn hello() -> Vec<u8> {
+        let start = [
+            0x41, 1, 0x41, 0, 0x41, 1, 0x41, 8, // stdout, iovs, 1, &nwritten
+            0x10, 0, 0x1a, 0x0b,                // call fd_write, drop
+        ];
+        module(&[
+            (1, vec(&[vec![0x60, 4, 0x7f, 0x7f, 0x7f, 0x7f, 1, 0x7f], vec![0x60, 0, 0]])),
+            (2, vec(&[[name("wasi_snapshot_preview1"), name("fd_write"), vec![0x00, 0]].concat()])),
+            (3, vec(&[vec![1]])),
+            (5, vec(&[vec![0x00, 1]])),
+            (7, vec(&[[name("_start"), vec![0x00, 1]].concat(), [name("memory"), vec![0x02, 0]].concat()])),
+            (10, vec(&[code(&[0], &start)])),
+            (11, vec(&[
+                [vec![0x00, 0x41, 0, 0x0b], vec(&[vec![16], vec![0], vec![0], vec![0], vec![6], vec![0], vec![0], vec![0]])].concat(),
+                [vec![0x00, 0x41, 16, 0x0b], name("hello\n")].concat(),
+            ])),
+        ])
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn run_hello() {
+        let m = Module::decode(&hello()).unwrap();
+        let out = Output::default();
+        let mut wasi = wasi::Wasi::default();
+        wasi.stdout = Box::new(out.clone());
+        let instance = Instance::new(&m, wasi).unwrap();
+        instance.run().unwrap();
+        assert_eq!(out.0.borrow().as_slice(), b"hello\n");
+        assert_eq!(instance.memory()[8], 6);
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn memory_and_tables() {
+        let bytes = module(&[
+            (1, vec(&[vec![0x60, 1, 0x7f, 1, 0x7f], vec![0x60, 2, 0x7f, 0x7f, 0]])),
+            (3, vec(&[vec![0], vec![0], vec![1], vec![0], vec![0], vec![0]])),
+            (4, vec(&[vec![0x70, 0x00, 2]])),
+            (5, vec(&[vec![0x01, 1, 2]])),
+            (7, vec(&[
+                [name("grow"), vec![0x00, 0]].concat(),
+                [name("load"), vec![0x00, 1]].concat(),
+                [name("store"), vec![0x00, 2]].concat(),
+                [name("dispatch"), vec![0x00, 3]].concat(),
+            ])),
+            (9, vec(&[vec![0x00, 0x41, 0, 0x0b, 2, 4, 5]])),
+            (10, vec(&[
+                code(&[0], &[0x20, 0, 0x40, 0x00, 0x0b]),
+                code(&[0], &[0x20, 0, 0x2d, 0, 0, 0x0b]),
+                code(&[0], &[0x20, 0, 0x20, 1, 0x3b, 1, 0, 0x0b]),
+                code(&[0], &[0x41, 5, 0x20, 0, 0x11, 0, 0, 0x0b]),
+                code(&[0], &[0x20, 0, 0x41, 2, 0x6c, 0x0b]),
+                code(&[0], &[0x41, 0, 0x20, 0, 0x6b, 0x0b]),
+            ])),
+        ]);
+        let m = Module::decode(&bytes).unwrap();
+        let instance = Instance::new(&m, wasi::Wasi::default()).unwrap();
+        unsafe {
+            let grow: extern "C" fn(i32) -> i32 = std::mem::transmute(instance.export("grow").unwrap());
+            let load: extern "C" fn(i32) -> i32 = std::mem::transmute(instance.export("load").unwrap());
+            let store: extern "C" fn(i32, i32) = std::mem::transmute(instance.export("store").unwrap());
+            let dispatch: extern "C" fn(i32) -> i32 = std::mem::transmute(instance.export("dispatch").unwrap());
+            assert_eq!(grow(1), 1);
+            assert_eq!(grow(1), -1);
+            assert_eq!(instance.memory().len(), 2 * PAGE_SIZE);
+            store(70000, 0x1234);
+            assert_eq!((load(70000), load(70001)), (0x34, 0x12));
+            assert_eq!((dispatch(0), dispatch(1)), (10, -5));
+        }
+    }
+
+    #[test]
+    fn unknown_imports() {
+        let bytes = module(&[
+            (1, vec(&[vec![0x60, 0, 0]])),
+            (2, vec(&[[name("env"), name("f"), vec![0x00, 0]].concat()])),
+        ]);
+        let m = Module::decode(&bytes).unwrap();
+        assert!(matches!(link(&m), Err(Error::Link(msg)) if msg == "unknown import env.f"));
+
+        let bytes = module(&[
+            (1, vec(&[vec![0x60, 0, 0]])),
+            (2, vec(&[[name("wasi_snapshot_preview1"), name("fd_write"), vec![0x00, 0]].concat()])),
+        ]);
+        let m = Module::decode(&bytes).unwrap();
+        assert!(matches!(link(&m), Err(Error::Link(msg))
+            if msg == "incompatible import type for wasi_snapshot_preview1.fd_write"));
+    }
+}
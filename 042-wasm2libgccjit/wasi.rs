@@ -0,0 +1,291 @@
+/*
+ * The subset of WASI preview 1 needed to run C programs compiled with
+ * wasi-sdk that use stdio, their arguments and the environment.
+ *
+ * All functions get their arguments as 64 bit slots and return an errno,
+ * pointers into the linear memory are checked and fail with EFAULT.
+ */
+
+use std::io::{Read, Write};
+
+use crate::runtime::{Caller, Exit, HostFunc};
+use crate::Type;
+
+pub const MODULE: &str = "wasi_snapshot_preview1";
+
+const SUCCESS: i32 = 0;
+const EBADF: i32 = 8;
+const EFAULT: i32 = 21;
+const EINVAL: i32 = 28;
+const EIO: i32 = 29;
+const ESPIPE: i32 = 70;
+
+const FILETYPE_CHARACTER_DEVICE: u8 = 2;
+
+pub struct Wasi {
+    pub args: Vec<String>,
+    /// `NAME=value` pairs.
+    pub env: Vec<String>,
+    pub stdin: Box<dyn Read>,
+    pub stdout: Box<dyn Write>,
+    pub stderr: Box<dyn Write>,
+    start: std::time::Instant,
+}
+
+impl Default for Wasi {
+    fn default() -> Self {
+        Wasi::new(vec![], vec![])
+    }
+}
+
+impl Wasi {
+    pub fn new(args: Vec<String>, env: Vec<String>) -> Self {
+        Wasi {
+            args,
+            env,
+            stdin: Box::new(std::io::stdin()),
+            stdout: Box::new(std::io::stdout()),
+            stderr: Box::new(std::io::stderr()),
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+type Errno = std::result::Result<(), i32>;
+
+fn slice(memory: &mut [u8], ptr: u64, len: u64) -> std::result::Result<&mut [u8], i32> {
+    let start = ptr as usize;
+    let end = start.checked_add(len as usize).ok_or(EFAULT)?;
+    memory.get_mut(start..end).ok_or(EFAULT)
+}
+
+fn load_u32(memory: &mut [u8], ptr: u64) -> std::result::Result<u32, i32> {
+    Ok(u32::from_le_bytes(slice(memory, ptr, 4)?.try_into().unwrap()))
+}
+
+fn store(memory: &mut [u8], ptr: u64, bytes: &[u8]) -> Errno {
+    slice(memory, ptr, bytes.len() as u64)?.copy_from_slice(bytes);
+    Ok(())
+}
+
+/// The (pointer, length) pairs of an iovec array.
+fn iovecs(memory: &mut [u8], iovs: u64, n: u64) -> std::result::Result<Vec<(u64, u64)>, i32> {
+    (0..n).map(|i| {
+        let ptr = load_u32(memory, iovs + i * 8)?;
+        let len = load_u32(memory, iovs + i * 8 + 4)?;
+        Ok((ptr as u64, len as u64))
+    }).collect()
+}
+
+/// `args_get` and `environ_get`: a pointer array and the strings.
+fn strings_get(memory: &mut [u8], strings: &[String], ptrs: u64, buf: u64) -> Errno {
+    let mut offset = buf;
+    for (i, s) in strings.iter().enumerate() {
+        store(memory, ptrs + i as u64 * 4, &(offset as u32).to_le_bytes())?;
+        store(memory, offset, s.as_bytes())?;
+        store(memory, offset + s.len() as u64, &[0])?;
+        offset += s.len() as u64 + 1;
+    }
+    Ok(())
+}
+
+fn strings_sizes_get(memory: &mut [u8], strings: &[String], count: u64, size: u64) -> Errno {
+    let total: usize = strings.iter().map(|s| s.len() + 1).sum();
+    store(memory, count, &(strings.len() as u32).to_le_bytes())?;
+    store(memory, size, &(total as u32).to_le_bytes())
+}
+
+fn args_get(c: &mut Caller, s: &[u64]) -> Errno {
+    strings_get(c.memory, &c.wasi.args, s[0], s[1])
+}
+
+fn args_sizes_get(c: &mut Caller, s: &[u64]) -> Errno {
+    strings_sizes_get(c.memory, &c.wasi.args, s[0], s[1])
+}
+
+fn environ_get(c: &mut Caller, s: &[u64]) -> Errno {
+    strings_get(c.memory, &c.wasi.env, s[0], s[1])
+}
+
+fn environ_sizes_get(c: &mut Caller, s: &[u64]) -> Errno {
+    strings_sizes_get(c.memory, &c.wasi.env, s[0], s[1])
+}
+
+fn clock_time_get(c: &mut Caller, s: &[u64]) -> Errno {
+    let nanos = match s[0] as u32 {
+        // realtime
+        0 => std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_err(|_| EIO)?,
+        // monotonic, process and thread cputime
+        1..=3 => c.wasi.start.elapsed(),
+        _ => return Err(EINVAL)
+    }.as_nanos() as u64;
+    store(c.memory, s[2], &nanos.to_le_bytes())
+}
+
+fn fd_write(c: &mut Caller, s: &[u64]) -> Errno {
+    let out: &mut dyn Write = match s[0] as u32 {
+        1 => &mut c.wasi.stdout,
+        2 => &mut c.wasi.stderr,
+        _ => return Err(EBADF)
+    };
+    let mut written = 0u32;
+    for (ptr, len) in iovecs(c.memory, s[1], s[2] as u32 as u64)? {
+        out.write_all(slice(c.memory, ptr, len)?).map_err(|_| EIO)?;
+        written += len as u32;
+    }
+    out.flush().map_err(|_| EIO)?;
+    store(c.memory, s[3], &written.to_le_bytes())
+}
+
+fn fd_read(c: &mut Caller, s: &[u64]) -> Errno {
+    if s[0] as u32 != 0 {
+        return Err(EBADF);
+    }
+    let mut read = 0u32;
+    for (ptr, len) in iovecs(c.memory, s[1], s[2] as u32 as u64)? {
+        let n = c.wasi.stdin.read(slice(c.memory, ptr, len)?).map_err(|_| EIO)?;
+        read += n as u32;
+        if (n as u64) < len {
+            break;
+        }
+    }
+    store(c.memory, s[3], &read.to_le_bytes())
+}
+
+fn fd_close(_: &mut Caller, s: &[u64]) -> Errno {
+    match s[0] as u32 {
+        0..=2 => Ok(()),
+        _ => Err(EBADF)
+    }
+}
+
+fn fd_seek(_: &mut Caller, s: &[u64]) -> Errno {
+    match s[0] as u32 {
+        0..=2 => Err(ESPIPE),
+        _ => Err(EBADF)
+    }
+}
+
+fn fd_fdstat_get(c: &mut Caller, s: &[u64]) -> Errno {
+    if s[0] as u32 > 2 {
+        return Err(EBADF);
+    }
+    // filetype, flags, rights and inherited rights:
+    let mut stat = [0u8; 24];
+    stat[0] = FILETYPE_CHARACTER_DEVICE;
+    stat[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+    store(c.memory, s[1], &stat)
+}
+
+/// Wrap a function returning an errno in a `HostFunc`.
+macro_rules! errno {
+    ($f:ident) => {
+        |c: &mut Caller, s: &mut [u64]| -> std::result::Result<(), Exit> {
+            s[0] = match $f(c, s) {
+                Ok(()) => SUCCESS,
+                Err(errno) => errno,
+            } as u32 as u64;
+            Ok(())
+        }
+    }
+}
+
+/// Argument and result types.
+type Signature = (Vec<Type>, Vec<Type>);
+
+/// The implementation and type of a WASI function.
+pub fn lookup(name: &str) -> Option<(HostFunc, Signature)> {
+    use Type::*;
+    let (f, args): (HostFunc, Vec<Type>) = match name {
+        "args_get" => (errno!(args_get), vec![I32, I32]),
+        "args_sizes_get" => (errno!(args_sizes_get), vec![I32, I32]),
+        "environ_get" => (errno!(environ_get), vec![I32, I32]),
+        "environ_sizes_get" => (errno!(environ_sizes_get), vec![I32, I32]),
+        "clock_time_get" => (errno!(clock_time_get), vec![I32, I64, I32]),
+        "fd_write" => (errno!(fd_write), vec![I32, I32, I32, I32]),
+        "fd_read" => (errno!(fd_read), vec![I32, I32, I32, I32]),
+        "fd_close" => (errno!(fd_close), vec![I32]),
+        "fd_seek" => (errno!(fd_seek), vec![I32, I64, I32, I32]),
+        "fd_fdstat_get" => (errno!(fd_fdstat_get), vec![I32, I32]),
+        "proc_exit" => return Some((|_, s| Err(Exit(s[0] as i32)), (vec![I32], vec![]))),
+        _ => return None
+    };
+    Some((f, (args, vec![I32])))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A writer the test can look at after handing it to `Wasi`.
+    #[derive(Clone, Default)]
+    pub(crate) struct Output(pub Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn call(wasi: &mut Wasi, memory: &mut [u8], name: &str, args: &[u64]) -> i32 {
+        let (f, _) = lookup(name).unwrap();
+        let mut slots = args.to_vec();
+        f(&mut Caller { memory, wasi }, &mut slots).unwrap();
+        slots[0] as i32
+    }
+
+    #[test]
+    fn fd_write_and_read() {
+        let out = Output::default();
+        let mut wasi = Wasi::new(vec![], vec![]);
+        wasi.stdout = Box::new(out.clone());
+        wasi.stdin = Box::new(&b"input"[..]);
+
+        let mut memory = vec![0u8; 64];
+        memory[32..38].copy_from_slice(b"hello ");
+        memory[40..46].copy_from_slice(b"world\n");
+        // Two iovecs at 0: (32, 6), (40, 6).
+        memory[0..16].copy_from_slice(&[32, 0, 0, 0, 6, 0, 0, 0, 40, 0, 0, 0, 6, 0, 0, 0]);
+        assert_eq!(call(&mut wasi, &mut memory, "fd_write", &[1, 0, 2, 16]), SUCCESS);
+        assert_eq!(out.0.borrow().as_slice(), b"hello world\n");
+        assert_eq!(memory[16], 12);
+
+        assert_eq!(call(&mut wasi, &mut memory, "fd_read", &[0, 0, 1, 16]), SUCCESS);
+        assert_eq!(&memory[32..37], b"input");
+        assert_eq!(memory[16], 5);
+
+        assert_eq!(call(&mut wasi, &mut memory, "fd_write", &[5, 0, 1, 16]), EBADF);
+        assert_eq!(call(&mut wasi, &mut memory, "fd_write", &[1, 60, 1, 16]), EFAULT);
+    }
+
+    #[test]
+    fn args_and_environ() {
+        let mut wasi = Wasi::new(vec!["prog".to_string(), "-v".to_string()], vec!["A=1".to_string()]);
+        let mut memory = vec![0u8; 64];
+        assert_eq!(call(&mut wasi, &mut memory, "args_sizes_get", &[0, 4]), SUCCESS);
+        assert_eq!(&memory[0..8], &[2, 0, 0, 0, 8, 0, 0, 0]);
+        assert_eq!(call(&mut wasi, &mut memory, "args_get", &[16, 32]), SUCCESS);
+        assert_eq!(&memory[16..24], &[32, 0, 0, 0, 37, 0, 0, 0]);
+        assert_eq!(&memory[32..40], b"prog\0-v\0");
+
+        assert_eq!(call(&mut wasi, &mut memory, "environ_sizes_get", &[0, 4]), SUCCESS);
+        assert_eq!(&memory[0..8], &[1, 0, 0, 0, 4, 0, 0, 0]);
+        assert_eq!(call(&mut wasi, &mut memory, "environ_get", &[16, 48]), SUCCESS);
+        assert_eq!(&memory[48..52], b"A=1\0");
+
+        assert_eq!(call(&mut wasi, &mut memory, "clock_time_get", &[0, 0, 8]), SUCCESS);
+        assert!(u64::from_le_bytes(memory[8..16].try_into().unwrap()) > 0);
+        assert_eq!(call(&mut wasi, &mut memory, "clock_time_get", &[7, 0, 8]), EINVAL);
+
+        let (exit, _) = lookup("proc_exit").unwrap();
+        let mut wasi = Wasi::default();
+        assert_eq!(exit(&mut Caller { memory: &mut memory, wasi: &mut wasi }, &mut [3]), Err(Exit(3)));
+    }
+}
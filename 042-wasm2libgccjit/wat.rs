@@ -0,0 +1,745 @@
+/*
+ * Printing of modules in the WebAssembly text format, in the layout of
+ * `wasm-tools print`: names come from the "name" custom section, labels
+ * are numbered by their depth in comments, and floats are written in
+ * hexadecimal. The alternate form (`{:#}`) folds instructions into
+ * S-expressions wherever their operands are known.
+ */
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::{BlockType, DataMode, ElementInit, ElementMode, Export, Function, ImportDesc, Instr,
+            Module, Reader, Result, Type};
+
+/// Subsections of the name section holding a plain name map.
+const FUNCTION_NAMES: u8 = 1;
+const TYPE_NAMES: u8 = 4;
+const TABLE_NAMES: u8 = 5;
+const MEMORY_NAMES: u8 = 6;
+const GLOBAL_NAMES: u8 = 7;
+const ELEM_NAMES: u8 = 8;
+const DATA_NAMES: u8 = 9;
+
+#[derive(Default)]
+struct Names {
+    module: Option<String>,
+    /// Name maps by subsection ID.
+    maps: HashMap<u8, HashMap<usize, String>>,
+    /// Local names by function index.
+    locals: HashMap<usize, HashMap<usize, String>>,
+}
+
+impl Names {
+    /// Decode the name section, which is only informative: what comes
+    /// after an error is ignored.
+    fn parse(m: &Module) -> Names {
+        let mut names = Names::default();
+        if let Some((_, _, bytes)) = m.custom_sections.iter().find(|(_, name, _)| name == "name") {
+            let _ = names.parse_subsections(&mut Reader { bytes, pos: 0 });
+        }
+        names
+    }
+
+    fn parse_subsections(&mut self, r: &mut Reader) -> Result<()> {
+        let name_map = |r: &mut Reader| -> Result<HashMap<usize, String>> {
+            Ok(r.vec(|r| Ok((r.u32()? as usize, r.name()?)))?.into_iter().collect())
+        };
+        while !r.eof() {
+            let id = r.byte()?;
+            let size = r.u32()? as usize;
+            let mut sub = Reader { bytes: r.bytes(size)?, pos: 0 };
+            match id {
+                0 => self.module = Some(sub.name()?),
+                2 => {
+                    for (func, locals) in sub.vec(|r| Ok((r.u32()? as usize, name_map(r)?)))? {
+                        self.locals.insert(func, locals);
+                    }
+                }
+                1 | 4..=9 => {
+                    self.maps.insert(id, name_map(&mut sub)?);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self, map: u8, idx: usize) -> Option<&str> {
+        self.maps.get(&map)?.get(&idx).map(|n| n.as_str()).filter(|n| is_id(n))
+    }
+
+    fn local(&self, func: usize, idx: usize) -> Option<&str> {
+        self.locals.get(&func)?.get(&idx).map(|n| n.as_str()).filter(|n| is_id(n))
+    }
+}
+
+/// Whether `$name` is a valid identifier.
+fn is_id(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-./:<=>?@\\^_`|~".contains(c))
+}
+
+/// A string literal, bytes outside printable ASCII are escaped in hex.
+fn string(bytes: &[u8]) -> String {
+    let mut s = String::from("\"");
+    for b in bytes {
+        match b {
+            b'\t' => s.push_str("\\t"),
+            b'\n' => s.push_str("\\n"),
+            b'\r' => s.push_str("\\r"),
+            b'"' => s.push_str("\\\""),
+            b'\'' => s.push_str("\\'"),
+            b'\\' => s.push_str("\\\\"),
+            0x20..=0x7e => s.push(*b as char),
+            _ => write!(s, "\\{:02x}", b).unwrap(),
+        }
+    }
+    s.push('"');
+    s
+}
+
+/// A float in hexadecimal notation from its bits, for example 0x1.8p+1.
+fn hex_float(bits: u64, mantissa_bits: u32, exponent_bits: u32) -> String {
+    let sign = if bits >> (mantissa_bits + exponent_bits) != 0 { "-" } else { "" };
+    let max_exponent = (1 << exponent_bits) - 1;
+    let bias = (max_exponent >> 1) as i64;
+    let mut exponent = ((bits >> mantissa_bits) & max_exponent) as i64;
+    let mut mantissa = bits & ((1 << mantissa_bits) - 1);
+    let subnormal = exponent == 0;
+    if exponent == max_exponent as i64 {
+        return match mantissa {
+            0 => format!("{}inf", sign),
+            m if m == 1 << (mantissa_bits - 1) => format!("{}nan", sign),
+            m => format!("{}nan:0x{:x}", sign, m),
+        };
+    }
+    if subnormal {
+        if mantissa == 0 {
+            return format!("{}0x0p+0", sign);
+        }
+        // Subnormals are normalized:
+        exponent = 1;
+        while mantissa & (1 << mantissa_bits) == 0 {
+            mantissa <<= 1;
+            exponent -= 1;
+        }
+        mantissa &= (1 << mantissa_bits) - 1;
+    }
+    // Align the fraction to whole hex digits:
+    let digits = mantissa_bits.div_ceil(4);
+    let mut fraction = format!("{:01$x}", mantissa << (digits * 4 - mantissa_bits), digits as usize);
+    while fraction.ends_with('0') {
+        fraction.pop();
+    }
+    // Like wasmprinter, subnormals keep the dot even without a fraction
+    // left after normalizing (0x1.p-149):
+    let dot = if fraction.is_empty() && !subnormal { "" } else { "." };
+    format!("{}0x1{}{}p{:+}", sign, dot, fraction, exponent - bias)
+}
+
+fn f32_text(x: f32) -> String {
+    let hex = hex_float(x.to_bits() as u64, 23, 8);
+    if x.is_finite() { format!("{} (;={};)", hex, x) } else { hex }
+}
+
+fn f64_text(x: f64) -> String {
+    let hex = hex_float(x.to_bits(), 52, 11);
+    if x.is_finite() { format!("{} (;={};)", hex, x) } else { hex }
+}
+
+/// Lines of text, indented relative to the first.
+type Lines = Vec<String>;
+
+fn indent(lines: Lines, by: usize) -> impl Iterator<Item = String> {
+    lines.into_iter().map(move |l| format!("{:1$}{2}", "", by, l))
+}
+
+struct Printer<'a> {
+    m: &'a Module,
+    names: Names,
+}
+
+impl<'a> Printer<'a> {
+    /// The name of an index, or the index.
+    fn index(&self, map: u8, idx: usize) -> String {
+        match self.names.get(map, idx) {
+            Some(name) => format!("${}", name),
+            None => idx.to_string()
+        }
+    }
+
+    /// The name and index in a definition.
+    fn definition(&self, map: u8, idx: usize) -> String {
+        match self.names.get(map, idx) {
+            Some(name) => format!("${} (;{};)", name, idx),
+            None => format!("(;{};)", idx)
+        }
+    }
+
+    fn local(&self, func: usize, idx: u32) -> String {
+        match self.names.local(func, idx as usize) {
+            Some(name) => format!("${}", name),
+            None => idx.to_string()
+        }
+    }
+
+    fn block_type(&self, bt: &BlockType) -> String {
+        match bt {
+            BlockType::Type(idx) => format!(" (type {})", self.index(TYPE_NAMES, *idx as usize)),
+            bt => bt.to_string()
+        }
+    }
+
+    /// Params and results, named locals get a declaration of their own.
+    fn signature(&self, func: Option<usize>, params: &[Type], results: &[Type]) -> String {
+        let mut s = self.locals("param", func, 0, params);
+        if !results.is_empty() {
+            s.push_str(" (result");
+            for t in results {
+                write!(s, " {}", t).unwrap();
+            }
+            s.push(')');
+        }
+        s
+    }
+
+    fn locals(&self, kind: &str, func: Option<usize>, first: usize, types: &[Type]) -> String {
+        let mut s = String::new();
+        let mut open = false;
+        for (i, t) in types.iter().enumerate() {
+            match func.and_then(|f| self.names.local(f, first + i)) {
+                Some(name) => {
+                    if open {
+                        s.push(')');
+                        open = false;
+                    }
+                    write!(s, " ({} ${} {})", kind, name, t).unwrap();
+                }
+                None => {
+                    if !open {
+                        write!(s, " ({}", kind).unwrap();
+                        open = true;
+                    }
+                    write!(s, " {}", t).unwrap();
+                }
+            }
+        }
+        if open {
+            s.push(')');
+        }
+        s
+    }
+
+    /// Every instruction but the structured ones, `depth` blocks deep.
+    fn instr(&self, instr: &Instr, func: usize, depth: usize) -> String {
+        let label = |l: &u32| format!("{} (;@{};)", l, depth - *l as usize);
+        let func_index = |idx: &u32| self.index(FUNCTION_NAMES, *idx as usize);
+        let table = |idx: &u32| self.index(TABLE_NAMES, *idx as usize);
+        let elem = |idx: &u32| self.index(ELEM_NAMES, *idx as usize);
+        let data = |idx: &u32| self.index(DATA_NAMES, *idx as usize);
+        let global = |idx: &u32| self.index(GLOBAL_NAMES, *idx as usize);
+        match instr {
+            Instr::Unreachable => "unreachable".to_string(),
+            Instr::NOp => "nop".to_string(),
+            Instr::Block(_) | Instr::Loop(_) | Instr::If(_) | Instr::Else | Instr::End => {
+                unreachable!("structured instruction {}", instr)
+            }
+            Instr::Br(l) => format!("br {}", label(l)),
+            Instr::BrIf(l) => format!("br_if {}", label(l)),
+            Instr::BrTable(ls, l) => {
+                let mut s = "br_table".to_string();
+                for l in ls.iter().chain(std::iter::once(l)) {
+                    write!(s, " {}", label(l)).unwrap();
+                }
+                s
+            }
+            Instr::Return => "return".to_string(),
+            Instr::Call(idx) => format!("call {}", func_index(idx)),
+            Instr::CallIndirect(t, 0) => format!("call_indirect (type {})", self.index(TYPE_NAMES, *t as usize)),
+            Instr::CallIndirect(t, idx) => {
+                format!("call_indirect {} (type {})", table(idx), self.index(TYPE_NAMES, *t as usize))
+            }
+            Instr::RefNull(t) => format!("ref.null {}", if *t == Type::FuncRef { "func" } else { "extern" }),
+            Instr::RefIsNull => "ref.is_null".to_string(),
+            Instr::RefFunc(idx) => format!("ref.func {}", func_index(idx)),
+            Instr::Drop => "drop".to_string(),
+            Instr::Select => "select".to_string(),
+            Instr::SelectT(ts) => {
+                let mut s = "select (result".to_string();
+                for t in ts {
+                    write!(s, " {}", t).unwrap();
+                }
+                s + ")"
+            }
+            Instr::LocalGet(idx) => format!("local.get {}", self.local(func, *idx)),
+            Instr::LocalSet(idx) => format!("local.set {}", self.local(func, *idx)),
+            Instr::LocalTee(idx) => format!("local.tee {}", self.local(func, *idx)),
+            Instr::GlobalGet(idx) => format!("global.get {}", global(idx)),
+            Instr::GlobalSet(idx) => format!("global.set {}", global(idx)),
+            Instr::TableGet(idx) => format!("table.get {}", table(idx)),
+            Instr::TableSet(idx) => format!("table.set {}", table(idx)),
+            Instr::TableInit(e, 0) => format!("table.init {}", elem(e)),
+            Instr::TableInit(e, t) => format!("table.init {} {}", table(t), elem(e)),
+            Instr::ElemDrop(e) => format!("elem.drop {}", elem(e)),
+            Instr::TableCopy(0, 0) => "table.copy".to_string(),
+            Instr::TableCopy(dst, src) => format!("table.copy {} {}", table(dst), table(src)),
+            Instr::TableGrow(idx) => format!("table.grow {}", table(idx)),
+            Instr::TableSize(idx) => format!("table.size {}", table(idx)),
+            Instr::TableFill(idx) => format!("table.fill {}", table(idx)),
+            // Same as the short form:
+            Instr::Mem(..) => {
+                let s = instr.to_string();
+                s[1..s.len() - 1].to_string()
+            }
+            Instr::MemorySize => "memory.size".to_string(),
+            Instr::MemoryGrow => "memory.grow".to_string(),
+            Instr::MemoryInit(idx) => format!("memory.init {}", data(idx)),
+            Instr::DataDrop(idx) => format!("data.drop {}", data(idx)),
+            Instr::MemoryCopy => "memory.copy".to_string(),
+            Instr::MemoryFill => "memory.fill".to_string(),
+            Instr::I32Const(c) => format!("i32.const {}", c),
+            Instr::I64Const(c) => format!("i64.const {}", c),
+            Instr::F32Const(c) => format!("f32.const {}", f32_text(*c)),
+            Instr::F64Const(c) => format!("f64.const {}", f64_text(*c)),
+            Instr::Op(op) => op.name().to_string(),
+        }
+    }
+
+    /// The number of operands and results of a block.
+    fn block_arity(&self, bt: &BlockType) -> (usize, usize) {
+        match bt {
+            BlockType::Empty => (0, 0),
+            BlockType::Value(_) => (0, 1),
+            BlockType::Type(idx) => {
+                let (params, results) = &self.m.function_types[*idx as usize];
+                (params.len(), results.len())
+            }
+        }
+    }
+
+    /// The number of operands and results of an instruction that is not
+    /// structured, `labels` holds the arity of the enclosing blocks.
+    fn arity(&self, instr: &Instr, labels: &[usize]) -> (usize, usize) {
+        let label = |l: &u32| labels[labels.len() - 1 - *l as usize];
+        let call = |t: usize| {
+            let (params, results) = &self.m.function_types[t];
+            (params.len(), results.len())
+        };
+        match instr {
+            Instr::Unreachable | Instr::NOp | Instr::ElemDrop(_) | Instr::DataDrop(_) => (0, 0),
+            Instr::Br(l) => (label(l), 0),
+            Instr::BrIf(l) => (label(l) + 1, label(l)),
+            Instr::BrTable(_, l) => (label(l) + 1, 0),
+            Instr::Return => (labels[0], 0),
+            Instr::Call(idx) => call(self.m.function_type_index(*idx as usize).unwrap()),
+            Instr::CallIndirect(t, _) => {
+                let (params, results) = call(*t as usize);
+                (params + 1, results)
+            }
+            Instr::RefNull(_) | Instr::RefFunc(_) | Instr::LocalGet(_) | Instr::GlobalGet(_)
+                | Instr::TableSize(_) | Instr::MemorySize | Instr::I32Const(_) | Instr::I64Const(_)
+                | Instr::F32Const(_) | Instr::F64Const(_) => (0, 1),
+            Instr::Drop | Instr::LocalSet(_) | Instr::GlobalSet(_) => (1, 0),
+            Instr::RefIsNull | Instr::LocalTee(_) | Instr::TableGet(_) | Instr::MemoryGrow => (1, 1),
+            Instr::Select | Instr::SelectT(_) => (3, 1),
+            Instr::TableSet(_) => (2, 0),
+            Instr::TableGrow(_) => (2, 1),
+            Instr::TableInit(..) | Instr::TableCopy(..) | Instr::TableFill(_) | Instr::MemoryInit(_)
+                | Instr::MemoryCopy | Instr::MemoryFill => (3, 0),
+            Instr::Mem(op, _) if op.is_store() => (2, 0),
+            Instr::Mem(..) => (1, 1),
+            Instr::Op(op) => {
+                let opc = op.opcode();
+                let unary = matches!(opc, 0x45 | 0x50 | 0x67..=0x69 | 0x79..=0x7b | 0x8b..=0x91 | 0x99..=0x9f)
+                    || opc >= 0xa7;
+                (if unary { 1 } else { 2 }, 1)
+            }
+            Instr::Block(_) | Instr::Loop(_) | Instr::If(_) | Instr::Else | Instr::End => {
+                unreachable!("structured instruction {}", instr)
+            }
+        }
+    }
+
+    /// One instruction per line, the contents of blocks indented.
+    fn flat(&self, f: &Function) -> Lines {
+        let mut lines = vec![];
+        let mut depth = 0;
+        // Without the `end` of the function:
+        for instr in &f.body[..f.body.len() - 1] {
+            let pad = "  ".repeat(depth);
+            match instr {
+                Instr::Block(bt) | Instr::Loop(bt) | Instr::If(bt) => {
+                    let name = match instr {
+                        Instr::Block(_) => "block",
+                        Instr::Loop(_) => "loop",
+                        _ => "if"
+                    };
+                    lines.push(format!("{}{}{} ;; label = @{}", pad, name, self.block_type(bt), depth + 1));
+                    depth += 1;
+                }
+                Instr::Else => lines.push(format!("{}else", &pad[2..])),
+                Instr::End => {
+                    depth -= 1;
+                    lines.push(format!("{}end", &pad[2..]));
+                }
+                _ => lines.push(format!("{}{}", pad, self.instr(instr, f.index, depth))),
+            }
+        }
+        lines
+    }
+
+    /// The instructions from `pc` up to the `end` or `else` of the block,
+    /// folded into S-expressions. Returns whether an `else` ended them.
+    fn folded(&self, f: &Function, pc: &mut usize, labels: &mut Vec<usize>) -> (Lines, bool) {
+        let mut lines = vec![];
+        // Expressions whose value is still on the operand stack:
+        let mut stack: Vec<Lines> = vec![];
+        let flush = |stack: &mut Vec<Lines>, lines: &mut Lines| {
+            for expr in stack.drain(..) {
+                lines.extend(expr);
+            }
+        };
+        loop {
+            let instr = &f.body[*pc];
+            *pc += 1;
+            let depth = labels.len() - 1;
+            let (expr, results) = match instr {
+                Instr::End | Instr::Else => {
+                    flush(&mut stack, &mut lines);
+                    return (lines, *instr == Instr::Else);
+                }
+                Instr::Block(bt) | Instr::Loop(bt) => {
+                    let (params, results) = self.block_arity(bt);
+                    flush(&mut stack, &mut lines);
+                    let is_loop = matches!(instr, Instr::Loop(_));
+                    labels.push(if is_loop { params } else { results });
+                    let (body, _) = self.folded(f, pc, labels);
+                    labels.pop();
+                    let kind = if is_loop { "loop" } else { "block" };
+                    let mut expr = vec![format!("({}{} ;; label = @{}", kind, self.block_type(bt), depth + 1)];
+                    expr.extend(indent(body, 2));
+                    expr.push(")".to_string());
+                    (expr, if params == 0 { results } else { usize::MAX })
+                }
+                Instr::If(bt) => {
+                    let (params, results) = self.block_arity(bt);
+                    let cond = if params == 0 { stack.pop() } else { None };
+                    flush(&mut stack, &mut lines);
+                    labels.push(results);
+                    let (then, has_else) = self.folded(f, pc, labels);
+                    let otherwise = if has_else { Some(self.folded(f, pc, labels).0) } else { None };
+                    labels.pop();
+                    let mut expr = vec![format!("(if{} ;; label = @{}", self.block_type(bt), depth + 1)];
+                    expr.extend(indent(cond.unwrap_or_default(), 2));
+                    expr.push("  (then".to_string());
+                    expr.extend(indent(then, 4));
+                    expr.push("  )".to_string());
+                    if let Some(otherwise) = otherwise {
+                        expr.push("  (else".to_string());
+                        expr.extend(indent(otherwise, 4));
+                        expr.push("  )".to_string());
+                    }
+                    expr.push(")".to_string());
+                    (expr, if params == 0 { results } else { usize::MAX })
+                }
+                _ => {
+                    let (operands, results) = self.arity(instr, labels);
+                    let text = self.instr(instr, f.index, depth);
+                    if operands == 0 || operands > stack.len() {
+                        // Operands computed before are not shown.
+                        if operands != 0 {
+                            flush(&mut stack, &mut lines);
+                        }
+                        (vec![format!("({})", text)], results)
+                    } else {
+                        let mut expr = vec![format!("({}", text)];
+                        for operand in stack.split_off(stack.len() - operands) {
+                            expr.extend(indent(operand, 2));
+                        }
+                        expr.last_mut().unwrap().push(')');
+                        (expr, results)
+                    }
+                }
+            };
+            if results == 1 {
+                stack.push(expr);
+            } else {
+                flush(&mut stack, &mut lines);
+                lines.extend(expr);
+            }
+        }
+    }
+
+    fn function(&self, f: &mut std::fmt::Formatter<'_>, func: &Function) -> std::fmt::Result {
+        let t = self.m.function_type_index(func.index).unwrap();
+        write!(f, "  (func {} (type {}){}", self.definition(FUNCTION_NAMES, func.index),
+               self.index(TYPE_NAMES, t), self.signature(Some(func.index), &func.arguments, &func.returns))?;
+        let locals: Vec<Type> = func.locals.iter()
+            .flat_map(|(n, t)| std::iter::repeat_n(t.clone(), *n))
+            .collect();
+        let body = if f.alternate() {
+            self.folded(func, &mut 0, &mut vec![func.returns.len()]).0
+        } else {
+            self.flat(func)
+        };
+        if locals.is_empty() && body.is_empty() {
+            return writeln!(f, ")");
+        }
+        writeln!(f)?;
+        if !locals.is_empty() {
+            writeln!(f, "   {}", self.locals("local", Some(func.index), func.arguments.len(), &locals))?;
+        }
+        for line in body {
+            writeln!(f, "    {}", line)?;
+        }
+        writeln!(f, "  )")
+    }
+
+    /// A constant expression, without its `end`.
+    fn const_expr(&self, expr: &[Instr]) -> String {
+        self.instr(&expr[0], 0, 0)
+    }
+
+    fn limits(&self, (min, max): crate::Limits) -> String {
+        match max {
+            Some(max) => format!("{} {}", min, max),
+            None => min.to_string()
+        }
+    }
+
+    fn custom(&self, f: &mut std::fmt::Formatter<'_>, name: &str, bytes: &[u8]) -> std::fmt::Result {
+        if name == "producers" {
+            let r = &mut Reader { bytes, pos: 0 };
+            let fields = r.vec(|r| Ok((r.name()?, r.vec(|r| Ok((r.name()?, r.name()?)))?)));
+            if let (Ok(fields), true) = (fields, r.eof()) {
+                writeln!(f, "  (@producers")?;
+                for (field, values) in fields {
+                    for (value, version) in values {
+                        writeln!(f, "    ({} {} {})", field, string(value.as_bytes()), string(version.as_bytes()))?;
+                    }
+                }
+                return writeln!(f, "  )");
+            }
+        }
+        writeln!(f, "  (@custom {} {})", string(name.as_bytes()), string(bytes))
+    }
+}
+
+impl std::fmt::Display for Module {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let p = Printer { m: self, names: Names::parse(self) };
+        match p.names.module.as_deref().filter(|n| is_id(n)) {
+            Some(name) => writeln!(f, "(module ${}", name)?,
+            None => writeln!(f, "(module")?
+        }
+        for (i, (params, results)) in self.function_types.iter().enumerate() {
+            writeln!(f, "  (type {} (func{}))", p.definition(TYPE_NAMES, i), p.signature(None, params, results))?;
+        }
+
+        let (mut funcs, mut tables, mut memories, mut globals) = (0, 0, 0, 0);
+        for import in &self.imports {
+            let desc = match &import.desc {
+                ImportDesc::Func(t) => {
+                    funcs += 1;
+                    format!("func {} (type {})", p.definition(FUNCTION_NAMES, funcs - 1), p.index(TYPE_NAMES, *t))
+                }
+                ImportDesc::Table(t, limits) => {
+                    tables += 1;
+                    format!("table {} {} {}", p.definition(TABLE_NAMES, tables - 1), p.limits(*limits), t)
+                }
+                ImportDesc::Memory(limits) => {
+                    memories += 1;
+                    format!("memory {} {}", p.definition(MEMORY_NAMES, memories - 1), p.limits(*limits))
+                }
+                ImportDesc::Global(t, mutable) => {
+                    globals += 1;
+                    let t = if *mutable { format!("(mut {})", t) } else { t.to_string() };
+                    format!("global {} {}", p.definition(GLOBAL_NAMES, globals - 1), t)
+                }
+            };
+            writeln!(f, "  (import {} {} ({}))", string(import.module.as_bytes()), string(import.name.as_bytes()), desc)?;
+        }
+
+        for func in &self.functions {
+            p.function(f, func)?;
+        }
+        for (i, (t, limits)) in self.tables.iter().enumerate() {
+            writeln!(f, "  (table {} {} {})", p.definition(TABLE_NAMES, tables + i), p.limits(*limits), t)?;
+        }
+        for (i, limits) in self.memory_ranges.iter().enumerate() {
+            writeln!(f, "  (memory {} {})", p.definition(MEMORY_NAMES, memories + i), p.limits(*limits))?;
+        }
+        for (i, (t, mutable, expr)) in self.globals.iter().enumerate() {
+            let t = if *mutable { format!("(mut {})", t) } else { t.to_string() };
+            writeln!(f, "  (global {} {} {})", p.definition(GLOBAL_NAMES, globals + i), t, p.const_expr(expr))?;
+        }
+        for (name, kind, idx) in &self.exports {
+            let (kind, map) = match kind {
+                Export::Func => ("func", FUNCTION_NAMES),
+                Export::Table => ("table", TABLE_NAMES),
+                Export::Memory => ("memory", MEMORY_NAMES),
+                Export::Global => ("global", GLOBAL_NAMES),
+            };
+            writeln!(f, "  (export {} ({} {}))", string(name.as_bytes()), kind, p.index(map, *idx))?;
+        }
+        if let Some(start) = self.start {
+            writeln!(f, "  (start {})", p.index(FUNCTION_NAMES, start))?;
+        }
+
+        for (i, e) in self.elements.iter().enumerate() {
+            write!(f, "  (elem {}", p.definition(ELEM_NAMES, i))?;
+            match &e.mode {
+                ElementMode::Active(0, offset) => write!(f, " ({})", p.const_expr(offset))?,
+                ElementMode::Active(table, offset) => {
+                    write!(f, " (table {}) ({})", p.index(TABLE_NAMES, *table), p.const_expr(offset))?
+                }
+                ElementMode::Passive => {}
+                ElementMode::Declarative => write!(f, " declare")?,
+            }
+            match &e.init {
+                ElementInit::Funcs(funcs) => {
+                    write!(f, " func")?;
+                    for func in funcs {
+                        write!(f, " {}", p.index(FUNCTION_NAMES, *func))?;
+                    }
+                }
+                ElementInit::Exprs(exprs) => {
+                    write!(f, " {}", e.typ)?;
+                    for expr in exprs {
+                        write!(f, " ({})", p.const_expr(expr))?;
+                    }
+                }
+            }
+            writeln!(f, ")")?;
+        }
+        for (i, d) in self.data.iter().enumerate() {
+            write!(f, "  (data {}", p.definition(DATA_NAMES, i))?;
+            match &d.mode {
+                DataMode::Active(0, offset) => write!(f, " ({})", p.const_expr(offset))?,
+                DataMode::Active(mem, offset) => {
+                    write!(f, " (memory {}) ({})", p.index(MEMORY_NAMES, *mem), p.const_expr(offset))?
+                }
+                DataMode::Passive => {}
+            }
+            writeln!(f, " {})", string(&d.bytes))?;
+        }
+
+        for (_, name, bytes) in &self.custom_sections {
+            if name != "name" {
+                p.custom(f, name, bytes)?;
+            }
+        }
+        write!(f, ")")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{code, module, name, vec};
+
+    #[test]
+    fn id_module() {
+        let m = Module::decode(&std::fs::read("./tests/id.wasm").unwrap()).unwrap();
+        let expected = std::fs::read_to_string("./tests/id.wat").unwrap();
+        assert_eq!(format!("{}\n", m), expected);
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(f64_text(3.0), "0x1.8p+1 (;=3;)");
+        assert_eq!(f64_text(-0.1), "-0x1.999999999999ap-4 (;=-0.1;)");
+        assert_eq!(f32_text(1.0), "0x1p+0 (;=1;)");
+        assert_eq!(f32_text(f32::from_bits(1)), "0x1.p-149 (;=0.000000000000000000000000000000000000000000001;)");
+        assert_eq!(f32_text(f32::from_bits(3)), "0x1.8p-148 (;=0.000000000000000000000000000000000000000000004;)");
+        assert!(f64_text(-f64::from_bits(1 << 51)).starts_with("-0x1.p-1023 (;=-0.0"));
+        assert_eq!(f32_text(-0.0), "-0x0p+0 (;=-0;)");
+        assert_eq!(f64_text(f64::NEG_INFINITY), "-inf");
+        assert_eq!(f32_text(f32::NAN), "nan");
+        assert_eq!(f32_text(f32::from_bits(0x7f800001)), "nan:0x1");
+    }
+
+    #[test]
+    fn blocks() {
+        let body = [
+            0x02, 0x40,                         // block
+            0x20, 0, 0x45, 0x0d, 0,             // br_if 0 (n == 0)
+            0x20, 0, 0x04, 0x7f,                // if (result i32)
+            0x41, 1, 0x05, 0x41, 2, 0x0b,       // 1 else 2
+            0x41, 3, 0x6a, 0x21, 0,             // n = _ + 3
+            0x0b,
+            0x20, 0, 0x44, 0, 0, 0, 0, 0, 0, 0xf8, 0x3f, // f64.const 1.5
+            0x1a, 0x0b,                         // drop
+        ];
+        let bytes = module(&[
+            (1, vec(&[vec![0x60, 1, 0x7f, 1, 0x7f]])),
+            (3, vec(&[vec![0]])),
+            (4, vec(&[vec![0x70, 0x00, 1]])),
+            (5, vec(&[vec![0x01, 1, 2]])),
+            (7, vec(&[[name("f"), vec![0x00, 0]].concat()])),
+            (9, vec(&[vec![0x00, 0x41, 0, 0x0b, 1, 0]])),
+            (10, vec(&[code(&[1, 1, 0x7e], &body)])),
+            (11, vec(&[[vec![0x00, 0x41, 8, 0x0b], name("a\"\0\n")].concat()])),
+        ]);
+        let m = Module::decode(&bytes).unwrap();
+        assert_eq!(m.to_string(), r#"(module
+  (type (;0;) (func (param i32) (result i32)))
+  (func (;0;) (type 0) (param i32) (result i32)
+    (local i64)
+    block ;; label = @1
+      local.get 0
+      i32.eqz
+      br_if 0 (;@1;)
+      local.get 0
+      if (result i32) ;; label = @2
+        i32.const 1
+      else
+        i32.const 2
+      end
+      i32.const 3
+      i32.add
+      local.set 0
+    end
+    local.get 0
+    f64.const 0x1.8p+0 (;=1.5;)
+    drop
+  )
+  (table (;0;) 1 funcref)
+  (memory (;0;) 1 2)
+  (export "f" (func 0))
+  (elem (;0;) (i32.const 0) func 0)
+  (data (;0;) (i32.const 8) "a\"\00\n")
+)"#);
+        assert_eq!(format!("{:#}", m), r#"(module
+  (type (;0;) (func (param i32) (result i32)))
+  (func (;0;) (type 0) (param i32) (result i32)
+    (local i64)
+    (block ;; label = @1
+      (br_if 0 (;@1;)
+        (i32.eqz
+          (local.get 0)))
+      (local.set 0
+        (i32.add
+          (if (result i32) ;; label = @2
+            (local.get 0)
+            (then
+              (i32.const 1)
+            )
+            (else
+              (i32.const 2)
+            )
+          )
+          (i32.const 3)))
+    )
+    (local.get 0)
+    (drop
+      (f64.const 0x1.8p+0 (;=1.5;)))
+  )
+  (table (;0;) 1 funcref)
+  (memory (;0;) 1 2)
+  (export "f" (func 0))
+  (elem (;0;) (i32.const 0) func 0)
+  (data (;0;) (i32.const 8) "a\"\00\n")
+)"#);
+    }
+}